@@ -1,14 +1,31 @@
-use super::KernelInterface;
+use super::{FirewallRule, KernelInterface, KernelInterfaceError, ASYNC_COMMAND_TIMEOUT_SECS};
 
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::process::Output;
 use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::Future;
 
 use regex::Regex;
 
 use failure::Error;
 
-#[derive(Debug, Eq, PartialEq)]
+/// Which firewall tooling the traffic counters are built with, older images use ipset with the
+/// iptables SET target while fw4 based images only ship nftables
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CounterBackend {
+    Ipset,
+    Nftables,
+}
+
+lazy_static! {
+    static ref COUNTER_BACKEND: Mutex<CounterBackend> = Mutex::new(CounterBackend::Ipset);
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum FilterTarget {
     Input,
     Output,
@@ -40,6 +57,23 @@ impl FilterTarget {
             &FilterTarget::ForwardOutput | &FilterTarget::ForwardInput => "FORWARD",
         }
     }
+
+    /// The netfilter hook the nftables counter chain attaches to
+    pub fn hook(&self) -> &str {
+        match self {
+            &FilterTarget::Input => "input",
+            &FilterTarget::Output => "output",
+            &FilterTarget::ForwardOutput | &FilterTarget::ForwardInput => "forward",
+        }
+    }
+
+    /// The nftables meta key matching the same interface as `interface()` does for ipset
+    pub fn iface_key(&self) -> &str {
+        match self {
+            &FilterTarget::Input | &FilterTarget::ForwardInput => "iifname",
+            &FilterTarget::Output | &FilterTarget::ForwardOutput => "oifname",
+        }
+    }
 }
 
 #[test]
@@ -66,6 +100,24 @@ fn test_filter_table_table() {
     assert_eq!(FilterTarget::ForwardInput.table(), "FORWARD");
 }
 
+#[test]
+fn test_filter_table_hook() {
+    assert_eq!(FilterTarget::Input.hook(), "input");
+    assert_eq!(FilterTarget::Output.hook(), "output");
+    assert_eq!(FilterTarget::ForwardOutput.hook(), "forward");
+    assert_eq!(FilterTarget::ForwardInput.hook(), "forward");
+    assert_eq!(FilterTarget::Input.iface_key(), "iifname");
+    assert_eq!(FilterTarget::ForwardOutput.iface_key(), "oifname");
+}
+
+fn ipset_output<S: AsRef<str>>(args: &[S], output: Output) -> Result<Output, Error> {
+    if !output.status.success() {
+        let args: Vec<&str> = args.iter().map(|a| a.as_ref()).collect();
+        return Err(KernelInterfaceError::from_output("ipset", &args, &output).into());
+    }
+    Ok(output)
+}
+
 fn parse_ipset(input: &str) -> Result<HashMap<(IpAddr, String), u64>, Error> {
     lazy_static! {
         static ref RE: Regex =
@@ -119,7 +171,59 @@ add zxcv 1234:5678:9801:2345:6789:0123:4567:8902,wg0 packets 123456789 bytes 987
 }
 
 impl KernelInterface {
+    /// Picks the counter backend for this system, ipset is preferred whenever it's installed
+    /// so existing routers keep their current firewall setup
+    pub fn detect_counter_backend(&self) -> CounterBackend {
+        if let Ok(output) = self.run_command("ipset", &["version"]) {
+            if output.status.success() {
+                return CounterBackend::Ipset;
+            }
+        }
+        match self.run_command("nft", &["--version"]) {
+            Ok(ref output) if output.status.success() => CounterBackend::Nftables,
+            _ => CounterBackend::Ipset,
+        }
+    }
+
+    /// Sets the backend used by `init_counter` and `read_counters`, this should be done once at
+    /// startup before any counters are created
+    pub fn set_counter_backend(&self, backend: CounterBackend) {
+        *COUNTER_BACKEND.lock().unwrap() = backend;
+    }
+
+    pub fn get_counter_backend(&self) -> CounterBackend {
+        *COUNTER_BACKEND.lock().unwrap()
+    }
+
     pub fn init_counter(&self, target: &FilterTarget) -> Result<(), Error> {
+        match self.get_counter_backend() {
+            CounterBackend::Ipset => self.init_ipset_counter(target),
+            CounterBackend::Nftables => self.init_nft_counter(target),
+        }
+    }
+
+    pub fn read_counters(
+        &self,
+        target: &FilterTarget,
+    ) -> Result<HashMap<(IpAddr, String), u64>, Error> {
+        match self.get_counter_backend() {
+            CounterBackend::Ipset => self.read_ipset_counters(target),
+            CounterBackend::Nftables => self.read_nft_counters(target),
+        }
+    }
+
+    /// Same as `read_counters` but runs the commands in the background, for use inside actors
+    pub fn read_counters_async(
+        &'static self,
+        target: FilterTarget,
+    ) -> Box<Future<Item = HashMap<(IpAddr, String), u64>, Error = Error>> {
+        match self.get_counter_backend() {
+            CounterBackend::Ipset => self.read_ipset_counters_async(target),
+            CounterBackend::Nftables => self.read_nft_counters_async(target),
+        }
+    }
+
+    fn init_ipset_counter(&self, target: &FilterTarget) -> Result<(), Error> {
         self.run_command(
             "ipset",
             &[
@@ -131,60 +235,200 @@ impl KernelInterface {
                 "counters",
             ],
         )?;
-        self.add_iptables_rule(
-            "ip6tables",
-            &[
-                "-w",
-                "-A",
+        let match_set = format!("dst,{}", target.interface());
+        self.set_firewall_rules(
+            &format!("counter_{}", target.set_name()),
+            vec![FirewallRule::new(
+                "ip6tables",
+                "filter",
                 target.table(),
-                "-m",
-                "set",
-                "!",
-                "--match-set",
-                target.set_name(),
-                &format!("dst,{}", target.interface()),
-                "-j",
-                "SET",
-                "--add-set",
-                target.set_name(),
-                &format!("dst,{}", target.interface()),
-            ],
+                &[
+                    "-m",
+                    "set",
+                    "!",
+                    "--match-set",
+                    target.set_name(),
+                    &match_set,
+                    "-j",
+                    "SET",
+                    "--add-set",
+                    target.set_name(),
+                    &match_set,
+                ],
+            )],
         )?;
         Ok(())
     }
 
-    pub fn read_counters(
+    fn run_ipset(&self, args: &[&str]) -> Result<Output, Error> {
+        let output = self.run_command("ipset", args)?;
+        ipset_output(args, output)
+    }
+
+    fn run_ipset_async(&self, args: &[&str]) -> Box<Future<Item = Output, Error = Error>> {
+        let timeout = Duration::from_secs(ASYNC_COMMAND_TIMEOUT_SECS);
+        let owned: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        Box::new(
+            self.run_command_async("ipset", args, timeout)
+                .and_then(move |output| ipset_output(&owned, output)),
+        )
+    }
+
+    /// Swaps the counted set out for an empty one and reads it. The counts are only returned
+    /// once the swapped out set is destroyed, a read that fails part way leaves them in the tmp
+    /// set and the next read swaps them back in so they're counted exactly once
+    fn read_ipset_counters(
         &self,
         target: &FilterTarget,
     ) -> Result<HashMap<(IpAddr, String), u64>, Error> {
-        self.run_command(
-            "ipset",
-            &[
+        let tmp = format!("tmp_{}", target.set_name());
+        self.run_ipset(&[
+            "create",
+            &tmp,
+            "hash:net,iface",
+            "family",
+            "inet6",
+            "counters",
+            "-exist",
+        ])?;
+        self.run_ipset(&["swap", &tmp, target.set_name()])?;
+
+        let output = self.run_ipset(&["save", &tmp])?;
+        let res = parse_ipset(&String::from_utf8(output.stdout)?)?;
+        trace!("ipset parsed into {:?}", res);
+
+        self.run_ipset(&["destroy", &tmp])?;
+        Ok(res)
+    }
+
+    fn read_ipset_counters_async(
+        &'static self,
+        target: FilterTarget,
+    ) -> Box<Future<Item = HashMap<(IpAddr, String), u64>, Error = Error>> {
+        let tmp = format!("tmp_{}", target.set_name());
+        let (swap_tmp, save_tmp, destroy_tmp) = (tmp.clone(), tmp.clone(), tmp.clone());
+
+        // same steps as read_ipset_counters, each one has to succeed before the next runs
+        Box::new(
+            self.run_ipset_async(&[
                 "create",
-                &format!("tmp_{}", target.set_name()),
+                &tmp,
                 "hash:net,iface",
                 "family",
                 "inet6",
                 "counters",
-            ],
-        )?;
+                "-exist",
+            ]).and_then(move |_| self.run_ipset_async(&["swap", &swap_tmp, target.set_name()]))
+            .and_then(move |_| self.run_ipset_async(&["save", &save_tmp]))
+            .and_then(|output| -> Result<_, Error> {
+                let res = parse_ipset(&String::from_utf8(output.stdout)?)?;
+                trace!("ipset parsed into {:?}", res);
+                Ok(res)
+            }).and_then(move |res| {
+                self.run_ipset_async(&["destroy", &destroy_tmp])
+                    .map(|_| res)
+            }),
+        )
+    }
+}
 
-        self.run_command(
-            "ipset",
-            &[
-                "swap",
-                &format!("tmp_{}", target.set_name()),
-                target.set_name(),
-            ],
-        )?;
+#[test]
+fn test_read_counters_async() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
 
-        let output = self.run_command("ipset", &["save", &format!("tmp_{}", target.set_name())])?;
-        let res = parse_ipset(&String::from_utf8(output.stdout)?);
-        trace!("ipset parsed into {:?}", res);
+    use KI;
 
-        self.run_command("ipset", &["destroy", &format!("tmp_{}", target.set_name())])?;
-        res
-    }
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        assert_eq!(program, "ipset");
+        let stdout = match counter {
+            1 => {
+                assert_eq!(
+                    args,
+                    vec![
+                        "create",
+                        "tmp_rita_fwd_output",
+                        "hash:net,iface",
+                        "family",
+                        "inet6",
+                        "counters",
+                        "-exist",
+                    ]
+                );
+                ""
+            }
+            2 => {
+                assert_eq!(args, vec!["swap", "tmp_rita_fwd_output", "rita_fwd_output"]);
+                ""
+            }
+            3 => {
+                assert_eq!(args, vec!["save", "tmp_rita_fwd_output"]);
+                "add tmp_rita_fwd_output fd00::2,wg1 packets 10 bytes 1000\n"
+            }
+            4 => {
+                assert_eq!(args, vec!["destroy", "tmp_rita_fwd_output"]);
+                ""
+            }
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        };
+        Ok(Output {
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+
+    KI.set_counter_backend(CounterBackend::Ipset);
+    let result = KI
+        .read_counters_async(FilterTarget::ForwardOutput)
+        .wait()
+        .expect("Unable to read values");
+    assert_eq!(
+        result.get(&("fd00::2".parse().unwrap(), "wg1".to_string())),
+        Some(&1400)
+    );
+}
+
+#[test]
+fn test_read_counters_failed_destroy() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    use KI;
+
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        assert_eq!(program, "ipset");
+        let (stdout, status) = match counter {
+            1 | 2 => ("", 0),
+            3 => (
+                "add tmp_rita_fwd_input fd00::2,wg1 packets 10 bytes 1000\n",
+                0,
+            ),
+            4 => {
+                assert_eq!(args, vec!["destroy", "tmp_rita_fwd_input"]);
+                ("", 1)
+            }
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        };
+        Ok(Output {
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(status << 8),
+        })
+    }));
+
+    // the counts stay in the tmp set for the next read to swap back in, returning them now
+    // would count them twice
+    KI.set_counter_backend(CounterBackend::Ipset);
+    let result = KI.read_counters_async(FilterTarget::ForwardInput).wait();
+    assert!(result.is_err());
 }
 
 #[test]
@@ -195,6 +439,24 @@ fn test_init_counter() {
 
     use KI;
 
+    let rule = FirewallRule::new(
+        "ip6tables",
+        "filter",
+        "INPUT",
+        &[
+            "-m",
+            "set",
+            "!",
+            "--match-set",
+            "rita_input",
+            "dst,src",
+            "-j",
+            "SET",
+            "--add-set",
+            "rita_input",
+            "dst,src",
+        ],
+    );
     let mut counter = 0;
 
     KI.set_mock(Box::new(move |program, args| {
@@ -220,12 +482,38 @@ fn test_init_counter() {
                 })
             }
             2 => {
+                // the counter rule is already in place from an earlier run
+                assert_eq!(program, "ip6tables");
+                assert_eq!(args, vec!["-w", "-t", "filter", "-S", "RITA_INPUT"]);
+                Ok(Output {
+                    stdout: format!(
+                        "-N RITA_INPUT\n-A RITA_INPUT -m set ! --match-set rita_input dst,src \
+                         -m comment --comment \"{}\" -j SET --add-set rita_input dst,src\n",
+                        rule.tag()
+                    ).into_bytes(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            3 => {
+                assert_eq!(program, "ip6tables");
+                assert_eq!(args, vec!["-w", "-t", "filter", "-S", "INPUT"]);
+                Ok(Output {
+                    stdout: b"-P INPUT ACCEPT\n-A INPUT -j RITA_INPUT\n".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            4 => {
+                // no copy left in the builtin chain by an older rita
                 assert_eq!(program, "ip6tables");
                 assert_eq!(
                     args,
                     vec![
                         "-w",
-                        "-C",
+                        "-t",
+                        "filter",
+                        "-D",
                         "INPUT",
                         "-m",
                         "set",
@@ -242,8 +530,9 @@ fn test_init_counter() {
                 );
                 Ok(Output {
                     stdout: b"".to_vec(),
-                    stderr: b"".to_vec(),
-                    status: ExitStatus::from_raw(0),
+                    stderr: b"iptables: Bad rule (does a matching rule exist in that chain?).\n"
+                        .to_vec(),
+                    status: ExitStatus::from_raw(256),
                 })
             }
 
@@ -278,6 +567,7 @@ fn test_read_counters() {
                         "family",
                         "inet6",
                         "counters",
+                        "-exist",
                     ]
                 );
                 Ok(Output {
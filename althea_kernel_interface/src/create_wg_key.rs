@@ -17,6 +17,10 @@ pub struct WgKeypair {
 
 impl KernelInterface {
     pub fn create_wg_key(&self, path: &Path, private_key: &String) -> Result<(), Error> {
+        if self.dry_run() {
+            info!("Dry run, not overwriting the private key file {:?}", path);
+            return Ok(());
+        }
         trace!("Overwriting old private key file");
         let mut priv_key_file = File::create(path)?;
         write!(priv_key_file, "{}", private_key)?;
@@ -4,12 +4,17 @@ use failure::Error;
 
 impl KernelInterface {
     pub fn delete_tunnel(&self, interface: &String) -> Result<(), Error> {
-        let output = self.run_command("ip", &["link", "del", &interface])?;
+        if let Some(netlink) = self.netlink() {
+            return netlink.del_link(interface).map_err(|e| {
+                KernelInterfaceError::from_netlink("received error deleting wireguard interface", e)
+                    .into()
+            });
+        }
+
+        let args: &[&str] = &["link", "del", &interface];
+        let output = self.run_command("ip", args)?;
         if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error deleting wireguard interface: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+            return Err(KernelInterfaceError::from_output("ip", args, &output).into());
         }
         Ok(())
     }
@@ -0,0 +1,385 @@
+//! Dry run mode, read only commands like `ip route list` or `uci show` are run for real so that
+//! rita can make the same decisions it normally would, while anything that would change the
+//! system is only appended to an ordered plan for an operator to review.
+
+use super::{CommandFuture, CommandRunner, KernelInterface, LinuxCommandRunner};
+
+use std::fmt;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::future;
+
+use failure::Error;
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // rita, clu and settings each hold their own kernel interface, they share one plan
+    static ref PLAN: Mutex<Vec<PlannedCommand>> = Mutex::new(Vec::new());
+}
+
+/// A command that would have been run if this wasn't a dry run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    /// What would have been written to the command's stdin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<String>,
+}
+
+impl fmt::Display for PlannedCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in self.args.iter() {
+            if arg.is_empty() || arg.contains(char::is_whitespace) || arg.contains('\'') {
+                write!(f, " '{}'", arg.replace('\'', "'\\''"))?;
+            } else {
+                write!(f, " {}", arg)?;
+            }
+        }
+        if let Some(ref input) = self.input {
+            write!(f, " <<'EOF'\n{}", input)?;
+            if !input.ends_with('\n') {
+                writeln!(f)?;
+            }
+            write!(f, "EOF")?;
+        }
+        Ok(())
+    }
+}
+
+/// Makes every kernel interface created after this call a dry run one, this has to happen
+/// before the first use of KI
+pub fn enable_dry_run() {
+    DRY_RUN.store(true, Ordering::SeqCst);
+}
+
+pub fn dry_run_enabled() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+}
+
+/// Every command planned so far, in the order they would have run
+pub fn dry_run_plan() -> Vec<PlannedCommand> {
+    PLAN.lock().unwrap().clone()
+}
+
+pub fn plan_command(program: &str, args: &[&str]) {
+    plan(program, args, None)
+}
+
+fn plan(program: &str, args: &[&str], input: Option<&str>) {
+    let command = PlannedCommand {
+        program: program.to_string(),
+        args: args.iter().map(|a| a.to_string()).collect(),
+        input: input.map(|i| i.to_string()),
+    };
+    info!("Dry run, planned `{}`", command);
+    PLAN.lock().unwrap().push(command);
+}
+
+/// The first argument that isn't an option, for `ip -6 route list` this is `route`
+fn subcommand<'a>(args: &[&'a str]) -> Option<&'a str> {
+    args.iter().cloned().find(|a| !a.starts_with('-'))
+}
+
+/// True for commands that only read state, unknown programs are assumed to make changes
+pub fn is_read_only(program: &str, args: &[&str]) -> bool {
+    match program {
+        "ip" => {
+            let mut words = args.iter().cloned().filter(|a| !a.starts_with('-')).skip(1);
+            match words.next() {
+                None => true,
+                Some(command) => ["show", "list", "lst", "get"].contains(&command),
+            }
+        }
+        "wg" => match subcommand(args) {
+            None => true,
+            Some(command) => ["show", "showconf", "genkey", "genpsk", "pubkey"].contains(&command),
+        },
+        "uci" => match subcommand(args) {
+            Some(command) => ["show", "get", "export", "changes"].contains(&command),
+            None => false,
+        },
+        "iptables" | "ip6tables" | "ebtables" => args
+            .iter()
+            .any(|a| ["-C", "--check", "-L", "--list", "-S", "--list-rules"].contains(a)),
+        "ipset" => match subcommand(args) {
+            Some(command) => ["list", "save", "test", "version"].contains(&command),
+            None => false,
+        },
+        "nft" => {
+            args.contains(&"--version")
+                || args
+                    .iter()
+                    .find(|a| !a.starts_with('-'))
+                    .map(|a| a.starts_with("list"))
+                    .unwrap_or(false)
+        }
+        "tc" => {
+            let mut words = args.iter().cloned().filter(|a| !a.starts_with('-')).skip(1);
+            match words.next() {
+                None => true,
+                Some(command) => ["show", "list", "ls"].contains(&command),
+            }
+        }
+        "ubus" => match subcommand(args) {
+            Some("list") => true,
+            Some("call") => {
+                let reads = ["get", "status", "dump", "info", "board", "assoclist", "scan"];
+                args.len() > 2 && reads.contains(&args[2])
+            }
+            _ => false,
+        },
+        "cat" | "ping" | "ping6" | "sync" => true,
+        _ => false,
+    }
+}
+
+/// Runs read only commands with LinuxCommandRunner and plans everything else, planned
+/// commands appear to succeed with no output. Code that repeats a change until it fails has to
+/// check `dry_run()` or it never stops
+pub struct DryRunCommandRunner {
+    runner: Box<CommandRunner + Sync>,
+}
+
+impl DryRunCommandRunner {
+    pub fn new() -> DryRunCommandRunner {
+        DryRunCommandRunner::with_runner(Box::new(LinuxCommandRunner {}))
+    }
+
+    /// Runs the read only commands with `runner` instead, for testing against a fake system
+    pub fn with_runner(runner: Box<CommandRunner + Sync>) -> DryRunCommandRunner {
+        DryRunCommandRunner { runner }
+    }
+}
+
+impl CommandRunner for DryRunCommandRunner {
+    fn run_command(&self, program: &str, args: &[&str]) -> Result<Output, Error> {
+        if is_read_only(program, args) {
+            return self.runner.run_command(program, args);
+        }
+        plan_command(program, args);
+        Ok(Output {
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            status: ExitStatus::from_raw(0),
+        })
+    }
+
+    fn run_command_async(&self, program: &str, args: &[&str], timeout: Duration) -> CommandFuture {
+        if is_read_only(program, args) {
+            return self.runner.run_command_async(program, args, timeout);
+        }
+        Box::new(future::result(self.run_command(program, args)))
+    }
+
+    fn run_command_with_input(
+        &self,
+        program: &str,
+        args: &[&str],
+        input: &str,
+    ) -> Result<Output, Error> {
+        if is_read_only(program, args) {
+            return self.runner.run_command_with_input(program, args, input);
+        }
+        plan(program, args, Some(input));
+        Ok(Output {
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            status: ExitStatus::from_raw(0),
+        })
+    }
+
+    /// Mocks the runner the read only commands go to, planned commands still never reach it
+    fn set_mock(&self, mock: Box<FnMut(String, Vec<String>) -> Result<Output, Error> + Send>) {
+        self.runner.set_mock(mock)
+    }
+}
+
+impl KernelInterface for DryRunCommandRunner {
+    fn dry_run(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn test_is_read_only() {
+    assert!(is_read_only("ip", &["route", "list", "default"]));
+    assert!(is_read_only("ip", &["-6", "route", "show"]));
+    assert!(is_read_only("ip", &["neighbor"]));
+    assert!(is_read_only("ip", &["addr", "show", "dev", "wg0"]));
+    assert!(!is_read_only(
+        "ip",
+        &["route", "add", "default", "via", "1.2.3.4"]
+    ));
+    assert!(!is_read_only("ip", &["link", "set", "dev", "wg0", "up"]));
+
+    assert!(is_read_only("wg", &["show", "wg_exit", "peers"]));
+    assert!(!is_read_only(
+        "wg",
+        &["set", "wg0", "peer", "abc", "remove"]
+    ));
+
+    assert!(is_read_only("uci", &["show", "network"]));
+    assert!(is_read_only("uci", &["get", "network.lan.ipaddr"]));
+    assert!(!is_read_only(
+        "uci",
+        &["set", "network.lan.ipaddr=192.168.10.1"]
+    ));
+    assert!(!is_read_only("uci", &["commit", "network"]));
+
+    assert!(is_read_only(
+        "iptables",
+        &["-w", "-t", "nat", "-C", "POSTROUTING", "-j", "MASQUERADE"]
+    ));
+    assert!(!is_read_only(
+        "iptables",
+        &["-w", "-t", "nat", "-A", "POSTROUTING", "-j", "MASQUERADE"]
+    ));
+
+    assert!(is_read_only("ipset", &["save", "rita_input"]));
+    assert!(!is_read_only("ipset", &["create", "rita_input"]));
+    assert!(is_read_only(
+        "nft",
+        &["-j", "list", "set", "ip6", "rita_input", "destinations"]
+    ));
+    assert!(!is_read_only("nft", &["-j", "reset", "counters"]));
+
+    assert!(is_read_only("tc", &["class", "show", "dev", "wg_exit"]));
+    assert!(!is_read_only(
+        "tc",
+        &["class", "del", "dev", "wg_exit", "classid", "1:5"]
+    ));
+
+    assert!(is_read_only("ubus", &["call", "uci", "get", "{}"]));
+    assert!(!is_read_only("ubus", &["call", "uci", "set", "{}"]));
+    assert!(is_read_only(
+        "ubus",
+        &["call", "iwinfo", "assoclist", "{\"device\":\"wlan0\"}"]
+    ));
+
+    assert!(!is_read_only("/etc/init.d/network", &["restart"]));
+    assert!(!is_read_only("wifi", &[]));
+}
+
+#[test]
+fn test_planned_command_display() {
+    let command = PlannedCommand {
+        program: "uci".to_string(),
+        args: vec![
+            "set".to_string(),
+            "wireless.default_radio0.ssid=My Network".to_string(),
+        ],
+        input: None,
+    };
+    assert_eq!(
+        command.to_string(),
+        "uci set 'wireless.default_radio0.ssid=My Network'"
+    );
+
+    let command = PlannedCommand {
+        program: "uci".to_string(),
+        args: vec!["batch".to_string()],
+        input: Some("set 'network.lan.ifname=eth0.1'\ndelete 'network.rita_eth1'\n".to_string()),
+    };
+    assert_eq!(
+        command.to_string(),
+        "uci batch <<'EOF'\nset 'network.lan.ifname=eth0.1'\ndelete 'network.rita_eth1'\nEOF"
+    );
+}
+
+#[test]
+fn test_dry_run_runner() {
+    let runner = DryRunCommandRunner::new();
+    let output = runner
+        .run_command("wg", &["set", "wg_dry_run_test", "listen-port", "60000"])
+        .unwrap();
+    assert!(output.status.success());
+    assert!(dry_run_plan().contains(&PlannedCommand {
+        program: "wg".to_string(),
+        args: vec![
+            "set".to_string(),
+            "wg_dry_run_test".to_string(),
+            "listen-port".to_string(),
+            "60000".to_string(),
+        ],
+        input: None,
+    }));
+}
+
+#[test]
+fn test_dry_run_set_mock() {
+    use super::TestCommandRunner;
+    use std::sync::Arc;
+
+    let system = TestCommandRunner {
+        run_command: Arc::new(Mutex::new(Box::new(|_program, _args| {
+            panic!("mock not set");
+        }))),
+    };
+    let dry_run = DryRunCommandRunner::with_runner(Box::new(system));
+    dry_run.set_mock(Box::new(|program, args| {
+        assert_eq!(program, "ip");
+        assert_eq!(args, vec!["route", "list"]);
+        Ok(Output {
+            stdout: b"default via 10.0.0.1 dev eth1\n".to_vec(),
+            stderr: Vec::new(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+    let output = dry_run.run_command("ip", &["route", "list"]).unwrap();
+    assert_eq!(output.stdout, b"default via 10.0.0.1 dev eth1\n");
+    // changes are planned rather than handed to the mock
+    let output = dry_run
+        .run_command("ip", &["route", "add", "default", "via", "10.0.0.2"])
+        .unwrap();
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_dry_run_startup() {
+    use super::{FilterTarget, TestCommandRunner};
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    // rita's chains are in place and empty, so every rule is planned
+    let system = TestCommandRunner {
+        run_command: Arc::new(Mutex::new(Box::new(|_program, args: Vec<String>| {
+            let stdout = if args.len() > 4 && args[3] == "-S" {
+                format!("-N {}\n", args[4])
+            } else {
+                String::new()
+            };
+            Ok(Output {
+                stdout: stdout.into_bytes(),
+                stderr: Vec::new(),
+                status: ExitStatus::from_raw(0),
+            })
+        }))),
+    };
+    let dry_run = DryRunCommandRunner::with_runner(Box::new(system));
+    {
+        let ki: &KernelInterface = &dry_run;
+        ki.init_counter(&FilterTarget::ForwardOutput).unwrap();
+        ki.setup_nat("eth_dry_run").unwrap();
+    }
+
+    // other tests plan into the same plan, these are the commands for our rules
+    let ours: Vec<String> = dry_run_plan()
+        .iter()
+        .filter(|c| {
+            c.args
+                .iter()
+                .any(|a| a == "rita_fwd_output" || a == "eth_dry_run")
+        }).map(|c| c.to_string())
+        .collect();
+    // the set, then a legacy delete and an insert for each of the four rules
+    assert_eq!(ours.len(), 9, "{:#?}", ours);
+    let unique: HashSet<&String> = ours.iter().collect();
+    assert_eq!(unique.len(), ours.len(), "Planned twice in {:#?}", ours);
+}
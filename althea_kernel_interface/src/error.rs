@@ -0,0 +1,161 @@
+use super::netlink;
+
+use std::fmt;
+use std::process::Output;
+
+use libc;
+
+use failure::Error;
+
+/// A command that ran but didn't succeed, along with everything it told us about why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    /// The exit code, None if the command was killed by a signal
+    pub status: Option<i32>,
+    pub stderr: String,
+}
+
+impl FailedCommand {
+    pub fn new(program: &str, args: &[&str], output: &Output) -> FailedCommand {
+        FailedCommand {
+            program: program.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            status: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+    }
+}
+
+impl fmt::Display for FailedCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}", self.program)?;
+        for arg in self.args.iter() {
+            write!(f, " {}", arg)?;
+        }
+        match self.status {
+            Some(code) => write!(f, "` exited with {}", code)?,
+            None => write!(f, "` was killed")?,
+        }
+        if !self.stderr.trim().is_empty() {
+            write!(f, ": {}", self.stderr.trim())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum KernelInterfaceError {
+    #[fail(display = "Runtime Error: {:?}", _0)]
+    RuntimeError(String),
+    /// A failed command that doesn't fit any of the more specific variants
+    #[fail(display = "Command failed: {}", _0)]
+    CommandFailed(FailedCommand),
+    #[fail(display = "Interface not found: {}", _0)]
+    InterfaceNotFound(String),
+    /// Usually a listen port someone else already has
+    #[fail(display = "Address in use: {}", _0)]
+    AddressInUse(String),
+    #[fail(display = "Permission denied: {}", _0)]
+    PermissionDenied(String),
+    #[fail(display = "The {} binary was not found", _0)]
+    BinaryMissing(String),
+    #[fail(display = "Parse Error: {}", _0)]
+    ParseError(String),
+    /// An async command that was killed for running past its timeout
+    #[fail(display = "Timed out: {}", _0)]
+    TimedOut(String),
+}
+
+impl KernelInterfaceError {
+    /// Picks the most specific error for a command that didn't succeed. iproute2, wg and uci
+    /// all print strerror() for kernel failures so stderr is matched on those messages
+    pub fn from_output(program: &str, args: &[&str], output: &Output) -> KernelInterfaceError {
+        let failed = FailedCommand::new(program, args, output);
+        let stderr = &failed.stderr;
+        if stderr.contains("Address already in use") || stderr.contains("Address in use") {
+            KernelInterfaceError::AddressInUse(failed.to_string())
+        } else if stderr.contains("No such device") || stderr.contains("Cannot find device") {
+            KernelInterfaceError::InterfaceNotFound(failed.to_string())
+        } else if stderr.contains("Operation not permitted") || stderr.contains("Permission denied")
+        {
+            KernelInterfaceError::PermissionDenied(failed.to_string())
+        } else {
+            KernelInterfaceError::CommandFailed(failed)
+        }
+    }
+
+    /// The netlink equivalent of from_output, `context` says what we were trying to do
+    pub fn from_netlink(context: &str, e: Error) -> KernelInterfaceError {
+        let message = format!("{}: {}", context, e);
+        match netlink::errno_of(&e) {
+            Some(libc::EADDRINUSE) => KernelInterfaceError::AddressInUse(message),
+            Some(libc::ENODEV) => KernelInterfaceError::InterfaceNotFound(message),
+            Some(libc::EPERM) | Some(libc::EACCES) => {
+                KernelInterfaceError::PermissionDenied(message)
+            }
+            _ => KernelInterfaceError::RuntimeError(message),
+        }
+    }
+}
+
+#[test]
+fn test_from_output() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    let output = |stderr: &str, code: i32| Output {
+        stdout: Vec::new(),
+        stderr: stderr.as_bytes().to_vec(),
+        status: ExitStatus::from_raw(code << 8),
+    };
+
+    match KernelInterfaceError::from_output(
+        "wg",
+        &["set", "wg0", "listen-port", "60001"],
+        &output("Unable to modify interface: Address already in use\n", 1),
+    ) {
+        KernelInterfaceError::AddressInUse(message) => assert_eq!(
+            message,
+            "`wg set wg0 listen-port 60001` exited with 1: \
+             Unable to modify interface: Address already in use"
+        ),
+        e => panic!("Unexpected error {:?}", e),
+    }
+
+    match KernelInterfaceError::from_output(
+        "ip",
+        &["link", "set", "dev", "wg9", "up"],
+        &output("Cannot find device \"wg9\"\n", 1),
+    ) {
+        KernelInterfaceError::InterfaceNotFound(_) => {}
+        e => panic!("Unexpected error {:?}", e),
+    }
+
+    match KernelInterfaceError::from_output(
+        "ip",
+        &["route", "add", "default", "via", "10.0.0.1"],
+        &output("RTNETLINK answers: Operation not permitted\n", 2),
+    ) {
+        KernelInterfaceError::PermissionDenied(_) => {}
+        e => panic!("Unexpected error {:?}", e),
+    }
+
+    match KernelInterfaceError::from_output(
+        "uci",
+        &["commit", "network"],
+        &output("uci: I/O error\n", 1),
+    ) {
+        KernelInterfaceError::CommandFailed(failed) => assert_eq!(
+            failed,
+            FailedCommand {
+                program: "uci".to_string(),
+                args: vec!["commit".to_string(), "network".to_string()],
+                status: Some(1),
+                stderr: "uci: I/O error\n".to_string(),
+            }
+        ),
+        e => panic!("Unexpected error {:?}", e),
+    }
+}
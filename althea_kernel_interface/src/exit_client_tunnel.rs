@@ -1,10 +1,43 @@
-use super::{KernelInterface, KernelInterfaceError};
+use super::netlink::wireguard::{parse_key, read_key_file, WgDeviceConfig, WgPeerConfig};
+use super::{
+    FirewallRule, KernelInterface, KernelInterfaceError, UciBatch, UciPackage, UciValue,
+};
 
 use failure::Error;
 
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+
+/// The odhcpd options that advertise a LAN's addresses and hand them out over DHCPv6. The
+/// default route is through wg_exit rather than one netifd knows about, ra_default makes odhcpd
+/// announce us as a router anyway
+const LAN_IPV6_DHCP_OPTIONS: &[(&str, &str)] =
+    &[("ra", "server"), ("dhcpv6", "server"), ("ra_default", "1")];
+
+/// The uci interface a LAN device belongs to, a bridge is br-<interface> and other devices are
+/// listed in the ifname of their interface
+fn uci_network_interface(network: &UciPackage, lan_nic: &str) -> Option<String> {
+    network
+        .sections_of_type("interface")
+        .find(|section| {
+            let bridge = section.get("type") == Some(&UciValue::Option("bridge".to_string()));
+            if bridge && lan_nic == format!("br-{}", section.name) {
+                return true;
+            }
+            match section.get("ifname") {
+                Some(ifname) => ifname
+                    .values()
+                    .iter()
+                    .any(|names| names.split_whitespace().any(|name| name == lan_nic)),
+                None => false,
+            }
+        })
+        .map(|section| section.name.clone())
+}
 
 impl KernelInterface {
+    /// Points wg_exit at the exit, `mtu` is the last one discovered for wg_exit and the current
+    /// mtu is kept until there is one
     pub fn set_client_exit_tunnel_config(
         &self,
         endpoint: SocketAddr,
@@ -14,48 +47,63 @@ impl KernelInterface {
         local_ip: IpAddr,
         netmask: u8,
         rita_hello_port: u16,
+        ipv6: bool,
+        mtu: Option<u32>,
     ) -> Result<(), Error> {
-        self.run_command(
-            "wg",
-            &[
-                "set",
-                "wg_exit",
-                "listen-port",
-                &listen_port.to_string(),
-                "private-key",
-                &private_key_path,
-                "peer",
-                &pubkey,
-                "endpoint",
-                &format!("[{}]:{}", endpoint.ip(), endpoint.port()),
-                "allowed-ips",
-                "0.0.0.0/0",
-                "persistent-keepalive",
-                "5",
-            ],
-        )?;
-
-        for i in self.get_peers("wg_exit")? {
-            if i != pubkey {
-                self.run_command("wg", &["set", "wg_exit", "peer", &i, "remove"])?;
+        // old exits removed from wg_exit aren't journaled, there is no going back to them
+        let transaction = self.transaction();
+        if let Some(wg) = self.wireguard() {
+            let exit_key = parse_key(&pubkey)?;
+            let mut allowed_ips = vec![("0.0.0.0".parse()?, 0)];
+            if ipv6 {
+                allowed_ips.push(("::".parse()?, 0));
             }
+            let mut peers = vec![WgPeerConfig {
+                endpoint: Some(endpoint),
+                allowed_ips: Some(allowed_ips),
+                persistent_keepalive: Some(5),
+                ..WgPeerConfig::new(exit_key.clone())
+            }];
+            // remove old exits individually, replacing the whole peer list would also reset
+            // the session with the current one
+            for peer in wg.get_device("wg_exit")?.peers {
+                if peer.public_key != exit_key {
+                    peers.push(WgPeerConfig::remove(peer.public_key));
+                }
+            }
+            wg.set_device(
+                "wg_exit",
+                &WgDeviceConfig {
+                    private_key: Some(read_key_file(Path::new(&private_key_path))?),
+                    listen_port: Some(listen_port),
+                    replace_peers: false,
+                    peers,
+                },
+            ).map_err(|e| KernelInterfaceError::from_netlink("received error from wg netlink", e))?;
+            self.journal_wg_peer("wg_exit", exit_key);
+        } else {
+            self.wg_set_exit(endpoint, &pubkey, &private_key_path, listen_port, ipv6)?;
+            self.journal("wg", &["set", "wg_exit", "peer", &pubkey, "remove"]);
         }
 
         // block rita hello port on the exit tunnel
-        self.add_iptables_rule(
-            "iptables",
-            &[
-                "-I",
+        self.set_firewall_rules(
+            "exit_client_hello",
+            vec![FirewallRule::new(
+                "iptables",
+                "filter",
                 "OUTPUT",
-                "-o",
-                "wg_exit",
-                "-p",
-                "tcp",
-                "--dport",
-                &format!("{}", rita_hello_port),
-                "-j",
-                "DROP",
-            ],
+                &[
+                    "-o",
+                    "wg_exit",
+                    "-p",
+                    "tcp",
+                    "--dport",
+                    &format!("{}", rita_hello_port),
+                    "-j",
+                    "DROP",
+                ],
+            )],
         )?;
 
         let prev_ip: Result<Ipv4Addr, Error> = self.get_global_device_ip_v4("wg_exit");
@@ -63,122 +111,285 @@ impl KernelInterface {
         match prev_ip {
             Ok(prev_ip) => {
                 if prev_ip != local_ip {
-                    self.run_command(
+                    let prev_address = format!("{}/{}", prev_ip, netmask);
+                    let output = self.run_command(
                         "ip",
-                        &[
-                            "address",
-                            "delete",
-                            &format!("{}/{}", prev_ip, netmask),
-                            "dev",
-                            "wg_exit",
-                        ],
+                        &["address", "delete", &prev_address, "dev", "wg_exit"],
                     )?;
+                    if output.status.success() {
+                        self.journal("ip", &["address", "add", &prev_address, "dev", "wg_exit"]);
+                    }
 
-                    self.run_command(
-                        "ip",
-                        &[
-                            "address",
-                            "add",
-                            &format!("{}/{}", local_ip, netmask),
-                            "dev",
-                            "wg_exit",
-                        ],
-                    )?;
+                    self.add_exit_address(local_ip, netmask)?;
                 }
             }
             Err(e) => {
                 warn!("Finding wg exit's current IP returned {}", e);
-                self.run_command(
-                    "ip",
-                    &[
-                        "address",
-                        "add",
-                        &format!("{}/{}", local_ip, netmask),
-                        "dev",
-                        "wg_exit",
-                    ],
-                )?;
+                self.add_exit_address(local_ip, netmask)?;
             }
         }
 
-        let output = self.run_command("ip", &["link", "set", "dev", "wg_exit", "mtu", "1340"])?;
-        if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error adding wg link: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+        if let Some(mtu) = mtu {
+            self.set_mtu("wg_exit", mtu)?;
         }
 
-        let output = self.run_command("ip", &["link", "set", "dev", "wg_exit", "up"])?;
+        let args = &["link", "set", "dev", "wg_exit", "up"];
+        let output = self.run_command("ip", args)?;
         if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error setting wg interface up: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+            return Err(KernelInterfaceError::from_output("ip", args, &output).into());
+        }
+
+        transaction.commit();
+        Ok(())
+    }
+
+    fn add_exit_address(&self, local_ip: IpAddr, netmask: u8) -> Result<(), Error> {
+        let address = format!("{}/{}", local_ip, netmask);
+        let output = self.run_command("ip", &["address", "add", &address, "dev", "wg_exit"])?;
+        if output.status.success() {
+            self.journal("ip", &["address", "delete", &address, "dev", "wg_exit"]);
+        }
+        Ok(())
+    }
+
+    /// Points wg_exit at a single exit with `wg set` and removes any other peers
+    fn wg_set_exit(
+        &self,
+        endpoint: SocketAddr,
+        pubkey: &String,
+        private_key_path: &String,
+        listen_port: u16,
+        ipv6: bool,
+    ) -> Result<(), Error> {
+        let listen_port = listen_port.to_string();
+        let allowed_ips = if ipv6 { "0.0.0.0/0,::/0" } else { "0.0.0.0/0" };
+        let endpoint = format!("[{}]:{}", endpoint.ip(), endpoint.port());
+        let args: &[&str] = &[
+            "set",
+            "wg_exit",
+            "listen-port",
+            &listen_port,
+            "private-key",
+            private_key_path,
+            "peer",
+            pubkey,
+            "endpoint",
+            &endpoint,
+            "allowed-ips",
+            allowed_ips,
+            "persistent-keepalive",
+            "5",
+        ];
+        let output = self.run_command("wg", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("wg", args, &output).into());
+        }
+
+        for i in self.get_peers("wg_exit")? {
+            if &i != pubkey {
+                self.run_command("wg", &["set", "wg_exit", "peer", &i, "remove"])?;
+            }
         }
 
         Ok(())
     }
 
     pub fn set_route_to_tunnel(&self, gateway: &IpAddr) -> Result<(), Error> {
+        let previous = self.get_default_route();
         match self.run_command("ip", &["route", "del", "default"]) {
             Err(e) => warn!("Failed to delete default route {:?}", e),
+            Ok(ref output) if output.status.success() => {
+                if let Some(previous) = previous {
+                    let mut restore = vec!["route", "add"];
+                    restore.extend(previous.iter().map(|token| token.as_str()));
+                    self.journal("ip", &restore);
+                }
+            }
             _ => (),
         };
 
-        let output = self.run_command(
-            "ip",
-            &[
-                "route",
-                "add",
-                "default",
-                "via",
-                &gateway.to_string(),
-                "dev",
-                "wg_exit",
-            ],
-        )?;
+        let gateway = gateway.to_string();
+        let args: &[&str] = &["route", "add", "default", "via", &gateway, "dev", "wg_exit"];
+        let output = self.run_command("ip", args)?;
         if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error setting ip route: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+            return Err(KernelInterfaceError::from_output("ip", args, &output).into());
         }
+        self.journal(
+            "ip",
+            &["route", "del", "default", "via", &gateway, "dev", "wg_exit"],
+        );
 
         Ok(())
     }
 
-    pub fn add_client_nat_rules(&self, lan_nic: &str) -> Result<(), Error> {
-        self.add_iptables_rule(
-            "iptables",
-            &[
-                "-t",
-                "nat",
-                "-A",
-                "POSTROUTING",
-                "-o",
-                "wg_exit",
-                "-j",
-                "MASQUERADE",
-            ],
-        )?;
-        self.add_iptables_rule(
-            "iptables",
+    /// The ipv6 default route doesn't need a gateway, wg_exit only has the one peer. A low metric
+    /// puts it ahead of any route learned from router advertisements on the WAN
+    pub fn set_ipv6_route_to_tunnel(&self) -> Result<(), Error> {
+        let args = &[
+            "-6", "route", "replace", "default", "dev", "wg_exit", "metric", "1",
+        ];
+        let output = self.run_command("ip", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("ip", args, &output).into());
+        }
+        self.journal(
+            "ip",
             &[
-                "-A", "FORWARD", "-i", &lan_nic, "-o", "wg_exit", "-j", "ACCEPT",
+                "-6", "route", "del", "default", "dev", "wg_exit", "metric", "1",
             ],
-        )?;
-        self.add_iptables_rule(
+        );
+        Ok(())
+    }
+
+    /// Gives a LAN interface the first address of its share of our delegated prefix and has
+    /// odhcpd advertise it, clients autoconfigure from the router advertisements or ask for an
+    /// address over DHCPv6
+    pub fn add_client_ipv6_lan(
+        &self,
+        lan_nic: &str,
+        address: IpAddr,
+        prefix_len: u8,
+    ) -> Result<(), Error> {
+        let transaction = self.transaction();
+        let address = format!("{}/{}", address, prefix_len);
+        let args = &["-6", "address", "replace", &address, "dev", lan_nic];
+        let output = self.run_command("ip", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("ip", args, &output).into());
+        }
+        self.journal("ip", &["-6", "address", "del", &address, "dev", lan_nic]);
+        self.serve_client_ipv6_lan(lan_nic)?;
+        transaction.commit();
+        Ok(())
+    }
+
+    /// Turns on router advertisements and DHCPv6 in the dhcp section of a LAN's interface,
+    /// creating the section if it has none. What the options were before is journaled
+    fn serve_client_ipv6_lan(&self, lan_nic: &str) -> Result<(), Error> {
+        let interface = match uci_network_interface(&self.uci_get_package("network")?, lan_nic) {
+            Some(interface) => interface,
+            None => {
+                return Err(KernelInterfaceError::RuntimeError(format!(
+                    "{} isn't part of any uci interface",
+                    lan_nic
+                ))
+                .into())
+            }
+        };
+        let dhcp = self.uci_get_package("dhcp")?;
+        let interface_value = UciValue::Option(interface.clone());
+        let section = dhcp
+            .sections_of_type("dhcp")
+            .find(|section| section.get("interface") == Some(&interface_value));
+
+        let mut batch = UciBatch::new();
+        let mut revert = UciBatch::new();
+        let section_name = match section {
+            Some(section) => section.name.clone(),
+            None => {
+                batch
+                    .set(&format!("dhcp.{}", interface), "dhcp")
+                    .set(&format!("dhcp.{}.interface", interface), &interface);
+                revert.delete(&format!("dhcp.{}", interface));
+                interface.clone()
+            }
+        };
+        for &(option, value) in LAN_IPV6_DHCP_OPTIONS {
+            let previous = section.and_then(|section| section.get(option));
+            if previous == Some(&UciValue::Option(value.to_string())) {
+                continue;
+            }
+            let path = format!("dhcp.{}.{}", section_name, option);
+            batch.set(&path, value);
+            if section.is_some() {
+                revert.restore(&path, previous);
+            }
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        self.uci_apply(&batch)?;
+        self.journal_uci(revert, &["odhcpd"]);
+        self.refresh_initd("odhcpd")
+    }
+
+    /// Forwarding for the delegated prefix on each LAN that got an address from it, there's no
+    /// NAT since every LAN device has a routable address of its own
+    pub fn set_client_ipv6_rules(&self, lan_nics: &[String]) -> Result<(), Error> {
+        let mut rules = Vec::new();
+        for lan_nic in lan_nics {
+            rules.push(FirewallRule::new(
+                "ip6tables",
+                "filter",
+                "FORWARD",
+                &["-i", lan_nic, "-o", "wg_exit", "-j", "ACCEPT"],
+            ));
+            rules.push(FirewallRule::new(
+                "ip6tables",
+                "filter",
+                "FORWARD",
+                &[
+                    "-i",
+                    "wg_exit",
+                    "-o",
+                    lan_nic,
+                    "-m",
+                    "state",
+                    "--state",
+                    "RELATED,ESTABLISHED",
+                    "-j",
+                    "ACCEPT",
+                ],
+            ));
+        }
+        if !lan_nics.is_empty() {
+            rules.push(FirewallRule::new(
+                "ip6tables",
+                "filter",
+                "FORWARD",
+                &[
+                    "-p",
+                    "tcp",
+                    "--tcp-flags",
+                    "SYN,RST",
+                    "SYN",
+                    "-j",
+                    "TCPMSS",
+                    "--clamp-mss-to-pmtu",
+                ],
+            ));
+        }
+        self.set_firewall_rules("exit_client_ipv6", rules)
+    }
+
+    /// NAT and forwarding between the LANs and the exit tunnel
+    pub fn set_client_nat_rules(&self, lan_nics: &[String]) -> Result<(), Error> {
+        let mut rules = vec![FirewallRule::new(
             "iptables",
-            &[
-                "-A", "FORWARD", "-i", "wg_exit", "-o", &lan_nic, "-j", "ACCEPT",
-            ],
-        )?;
-        self.add_iptables_rule(
+            "nat",
+            "POSTROUTING",
+            &["-o", "wg_exit", "-j", "MASQUERADE"],
+        )];
+        for lan_nic in lan_nics {
+            rules.push(FirewallRule::new(
+                "iptables",
+                "filter",
+                "FORWARD",
+                &["-i", lan_nic, "-o", "wg_exit", "-j", "ACCEPT"],
+            ));
+            rules.push(FirewallRule::new(
+                "iptables",
+                "filter",
+                "FORWARD",
+                &["-i", "wg_exit", "-o", lan_nic, "-j", "ACCEPT"],
+            ));
+        }
+        rules.push(FirewallRule::new(
             "iptables",
+            "filter",
+            "FORWARD",
             &[
-                "-A",
-                "FORWARD",
                 "-p",
                 "tcp",
                 "--tcp-flags",
@@ -188,9 +399,113 @@ impl KernelInterface {
                 "TCPMSS",
                 "--clamp-mss-to-pmtu", //should be the same as --set-mss 1300
             ],
-        )?;
-        //TODO ipv6 support
+        ));
+        self.set_firewall_rules("exit_client_nat", rules)
+    }
+}
 
-        Ok(())
+/// The address for the index-th LAN interface, each one gets a /64 out of the delegated prefix
+/// and uses the first address in it. None once the prefix is used up
+pub fn lan_ipv6_address(prefix: IpAddr, prefix_len: u8, index: usize) -> Option<IpAddr> {
+    let prefix = match prefix {
+        IpAddr::V6(prefix) => u128::from(prefix),
+        IpAddr::V4(_) => return None,
+    };
+    if prefix_len > 64 || index as u128 >= 1u128 << (64 - prefix_len) {
+        return None;
+    }
+    let network = prefix + ((index as u128) << 64);
+    Some(Ipv6Addr::from(network + 1).into())
+}
+
+#[test]
+fn test_lan_ipv6_address() {
+    let prefix = "2001:db8:0:5::".parse().unwrap();
+    assert_eq!(
+        lan_ipv6_address(prefix, 64, 0),
+        Some("2001:db8:0:5::1".parse().unwrap())
+    );
+    // a /64 only covers a single LAN
+    assert_eq!(lan_ipv6_address(prefix, 64, 1), None);
+
+    let prefix = "2001:db8:0:500::".parse().unwrap();
+    assert_eq!(
+        lan_ipv6_address(prefix, 56, 2),
+        Some("2001:db8:0:502::1".parse().unwrap())
+    );
+    assert_eq!(lan_ipv6_address(prefix, 56, 256), None);
+    assert_eq!(lan_ipv6_address("2001:db8::".parse().unwrap(), 96, 0), None);
+    assert_eq!(lan_ipv6_address("10.0.0.0".parse().unwrap(), 24, 0), None);
+}
+
+#[test]
+fn test_set_client_exit_tunnel_config_replay() {
+    use super::ReplayCommandRunner;
+
+    // fw3's delegate_output ACCEPTs are ahead of the jump an older rita appended, the jump has
+    // to be moved above them or the hello port DROP is never reached
+    let replay = ReplayCommandRunner::from_jsonl(include_str!(
+        "../fixtures/set_client_exit_tunnel_config.jsonl"
+    )).unwrap();
+    {
+        let ki: &KernelInterface = &replay;
+        ki.set_client_exit_tunnel_config(
+            "[fd00::1337]:59999".parse().unwrap(),
+            "bvM10HW73yePrxdtCQQ4U20W5ogogdiZtUihrPc/oGY=".to_string(),
+            "/etc/rita-exit-key".to_string(),
+            59999,
+            "172.168.1.254".parse().unwrap(),
+            24,
+            4876,
+            false,
+            Some(1400),
+        ).unwrap();
+    }
+    replay.assert_done();
+}
+
+#[test]
+fn test_uci_network_interface() {
+    use super::uci::parse_uci_show;
+
+    let network = parse_uci_show(
+        "network",
+        "network.lan=interface
+network.lan.type='bridge'
+network.lan.ifname='eth0.1 eth1'
+network.guest=interface
+network.guest.ifname='eth2'
+",
+    ).unwrap();
+    assert_eq!(
+        uci_network_interface(&network, "br-lan"),
+        Some("lan".to_string())
+    );
+    assert_eq!(
+        uci_network_interface(&network, "eth1"),
+        Some("lan".to_string())
+    );
+    assert_eq!(
+        uci_network_interface(&network, "eth2"),
+        Some("guest".to_string())
+    );
+    assert_eq!(uci_network_interface(&network, "br-guest"), None);
+}
+
+#[test]
+fn test_add_client_ipv6_lan_rollback() {
+    use super::ReplayCommandRunner;
+
+    // lan relays RAs from the WAN, odhcpd failing to reload puts that back along with the address
+    let replay = ReplayCommandRunner::from_jsonl(include_str!(
+        "../fixtures/add_client_ipv6_lan_rollback.jsonl"
+    )).unwrap();
+    {
+        let ki: &KernelInterface = &replay;
+        assert!(
+            ki.add_client_ipv6_lan("br-lan", "2001:db8:0:500::1".parse().unwrap(), 64)
+                .is_err()
+        );
     }
+    replay.assert_done();
 }
@@ -1,20 +1,26 @@
-use super::{KernelInterface, KernelInterfaceError};
+use super::netlink::wireguard::{parse_key, read_key_file, WgDeviceConfig, WgPeerConfig};
+use super::{FirewallRule, KernelInterface, KernelInterfaceError};
 
 use std::collections::HashSet;
 
 use failure::Error;
 
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
 
 #[derive(Debug)]
 pub struct ExitClient {
     pub internal_ip: IpAddr,
+    /// The ipv6 prefix delegated to this client and its length, if it has one
+    pub internal_ipv6: Option<(IpAddr, u8)>,
     pub public_key: String,
     pub mesh_ip: IpAddr,
     pub port: u16,
 }
 
 impl KernelInterface {
+    /// Configures wg_exit for every client, `mtu` is the last one discovered for wg_exit and
+    /// the current mtu is kept until there is one
     pub fn set_exit_wg_config(
         &self,
         clients: Vec<ExitClient>,
@@ -22,6 +28,94 @@ impl KernelInterface {
         private_key_path: &str,
         local_ip: &IpAddr,
         netmask: u8,
+        local_ipv6: Option<(IpAddr, u8)>,
+        mtu: Option<u32>,
+    ) -> Result<(), Error> {
+        if let Some(wg) = self.wireguard() {
+            let mut client_pubkeys = HashSet::new();
+            let mut peers = Vec::new();
+            for c in clients {
+                let public_key = match parse_key(&c.public_key) {
+                    Ok(key) => key,
+                    Err(e) => {
+                        error!("Skipping exit client {:?}, {}", c, e);
+                        continue;
+                    }
+                };
+                let host_prefix = if c.internal_ip.is_ipv4() { 32 } else { 128 };
+                let mut allowed_ips = vec![(c.internal_ip, host_prefix)];
+                allowed_ips.extend(c.internal_ipv6);
+                peers.push(WgPeerConfig {
+                    endpoint: Some(SocketAddr::new(c.mesh_ip, c.port)),
+                    allowed_ips: Some(allowed_ips),
+                    persistent_keepalive: Some(5),
+                    ..WgPeerConfig::new(public_key.clone())
+                });
+                client_pubkeys.insert(public_key);
+            }
+
+            for peer in wg.get_device("wg_exit")?.peers {
+                if !client_pubkeys.contains(&peer.public_key) {
+                    peers.push(WgPeerConfig::remove(peer.public_key));
+                }
+            }
+
+            wg.set_device(
+                "wg_exit",
+                &WgDeviceConfig {
+                    private_key: Some(read_key_file(Path::new(private_key_path))?),
+                    listen_port: Some(listen_port),
+                    replace_peers: false,
+                    peers,
+                },
+            ).map_err(|e| KernelInterfaceError::from_netlink("received error from wg netlink", e))?;
+        } else {
+            self.wg_set_exit_clients(clients, listen_port, private_key_path)?;
+        }
+
+        let _output = self.run_command(
+            "ip",
+            &[
+                "address",
+                "add",
+                &format!("{}/{}", local_ip, netmask),
+                "dev",
+                "wg_exit",
+            ],
+        )?;
+
+        if let Some((local_ipv6, ipv6_netmask)) = local_ipv6 {
+            let _output = self.run_command(
+                "ip",
+                &[
+                    "address",
+                    "add",
+                    &format!("{}/{}", local_ipv6, ipv6_netmask),
+                    "dev",
+                    "wg_exit",
+                ],
+            )?;
+        }
+
+        if let Some(mtu) = mtu {
+            self.set_mtu("wg_exit", mtu)?;
+        }
+
+        let args = &["link", "set", "dev", "wg_exit", "up"];
+        let output = self.run_command("ip", args)?;
+        if !output.stderr.is_empty() {
+            return Err(KernelInterfaceError::from_output("ip", args, &output).into());
+        }
+
+        Ok(())
+    }
+
+    /// Configures every exit client with a single `wg set` and removes stale peers
+    fn wg_set_exit_clients(
+        &self,
+        clients: Vec<ExitClient>,
+        listen_port: u16,
+        private_key_path: &str,
     ) -> Result<(), Error> {
         let command = "wg".to_string();
 
@@ -41,7 +135,12 @@ impl KernelInterface {
             args.push("endpoint".into());
             args.push(format!("[{}]:{}", c.mesh_ip, c.port));
             args.push("allowed-ips".into());
-            args.push(format!("{}", c.internal_ip));
+            match c.internal_ipv6 {
+                Some((prefix, prefix_len)) => {
+                    args.push(format!("{},{}/{}", c.internal_ip, prefix, prefix_len))
+                }
+                None => args.push(format!("{}", c.internal_ip)),
+            }
             args.push("persistent-keepalive".into());
             args.push("5".into());
 
@@ -50,7 +149,10 @@ impl KernelInterface {
 
         let arg_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
 
-        self.run_command(&command, &arg_str[..])?;
+        let output = self.run_command(&command, &arg_str[..])?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output(&command, &arg_str, &output).into());
+        }
 
         for i in self.get_peers("wg_exit")? {
             if !client_pubkeys.contains(&i.to_string()) {
@@ -58,90 +160,134 @@ impl KernelInterface {
             }
         }
 
-        let _output = self.run_command(
-            "ip",
-            &[
-                "address",
-                "add",
-                &format!("{}/{}", local_ip, netmask),
-                "dev",
-                "wg_exit",
-            ],
-        )?;
-
-        let output = self.run_command("ip", &["link", "set", "dev", "wg_exit", "mtu", "1340"])?;
-        if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error adding wg link: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
-        }
-
-        let output = self.run_command("ip", &["link", "set", "dev", "wg_exit", "up"])?;
-        if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error setting wg interface up: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
-        }
-
         Ok(())
     }
 
     pub fn setup_nat(&self, external_interface: &str) -> Result<(), Error> {
-        self.add_iptables_rule(
-            "iptables",
-            &[
-                "-w",
-                "-t",
-                "nat",
-                "-A",
-                "POSTROUTING",
-                "-o",
-                external_interface,
-                "-j",
-                "MASQUERADE",
+        self.set_firewall_rules(
+            "exit_nat",
+            vec![
+                FirewallRule::new(
+                    "iptables",
+                    "nat",
+                    "POSTROUTING",
+                    &["-o", external_interface, "-j", "MASQUERADE"],
+                ),
+                FirewallRule::new(
+                    "iptables",
+                    "filter",
+                    "FORWARD",
+                    &["-o", external_interface, "-i", "wg_exit", "-j", "ACCEPT"],
+                ),
+                FirewallRule::new(
+                    "iptables",
+                    "filter",
+                    "FORWARD",
+                    &[
+                        "-o",
+                        "wg_exit",
+                        "-i",
+                        external_interface,
+                        "-m",
+                        "state",
+                        "--state",
+                        "RELATED,ESTABLISHED",
+                        "-j",
+                        "ACCEPT",
+                    ],
+                ),
             ],
-        )?;
+        )
+    }
 
-        self.add_iptables_rule(
-            "iptables",
-            &[
-                "-w",
-                "-t",
-                "filter",
-                "-A",
-                "FORWARD",
-                "-o",
-                external_interface,
-                "-i",
-                "wg_exit",
-                "-j",
-                "ACCEPT",
-            ],
-        )?;
+    /// Client ipv6 prefixes are routed rather than translated, so all the exit has to do is
+    /// forward between them and the internet. Forwarding turns off router advertisement
+    /// processing, an external interface configured with SLAAC needs accept_ra set to 2
+    pub fn setup_ipv6_forwarding(&self, external_interface: &str) -> Result<(), Error> {
+        let args = &["-w", "net.ipv6.conf.all.forwarding=1"];
+        let output = self.run_command("sysctl", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("sysctl", args, &output).into());
+        }
 
-        self.add_iptables_rule(
-            "iptables",
-            &[
-                "-w",
-                "-t",
-                "filter",
-                "-A",
-                "FORWARD",
-                "-o",
-                "wg_exit",
-                "-i",
-                external_interface,
-                "-m",
-                "state",
-                "--state",
-                "RELATED,ESTABLISHED",
-                "-j",
-                "ACCEPT",
+        self.set_firewall_rules(
+            "exit_ipv6",
+            vec![
+                FirewallRule::new(
+                    "ip6tables",
+                    "filter",
+                    "FORWARD",
+                    &["-o", external_interface, "-i", "wg_exit", "-j", "ACCEPT"],
+                ),
+                FirewallRule::new(
+                    "ip6tables",
+                    "filter",
+                    "FORWARD",
+                    &[
+                        "-o",
+                        "wg_exit",
+                        "-i",
+                        external_interface,
+                        "-m",
+                        "state",
+                        "--state",
+                        "RELATED,ESTABLISHED",
+                        "-j",
+                        "ACCEPT",
+                    ],
+                ),
             ],
-        )?;
+        )
+    }
+}
 
-        Ok(())
+#[test]
+fn test_set_exit_wg_config_replay() {
+    use super::ReplayCommandRunner;
+
+    let replay =
+        ReplayCommandRunner::from_jsonl(include_str!("../fixtures/set_exit_wg_config.jsonl"))
+            .unwrap();
+    let clients = vec![
+        ExitClient {
+            internal_ip: "172.168.1.2".parse().unwrap(),
+            internal_ipv6: None,
+            public_key: "bvM10HW73yePrxdtCQQ4U20W5ogogdiZtUihrPc/oGY=".to_string(),
+            mesh_ip: "fd00::1337".parse().unwrap(),
+            port: 60001,
+        },
+        ExitClient {
+            internal_ip: "172.168.1.3".parse().unwrap(),
+            internal_ipv6: Some(("2001:db8:0:1::".parse().unwrap(), 64)),
+            public_key: "x8AcR9wI4t97aowYFlis077BDBk9SLdq6khMiixuTsQ=".to_string(),
+            mesh_ip: "fd00::1447".parse().unwrap(),
+            port: 60002,
+        },
+    ];
+    {
+        let ki: &KernelInterface = &replay;
+        ki.set_exit_wg_config(
+            clients,
+            59999,
+            "/etc/rita-exit-key",
+            &"172.168.1.254".parse().unwrap(),
+            24,
+            Some(("2001:db8::1".parse().unwrap(), 48)),
+            Some(1420),
+        ).unwrap();
+    }
+    replay.assert_done();
+}
+
+#[test]
+fn test_setup_nat_replay() {
+    use super::ReplayCommandRunner;
+
+    let replay =
+        ReplayCommandRunner::from_jsonl(include_str!("../fixtures/setup_nat.jsonl")).unwrap();
+    {
+        let ki: &KernelInterface = &replay;
+        ki.setup_nat("eth0").unwrap();
     }
+    replay.assert_done();
 }
@@ -0,0 +1,555 @@
+//! Declarative firewall management. Everything in rita that needs a firewall rule declares the
+//! complete list it wants under an owner name, replacing whatever that owner declared before.
+//! Rules never go into the builtin chains directly, each builtin chain gets a single jump to a
+//! chain rita owns (FORWARD jumps to RITA_FORWARD and so on) and the reconciler makes the owned
+//! chains match the declared rules exactly, adding what's missing and deleting anything else.
+//! Rules in the builtin chains or in chains rita doesn't own are never touched.
+//!
+//! Every rule is tagged with a comment holding a hash of the rule, that's how rules are matched
+//! up with the live ruleset without having to understand how iptables prints them back.
+//!
+//! Inside a transaction a declaration journals the one it replaced, a rollback declares that
+//! again and reconciles so the live ruleset follows.
+//!
+//! The jumps are the first rule of their builtin chains so rita's rules are matched before any
+//! the system adds, an ACCEPT in fw3's chains would otherwise let through traffic a rita DROP is
+//! there to stop. A jump that something else pushed down is moved back up on every reconcile.
+//! Copies of declared rules that an older rita left directly in the builtin chains are deleted
+//! the first time each rule is reconciled.
+
+use super::{KernelInterface, KernelInterfaceError};
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Mutex;
+
+use regex::Regex;
+
+use failure::Error;
+
+/// Every builtin chain rita hangs an owned chain off of, `reconcile_firewall` walks all of them
+/// so rules left behind by an older rita are cleaned up too
+const MANAGED_CHAINS: &[(&str, &str, &str)] = &[
+    ("iptables", "filter", "INPUT"),
+    ("iptables", "filter", "FORWARD"),
+    ("iptables", "filter", "OUTPUT"),
+    ("iptables", "nat", "POSTROUTING"),
+    ("ip6tables", "filter", "INPUT"),
+    ("ip6tables", "filter", "FORWARD"),
+    ("ip6tables", "filter", "OUTPUT"),
+];
+
+/// Legacy copies of a rule deleted before giving up, an older rita adds one copy per start so
+/// a router that's been up for a while has a few but an iptables that keeps succeeding would
+/// otherwise never let the loop end
+const MAX_LEGACY_COPIES: usize = 32;
+
+lazy_static! {
+    // declared rules by owner, owners are kept in order so the rules in a chain are too
+    static ref DECLARED: Mutex<BTreeMap<String, Vec<FirewallRule>>> = Mutex::new(BTreeMap::new());
+    // tags of the rules whose legacy copies have already been deleted from the builtin chains
+    static ref MIGRATED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// A single rule, `chain` is the builtin chain the rule belongs in and `args` is everything
+/// that would follow `-A <chain>`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FirewallRule {
+    pub command: String,
+    pub table: String,
+    pub chain: String,
+    pub args: Vec<String>,
+}
+
+impl FirewallRule {
+    pub fn new(command: &str, table: &str, chain: &str, args: &[&str]) -> FirewallRule {
+        FirewallRule {
+            command: command.to_string(),
+            table: table.to_string(),
+            chain: chain.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    /// The rita owned chain this rule is put in
+    pub fn owned_chain(&self) -> String {
+        owned_chain(&self.chain)
+    }
+
+    /// The comment that identifies this rule in the live ruleset, a 64 bit FNV-1a hash of the
+    /// rule so any change to it makes a new tag
+    pub fn tag(&self) -> String {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let fields = [&self.command, &self.table, &self.chain]
+            .iter()
+            .map(|f| f.as_str())
+            .chain(self.args.iter().map(|a| a.as_str()))
+            .collect::<Vec<&str>>();
+        for field in fields {
+            for byte in field.bytes().chain(Some(0)) {
+                hash ^= u64::from(byte);
+                hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+            }
+        }
+        format!("rita:{:016x}", hash)
+    }
+}
+
+fn owned_chain(builtin: &str) -> String {
+    format!("RITA_{}", builtin)
+}
+
+/// A rule in an owned chain as `iptables -S` lists it, `position` is its rule number
+#[derive(Debug, PartialEq, Eq)]
+struct LiveRule {
+    position: usize,
+    tag: Option<String>,
+}
+
+/// Finds the rules of a chain in `iptables -S <chain>` output
+fn parse_live_rules(chain: &str, input: &str) -> Vec<LiveRule> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r#"--comment "?(rita:[0-9a-f]{16})"?"#)
+            .expect("Unable to compile regular expression");
+    }
+    let prefix = format!("-A {} ", chain);
+    input
+        .lines()
+        .filter(|line| line.starts_with(&prefix))
+        .enumerate()
+        .map(|(index, line)| LiveRule {
+            position: index + 1,
+            tag: RE.captures(line).map(|caps| caps[1].to_string()),
+        })
+        .collect()
+}
+
+/// Rule numbers of the jumps to `chain` in `iptables -S <builtin>` output
+fn parse_jump_positions(builtin: &str, chain: &str, input: &str) -> Vec<usize> {
+    let prefix = format!("-A {} ", builtin);
+    let jump = format!("-A {} -j {}", builtin, chain);
+    input
+        .lines()
+        .filter(|line| line.starts_with(&prefix))
+        .enumerate()
+        .filter(|&(_, line)| line.trim_right() == jump)
+        .map(|(index, _)| index + 1)
+        .collect()
+}
+
+impl KernelInterface {
+    /// Replaces every rule declared by `owner` with `rules` and reconciles the chains either
+    /// set of rules lives in
+    pub fn set_firewall_rules(&self, owner: &str, rules: Vec<FirewallRule>) -> Result<(), Error> {
+        let mut declared = DECLARED.lock().unwrap();
+        let previous = declared.get(owner).cloned().unwrap_or_else(Vec::new);
+        self.journal_firewall_rules(owner, previous);
+        self.declare(&mut declared, owner, rules)
+    }
+
+    /// Puts back a declaration replaced inside a transaction that was rolled back, this isn't
+    /// journaled itself
+    pub(crate) fn restore_firewall_rules(
+        &self,
+        owner: &str,
+        rules: Vec<FirewallRule>,
+    ) -> Result<(), Error> {
+        let mut declared = DECLARED.lock().unwrap();
+        self.declare(&mut declared, owner, rules)
+    }
+
+    fn declare(
+        &self,
+        declared: &mut BTreeMap<String, Vec<FirewallRule>>,
+        owner: &str,
+        rules: Vec<FirewallRule>,
+    ) -> Result<(), Error> {
+        let mut chains: HashSet<(String, String, String)> = HashSet::new();
+        for rule in declared
+            .get(owner)
+            .iter()
+            .flat_map(|r| r.iter())
+            .chain(rules.iter())
+        {
+            chains.insert((rule.command.clone(), rule.table.clone(), rule.chain.clone()));
+        }
+        declared.insert(owner.to_string(), rules);
+
+        // sorted so the commands run in the same order every time
+        let mut chains: Vec<(String, String, String)> = chains.into_iter().collect();
+        chains.sort();
+        for (command, table, chain) in chains {
+            self.reconcile_chain(declared, &command, &table, &chain)?;
+        }
+        Ok(())
+    }
+
+    /// Makes every owned chain match the declared rules, this fixes any drift in the ruleset
+    pub fn reconcile_firewall(&self) -> Result<(), Error> {
+        let declared = DECLARED.lock().unwrap();
+        for &(command, table, chain) in MANAGED_CHAINS {
+            self.reconcile_chain(&declared, command, table, chain)?;
+        }
+        Ok(())
+    }
+
+    fn reconcile_chain(
+        &self,
+        declared: &BTreeMap<String, Vec<FirewallRule>>,
+        command: &str,
+        table: &str,
+        builtin: &str,
+    ) -> Result<(), Error> {
+        let chain = owned_chain(builtin);
+
+        let mut seen = HashSet::new();
+        let wanted: Vec<&FirewallRule> = declared
+            .values()
+            .flat_map(|rules| rules.iter())
+            .filter(|r| r.command == command && r.table == table && r.chain == builtin)
+            .filter(|r| seen.insert(r.tag()))
+            .collect();
+        let wanted_tags: Vec<String> = wanted.iter().map(|r| r.tag()).collect();
+
+        let output = self.run_command(command, &["-w", "-t", table, "-S", &chain])?;
+        let live = if output.status.success() {
+            parse_live_rules(&chain, &String::from_utf8(output.stdout)?)
+        } else if wanted.is_empty() {
+            // never created and nothing to put in it
+            return Ok(());
+        } else {
+            let args = &["-w", "-t", table, "-N", &chain];
+            let output = self.run_command(command, args)?;
+            if !output.status.success() {
+                return Err(KernelInterfaceError::from_output(command, args, &output).into());
+            }
+            Vec::new()
+        };
+
+        if !wanted.is_empty() {
+            self.reconcile_jump(command, table, builtin, &chain)?;
+        }
+
+        for rule in wanted.iter() {
+            if !MIGRATED.lock().unwrap().contains(&rule.tag()) {
+                self.remove_legacy_rule(rule)?;
+                MIGRATED.lock().unwrap().insert(rule.tag());
+            }
+        }
+
+        // the first live copy of every wanted rule is kept, everything else goes
+        let mut kept = Vec::new();
+        let mut stale = Vec::new();
+        for rule in live.iter() {
+            match rule.tag {
+                Some(ref tag) if wanted_tags.contains(tag) && !kept.contains(tag) => {
+                    kept.push(tag.clone())
+                }
+                _ => stale.push(rule.position),
+            }
+        }
+
+        let in_order: Vec<&String> = wanted_tags.iter().filter(|t| kept.contains(t)).collect();
+        if kept.iter().collect::<Vec<&String>>() != in_order {
+            info!(
+                "Rules in {} {} are out of order, rebuilding it",
+                command, chain
+            );
+            let args = &["-w", "-t", table, "-F", &chain];
+            let output = self.run_command(command, args)?;
+            if !output.status.success() {
+                return Err(KernelInterfaceError::from_output(command, args, &output).into());
+            }
+            kept.clear();
+        } else {
+            // deleting from the bottom up keeps the earlier rule numbers valid
+            for position in stale.iter().rev() {
+                let position = position.to_string();
+                let args = &["-w", "-t", table, "-D", &chain, &position];
+                let output = self.run_command(command, args)?;
+                if !output.status.success() {
+                    return Err(KernelInterfaceError::from_output(command, args, &output).into());
+                }
+            }
+        }
+
+        for (index, (rule, tag)) in wanted.iter().zip(wanted_tags.iter()).enumerate() {
+            if kept.contains(tag) {
+                continue;
+            }
+            let position = (index + 1).to_string();
+            let mut args: Vec<&str> = vec!["-w", "-t", table, "-I", &chain, &position];
+            args.extend(rule.args.iter().map(|a| a.as_str()));
+            args.extend(&["-m", "comment", "--comment", tag.as_str()]);
+            let output = self.run_command(command, &args)?;
+            if !output.status.success() {
+                return Err(KernelInterfaceError::from_output(command, &args, &output).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes the jump to an owned chain the first rule of its builtin chain, anything that was
+    /// put above it since or a jump an older rita appended is moved back to the top
+    fn reconcile_jump(
+        &self,
+        command: &str,
+        table: &str,
+        builtin: &str,
+        chain: &str,
+    ) -> Result<(), Error> {
+        let args = &["-w", "-t", table, "-S", builtin];
+        let output = self.run_command(command, args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output(command, args, &output).into());
+        }
+        let positions = parse_jump_positions(builtin, chain, &String::from_utf8(output.stdout)?);
+        if positions == [1] {
+            return Ok(());
+        }
+
+        if !positions.is_empty() {
+            info!("Jump to {} {} isn't first in {}, moving it", command, chain, builtin);
+        }
+        for position in positions.iter().rev() {
+            let position = position.to_string();
+            let args = &["-w", "-t", table, "-D", builtin, &position];
+            let output = self.run_command(command, args)?;
+            if !output.status.success() {
+                return Err(KernelInterfaceError::from_output(command, args, &output).into());
+            }
+        }
+        let args = &["-w", "-t", table, "-I", builtin, "1", "-j", chain];
+        let output = self.run_command(command, args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output(command, args, &output).into());
+        }
+        Ok(())
+    }
+
+    /// Deletes the copies of a rule that a rita from before owned chains added straight to the
+    /// builtin chain with the same arguments
+    fn remove_legacy_rule(&self, rule: &FirewallRule) -> Result<(), Error> {
+        let mut args: Vec<&str> = vec!["-w", "-t", &rule.table, "-D", &rule.chain];
+        args.extend(rule.args.iter().map(|a| a.as_str()));
+        if self.dry_run() {
+            // a planned delete always succeeds, one is enough to show it would happen
+            self.run_command(&rule.command, &args)?;
+            return Ok(());
+        }
+        // iptables fails once there's no copy left
+        for _ in 0..MAX_LEGACY_COPIES {
+            if !self.run_command(&rule.command, &args)?.status.success() {
+                return Ok(());
+            }
+            info!("Removed legacy firewall rule {:?}", rule);
+        }
+        warn!(
+            "Legacy firewall rule {:?} still there after deleting {} copies, giving up",
+            rule, MAX_LEGACY_COPIES
+        );
+        Ok(())
+    }
+}
+
+#[test]
+fn test_rule_tag() {
+    let rule = FirewallRule::new("iptables", "nat", "POSTROUTING", &["-j", "MASQUERADE"]);
+    assert_eq!(rule.tag(), rule.clone().tag());
+    assert!(rule.tag().starts_with("rita:"));
+    assert_eq!(rule.tag().len(), 21);
+    assert_ne!(
+        rule.tag(),
+        FirewallRule::new("ip6tables", "nat", "POSTROUTING", &["-j", "MASQUERADE"]).tag()
+    );
+    // the field boundaries are part of the hash
+    assert_ne!(
+        FirewallRule::new("iptables", "filter", "FORWARD", &["-i", "wg0"]).tag(),
+        FirewallRule::new("iptables", "filter", "FORWARD", &["-iwg0"]).tag()
+    );
+    assert_eq!(rule.owned_chain(), "RITA_POSTROUTING");
+}
+
+#[test]
+fn test_parse_live_rules() {
+    let input = "-N RITA_FORWARD
+-A RITA_FORWARD -i br-lan -o wg_exit -m comment --comment \"rita:00112233445566aa\" -j ACCEPT
+-A RITA_FORWARD -i wg_exit -o br-lan -j ACCEPT
+-A RITA_FORWARD -m comment --comment rita:ffeeddccbbaa9988 -j ACCEPT
+";
+    assert_eq!(
+        parse_live_rules("RITA_FORWARD", input),
+        vec![
+            LiveRule {
+                position: 1,
+                tag: Some("rita:00112233445566aa".to_string()),
+            },
+            LiveRule {
+                position: 2,
+                tag: None,
+            },
+            LiveRule {
+                position: 3,
+                tag: Some("rita:ffeeddccbbaa9988".to_string()),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_set_firewall_rules() {
+    use super::ReplayCommandRunner;
+
+    let first = FirewallRule::new(
+        "iptables",
+        "mangle",
+        "PREROUTING",
+        &["-i", "wg_test", "-j", "ACCEPT"],
+    );
+    let second = FirewallRule::new(
+        "iptables",
+        "mangle",
+        "PREROUTING",
+        &["-i", "wg_test2", "-j", "ACCEPT"],
+    );
+    assert_eq!(first.tag(), "rita:270b5b545bc54506");
+    assert_eq!(second.tag(), "rita:367b6e9a44d5f99e");
+
+    let replay =
+        ReplayCommandRunner::from_jsonl(include_str!("../fixtures/set_firewall_rules.jsonl"))
+            .unwrap();
+    {
+        let ki: &KernelInterface = &replay;
+        ki.set_firewall_rules("test", vec![first, second]).unwrap();
+    }
+    replay.assert_done();
+}
+
+#[test]
+fn test_firewall_rollback() {
+    use super::{CommandFixture, ReplayCommandRunner};
+
+    let rule = FirewallRule::new(
+        "iptables",
+        "raw",
+        "PREROUTING",
+        &["-i", "wg_test", "-j", "CT"],
+    );
+    let tag = rule.tag();
+    let fixture = |args: &[&str], stdout: &str, status: i32| CommandFixture {
+        program: "iptables".to_string(),
+        args: args.iter().map(|a| a.to_string()).collect(),
+        stdout: stdout.to_string(),
+        stderr: String::new(),
+        status: Some(status),
+        stdin: None,
+    };
+    let replay = ReplayCommandRunner::new(vec![
+        fixture(&["-w", "-t", "raw", "-S", "RITA_PREROUTING"], "-N RITA_PREROUTING\n", 0),
+        fixture(
+            &["-w", "-t", "raw", "-S", "PREROUTING"],
+            "-P PREROUTING ACCEPT\n-A PREROUTING -j RITA_PREROUTING\n",
+            0,
+        ),
+        fixture(
+            &["-w", "-t", "raw", "-D", "PREROUTING", "-i", "wg_test", "-j", "CT"],
+            "",
+            1,
+        ),
+        fixture(
+            &[
+                "-w",
+                "-t",
+                "raw",
+                "-I",
+                "RITA_PREROUTING",
+                "1",
+                "-i",
+                "wg_test",
+                "-j",
+                "CT",
+                "-m",
+                "comment",
+                "--comment",
+                &tag,
+            ],
+            "",
+            0,
+        ),
+        // the rollback declares nothing again, so the rule goes
+        fixture(
+            &["-w", "-t", "raw", "-S", "RITA_PREROUTING"],
+            &format!(
+                "-N RITA_PREROUTING\n-A RITA_PREROUTING -i wg_test -m comment --comment \"{}\" -j CT\n",
+                tag
+            ),
+            0,
+        ),
+        fixture(&["-w", "-t", "raw", "-D", "RITA_PREROUTING", "1"], "", 0),
+    ]);
+    {
+        let ki: &KernelInterface = &replay;
+        let _transaction = ki.transaction();
+        ki.set_firewall_rules("rollback_test", vec![rule]).unwrap();
+    }
+    replay.assert_done();
+    assert!(DECLARED.lock().unwrap()["rollback_test"].is_empty());
+}
+
+#[test]
+fn test_dry_run_legacy_rules() {
+    use super::{dry_run_plan, DryRunCommandRunner, TestCommandRunner};
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+    use std::sync::Arc;
+
+    // the owned chains exist and are empty, every check succeeds. mangle OUTPUT because no
+    // other test declares rules there, declarations are shared by every kernel interface
+    let ruleset = TestCommandRunner {
+        run_command: Arc::new(Mutex::new(Box::new(|_program, args: Vec<String>| {
+            let stdout = if args[3] == "-S" {
+                format!("-N {}\n", args[4])
+            } else {
+                String::new()
+            };
+            Ok(Output {
+                stdout: stdout.into_bytes(),
+                stderr: Vec::new(),
+                status: ExitStatus::from_raw(0),
+            })
+        }))),
+    };
+    let rules = vec![
+        FirewallRule::new(
+            "iptables",
+            "mangle",
+            "OUTPUT",
+            &["-o", "wg_dry_run", "-j", "ACCEPT"],
+        ),
+        FirewallRule::new(
+            "ip6tables",
+            "mangle",
+            "OUTPUT",
+            &["-o", "wg_dry_run", "-j", "ACCEPT"],
+        ),
+    ];
+    let dry_run = DryRunCommandRunner::with_runner(Box::new(ruleset));
+    {
+        let ki: &KernelInterface = &dry_run;
+        ki.set_firewall_rules("dry_run_test", rules.clone())
+            .unwrap();
+        ki.set_firewall_rules("dry_run_test", rules.clone())
+            .unwrap();
+    }
+
+    let plan = dry_run_plan();
+    for rule in rules {
+        let mut delete = vec!["-w", "-t", "mangle", "-D", "OUTPUT"];
+        delete.extend(rule.args.iter().map(|a| a.as_str()));
+        let deletes = plan
+            .iter()
+            .filter(|c| c.program == rule.command && c.args == delete)
+            .count();
+        assert_eq!(deletes, 1, "{:?} should be deleted once", rule);
+    }
+}
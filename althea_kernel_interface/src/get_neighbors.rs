@@ -12,6 +12,21 @@ impl KernelInterface {
     /// Returns a vector of neighbors reachable over layer 2, giving IP address of each.
     /// Implemented with `ip neighbor` on Linux.
     pub fn get_neighbors(&self) -> Result<Vec<(IpAddr, String)>, Error> {
+        if let Some(netlink) = self.netlink() {
+            let links = netlink.links()?;
+            let mut vec = Vec::new();
+            for neighbor in netlink.neighbors()? {
+                if !neighbor.is_live() {
+                    continue;
+                }
+                if let Some(link) = links.iter().find(|l| l.index == neighbor.index) {
+                    vec.push((neighbor.address, link.name.clone()));
+                }
+            }
+            trace!("Got neighbors {:?}", vec);
+            return Ok(vec);
+        }
+
         let output = self.run_command("ip", &["neighbor"])?;
         trace!("Got {:?} from `ip neighbor`", output);
 
@@ -9,6 +9,12 @@ use std::str::from_utf8;
 impl KernelInterface {
     /// Returns all existing interfaces
     pub fn get_interfaces(&self) -> Result<Vec<String>, Error> {
+        if let Some(netlink) = self.netlink() {
+            let vec: Vec<String> = netlink.links()?.into_iter().map(|l| l.name).collect();
+            trace!("interfaces: {:?}", vec);
+            return Ok(vec);
+        }
+
         let links = String::from_utf8(self.run_command("ip", &["link"])?.stdout)?;
 
         let mut vec = Vec::new();
@@ -23,6 +29,14 @@ impl KernelInterface {
 
     /// Deletes an named interface
     pub fn del_interface(&self, name: &str) -> Result<(), Error> {
+        if let Some(netlink) = self.netlink() {
+            // like the command version, a failed delete is logged rather than returned
+            if let Err(e) = netlink.del_link(name) {
+                info!("Deleting interface {} returned: an error {:?}", name, e);
+            }
+            return Ok(());
+        }
+
         self.run_command("ip", &["link", "del", "dev", name])?;
         Ok(())
     }
@@ -37,6 +51,13 @@ impl KernelInterface {
     }
 
     pub fn get_wg_remote_ip(&self, name: &str) -> Result<IpAddr, Error> {
+        if let Some(wg) = self.wireguard() {
+            return match wg.get_device(name)?.peers.iter().filter_map(|p| p.endpoint).next() {
+                Some(endpoint) => Ok(endpoint.ip()),
+                None => bail!("No peer on {} has an endpoint", name),
+            };
+        }
+
         let output = self.run_command("wg", &["show", name, "endpoints"])?;
         let stdout = String::from_utf8(output.stdout)?;
 
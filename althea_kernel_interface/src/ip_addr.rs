@@ -4,6 +4,16 @@ impl KernelInterface {
     /// Returns a bool based on device state, "UP" or "DOWN", "UNKNOWN" is
     /// interpreted as DOWN
     pub fn is_iface_up(&self, dev: &str) -> Option<bool> {
+        if let Some(netlink) = self.netlink() {
+            return match netlink.link_by_name(dev) {
+                Ok(link) => link.map(|link| link.is_up()),
+                Err(e) => {
+                    warn!("Failed to get the state of {} over netlink {:?}", dev, e);
+                    None
+                }
+            };
+        }
+
         let output = self
             .run_command("ip", &["addr", "show", "dev", dev])
             .unwrap();
@@ -1,6 +1,11 @@
-use super::KernelInterface;
+use super::netlink::{self, Netlink, Route};
+use super::{KernelInterface, ASYNC_COMMAND_TIMEOUT_SECS};
 
 use std::net::IpAddr;
+use std::time::Duration;
+
+use futures::{future, Future};
+use libc;
 
 use failure::Error;
 
@@ -20,39 +25,76 @@ impl ToString for IpRoute {
     }
 }
 
+/// Get the first line of `ip route list default` that starts with "default", and convert
+/// tokens separated by whitespace into a Vec<String>. None if it couldn't be found.
+fn parse_default_route(stdout: &str) -> Option<Vec<String>> {
+    Some(
+        stdout
+            .lines()
+            .filter(|line| line.starts_with("default"))
+            .nth(0)?
+            .split_whitespace() // Extract first
+            .map(|s| s.to_string())
+            .collect(),
+    )
+}
+
 impl KernelInterface {
     pub fn get_default_route(&self) -> Option<Vec<String>> {
+        if let Some(netlink) = self.netlink() {
+            return match netlink_default_route(netlink) {
+                Ok(route) => route,
+                Err(e) => {
+                    warn!("Failed to get the default route over netlink {:?}", e);
+                    None
+                }
+            };
+        }
+
         let output = self
             .run_command("ip", &["route", "list", "default"])
             .unwrap();
 
         let stdout = String::from_utf8(output.stdout).unwrap();
+        parse_default_route(&stdout)
+    }
 
-        // Get the first line that starts with "default", and
-        // convert token separated by whitespace into a valid
-        // result of type Vec<String>. Otherwise returns
-        // None if it couldn't be found.
-        Some(
-            stdout
-                .lines()
-                .filter(|line| line.starts_with("default"))
-                .nth(0)?
-                .split_whitespace() // Extract first
-                .map(|s| s.to_string())
-                .collect(),
+    /// Same as `get_default_route` but doesn't block on `ip`, unlike the sync version a failure
+    /// to run `ip` is returned as an error
+    pub fn get_default_route_async(
+        &self,
+    ) -> Box<Future<Item = Option<Vec<String>>, Error = Error>> {
+        if self.netlink().is_some() {
+            return Box::new(future::ok(self.get_default_route()));
+        }
+
+        let timeout = Duration::from_secs(ASYNC_COMMAND_TIMEOUT_SECS);
+        Box::new(
+            self.run_command_async("ip", &["route", "list", "default"], timeout)
+                .and_then(|output| -> Result<_, Error> {
+                    Ok(parse_default_route(&String::from_utf8(output.stdout)?))
+                }),
         )
     }
 
+    /// Adds the route and journals its removal, routes that already existed are left alone
     fn set_route(&self, to: &IpRoute, route: &Vec<String>) -> Result<(), Error> {
-        let to = to.to_string();
-        let mut def_route = vec!["route", "add", &to];
+        let dest = to.to_string();
+        let added = if let Some(netlink) = self.netlink() {
+            netlink_set_route(netlink, to, route)?
+        } else {
+            let mut def_route = vec!["route", "add", &dest];
 
-        let tokens = route.iter().skip(1);
-        def_route.reserve_exact(tokens.len());
-        for token in tokens {
-            def_route.push(&token);
+            let tokens = route.iter().skip(1);
+            def_route.reserve_exact(tokens.len());
+            for token in tokens {
+                def_route.push(&token);
+            }
+            self.run_command("ip", &def_route)?.status.success()
+        };
+        if added {
+            self.journal("ip", &["route", "del", &dest]);
         }
-        self.run_command("ip", &def_route)?;
         Ok(())
     }
 
@@ -103,6 +145,131 @@ impl KernelInterface {
     }
 }
 
+/// Route protocols as named by iproute2, the rest are printed as numbers
+const PROTOCOL_NAMES: &[(u8, &str)] = &[
+    (2, "kernel"),
+    (3, "boot"),
+    (4, "static"),
+    (9, "ra"),
+    (16, "dhcp"),
+];
+
+/// Finds the first ipv4 default route and renders it the way `ip route list default` would,
+/// since the token form is what we persist in the settings file
+fn netlink_default_route(netlink: &Netlink) -> Result<Option<Vec<String>>, Error> {
+    let route = match netlink
+        .routes(netlink::AF_INET)?
+        .into_iter()
+        .find(|r| r.is_default())
+    {
+        Some(route) => route,
+        None => return Ok(None),
+    };
+    let links = netlink.links()?;
+
+    let mut tokens = vec!["default".to_string()];
+    if let Some(gateway) = route.gateway {
+        tokens.push("via".to_string());
+        tokens.push(gateway.to_string());
+    }
+    if let Some(oif) = route.oif {
+        if let Some(link) = links.iter().find(|l| l.index == oif) {
+            tokens.push("dev".to_string());
+            tokens.push(link.name.clone());
+        }
+    }
+    // iproute2 doesn't print the protocol for routes added without one
+    if route.protocol != netlink::RTPROT_BOOT {
+        let protocol = match PROTOCOL_NAMES.iter().find(|p| p.0 == route.protocol) {
+            Some(&(_, name)) => name.to_string(),
+            None => route.protocol.to_string(),
+        };
+        tokens.push("proto".to_string());
+        tokens.push(protocol);
+    }
+    if let Some(prefsrc) = route.prefsrc {
+        tokens.push("src".to_string());
+        tokens.push(prefsrc.to_string());
+    }
+    if let Some(metric) = route.metric {
+        tokens.push("metric".to_string());
+        tokens.push(metric.to_string());
+    }
+    Ok(Some(tokens))
+}
+
+/// Netlink version of `ip route add <to> <route tokens>`, understands the tokens produced
+/// by `ip route list` and by netlink_default_route. Returns false if the kernel refused the
+/// route
+fn netlink_set_route(netlink: &Netlink, to: &IpRoute, tokens: &Vec<String>) -> Result<bool, Error> {
+    let (dst, dst_len) = match *to {
+        IpRoute::DefaultRoute => (None, 0),
+        IpRoute::ToAddr(IpAddr::V4(addr)) => (Some(IpAddr::V4(addr)), 32),
+        IpRoute::ToAddr(IpAddr::V6(addr)) => (Some(IpAddr::V6(addr)), 128),
+    };
+    let mut route = Route {
+        family: netlink::AF_INET,
+        dst,
+        dst_len,
+        gateway: None,
+        oif: None,
+        prefsrc: None,
+        metric: None,
+        protocol: netlink::RTPROT_BOOT,
+        scope: netlink::RT_SCOPE_UNIVERSE,
+        table: netlink::RT_TABLE_MAIN,
+        kind: netlink::RTN_UNICAST,
+    };
+
+    let mut tokens = tokens.iter().skip(1);
+    while let Some(token) = tokens.next() {
+        match token.as_str() {
+            "via" | "dev" | "proto" | "metric" | "priority" | "src" | "scope" | "table" => {
+                let value = match tokens.next() {
+                    Some(value) => value,
+                    None => bail!("Route token {} is missing a value", token),
+                };
+                match token.as_str() {
+                    "via" => route.gateway = Some(value.parse()?),
+                    "dev" => match netlink.link_by_name(value)? {
+                        Some(link) => route.oif = Some(link.index),
+                        None => bail!("Route device {} not found", value),
+                    },
+                    "proto" => {
+                        route.protocol = match PROTOCOL_NAMES.iter().find(|p| p.1 == value) {
+                            Some(&(number, _)) => number,
+                            None => value.parse()?,
+                        }
+                    }
+                    "metric" | "priority" => route.metric = Some(value.parse()?),
+                    "src" => route.prefsrc = Some(value.parse()?),
+                    _ => trace!("Ignoring route option {} {}", token, value),
+                }
+            }
+            _ => trace!("Ignoring route flag {}", token),
+        }
+    }
+
+    let res = netlink.add_route(&route);
+    route_added(&route, res)
+}
+
+/// Whether adding a route put it in the table, a route that's already present is left alone
+/// like `ip route add` does. Anything else the kernel refuses, an unreachable gateway or a
+/// missing device, is returned
+fn route_added(route: &Route, res: Result<(), Error>) -> Result<bool, Error> {
+    match res {
+        Ok(()) => Ok(true),
+        Err(e) => match netlink::errno_of(&e) {
+            Some(libc::EEXIST) => {
+                info!("Route {:?} is already present", route);
+                Ok(false)
+            }
+            _ => Err(e),
+        },
+    }
+}
+
 #[test]
 fn test_get_default_route_invalid() {
     use std::os::unix::process::ExitStatusExt;
@@ -248,3 +415,34 @@ fn test_set_default_route() {
     KI.set_route(&IpRoute::DefaultRoute, &vec![])
         .expect("Unable to set default route");
 }
+
+#[test]
+fn test_route_added() {
+    use netlink::NetlinkError;
+
+    let route = Route {
+        family: netlink::AF_INET,
+        dst: None,
+        dst_len: 0,
+        gateway: Some("192.168.8.1".parse().unwrap()),
+        oif: Some(2),
+        prefsrc: None,
+        metric: None,
+        protocol: netlink::RTPROT_BOOT,
+        scope: netlink::RT_SCOPE_UNIVERSE,
+        table: netlink::RT_TABLE_MAIN,
+        kind: netlink::RTN_UNICAST,
+    };
+
+    assert_eq!(route_added(&route, Ok(())).unwrap(), true);
+    assert_eq!(
+        route_added(&route, Err(NetlinkError::from_errno(libc::EEXIST).into())).unwrap(),
+        false
+    );
+    for &errno in &[libc::ENETUNREACH, libc::ENODEV, libc::EINVAL] {
+        let e = route_added(&route, Err(NetlinkError::from_errno(errno).into())).unwrap_err();
+        assert_eq!(netlink::errno_of(&e), Some(errno));
+    }
+    let malformed = NetlinkError::Malformed("truncated error message".to_string());
+    assert!(route_added(&route, Err(malformed.into())).is_err());
+}
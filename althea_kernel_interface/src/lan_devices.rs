@@ -0,0 +1,469 @@
+//! An inventory of the devices behind a router. dnsmasq's leases give us hostnames, the
+//! neighbor tables tell us who is actually around and conntrack tells us how much each of them
+//! is using, everything is merged by MAC address.
+//!
+//! Usage comes from the connections conntrack is tracking right now, so it's a picture of who
+//! is busy at the moment rather than a running total, closed connections drop out of it.
+
+use super::{KernelInterface, KernelInterfaceError};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use eui48::MacAddress;
+use regex::Regex;
+
+use failure::Error;
+
+/// Where dnsmasq keeps its leases on OpenWrt
+const DHCP_LEASES_PATH: &str = "/tmp/dhcp.leases";
+const CONNTRACK_PATH: &str = "/proc/net/nf_conntrack";
+
+/// A device on the lan
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LanDevice {
+    pub mac: MacAddress,
+    pub hostname: Option<String>,
+    pub ips: Vec<IpAddr>,
+    /// Unix time we last heard from the device, None if all we know of it is a lease
+    pub last_seen: Option<u64>,
+    /// Bytes the device sent and received over the connections it has open
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpLease {
+    /// Unix time the lease runs out, 0 for leases that never do
+    pub expires: u64,
+    pub mac: MacAddress,
+    pub ip: IpAddr,
+    pub hostname: Option<String>,
+}
+
+/// An entry from the neighbor table that we've recently heard from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NeighborEntry {
+    pub ip: IpAddr,
+    pub mac: MacAddress,
+    pub dev: String,
+    /// Seconds since the neighbor was last confirmed reachable
+    pub confirmed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConntrackUsage {
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+fn read_optional_file(path: &str) -> Result<Option<String>, Error> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut f) => {
+            f.read_to_string(&mut contents)?;
+            Ok(Some(contents))
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn parse_mac(input: &str) -> Result<MacAddress, Error> {
+    MacAddress::parse_str(input).map_err(|e| {
+        KernelInterfaceError::ParseError(format!("Invalid MAC address {:?}: {:?}", input, e)).into()
+    })
+}
+
+/// Parses a dnsmasq lease file, lines are `<expiry> <mac> <ip> <hostname> <client id>` with
+/// `*` for an unknown hostname. DHCPv6 leases have no MAC and are skipped, so are lines we
+/// can't make sense of
+fn parse_dhcp_leases(input: &str) -> Vec<DhcpLease> {
+    let mut leases = Vec::new();
+    for line in input.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 || fields[0] == "duid" {
+            continue;
+        }
+        let mac = match parse_mac(fields[1]) {
+            Ok(mac) => mac,
+            Err(_) => continue,
+        };
+        let (expires, ip) = match (fields[0].parse(), fields[2].parse()) {
+            (Ok(expires), Ok(ip)) => (expires, ip),
+            _ => {
+                warn!("Skipping malformed dhcp lease {:?}", line);
+                continue;
+            }
+        };
+        leases.push(DhcpLease {
+            expires,
+            mac,
+            ip,
+            hostname: match fields[3] {
+                "*" => None,
+                hostname => Some(hostname.to_string()),
+            },
+        });
+    }
+    leases
+}
+
+/// Parses `ip -s neighbor`, the used field is seconds since the entry was last used, confirmed
+/// and updated. Entries we can't parse are skipped
+fn parse_neighbor_stats(input: &str) -> Vec<NeighborEntry> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(
+            r"^(\S+) dev (\S+) lladdr (\S+).* used \d+/(\d+)/\d+.*(REACHABLE|STALE|DELAY)"
+        )
+        .expect("Unable to compile regular expression");
+    }
+    let mut neighbors = Vec::new();
+    for line in input.lines() {
+        let caps = match RE.captures(line) {
+            Some(caps) => caps,
+            None => continue,
+        };
+        match (
+            IpAddr::from_str(&caps[1]),
+            parse_mac(&caps[3]),
+            caps[4].parse(),
+        ) {
+            (Ok(ip), Ok(mac), Ok(confirmed)) => neighbors.push(NeighborEntry {
+                ip,
+                mac,
+                dev: caps[2].to_string(),
+                confirmed: Some(confirmed),
+            }),
+            _ => warn!("Skipping malformed neighbor {:?}", line),
+        }
+    }
+    neighbors
+}
+
+/// Sums up /proc/net/nf_conntrack by the address that opened each connection, the first
+/// `bytes=` on a line counts the original direction and the second the reply. The counters
+/// are only there with net.netfilter.nf_conntrack_acct enabled. Entries we can't parse are
+/// skipped
+fn parse_conntrack(input: &str) -> HashMap<IpAddr, ConntrackUsage> {
+    let mut usage: HashMap<IpAddr, ConntrackUsage> = HashMap::new();
+    for line in input.lines() {
+        let mut src = None;
+        let mut bytes = Vec::new();
+        let mut malformed = false;
+        for field in line.split_whitespace() {
+            if field.starts_with("src=") && src.is_none() {
+                match IpAddr::from_str(&field[4..]) {
+                    Ok(ip) => src = Some(ip),
+                    Err(_) => malformed = true,
+                }
+            } else if field.starts_with("bytes=") {
+                match field[6..].parse::<u64>() {
+                    Ok(count) => bytes.push(count),
+                    Err(_) => malformed = true,
+                }
+            }
+        }
+        if malformed {
+            warn!("Skipping malformed conntrack entry {:?}", line);
+            continue;
+        }
+        if let (Some(src), 2) = (src, bytes.len()) {
+            let entry = usage.entry(src).or_default();
+            entry.bytes_up += bytes[0];
+            entry.bytes_down += bytes[1];
+        }
+    }
+    usage
+}
+
+fn device_entry(
+    devices: &mut BTreeMap<MacAddress, LanDevice>,
+    mac: MacAddress,
+    ip: IpAddr,
+) -> &mut LanDevice {
+    let device = devices.entry(mac).or_insert_with(|| LanDevice {
+        mac,
+        hostname: None,
+        ips: Vec::new(),
+        last_seen: None,
+        bytes_up: 0,
+        bytes_down: 0,
+    });
+    if !device.ips.contains(&ip) {
+        device.ips.push(ip);
+    }
+    device
+}
+
+/// Merges everything we know by MAC address, neighbors are only counted if they're on one of
+/// `lan_nics`. Devices come out sorted by MAC so the list doesn't shuffle between calls
+fn merge_lan_devices(
+    leases: &[DhcpLease],
+    neighbors: &[NeighborEntry],
+    usage: &HashMap<IpAddr, ConntrackUsage>,
+    lan_nics: &[String],
+    now: u64,
+) -> Vec<LanDevice> {
+    let mut devices: BTreeMap<MacAddress, LanDevice> = BTreeMap::new();
+    for lease in leases {
+        device_entry(&mut devices, lease.mac, lease.ip).hostname = lease.hostname.clone();
+    }
+    for neighbor in neighbors.iter().filter(|n| lan_nics.contains(&n.dev)) {
+        let last_seen = neighbor.confirmed.map(|c| now.saturating_sub(c));
+        let device = device_entry(&mut devices, neighbor.mac, neighbor.ip);
+        if last_seen > device.last_seen {
+            device.last_seen = last_seen;
+        }
+    }
+
+    for device in devices.values_mut() {
+        device.ips.sort();
+        for ip in device.ips.iter() {
+            if let Some(usage) = usage.get(ip) {
+                device.bytes_up += usage.bytes_up;
+                device.bytes_down += usage.bytes_down;
+            }
+        }
+    }
+    devices.into_iter().map(|(_, device)| device).collect()
+}
+
+impl KernelInterface {
+    /// The leases dnsmasq has handed out, empty if it isn't running
+    pub fn get_dhcp_leases(&self) -> Result<Vec<DhcpLease>, Error> {
+        match read_optional_file(DHCP_LEASES_PATH)? {
+            Some(contents) => Ok(parse_dhcp_leases(&contents)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Like `get_neighbors` but with the MAC address of each neighbor and when we last heard
+    /// from it
+    pub fn get_neighbor_entries(&self) -> Result<Vec<NeighborEntry>, Error> {
+        if let Some(netlink) = self.netlink() {
+            let links = netlink.links()?;
+            let mut entries = Vec::new();
+            for neighbor in netlink.neighbors()? {
+                if !neighbor.is_live() {
+                    continue;
+                }
+                let mac = match neighbor.lladdr {
+                    Some(ref lladdr) => match MacAddress::from_bytes(lladdr) {
+                        Ok(mac) => mac,
+                        // not ethernet, tunnels and the like
+                        Err(_) => continue,
+                    },
+                    None => continue,
+                };
+                if let Some(link) = links.iter().find(|l| l.index == neighbor.index) {
+                    entries.push(NeighborEntry {
+                        ip: neighbor.address,
+                        mac,
+                        dev: link.name.clone(),
+                        confirmed: neighbor.confirmed.map(|c| u64::from(c) / 100),
+                    });
+                }
+            }
+            return Ok(entries);
+        }
+
+        let args = &["-s", "neighbor"];
+        let output = self.run_command("ip", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("ip", args, &output).into());
+        }
+        Ok(parse_neighbor_stats(&String::from_utf8(output.stdout)?))
+    }
+
+    /// Bytes up and down by the address that opened the connections, empty if conntrack
+    /// isn't loaded
+    pub fn get_conntrack_usage(&self) -> Result<HashMap<IpAddr, ConntrackUsage>, Error> {
+        match read_optional_file(CONNTRACK_PATH)? {
+            Some(contents) => Ok(parse_conntrack(&contents)),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    /// Every device we know of on the given lan interfaces
+    pub fn get_lan_devices(&self, lan_nics: &[String]) -> Result<Vec<LanDevice>, Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(merge_lan_devices(
+            &self.get_dhcp_leases()?,
+            &self.get_neighbor_entries()?,
+            &self.get_conntrack_usage()?,
+            lan_nics,
+            now,
+        ))
+    }
+}
+
+#[test]
+fn test_parse_dhcp_leases() {
+    let input = "1539800000 a4:5e:60:11:22:33 192.168.10.120 laptop 01:a4:5e:60:11:22:33
+0 00:0e:c6:aa:bb:cc 192.168.10.2 * *
+duid 00:01:00:01:23:45:67:89:94:83:c4:01:02:03
+1539800000 1234567 fd00::120 laptop 00:01:00:01:23:45:67:89:a4:5e:60:11:22:33
+";
+    let leases = parse_dhcp_leases(input);
+    assert_eq!(
+        leases,
+        vec![
+            DhcpLease {
+                expires: 1539800000,
+                mac: MacAddress::parse_str("a4:5e:60:11:22:33").unwrap(),
+                ip: "192.168.10.120".parse().unwrap(),
+                hostname: Some("laptop".to_string()),
+            },
+            DhcpLease {
+                expires: 0,
+                mac: MacAddress::parse_str("00:0e:c6:aa:bb:cc").unwrap(),
+                ip: "192.168.10.2".parse().unwrap(),
+                hostname: None,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_parse_neighbor_stats() {
+    let input = "192.168.10.120 dev br-lan lladdr a4:5e:60:11:22:33 ref 1 used 12/7/5 probes 1 \
+REACHABLE
+10.0.0.1 dev eth1 lladdr 94:83:c4:01:02:09 used 210/205/180 probes 4 STALE
+192.168.10.99 dev br-lan  used 30/30/30 probes 6 FAILED
+fe80::a65e:60ff:fe11:2233 dev br-lan lladdr a4:5e:60:11:22:33 router used 40/40/20 probes 1 STALE
+";
+    let neighbors = parse_neighbor_stats(input);
+    assert_eq!(neighbors.len(), 3);
+    assert_eq!(
+        neighbors[0],
+        NeighborEntry {
+            ip: "192.168.10.120".parse().unwrap(),
+            mac: MacAddress::parse_str("a4:5e:60:11:22:33").unwrap(),
+            dev: "br-lan".to_string(),
+            confirmed: Some(7),
+        }
+    );
+    assert_eq!(neighbors[1].dev, "eth1");
+    assert_eq!(neighbors[2].confirmed, Some(40));
+}
+
+#[test]
+fn test_parse_conntrack() {
+    let input = "ipv4     2 tcp      6 7437 ESTABLISHED src=192.168.10.120 dst=1.1.1.1 \
+sport=51234 dport=443 packets=10 bytes=1200 src=1.1.1.1 dst=10.0.0.23 sport=443 dport=51234 \
+packets=8 bytes=4000 [ASSURED] mark=0 zone=0 use=2
+ipv4     2 udp      17 25 src=192.168.10.120 dst=8.8.8.8 sport=5353 dport=53 packets=1 bytes=60 \
+src=8.8.8.8 dst=10.0.0.23 sport=53 dport=5353 packets=1 bytes=120 mark=0 zone=0 use=2
+ipv4     2 tcp      6 40 TIME_WAIT src=192.168.10.2 dst=10.0.0.1 sport=40000 dport=80 \
+src=10.0.0.1 dst=192.168.10.2 sport=80 dport=40000 [ASSURED] mark=0 zone=0 use=2
+";
+    let usage = parse_conntrack(input);
+    // without accounting there's nothing to count
+    assert_eq!(usage.len(), 1);
+    assert_eq!(
+        usage[&"192.168.10.120".parse::<IpAddr>().unwrap()],
+        ConntrackUsage {
+            bytes_up: 1260,
+            bytes_down: 4120,
+        }
+    );
+}
+
+#[test]
+fn test_parse_malformed_lines() {
+    // one bad line doesn't cost us the rest of the inventory
+    let leases = parse_dhcp_leases(
+        "soon a4:5e:60:11:22:33 192.168.10.120 laptop *
+0 00:0e:c6:aa:bb:cc 192.168.10.2 * *
+",
+    );
+    assert_eq!(leases.len(), 1);
+    assert_eq!(leases[0].hostname, None);
+
+    let neighbors = parse_neighbor_stats(
+        "192.168.10.120 dev br-lan lladdr a4:5e:60:11:22 used 12/7/5 probes 1 REACHABLE
+192.168.10.2 dev br-lan lladdr 00:0e:c6:aa:bb:cc used 12/7/5 probes 1 REACHABLE
+",
+    );
+    assert_eq!(neighbors.len(), 1);
+    assert_eq!(neighbors[0].ip, "192.168.10.2".parse::<IpAddr>().unwrap());
+
+    let usage = parse_conntrack(
+        "ipv4     2 udp      17 25 src=192.168.10.999 dst=8.8.8.8 sport=5353 dport=53 packets=1 \
+bytes=60 src=8.8.8.8 dst=10.0.0.23 sport=53 dport=5353 packets=1 bytes=120 mark=0 zone=0 use=2
+ipv4     2 udp      17 25 src=192.168.10.2 dst=8.8.8.8 sport=5353 dport=53 packets=1 bytes=60 \
+src=8.8.8.8 dst=10.0.0.23 sport=53 dport=5353 packets=1 bytes=120 mark=0 zone=0 use=2
+",
+    );
+    assert_eq!(usage.len(), 1);
+    assert!(usage.contains_key(&"192.168.10.2".parse::<IpAddr>().unwrap()));
+}
+
+#[test]
+fn test_merge_lan_devices() {
+    let laptop = MacAddress::parse_str("a4:5e:60:11:22:33").unwrap();
+    let printer = MacAddress::parse_str("00:0e:c6:aa:bb:cc").unwrap();
+    let phone = MacAddress::parse_str("58:ef:68:12:34:56").unwrap();
+    let upstream = MacAddress::parse_str("94:83:c4:01:02:09").unwrap();
+    let leases = vec![
+        DhcpLease {
+            expires: 1539800000,
+            mac: laptop,
+            ip: "192.168.10.120".parse().unwrap(),
+            hostname: Some("laptop".to_string()),
+        },
+        DhcpLease {
+            expires: 0,
+            mac: printer,
+            ip: "192.168.10.2".parse().unwrap(),
+            hostname: None,
+        },
+    ];
+    let neighbor = |ip: &str, mac, dev: &str, confirmed| NeighborEntry {
+        ip: ip.parse().unwrap(),
+        mac,
+        dev: dev.to_string(),
+        confirmed: Some(confirmed),
+    };
+    let neighbors = vec![
+        neighbor("192.168.10.120", laptop, "br-lan", 40),
+        neighbor("fe80::a65e:60ff:fe11:2233", laptop, "br-lan", 7),
+        neighbor("192.168.10.150", phone, "br-lan", 100),
+        neighbor("10.0.0.1", upstream, "eth1", 1),
+    ];
+    let mut usage = HashMap::new();
+    usage.insert(
+        "192.168.10.120".parse().unwrap(),
+        ConntrackUsage {
+            bytes_up: 1260,
+            bytes_down: 4120,
+        },
+    );
+
+    let devices = merge_lan_devices(&leases, &neighbors, &usage, &["br-lan".to_string()], 1000);
+    assert_eq!(
+        devices.iter().map(|d| d.mac).collect::<Vec<MacAddress>>(),
+        vec![printer, phone, laptop]
+    );
+
+    let laptop = &devices[2];
+    assert_eq!(laptop.hostname, Some("laptop".to_string()));
+    assert_eq!(
+        laptop.ips,
+        vec![
+            "192.168.10.120".parse::<IpAddr>().unwrap(),
+            "fe80::a65e:60ff:fe11:2233".parse().unwrap(),
+        ]
+    );
+    assert_eq!(laptop.last_seen, Some(993));
+    assert_eq!((laptop.bytes_up, laptop.bytes_down), (1260, 4120));
+
+    assert_eq!(devices[0].last_seen, None);
+    assert_eq!(devices[1].hostname, None);
+    assert_eq!(devices[1].last_seen, Some(900));
+}
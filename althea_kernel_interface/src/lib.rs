@@ -5,17 +5,28 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
+extern crate byteorder;
 extern crate eui48;
+extern crate futures;
 extern crate itertools;
+extern crate libc;
 extern crate regex;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+#[cfg(test)]
+extern crate tokio;
+extern crate tokio_process;
+extern crate tokio_timer;
 
 extern crate althea_types;
 
 use std::env;
-use std::io::ErrorKind;
-use std::process::{Command, Output};
+use std::io::{self, ErrorKind, Write};
+use std::process::{Command, Output, Stdio};
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use std::str;
 
@@ -23,34 +34,70 @@ mod counter;
 mod create_wg_key;
 mod delete_tunnel;
 mod dns;
+mod dry_run;
+mod error;
 mod exit_client_tunnel;
 mod exit_server_tunnel;
+mod firewall;
 mod fs_sync;
 mod get_neighbors;
 mod interface_tools;
 mod ip_addr;
 mod ip_route;
-mod iptables;
+mod lan_devices;
 mod link_local_tools;
 mod manipulate_uci;
+mod mtu;
+pub mod netlink;
+mod nft_counter;
 mod open_tunnel;
 mod openwrt_ubus;
 mod ping_check;
+mod record_replay;
 mod setup_wg_if;
+mod stats;
+mod traffic_shaping;
+mod transaction;
+mod uci;
 mod udp_socket_table;
 pub mod wg_iface_counter;
 
-pub use counter::FilterTarget;
+pub use counter::{CounterBackend, FilterTarget};
 pub use create_wg_key::WgKeypair;
+pub use dry_run::{
+    dry_run_enabled, dry_run_plan, enable_dry_run, DryRunCommandRunner, PlannedCommand,
+};
+pub use error::{FailedCommand, KernelInterfaceError};
+pub use exit_client_tunnel::lan_ipv6_address;
 pub use exit_server_tunnel::ExitClient;
+pub use firewall::FirewallRule;
+pub use lan_devices::{ConntrackUsage, DhcpLease, LanDevice, NeighborEntry};
+pub use open_tunnel::tunnel_link_local;
+pub use openwrt_ubus::{
+    BoardInfo, BoardRelease, IwinfoBss, IwinfoInfo, IwinfoRate, IwinfoStation, SystemInfo,
+    SystemMemory, UbusAddress, UbusDeviceStatistics, UbusDeviceStatus, UbusInterface,
+};
+pub use ping_check::ProbeResult;
+pub use record_replay::{CommandFixture, RecordingCommandRunner, ReplayCommandRunner};
+pub use stats::CpuTimes;
+pub use traffic_shaping::{client_class_id, ClientRate};
+pub use transaction::Transaction;
+pub use uci::{UciBatch, UciPackage, UciSection, UciValue};
+
+use netlink::{Netlink, WireGuard};
+
+use futures::{future, Future};
+use tokio_process::CommandExt;
+use tokio_timer::Timeout;
 
 use failure::Error;
 
-#[derive(Debug, Fail)]
-pub enum KernelInterfaceError {
-    #[fail(display = "Runtime Error: {:?}", _0)]
-    RuntimeError(String),
-}
+/// How long the hot path reads wait on a single command, shorter than a rita loop tick so a
+/// hung command fails the read instead of piling up behind the next one
+pub const ASYNC_COMMAND_TIMEOUT_SECS: u64 = 4;
+
+/// The output of a command that runs in the background
+pub type CommandFuture = Box<Future<Item = Output, Error = Error>>;
 
 #[cfg(test)]
 lazy_static! {
@@ -63,14 +110,60 @@ lazy_static! {
 
 #[cfg(not(test))]
 lazy_static! {
-    pub static ref KI: Box<KernelInterface> = Box::new(LinuxCommandRunner {});
+    pub static ref KI: Box<KernelInterface> = new_kernel_interface();
 }
 
 pub trait CommandRunner {
     fn run_command(&self, program: &str, args: &[&str]) -> Result<Output, Error>;
+
+    /// Runs a command without blocking the thread, for use inside actors. The command fails
+    /// with `KernelInterfaceError::TimedOut` if it's still running after `timeout`. By default
+    /// this just runs the command synchronously, which is all the mock and replay runners need
+    fn run_command_async(&self, program: &str, args: &[&str], _timeout: Duration) -> CommandFuture {
+        Box::new(future::result(self.run_command(program, args)))
+    }
+
+    /// Like `run_command` with `input` written to the command's stdin
+    fn run_command_with_input(
+        &self,
+        program: &str,
+        args: &[&str],
+        input: &str,
+    ) -> Result<Output, Error>;
+
     fn set_mock(&self, mock: Box<FnMut(String, Vec<String>) -> Result<Output, Error> + Send>);
 }
 
+/// Maps a failure to start a command onto the matching KernelInterfaceError
+fn spawn_error(program: &str, e: io::Error) -> Error {
+    match e.kind() {
+        ErrorKind::NotFound => {
+            error!("The {:?} binary was not found. Please install a package that provides it. PATH={:?}", program, env::var("PATH"));
+            KernelInterfaceError::BinaryMissing(program.to_string()).into()
+        }
+        ErrorKind::PermissionDenied => {
+            KernelInterfaceError::PermissionDenied(program.to_string()).into()
+        }
+        _ => e.into(),
+    }
+}
+
+fn log_output<S: AsRef<str>>(program: &str, args: &[S], output: &Output, start: Instant) {
+    let args: Vec<&str> = args.iter().map(|a| a.as_ref()).collect();
+    trace!("Command {:?} {:?} returned: {:?}", program, args, output);
+    if !output.status.success() {
+        info!(
+            "Command {:?} {:?} returned: an error {:?}",
+            program, args, output
+        );
+    }
+    trace!(
+        "command completed in {}s {}ms",
+        start.elapsed().as_secs(),
+        start.elapsed().subsec_nanos() / 1000000
+    );
+}
+
 pub struct LinuxCommandRunner;
 
 impl CommandRunner for LinuxCommandRunner {
@@ -78,27 +171,67 @@ impl CommandRunner for LinuxCommandRunner {
         let start = Instant::now();
         let output = match Command::new(program).args(args).output() {
             Ok(o) => o,
-            Err(e) => {
-                if e.kind() == ErrorKind::NotFound {
-                    error!("The {:?} binary was not found. Please install a package that provides it. PATH={:?}", program, env::var("PATH"));
-                }
-                return Err(e.into());
-            }
+            Err(e) => return Err(spawn_error(program, e)),
         };
+        log_output(program, args, &output, start);
+        Ok(output)
+    }
 
-        trace!("Command {:?} {:?} returned: {:?}", program, args, output);
-        if !output.status.success() {
-            info!(
-                "Command {:?} {:?} returned: an error {:?}",
-                program, args, output
-            );
+    fn run_command_with_input(
+        &self,
+        program: &str,
+        args: &[&str],
+        input: &str,
+    ) -> Result<Output, Error> {
+        let start = Instant::now();
+        let mut child = match Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => return Err(spawn_error(program, e)),
+        };
+        // stdin is closed when it's dropped here, so the command sees the end of its input
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input.as_bytes())?;
         }
-        trace!(
-            "command completed in {}s {}ms",
-            start.elapsed().as_secs(),
-            start.elapsed().subsec_nanos() / 1000000
-        );
-        return Ok(output);
+        let output = child.wait_with_output()?;
+        log_output(program, args, &output, start);
+        Ok(output)
+    }
+
+    fn run_command_async(&self, program: &str, args: &[&str], timeout: Duration) -> CommandFuture {
+        let start = Instant::now();
+        let program = program.to_string();
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        // tokio-process kills the child when its future is dropped, so a command that times out
+        // doesn't outlive the read that was waiting on it
+        let output = Command::new(&program).args(&args).output_async();
+        Box::new(Timeout::new(output, timeout).then(move |res| {
+            match res {
+                Ok(output) => {
+                    log_output(&program, &args, &output, start);
+                    Ok(output)
+                }
+                Err(ref e) if e.is_elapsed() => Err(KernelInterfaceError::TimedOut(format!(
+                    "{} {} after {}ms",
+                    program,
+                    args.join(" "),
+                    timeout.as_secs() * 1000 + u64::from(timeout.subsec_millis())
+                )).into()),
+                Err(e) => match e.into_inner() {
+                    Some(e) => Err(spawn_error(&program, e)),
+                    None => Err(format_err!(
+                        "Timer failed while running {} {:?}",
+                        program,
+                        args
+                    )),
+                },
+            }
+        }))
     }
 
     fn set_mock(&self, _mock: Box<FnMut(String, Vec<String>) -> Result<Output, Error> + Send>) {
@@ -120,12 +253,162 @@ impl CommandRunner for TestCommandRunner {
         (&mut *self.run_command.lock().unwrap())(program.to_string(), args_owned)
     }
 
+    /// Mocks only see the program and its arguments
+    fn run_command_with_input(
+        &self,
+        program: &str,
+        args: &[&str],
+        _input: &str,
+    ) -> Result<Output, Error> {
+        self.run_command(program, args)
+    }
+
     fn set_mock(&self, mock: Box<FnMut(String, Vec<String>) -> Result<Output, Error> + Send>) {
         *self.run_command.lock().unwrap() = mock
     }
 }
 
-pub trait KernelInterface: CommandRunner + Sync {}
+/// Runs link, address, route and neighbor operations over rtnetlink, WireGuard configuration
+/// over generic netlink and everything else through LinuxCommandRunner
+pub struct NetlinkKernelInterface {
+    runner: LinuxCommandRunner,
+    netlink: Netlink,
+    wireguard: WireGuard,
+}
+
+impl NetlinkKernelInterface {
+    pub fn new() -> Result<NetlinkKernelInterface, Error> {
+        Ok(NetlinkKernelInterface {
+            runner: LinuxCommandRunner {},
+            netlink: Netlink::new()?,
+            wireguard: WireGuard::new()?,
+        })
+    }
+}
+
+impl CommandRunner for NetlinkKernelInterface {
+    fn run_command(&self, program: &str, args: &[&str]) -> Result<Output, Error> {
+        self.runner.run_command(program, args)
+    }
+
+    fn run_command_async(&self, program: &str, args: &[&str], timeout: Duration) -> CommandFuture {
+        self.runner.run_command_async(program, args, timeout)
+    }
+
+    fn run_command_with_input(
+        &self,
+        program: &str,
+        args: &[&str],
+        input: &str,
+    ) -> Result<Output, Error> {
+        self.runner.run_command_with_input(program, args, input)
+    }
+
+    fn set_mock(&self, mock: Box<FnMut(String, Vec<String>) -> Result<Output, Error> + Send>) {
+        self.runner.set_mock(mock)
+    }
+}
+
+pub trait KernelInterface: CommandRunner + Sync {
+    /// The rtnetlink handle to use in place of `ip`, if this implementation has one
+    fn netlink(&self) -> Option<&Netlink> {
+        None
+    }
+
+    /// The WireGuard netlink handle to use in place of `wg`, if this implementation has one
+    fn wireguard(&self) -> Option<&WireGuard> {
+        None
+    }
+
+    /// True if changes are only being planned, see DryRunCommandRunner
+    fn dry_run(&self) -> bool {
+        false
+    }
+}
 
 impl KernelInterface for LinuxCommandRunner {}
 impl KernelInterface for TestCommandRunner {}
+impl KernelInterface for RecordingCommandRunner {}
+impl KernelInterface for ReplayCommandRunner {}
+impl KernelInterface for NetlinkKernelInterface {
+    fn netlink(&self) -> Option<&Netlink> {
+        Some(&self.netlink)
+    }
+
+    fn wireguard(&self) -> Option<&WireGuard> {
+        Some(&self.wireguard)
+    }
+}
+
+/// Picks the kernel interface implementation at runtime, set ALTHEA_KI_BACKEND=netlink to
+/// talk to the kernel directly instead of shelling out to `ip` and `wg`. If the netlink sockets
+/// can't be opened we fall back to the command runner so the router stays manageable.
+///
+/// Setting ALTHEA_KI_RECORD=<path> instead runs every command and saves it to a fixture file
+/// for ReplayCommandRunner, this always uses commands so that the whole session is captured.
+/// The same goes for dry runs, netlink changes can't be planned.
+pub fn new_kernel_interface() -> Box<KernelInterface> {
+    if dry_run_enabled() {
+        info!("Dry run, changes to the system will only be planned");
+        return Box::new(DryRunCommandRunner::new());
+    }
+
+    if let Ok(path) = env::var("ALTHEA_KI_RECORD") {
+        info!("Recording kernel interface commands to {}", path);
+        return Box::new(RecordingCommandRunner::new(path));
+    }
+
+    match env::var("ALTHEA_KI_BACKEND") {
+        Ok(ref backend) if backend == "netlink" => match NetlinkKernelInterface::new() {
+            Ok(ki) => {
+                info!("Using the netlink kernel interface");
+                return Box::new(ki);
+            }
+            Err(e) => error!(
+                "Failed to open netlink socket, falling back to commands {:?}",
+                e
+            ),
+        },
+        Ok(ref backend) if backend != "command" => {
+            warn!("Unknown ALTHEA_KI_BACKEND {}, using commands", backend)
+        }
+        _ => {}
+    }
+    Box::new(LinuxCommandRunner {})
+}
+
+#[test]
+fn test_run_command_async_timeout() {
+    use tokio::runtime::current_thread::Runtime;
+
+    let mut runtime = Runtime::new().unwrap();
+    let runner = LinuxCommandRunner {};
+
+    let output = runtime
+        .block_on(runner.run_command_async("echo", &["hello"], Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(output.stdout, b"hello\n".to_vec());
+
+    let start = Instant::now();
+    let res = runtime.block_on(runner.run_command_async(
+        "sleep",
+        &["10"],
+        Duration::from_millis(100),
+    ));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    match res.unwrap_err().downcast::<KernelInterfaceError>() {
+        Ok(KernelInterfaceError::TimedOut(message)) => assert_eq!(message, "sleep 10 after 100ms"),
+        e => panic!("Unexpected result {:?}", e),
+    }
+
+    match runtime
+        .block_on(runner.run_command_async("not-a-real-binary", &[], Duration::from_secs(5)))
+        .unwrap_err()
+        .downcast::<KernelInterfaceError>()
+    {
+        Ok(KernelInterfaceError::BinaryMissing(program)) => {
+            assert_eq!(program, "not-a-real-binary")
+        }
+        e => panic!("Unexpected result {:?}", e),
+    }
+}
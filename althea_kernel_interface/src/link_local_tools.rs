@@ -1,3 +1,4 @@
+use super::netlink::{self, Netlink};
 use super::{KernelInterface, KernelInterfaceError};
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
@@ -6,9 +7,39 @@ use regex::Regex;
 
 use failure::Error;
 
+/// Returns the first address of the wanted version and scope on the named device
+fn netlink_device_ip(
+    netlink: &Netlink,
+    dev: &str,
+    scope: u8,
+    v6: bool,
+) -> Result<Option<IpAddr>, Error> {
+    let link = match netlink.link_by_name(dev)? {
+        Some(link) => link,
+        None => return Ok(None),
+    };
+    Ok(netlink
+        .addresses_on(link.index)?
+        .into_iter()
+        .find(|a| a.scope == scope && a.address.is_ipv6() == v6)
+        .map(|a| a.address))
+}
+
 impl KernelInterface {
     /// This gets our link local ip for a given device
     pub fn get_link_local_device_ip(&self, dev: &str) -> Result<Ipv6Addr, Error> {
+        if let Some(netlink) = self.netlink() {
+            return match netlink_device_ip(netlink, dev, netlink::RT_SCOPE_LINK, true)? {
+                Some(IpAddr::V6(ip)) => {
+                    trace!("got link local IP of {} from device {}", ip, dev);
+                    Ok(ip)
+                }
+                _ => Err(KernelInterfaceError::RuntimeError(
+                    "No link local addresses found or no interface found".to_string(),
+                ).into()),
+            };
+        }
+
         let output = self.run_command("ip", &["addr", "show", "dev", dev, "scope", "link"])?;
         trace!("Got {:?} from `ip addr`", output);
 
@@ -31,6 +62,18 @@ impl KernelInterface {
 
     /// This gets our global ip for a given device
     pub fn get_global_device_ip(&self, dev: &str) -> Result<Ipv6Addr, Error> {
+        if let Some(netlink) = self.netlink() {
+            return match netlink_device_ip(netlink, dev, netlink::RT_SCOPE_UNIVERSE, true)? {
+                Some(IpAddr::V6(ip)) => {
+                    trace!("got global IP of {} from device {}", ip, dev);
+                    Ok(ip)
+                }
+                _ => Err(KernelInterfaceError::RuntimeError(
+                    "No global found or no interface found".to_string(),
+                ).into()),
+            };
+        }
+
         let output = self.run_command("ip", &["addr", "show", "dev", dev, "scope", "global"])?;
         trace!("Got {:?} from `ip addr`", output);
 
@@ -52,6 +95,18 @@ impl KernelInterface {
     }
 
     pub fn get_global_device_ip_v4(&self, dev: &str) -> Result<Ipv4Addr, Error> {
+        if let Some(netlink) = self.netlink() {
+            return match netlink_device_ip(netlink, dev, netlink::RT_SCOPE_UNIVERSE, false)? {
+                Some(IpAddr::V4(ip)) => {
+                    trace!("got global IP of {} from device {}", ip, dev);
+                    Ok(ip)
+                }
+                _ => Err(KernelInterfaceError::RuntimeError(
+                    "No global found or no interface found".to_string(),
+                ).into()),
+            };
+        }
+
         let output = self.run_command("ip", &["addr", "show", "dev", dev, "scope", "global"])?;
         trace!("Got {:?} from `ip addr`", output);
 
@@ -118,6 +173,13 @@ impl KernelInterface {
     }
     /// Returns all existing interfaces
     pub fn get_iface_index(&self, name: &str) -> Result<u32, Error> {
+        if let Some(netlink) = self.netlink() {
+            return match netlink.link_by_name(name)? {
+                Some(link) => Ok(link.index),
+                None => Err(KernelInterfaceError::InterfaceNotFound(name.to_string()).into()),
+            };
+        }
+
         let links = String::from_utf8(self.run_command("ip", &["link"])?.stdout)?;
 
         lazy_static! {
@@ -130,7 +192,7 @@ impl KernelInterface {
                 return Ok(caps[1].parse()?);
             }
         }
-        Err(KernelInterfaceError::RuntimeError("Interface not found".to_string()).into())
+        Err(KernelInterfaceError::InterfaceNotFound(name.to_string()).into())
     }
 }
 
@@ -9,10 +9,7 @@ impl KernelInterface {
     fn run_uci(&self, command: &str, args: &[&str]) -> Result<(), Error> {
         let output = self.run_command(command, args)?;
         if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error while setting UCI: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+            return Err(KernelInterfaceError::from_output(command, args, &output).into());
         }
         Ok(())
     }
@@ -50,12 +47,10 @@ impl KernelInterface {
 
     //Retrieves the value of a given UCI path, could be one or multiple values
     pub fn get_uci_var(&self, key: &str) -> Result<String, Error> {
-        let output = self.run_command("uci", &["get", &key])?;
+        let args = &["get", key];
+        let output = self.run_command("uci", args)?;
         if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error while getting UCI: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+            return Err(KernelInterfaceError::from_output("uci", args, &output).into());
         }
         let clean_string = String::from_utf8(output.stdout)?.trim().to_string();
         Ok(clean_string)
@@ -63,36 +58,30 @@ impl KernelInterface {
 
     //Commits changes to UCI
     pub fn uci_commit(&self, subsection: &str) -> Result<bool, Error> {
-        let output = self.run_command("uci", &["commit", subsection])?;
+        let args = &["commit", subsection];
+        let output = self.run_command("uci", args)?;
         if !output.status.success() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error while commiting UCI: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+            return Err(KernelInterfaceError::from_output("uci", args, &output).into());
         }
         Ok(true)
     }
 
     //Resets unsaved changes to UCI
     pub fn uci_revert(&self, section: &str) -> Result<(), Error> {
-        let output = self.run_command("uci", &["revert", section])?;
+        let args = &["revert", section];
+        let output = self.run_command("uci", args)?;
         if !output.status.success() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error while reverting UCI: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+            return Err(KernelInterfaceError::from_output("uci", args, &output).into());
         }
         Ok(())
     }
 
     pub fn refresh_initd(&self, program: &str) -> Result<(), Error> {
-        let output = self.run_command(&format!("/etc/init.d/{}", program), &["reload"])?;
+        let init_script = format!("/etc/init.d/{}", program);
+        let args = &["reload"];
+        let output = self.run_command(&init_script, args)?;
         if !output.status.success() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error while refreshing {}: {}",
-                program,
-                String::from_utf8(output.stderr)?
-            )).into());
+            return Err(KernelInterfaceError::from_output(&init_script, args, &output).into());
         }
         Ok(())
     }
@@ -104,17 +93,14 @@ impl KernelInterface {
             static ref RE: Regex = Regex::new(r"(.+)=(.+)").unwrap();
         }
 
-        let output = match section {
-            Some(s) => self.run_command("uci", &["show", s])?,
-            None => self.run_command("uci", &["show"])?,
+        let args = match section {
+            Some(s) => vec!["show", s],
+            None => vec!["show"],
         };
+        let output = self.run_command("uci", &args)?;
 
         if !output.status.success() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "`uci show` experienced a problem:\nstdout:\n{:?}\nstderr:\n{:?}",
-                String::from_utf8(output.stdout)?,
-                String::from_utf8(output.stderr)?
-            )).into());
+            return Err(KernelInterfaceError::from_output("uci", &args, &output).into());
         }
 
         let stdout = String::from_utf8(output.stdout)?;
@@ -124,11 +110,12 @@ impl KernelInterface {
         for line in stdout.lines() {
             let caps = match RE.captures(line) {
                 Some(c) => c,
-                None => bail!(
-                    "uci_show: Could not match regex {:?} on line {:?}",
-                    *RE,
-                    line
-                ),
+                None => {
+                    return Err(KernelInterfaceError::ParseError(format!(
+                        "uci_show: Could not match regex {:?} on line {:?}",
+                        *RE, line
+                    )).into())
+                }
             };
             retval.insert(
                 caps[1].to_owned(),
@@ -149,3 +136,32 @@ impl KernelInterface {
         Ok(())
     }
 }
+
+#[test]
+fn test_uci_replay() {
+    use super::ReplayCommandRunner;
+
+    let replay = ReplayCommandRunner::from_jsonl(include_str!("../fixtures/uci.jsonl")).unwrap();
+    {
+        let ki: &KernelInterface = &replay;
+        ki.set_uci_var("network.lan.ipaddr", "192.168.10.1")
+            .unwrap();
+        assert_eq!(
+            ki.get_uci_var("network.lan.ipaddr").unwrap(),
+            "192.168.10.1"
+        );
+        ki.set_uci_list("dhcp.@dnsmasq[0].server", &["1.1.1.1", "8.8.8.8"])
+            .unwrap();
+
+        let lan = ki.uci_show(Some("network.lan")).unwrap();
+        assert_eq!(lan.len(), 6);
+        assert_eq!(lan["network.lan"], "interface");
+        assert_eq!(lan["network.lan.ifname"], "eth0.1");
+        assert_eq!(lan["network.lan.ipaddr"], "192.168.10.1");
+
+        assert!(ki.uci_commit("network").unwrap());
+        assert!(ki.get_uci_var("network.wan6.ipaddr").is_err());
+        ki.uci_revert("network").unwrap();
+    }
+    replay.assert_done();
+}
@@ -0,0 +1,265 @@
+//! Path MTU discovery for WireGuard tunnels. The far end of a tunnel is pinged over the
+//! underlying link with fragmentation forbidden, and a binary search finds the largest packet
+//! that gets through. The tunnel mtu is that minus WireGuard's overhead. PPPoE, VLANs and some
+//! wireless bridges silently drop packets that don't fit, so guessing too high blackholes traffic.
+//!
+//! This needs iputils ping (`iputils-ping` and `iputils-ping6` on OpenWrt) for `-M do`, busybox's
+//! ping can't forbid fragmentation. With only busybox discovery fails with BinaryMissing and
+//! tunnels keep the mtu they already have.
+
+use super::{KernelInterface, KernelInterfaceError};
+
+use std::cmp::max;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use futures::{future, Future};
+use regex::Regex;
+
+use failure::Error;
+
+/// Tunnels are never set below this, the mesh runs over ipv6 and Linux drops the ipv6
+/// addresses of an interface with a smaller mtu. The kernel fragments the outer packets instead
+const MIN_TUNNEL_MTU: u32 = 1280;
+/// The smallest path we search, ipv6 guarantees at least this much
+const MIN_PATH_MTU: u32 = 1280;
+/// Used as the upper bound when we don't know which device the path starts on
+const DEFAULT_PATH_MTU: u32 = 1500;
+
+/// Outer IP header, UDP header and WireGuard's own header and auth tag
+fn wg_overhead(endpoint: IpAddr) -> u32 {
+    match endpoint {
+        IpAddr::V4(_) => 20 + 8 + 32,
+        IpAddr::V6(_) => 40 + 8 + 32,
+    }
+}
+
+/// IP and ICMP headers, ping's -s only counts the payload
+fn icmp_overhead(ip: IpAddr) -> u32 {
+    match ip {
+        IpAddr::V4(_) => 20 + 8,
+        IpAddr::V6(_) => 40 + 8,
+    }
+}
+
+/// The mtu for a tunnel whose packets go to `endpoint` over a path of `path_mtu`
+fn tunnel_mtu(path_mtu: u32, endpoint: IpAddr) -> u32 {
+    max(
+        path_mtu.saturating_sub(wg_overhead(endpoint)),
+        MIN_TUNNEL_MTU,
+    )
+}
+
+impl KernelInterface {
+    pub fn get_mtu(&self, dev: &str) -> Result<u32, Error> {
+        if let Some(netlink) = self.netlink() {
+            return match netlink.link_by_name(dev)? {
+                Some(link) => match link.mtu {
+                    Some(mtu) => Ok(mtu),
+                    None => bail!("No mtu reported for {}", dev),
+                },
+                None => Err(KernelInterfaceError::InterfaceNotFound(dev.to_string()).into()),
+            };
+        }
+
+        let args = &["link", "show", "dev", dev];
+        let output = self.run_command("ip", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("ip", args, &output).into());
+        }
+
+        lazy_static! {
+            static ref RE: Regex =
+                Regex::new(r" mtu ([0-9]+) ").expect("Unable to compile regular expression");
+        }
+        let stdout = String::from_utf8(output.stdout)?;
+        match RE.captures(&stdout) {
+            Some(caps) => Ok(caps[1].parse()?),
+            None => Err(KernelInterfaceError::ParseError(format!("No mtu in {:?}", stdout)).into()),
+        }
+    }
+
+    pub fn set_mtu(&self, dev: &str, mtu: u32) -> Result<(), Error> {
+        if let Some(netlink) = self.netlink() {
+            return netlink.set_link_mtu(dev, mtu).map_err(|e| {
+                KernelInterfaceError::from_netlink("received error setting mtu", e).into()
+            });
+        }
+
+        let mtu = mtu.to_string();
+        let args = &["link", "set", "dev", dev, "mtu", &mtu];
+        let output = self.run_command("ip", args)?;
+        if !output.stderr.is_empty() {
+            return Err(KernelInterfaceError::from_output("ip", args, &output).into());
+        }
+        Ok(())
+    }
+
+    /// Whether a packet of `size` bytes reaches `ip` without being fragmented, `dev` picks the
+    /// interface for link local addresses
+    fn df_ping_async(
+        &self,
+        ip: IpAddr,
+        dev: Option<String>,
+        size: u32,
+    ) -> Box<Future<Item = bool, Error = Error>> {
+        let program = match ip {
+            IpAddr::V4(_) => "ping",
+            IpAddr::V6(_) => "ping6",
+        };
+        let payload = size.saturating_sub(icmp_overhead(ip)).to_string();
+        let ip = ip.to_string();
+        let mut args = vec!["-M", "do", "-c", "2", "-W1", "-w", "2", "-s", &payload];
+        if let Some(ref dev) = dev {
+            args.push("-I");
+            args.push(dev);
+        }
+        args.push(&ip);
+
+        let failed_args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        Box::new(
+            self.run_command_async(program, &args, Duration::from_secs(4))
+                .and_then(move |output| {
+                    if output.status.success() {
+                        return Ok(true);
+                    }
+                    // a ping without -M can't tell us anything, as opposed to one that sent
+                    // pings and got nothing back
+                    let stderr = String::from_utf8_lossy(&output.stderr);
+                    if !stderr.contains("invalid option") && !stderr.contains("unrecognized option")
+                    {
+                        return Ok(false);
+                    }
+                    let args: Vec<&str> = failed_args.iter().map(|a| a.as_str()).collect();
+                    error!(
+                        "{} can't forbid fragmentation, mtu discovery needs iputils ping. \
+                         Please install iputils-ping and iputils-ping6. {}",
+                        program,
+                        KernelInterfaceError::from_output(program, &args, &output)
+                    );
+                    Err(KernelInterfaceError::BinaryMissing(format!("iputils {}", program)).into())
+                }),
+        )
+    }
+
+    /// Binary search between a size we know fits and one that might
+    fn search_path_mtu(
+        &'static self,
+        ip: IpAddr,
+        dev: Option<String>,
+        fits: u32,
+        upper: u32,
+    ) -> Box<Future<Item = u32, Error = Error>> {
+        if fits >= upper {
+            return Box::new(future::ok(fits));
+        }
+        let size = fits + (upper - fits + 1) / 2;
+        Box::new(
+            self.df_ping_async(ip, dev.clone(), size)
+                .and_then(move |ok| match ok {
+                    true => self.search_path_mtu(ip, dev, size, upper),
+                    false => self.search_path_mtu(ip, dev, fits, size - 1),
+                }),
+        )
+    }
+
+    /// Finds the largest packet that reaches `ip` unfragmented, starting on `dev` if it's given.
+    /// Fails if even the smallest size gets no reply since then we've learned nothing
+    pub fn discover_path_mtu_async(
+        &'static self,
+        ip: IpAddr,
+        dev: Option<String>,
+    ) -> Box<Future<Item = u32, Error = Error>> {
+        let upper = match dev {
+            Some(ref dev) => match self.get_mtu(dev) {
+                Ok(mtu) => mtu,
+                Err(e) => return Box::new(future::err(e)),
+            },
+            None => DEFAULT_PATH_MTU,
+        };
+        if upper <= MIN_PATH_MTU {
+            return Box::new(future::ok(upper));
+        }
+
+        Box::new(
+            self.df_ping_async(ip, dev.clone(), MIN_PATH_MTU)
+                .and_then(move |ok| {
+                    if !ok {
+                        bail!("No reply from {} to find the mtu with", ip);
+                    }
+                    Ok(())
+                }).and_then(move |_| self.search_path_mtu(ip, dev, MIN_PATH_MTU, upper)),
+        )
+    }
+
+    /// The mtu for a tunnel to `endpoint`, see `discover_path_mtu_async`
+    pub fn discover_tunnel_mtu_async(
+        &'static self,
+        endpoint: IpAddr,
+        dev: Option<String>,
+    ) -> Box<Future<Item = u32, Error = Error>> {
+        Box::new(
+            self.discover_path_mtu_async(endpoint, dev)
+                .map(move |path_mtu| tunnel_mtu(path_mtu, endpoint)),
+        )
+    }
+}
+
+#[test]
+fn test_tunnel_mtu() {
+    assert_eq!(tunnel_mtu(1500, "fe80::1".parse().unwrap()), 1420);
+    assert_eq!(tunnel_mtu(1492, "10.0.0.1".parse().unwrap()), 1432);
+    assert_eq!(tunnel_mtu(1300, "fe80::1".parse().unwrap()), MIN_TUNNEL_MTU);
+}
+
+#[test]
+fn test_discover_tunnel_mtu() {
+    use KI;
+
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+
+    // a PPPoE link, 1492 on the wire
+    KI.set_mock(Box::new(move |program, args| {
+        if program == "ip" {
+            assert_eq!(args, &["link", "show", "dev", "eth0"]);
+            return Ok(Output {
+                stdout: b"2: eth0: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc fq_codel state UP mode DEFAULT group default qlen 1000
+    link/ether 74:df:bf:30:37:f3 brd ff:ff:ff:ff:ff:ff"
+                    .to_vec(),
+                stderr: b"".to_vec(),
+                status: ExitStatus::from_raw(0),
+            });
+        }
+        assert_eq!(program, "ping6");
+        assert_eq!(&args[..4], &["-M", "do", "-c", "2"]);
+        assert_eq!(&args[9..], &["-I", "eth0", "fe80::1"]);
+        let payload: u32 = args[8].parse().unwrap();
+        Ok(Output {
+            stdout: b"".to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(if payload + 48 <= 1492 { 0 } else { 256 }),
+        })
+    }));
+    let mtu = KI
+        .discover_tunnel_mtu_async("fe80::1".parse().unwrap(), Some("eth0".to_string()))
+        .wait()
+        .unwrap();
+    assert_eq!(mtu, 1492 - 80);
+
+    // busybox ping
+    KI.set_mock(Box::new(move |_program, _args| {
+        Ok(Output {
+            stdout: b"".to_vec(),
+            stderr: b"ping: invalid option -- 'M'\n".to_vec(),
+            status: ExitStatus::from_raw(256),
+        })
+    }));
+    let res = KI
+        .discover_tunnel_mtu_async("10.0.0.1".parse().unwrap(), None)
+        .wait();
+    match res.unwrap_err().downcast::<KernelInterfaceError>() {
+        Ok(KernelInterfaceError::BinaryMissing(program)) => assert_eq!(program, "iputils ping"),
+        other => panic!("expected BinaryMissing, got {:?}", other),
+    }
+}
@@ -0,0 +1,101 @@
+use super::{attrs, parse_ip, Netlink, NetlinkError, AF_UNSPEC, RTM_GETADDR, RTM_NEWADDR};
+
+use byteorder::{ByteOrder, NativeEndian};
+
+use std::net::IpAddr;
+
+use failure::Error;
+
+const IFADDRMSG_LEN: usize = 8;
+
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub index: u32,
+    pub address: IpAddr,
+    pub prefix_len: u8,
+    pub scope: u8,
+}
+
+fn parse_address(payload: &[u8]) -> Result<Option<Address>, Error> {
+    if payload.len() < IFADDRMSG_LEN {
+        return Err(NetlinkError::Malformed("truncated ifaddrmsg".to_string()).into());
+    }
+    let prefix_len = payload[1];
+    let scope = payload[3];
+    let index = NativeEndian::read_u32(&payload[4..8]);
+
+    // on point to point links IFA_ADDRESS is the remote end, IFA_LOCAL is always ours
+    // when present, ipv6 only ever sends IFA_ADDRESS
+    let mut address = None;
+    let mut local = None;
+    for (kind, value) in attrs(&payload[IFADDRMSG_LEN..]) {
+        match kind {
+            IFA_ADDRESS => address = parse_ip(value),
+            IFA_LOCAL => local = parse_ip(value),
+            _ => {}
+        }
+    }
+
+    Ok(local.or(address).map(|address| Address {
+        index,
+        address,
+        prefix_len,
+        scope,
+    }))
+}
+
+impl Netlink {
+    /// Lists every address on every interface, both ipv4 and ipv6
+    pub fn addresses(&self) -> Result<Vec<Address>, Error> {
+        let mut header = [0u8; IFADDRMSG_LEN];
+        header[0] = AF_UNSPEC;
+
+        let mut addresses = Vec::new();
+        for (kind, payload) in self.dump(RTM_GETADDR, &header)? {
+            if kind == RTM_NEWADDR {
+                if let Some(address) = parse_address(&payload)? {
+                    addresses.push(address);
+                }
+            }
+        }
+        Ok(addresses)
+    }
+
+    /// Lists the addresses on a single interface
+    pub fn addresses_on(&self, index: u32) -> Result<Vec<Address>, Error> {
+        Ok(self
+            .addresses()?
+            .into_iter()
+            .filter(|a| a.index == index)
+            .collect())
+    }
+}
+
+#[test]
+fn test_parse_address() {
+    use super::{Request, AF_INET, RT_SCOPE_UNIVERSE};
+
+    let mut header = [0u8; IFADDRMSG_LEN];
+    header[0] = AF_INET;
+    header[1] = 24;
+    header[3] = RT_SCOPE_UNIVERSE;
+    NativeEndian::write_u32(&mut header[4..8], 2);
+    let mut request = Request::new(RTM_NEWADDR, 0);
+    request.push(&header);
+    request.attr_ip(IFA_ADDRESS, &"10.0.0.2".parse().unwrap());
+    request.attr_ip(IFA_LOCAL, &"10.0.0.1".parse().unwrap());
+    let buf = request.finish(1);
+
+    assert_eq!(
+        parse_address(&buf[16..]).unwrap(),
+        Some(Address {
+            index: 2,
+            address: "10.0.0.1".parse().unwrap(),
+            prefix_len: 24,
+            scope: RT_SCOPE_UNIVERSE,
+        })
+    );
+}
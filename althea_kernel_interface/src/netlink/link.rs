@@ -0,0 +1,155 @@
+use super::{
+    attrs, errno_of, parse_string, parse_u32, Netlink, NetlinkError, Request, AF_UNSPEC, NLM_F_ACK,
+    NLM_F_CREATE, NLM_F_EXCL, RTM_DELLINK, RTM_GETLINK, RTM_NEWLINK,
+};
+
+use byteorder::{ByteOrder, NativeEndian};
+use libc;
+
+use failure::Error;
+
+const IFINFOMSG_LEN: usize = 16;
+
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MTU: u16 = 4;
+const IFLA_OPERSTATE: u16 = 16;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_INFO_KIND: u16 = 1;
+
+const IF_OPER_UP: u8 = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub index: u32,
+    pub name: String,
+    pub flags: u32,
+    pub mtu: Option<u32>,
+    /// RFC 2863 operational state, this is what `ip` prints as "state UP"
+    pub operstate: u8,
+}
+
+impl Link {
+    pub fn is_up(&self) -> bool {
+        self.operstate == IF_OPER_UP
+    }
+}
+
+fn ifinfomsg(index: u32) -> [u8; IFINFOMSG_LEN] {
+    let mut header = [0u8; IFINFOMSG_LEN];
+    header[0] = AF_UNSPEC;
+    NativeEndian::write_u32(&mut header[4..8], index);
+    header
+}
+
+fn parse_link(payload: &[u8]) -> Result<Link, Error> {
+    if payload.len() < IFINFOMSG_LEN {
+        return Err(NetlinkError::Malformed("truncated ifinfomsg".to_string()).into());
+    }
+    let mut link = Link {
+        index: NativeEndian::read_u32(&payload[4..8]),
+        name: String::new(),
+        flags: NativeEndian::read_u32(&payload[8..12]),
+        mtu: None,
+        operstate: 0,
+    };
+    for (kind, value) in attrs(&payload[IFINFOMSG_LEN..]) {
+        match kind {
+            IFLA_IFNAME => link.name = parse_string(value),
+            IFLA_MTU => link.mtu = parse_u32(value),
+            IFLA_OPERSTATE if !value.is_empty() => link.operstate = value[0],
+            _ => {}
+        }
+    }
+    Ok(link)
+}
+
+impl Netlink {
+    /// Lists every link on the system, in index order
+    pub fn links(&self) -> Result<Vec<Link>, Error> {
+        let mut links = Vec::new();
+        for (kind, payload) in self.dump(RTM_GETLINK, &ifinfomsg(0))? {
+            if kind == RTM_NEWLINK {
+                links.push(parse_link(&payload)?);
+            }
+        }
+        Ok(links)
+    }
+
+    /// Looks up a single link by name, returns None if there is no such interface
+    pub fn link_by_name(&self, name: &str) -> Result<Option<Link>, Error> {
+        // a plain get is answered with a single message and nothing else, ask for an ack
+        // so request() knows when the reply is complete
+        let mut request = Request::new(RTM_GETLINK, NLM_F_ACK);
+        request.push(&ifinfomsg(0));
+        request.attr_str(IFLA_IFNAME, name);
+        let replies = match self.request(request) {
+            Ok(replies) => replies,
+            Err(e) => match errno_of(&e) {
+                Some(libc::ENODEV) => return Ok(None),
+                _ => return Err(e),
+            },
+        };
+        for (kind, payload) in replies {
+            if kind == RTM_NEWLINK {
+                return Ok(Some(parse_link(&payload)?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Creates a link of the given kind, equivalent to `ip link add <name> type <kind>`
+    pub fn add_link(&self, name: &str, kind: &str) -> Result<(), Error> {
+        let mut request = Request::new(RTM_NEWLINK, NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL);
+        request.push(&ifinfomsg(0));
+        request.attr_str(IFLA_IFNAME, name);
+        let linkinfo = request.nest_start(IFLA_LINKINFO);
+        request.attr_str(IFLA_INFO_KIND, kind);
+        request.nest_end(linkinfo);
+        self.request(request)?;
+        Ok(())
+    }
+
+    /// Equivalent to `ip link set dev <name> mtu <mtu>`
+    pub fn set_link_mtu(&self, name: &str, mtu: u32) -> Result<(), Error> {
+        let mut request = Request::new(RTM_NEWLINK, NLM_F_ACK);
+        request.push(&ifinfomsg(0));
+        request.attr_str(IFLA_IFNAME, name);
+        request.attr_u32(IFLA_MTU, mtu);
+        self.request(request)?;
+        Ok(())
+    }
+
+    /// Deletes the named link, equivalent to `ip link del dev <name>`
+    pub fn del_link(&self, name: &str) -> Result<(), Error> {
+        let mut request = Request::new(RTM_DELLINK, NLM_F_ACK);
+        request.push(&ifinfomsg(0));
+        request.attr_str(IFLA_IFNAME, name);
+        self.request(request)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_link() {
+    let mut payload = ifinfomsg(3).to_vec();
+    NativeEndian::write_u32(&mut payload[8..12], libc::IFF_UP as u32);
+    let mut request = Request::new(RTM_NEWLINK, 0);
+    request.push(&payload);
+    request.attr_str(IFLA_IFNAME, "wg0");
+    request.attr_u32(IFLA_MTU, 1420);
+    request.attr(IFLA_OPERSTATE, &[IF_OPER_UP]);
+    let buf = request.finish(1);
+
+    let link = parse_link(&buf[16..]).unwrap();
+    assert_eq!(
+        link,
+        Link {
+            index: 3,
+            name: "wg0".to_string(),
+            flags: libc::IFF_UP as u32,
+            mtu: Some(1420),
+            operstate: IF_OPER_UP,
+        }
+    );
+    assert!(link.is_up());
+}
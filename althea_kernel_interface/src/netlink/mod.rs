@@ -0,0 +1,415 @@
+//! A minimal rtnetlink client, this lets the kernel interface manage links, addresses, routes
+//! and neighbors without forking `ip` and regex parsing whatever version of iproute2 or busybox
+//! happens to be installed. Only the messages and attributes we actually use are implemented.
+
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+
+use byteorder::{ByteOrder, NativeEndian};
+use libc;
+
+use failure::Error;
+
+mod addr;
+mod link;
+mod neigh;
+mod route;
+pub mod wireguard;
+
+pub use self::addr::Address;
+pub use self::link::Link;
+pub use self::neigh::Neighbor;
+pub use self::route::{Route, RTN_UNICAST, RTPROT_BOOT, RT_TABLE_MAIN};
+pub use self::wireguard::WireGuard;
+
+pub const AF_INET: u8 = libc::AF_INET as u8;
+pub const AF_INET6: u8 = libc::AF_INET6 as u8;
+pub const AF_UNSPEC: u8 = libc::AF_UNSPEC as u8;
+
+pub const RT_SCOPE_UNIVERSE: u8 = 0;
+pub const RT_SCOPE_LINK: u8 = 253;
+pub const RT_SCOPE_HOST: u8 = 254;
+
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+
+const NLA_F_NESTED: u16 = 0x8000;
+
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_DUMP: u16 = 0x300;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_GETLINK: u16 = 18;
+const RTM_NEWADDR: u16 = 20;
+const RTM_GETADDR: u16 = 22;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_GETROUTE: u16 = 26;
+const RTM_NEWNEIGH: u16 = 28;
+const RTM_GETNEIGH: u16 = 30;
+
+const RECV_BUF_SIZE: usize = 65536;
+
+#[derive(Debug, Fail)]
+pub enum NetlinkError {
+    #[fail(display = "Netlink request failed with errno {}: {}", _0, _1)]
+    Errno(i32, String),
+    #[fail(display = "Malformed netlink message: {}", _0)]
+    Malformed(String),
+}
+
+impl NetlinkError {
+    pub fn from_errno(errno: i32) -> NetlinkError {
+        NetlinkError::Errno(errno, io::Error::from_raw_os_error(errno).to_string())
+    }
+
+    /// The errno the kernel answered with, if this was a kernel side failure
+    pub fn errno(&self) -> Option<i32> {
+        match *self {
+            NetlinkError::Errno(errno, _) => Some(errno),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the errno carried by a netlink failure, if the error is one
+pub fn errno_of(e: &Error) -> Option<i32> {
+    e.downcast_ref::<NetlinkError>().and_then(|e| e.errno())
+}
+
+/// A request under construction, the header length and sequence number are filled
+/// in when it is sent
+struct Request {
+    buf: Vec<u8>,
+}
+
+impl Request {
+    fn new(kind: u16, flags: u16) -> Request {
+        let mut buf = vec![0u8; NLMSG_HDRLEN];
+        NativeEndian::write_u16(&mut buf[4..6], kind);
+        NativeEndian::write_u16(&mut buf[6..8], flags | NLM_F_REQUEST);
+        Request { buf }
+    }
+
+    /// Appends a fixed size family header such as ifinfomsg or rtmsg
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+        self.pad();
+    }
+
+    fn attr(&mut self, kind: u16, value: &[u8]) {
+        let mut header = [0u8; 4];
+        NativeEndian::write_u16(&mut header[0..2], (4 + value.len()) as u16);
+        NativeEndian::write_u16(&mut header[2..4], kind);
+        self.buf.extend_from_slice(&header);
+        self.buf.extend_from_slice(value);
+        self.pad();
+    }
+
+    fn attr_u32(&mut self, kind: u16, value: u32) {
+        let mut bytes = [0u8; 4];
+        NativeEndian::write_u32(&mut bytes, value);
+        self.attr(kind, &bytes);
+    }
+
+    fn attr_str(&mut self, kind: u16, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.attr(kind, &bytes);
+    }
+
+    fn attr_u16(&mut self, kind: u16, value: u16) {
+        let mut bytes = [0u8; 2];
+        NativeEndian::write_u16(&mut bytes, value);
+        self.attr(kind, &bytes);
+    }
+
+    fn attr_ip(&mut self, kind: u16, value: &IpAddr) {
+        match *value {
+            IpAddr::V4(ip) => self.attr(kind, &ip.octets()),
+            IpAddr::V6(ip) => self.attr(kind, &ip.octets()),
+        }
+    }
+
+    /// Opens a nested attribute, everything added until the matching nest_end call
+    /// becomes part of its value
+    fn nest_start(&mut self, kind: u16) -> usize {
+        let start = self.buf.len();
+        self.attr(kind | NLA_F_NESTED, &[]);
+        start
+    }
+
+    fn nest_end(&mut self, start: usize) {
+        let len = (self.buf.len() - start) as u16;
+        NativeEndian::write_u16(&mut self.buf[start..start + 2], len);
+    }
+
+    fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn pad(&mut self) {
+        while self.buf.len() % 4 != 0 {
+            self.buf.push(0);
+        }
+    }
+
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        NativeEndian::write_u32(&mut self.buf[0..4], len);
+        NativeEndian::write_u32(&mut self.buf[8..12], seq);
+        self.buf
+    }
+}
+
+/// Iterates over the rtattrs in a message payload, yielding the attribute type and value
+struct Attrs<'a> {
+    buf: &'a [u8],
+}
+
+fn attrs(buf: &[u8]) -> Attrs {
+    Attrs { buf }
+}
+
+impl<'a> Iterator for Attrs<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<(u16, &'a [u8])> {
+        if self.buf.len() < 4 {
+            return None;
+        }
+        let len = NativeEndian::read_u16(&self.buf[0..2]) as usize;
+        let kind = NativeEndian::read_u16(&self.buf[2..4]);
+        if len < 4 || len > self.buf.len() {
+            return None;
+        }
+        let value = &self.buf[4..len];
+        let aligned = (len + 3) & !3;
+        self.buf = if aligned >= self.buf.len() {
+            &[]
+        } else {
+            &self.buf[aligned..]
+        };
+        // the top bits are the nested and byte order flags, we don't care about either
+        Some((kind & 0x3fff, value))
+    }
+}
+
+fn parse_ip(value: &[u8]) -> Option<IpAddr> {
+    match value.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::new(
+            value[0], value[1], value[2], value[3],
+        ))),
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(value);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+fn parse_string(value: &[u8]) -> String {
+    let end = value.iter().position(|b| *b == 0).unwrap_or(value.len());
+    String::from_utf8_lossy(&value[..end]).into_owned()
+}
+
+fn parse_u32(value: &[u8]) -> Option<u32> {
+    if value.len() >= 4 {
+        Some(NativeEndian::read_u32(value))
+    } else {
+        None
+    }
+}
+
+/// Splits a datagram into (type, flags, seq, payload) messages
+fn split_messages(buf: &[u8]) -> Result<Vec<(u16, u16, u32, &[u8])>, Error> {
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    while offset + NLMSG_HDRLEN <= buf.len() {
+        let len = NativeEndian::read_u32(&buf[offset..offset + 4]) as usize;
+        if len < NLMSG_HDRLEN || offset + len > buf.len() {
+            return Err(NetlinkError::Malformed(format!(
+                "message length {} at offset {} in a {} byte datagram",
+                len,
+                offset,
+                buf.len()
+            )).into());
+        }
+        let kind = NativeEndian::read_u16(&buf[offset + 4..offset + 6]);
+        let flags = NativeEndian::read_u16(&buf[offset + 6..offset + 8]);
+        let seq = NativeEndian::read_u32(&buf[offset + 8..offset + 12]);
+        messages.push((kind, flags, seq, &buf[offset + NLMSG_HDRLEN..offset + len]));
+        offset += (len + 3) & !3;
+    }
+    Ok(messages)
+}
+
+struct Socket {
+    fd: RawFd,
+    seq: u32,
+}
+
+impl Socket {
+    fn open(protocol: libc::c_int) -> Result<Socket, Error> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                protocol,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let res = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(e.into());
+        }
+
+        Ok(Socket { fd, seq: 0 })
+    }
+
+    fn send(&mut self, request: Request) -> Result<u32, Error> {
+        self.seq = self.seq.wrapping_add(1);
+        let buf = request.finish(self.seq);
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let res = unsafe {
+            libc::sendto(
+                self.fd,
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                0,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(self.seq)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            let res =
+                unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e.into());
+            }
+            return Ok(res as usize);
+        }
+    }
+
+    /// Sends a request and collects every reply payload until the kernel signals the end of a
+    /// dump or acknowledges the change. A negative acknowledgement is returned as a NetlinkError
+    fn request(&mut self, request: Request) -> Result<Vec<(u16, Vec<u8>)>, Error> {
+        let seq = self.send(request)?;
+
+        let mut replies = Vec::new();
+        let mut buf = vec![0u8; RECV_BUF_SIZE];
+        loop {
+            let len = self.recv(&mut buf)?;
+            for (kind, _flags, reply_seq, payload) in split_messages(&buf[..len])? {
+                if reply_seq != seq {
+                    trace!("Dropping stale netlink reply with seq {}", reply_seq);
+                    continue;
+                }
+                match kind {
+                    NLMSG_DONE => return Ok(replies),
+                    NLMSG_ERROR => {
+                        if payload.len() < 4 {
+                            return Err(NetlinkError::Malformed(
+                                "truncated error message".to_string(),
+                            ).into());
+                        }
+                        let errno = -NativeEndian::read_i32(&payload[0..4]);
+                        if errno == 0 {
+                            return Ok(replies);
+                        }
+                        return Err(NetlinkError::from_errno(errno).into());
+                    }
+                    _ => replies.push((kind, payload.to_vec())),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// A NETLINK_ROUTE socket, requests are serialized so a single handle can be shared
+/// between threads just like the command runners
+pub struct Netlink {
+    socket: Mutex<Socket>,
+}
+
+impl Netlink {
+    pub fn new() -> Result<Netlink, Error> {
+        Ok(Netlink {
+            socket: Mutex::new(Socket::open(libc::NETLINK_ROUTE)?),
+        })
+    }
+
+    fn request(&self, request: Request) -> Result<Vec<(u16, Vec<u8>)>, Error> {
+        self.socket.lock().unwrap().request(request)
+    }
+
+    fn dump(&self, kind: u16, header: &[u8]) -> Result<Vec<(u16, Vec<u8>)>, Error> {
+        let mut request = Request::new(kind, NLM_F_DUMP);
+        request.push(header);
+        self.request(request)
+    }
+}
+
+#[test]
+fn test_attrs_roundtrip() {
+    let mut request = Request::new(RTM_GETLINK, 0);
+    request.push(&[0u8; 16]);
+    request.attr_str(3, "wg0");
+    request.attr_u32(4, 1420);
+    request.attr_ip(1, &"fe80::1".parse().unwrap());
+    let buf = request.finish(7);
+
+    let messages = split_messages(&buf).unwrap();
+    assert_eq!(messages.len(), 1);
+    let (kind, flags, seq, payload) = messages[0];
+    assert_eq!(kind, RTM_GETLINK);
+    assert_eq!(flags, NLM_F_REQUEST);
+    assert_eq!(seq, 7);
+
+    let parsed: Vec<(u16, &[u8])> = attrs(&payload[16..]).collect();
+    assert_eq!(parsed.len(), 3);
+    assert_eq!(parsed[0].0, 3);
+    assert_eq!(parse_string(parsed[0].1), "wg0");
+    assert_eq!(parse_u32(parsed[1].1), Some(1420));
+    assert_eq!(parse_ip(parsed[2].1), Some("fe80::1".parse().unwrap()));
+}
@@ -0,0 +1,111 @@
+use super::{attrs, parse_ip, Netlink, NetlinkError, AF_UNSPEC, RTM_GETNEIGH, RTM_NEWNEIGH};
+
+use byteorder::{ByteOrder, NativeEndian};
+
+use std::net::IpAddr;
+
+use failure::Error;
+
+const NDMSG_LEN: usize = 12;
+
+const NDA_DST: u16 = 1;
+const NDA_LLADDR: u16 = 2;
+const NDA_CACHEINFO: u16 = 3;
+
+pub const NUD_REACHABLE: u16 = 0x02;
+pub const NUD_STALE: u16 = 0x04;
+pub const NUD_DELAY: u16 = 0x08;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Neighbor {
+    pub index: u32,
+    pub address: IpAddr,
+    pub lladdr: Option<Vec<u8>>,
+    pub state: u16,
+    /// Hundredths of a second since the neighbor was last confirmed reachable
+    pub confirmed: Option<u32>,
+}
+
+impl Neighbor {
+    /// True for the states `ip neighbor` shows as REACHABLE, STALE or DELAY, in other
+    /// words neighbors we have recently heard from
+    pub fn is_live(&self) -> bool {
+        self.lladdr.is_some() && self.state & (NUD_REACHABLE | NUD_STALE | NUD_DELAY) != 0
+    }
+}
+
+fn parse_neighbor(payload: &[u8]) -> Result<Option<Neighbor>, Error> {
+    if payload.len() < NDMSG_LEN {
+        return Err(NetlinkError::Malformed("truncated ndmsg".to_string()).into());
+    }
+    let index = NativeEndian::read_u32(&payload[4..8]);
+    let state = NativeEndian::read_u16(&payload[8..10]);
+
+    let mut address = None;
+    let mut lladdr = None;
+    let mut confirmed = None;
+    for (kind, value) in attrs(&payload[NDMSG_LEN..]) {
+        match kind {
+            NDA_DST => address = parse_ip(value),
+            NDA_LLADDR => lladdr = Some(value.to_vec()),
+            // struct nda_cacheinfo, confirmed comes first
+            NDA_CACHEINFO if value.len() >= 4 => {
+                confirmed = Some(NativeEndian::read_u32(&value[0..4]))
+            }
+            _ => {}
+        }
+    }
+
+    Ok(address.map(|address| Neighbor {
+        index,
+        address,
+        lladdr,
+        state,
+        confirmed,
+    }))
+}
+
+impl Netlink {
+    /// Dumps the ipv4 and ipv6 neighbor tables
+    pub fn neighbors(&self) -> Result<Vec<Neighbor>, Error> {
+        let mut header = [0u8; NDMSG_LEN];
+        header[0] = AF_UNSPEC;
+
+        let mut neighbors = Vec::new();
+        for (kind, payload) in self.dump(RTM_GETNEIGH, &header)? {
+            if kind == RTM_NEWNEIGH {
+                if let Some(neighbor) = parse_neighbor(&payload)? {
+                    neighbors.push(neighbor);
+                }
+            }
+        }
+        Ok(neighbors)
+    }
+}
+
+#[test]
+fn test_parse_neighbor() {
+    use super::{Request, AF_INET6};
+
+    let mut header = [0u8; NDMSG_LEN];
+    header[0] = AF_INET6;
+    NativeEndian::write_u32(&mut header[4..8], 4);
+    NativeEndian::write_u16(&mut header[8..10], NUD_STALE);
+    let mut request = Request::new(RTM_NEWNEIGH, 0);
+    request.push(&header);
+    request.attr_ip(NDA_DST, &"fe80::433:25ff:fe8c:e1ea".parse().unwrap());
+    request.attr(NDA_LLADDR, &[0x1a, 0x32, 0x06, 0x78, 0x05, 0x0a]);
+    let mut cacheinfo = [0u8; 16];
+    NativeEndian::write_u32(&mut cacheinfo[0..4], 700);
+    request.attr(NDA_CACHEINFO, &cacheinfo);
+    let buf = request.finish(1);
+
+    let neighbor = parse_neighbor(&buf[16..]).unwrap().unwrap();
+    assert_eq!(neighbor.index, 4);
+    assert_eq!(
+        neighbor.address,
+        "fe80::433:25ff:fe8c:e1ea".parse::<IpAddr>().unwrap()
+    );
+    assert!(neighbor.is_live());
+    assert_eq!(neighbor.confirmed, Some(700));
+}
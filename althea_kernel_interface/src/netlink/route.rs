@@ -0,0 +1,175 @@
+use super::{
+    attrs, parse_ip, parse_u32, Netlink, NetlinkError, Request, AF_INET, AF_INET6, NLM_F_ACK,
+    NLM_F_CREATE, NLM_F_EXCL, RTM_GETROUTE, RTM_NEWROUTE, RT_SCOPE_LINK, RT_SCOPE_UNIVERSE,
+};
+
+use std::net::IpAddr;
+
+use failure::Error;
+
+const RTMSG_LEN: usize = 12;
+
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_PRIORITY: u16 = 6;
+const RTA_PREFSRC: u16 = 7;
+const RTA_TABLE: u16 = 15;
+
+pub const RT_TABLE_MAIN: u32 = 254;
+pub const RTN_UNICAST: u8 = 1;
+pub const RTPROT_BOOT: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub family: u8,
+    /// None for a default route
+    pub dst: Option<IpAddr>,
+    pub dst_len: u8,
+    pub gateway: Option<IpAddr>,
+    /// Index of the outgoing interface
+    pub oif: Option<u32>,
+    pub prefsrc: Option<IpAddr>,
+    pub metric: Option<u32>,
+    pub protocol: u8,
+    pub scope: u8,
+    pub table: u32,
+    pub kind: u8,
+}
+
+impl Route {
+    pub fn is_default(&self) -> bool {
+        self.dst_len == 0
+    }
+}
+
+fn parse_route(payload: &[u8]) -> Result<Route, Error> {
+    if payload.len() < RTMSG_LEN {
+        return Err(NetlinkError::Malformed("truncated rtmsg".to_string()).into());
+    }
+    let mut route = Route {
+        family: payload[0],
+        dst: None,
+        dst_len: payload[1],
+        gateway: None,
+        oif: None,
+        prefsrc: None,
+        metric: None,
+        protocol: payload[5],
+        scope: payload[6],
+        table: payload[4] as u32,
+        kind: payload[7],
+    };
+    for (kind, value) in attrs(&payload[RTMSG_LEN..]) {
+        match kind {
+            RTA_DST => route.dst = parse_ip(value),
+            RTA_OIF => route.oif = parse_u32(value),
+            RTA_GATEWAY => route.gateway = parse_ip(value),
+            RTA_PRIORITY => route.metric = parse_u32(value),
+            RTA_PREFSRC => route.prefsrc = parse_ip(value),
+            RTA_TABLE => {
+                if let Some(table) = parse_u32(value) {
+                    route.table = table
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(route)
+}
+
+impl Netlink {
+    /// Lists the routes in the main table for the given address family
+    pub fn routes(&self, family: u8) -> Result<Vec<Route>, Error> {
+        let mut header = [0u8; RTMSG_LEN];
+        header[0] = family;
+
+        let mut routes = Vec::new();
+        for (kind, payload) in self.dump(RTM_GETROUTE, &header)? {
+            if kind == RTM_NEWROUTE {
+                let route = parse_route(&payload)?;
+                if route.table == RT_TABLE_MAIN {
+                    routes.push(route);
+                }
+            }
+        }
+        Ok(routes)
+    }
+
+    /// Adds a route to the main table, fails with EEXIST like `ip route add` does if an
+    /// identical route is already present
+    pub fn add_route(&self, route: &Route) -> Result<(), Error> {
+        let family = match (route.dst, route.gateway) {
+            (Some(IpAddr::V6(_)), _) | (None, Some(IpAddr::V6(_))) => AF_INET6,
+            (Some(IpAddr::V4(_)), _) | (None, Some(IpAddr::V4(_))) => AF_INET,
+            (None, None) => route.family,
+        };
+
+        let mut header = [0u8; RTMSG_LEN];
+        header[0] = family;
+        header[1] = route.dst_len;
+        header[4] = RT_TABLE_MAIN as u8;
+        header[5] = route.protocol;
+        header[6] = if route.gateway.is_some() {
+            RT_SCOPE_UNIVERSE
+        } else {
+            RT_SCOPE_LINK
+        };
+        header[7] = RTN_UNICAST;
+
+        let mut request = Request::new(RTM_NEWROUTE, NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL);
+        request.push(&header);
+        if let Some(ref dst) = route.dst {
+            request.attr_ip(RTA_DST, dst);
+        }
+        if let Some(ref gateway) = route.gateway {
+            request.attr_ip(RTA_GATEWAY, gateway);
+        }
+        if let Some(oif) = route.oif {
+            request.attr_u32(RTA_OIF, oif);
+        }
+        if let Some(metric) = route.metric {
+            request.attr_u32(RTA_PRIORITY, metric);
+        }
+        if let Some(ref prefsrc) = route.prefsrc {
+            request.attr_ip(RTA_PREFSRC, prefsrc);
+        }
+        self.request(request)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_route() {
+    let mut header = [0u8; RTMSG_LEN];
+    header[0] = AF_INET;
+    header[4] = RT_TABLE_MAIN as u8;
+    header[5] = 16;
+    header[7] = RTN_UNICAST;
+    let mut request = Request::new(RTM_NEWROUTE, 0);
+    request.push(&header);
+    request.attr_u32(RTA_TABLE, RT_TABLE_MAIN);
+    request.attr_u32(RTA_PRIORITY, 600);
+    request.attr_ip(RTA_GATEWAY, &"192.168.8.1".parse().unwrap());
+    request.attr_u32(RTA_OIF, 2);
+    let buf = request.finish(1);
+
+    let route = parse_route(&buf[16..]).unwrap();
+    assert!(route.is_default());
+    assert_eq!(
+        route,
+        Route {
+            family: AF_INET,
+            dst: None,
+            dst_len: 0,
+            gateway: Some("192.168.8.1".parse().unwrap()),
+            oif: Some(2),
+            prefsrc: None,
+            metric: Some(600),
+            protocol: 16,
+            scope: RT_SCOPE_UNIVERSE,
+            table: RT_TABLE_MAIN,
+            kind: RTN_UNICAST,
+        }
+    );
+}
@@ -0,0 +1,516 @@
+//! WireGuard configuration over the kernel's "wireguard" generic netlink family, this is the
+//! same interface the `wg` tool uses internally but without building an argv for every peer and
+//! parsing the text it prints back.
+
+use super::{attrs, errno_of, parse_string, NetlinkError, Request, Socket, NLM_F_ACK, NLM_F_DUMP};
+
+use althea_types::WgKey;
+use byteorder::{BigEndian, ByteOrder, NativeEndian};
+use libc;
+
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::Error;
+
+const GENL_HDRLEN: usize = 4;
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+const WG_GENL_NAME: &str = "wireguard";
+const WG_GENL_VERSION: u8 = 1;
+const WG_CMD_GET_DEVICE: u8 = 0;
+const WG_CMD_SET_DEVICE: u8 = 1;
+
+const WGDEVICE_A_IFNAME: u16 = 2;
+const WGDEVICE_A_PRIVATE_KEY: u16 = 3;
+const WGDEVICE_A_PUBLIC_KEY: u16 = 4;
+const WGDEVICE_A_FLAGS: u16 = 5;
+const WGDEVICE_A_LISTEN_PORT: u16 = 6;
+const WGDEVICE_A_PEERS: u16 = 8;
+const WGDEVICE_F_REPLACE_PEERS: u32 = 1;
+
+const WGPEER_A_PUBLIC_KEY: u16 = 1;
+const WGPEER_A_FLAGS: u16 = 3;
+const WGPEER_A_ENDPOINT: u16 = 4;
+const WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL: u16 = 5;
+const WGPEER_A_LAST_HANDSHAKE_TIME: u16 = 6;
+const WGPEER_A_RX_BYTES: u16 = 7;
+const WGPEER_A_TX_BYTES: u16 = 8;
+const WGPEER_A_ALLOWEDIPS: u16 = 9;
+const WGPEER_F_REMOVE_ME: u32 = 1;
+const WGPEER_F_REPLACE_ALLOWEDIPS: u32 = 2;
+
+const WGALLOWEDIP_A_FAMILY: u16 = 1;
+const WGALLOWEDIP_A_IPADDR: u16 = 2;
+const WGALLOWEDIP_A_CIDR_MASK: u16 = 3;
+
+/// Peers are split over several set requests past this size, the kernel will accept far larger
+/// messages but `wg` itself stays under a page or two and so do we
+const MAX_SET_REQUEST: usize = 16384;
+
+/// A peer as reported by the kernel
+#[derive(Debug, Clone, PartialEq)]
+pub struct WgPeer {
+    pub public_key: WgKey,
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<(IpAddr, u8)>,
+    pub persistent_keepalive: Option<u16>,
+    /// None if we have never completed a handshake with this peer
+    pub last_handshake: Option<SystemTime>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// A WireGuard device and all of its peers
+#[derive(Debug, Clone, PartialEq)]
+pub struct WgDevice {
+    pub name: String,
+    pub public_key: Option<WgKey>,
+    pub listen_port: u16,
+    pub peers: Vec<WgPeer>,
+}
+
+/// The settings to apply to a single peer, peers that are not mentioned are left alone
+#[derive(Debug, Clone, PartialEq)]
+pub struct WgPeerConfig {
+    pub public_key: WgKey,
+    pub endpoint: Option<SocketAddr>,
+    /// Replaces the peer's allowed ips when Some
+    pub allowed_ips: Option<Vec<(IpAddr, u8)>>,
+    pub persistent_keepalive: Option<u16>,
+    pub remove: bool,
+}
+
+impl WgPeerConfig {
+    pub fn new(public_key: WgKey) -> WgPeerConfig {
+        WgPeerConfig {
+            public_key,
+            endpoint: None,
+            allowed_ips: None,
+            persistent_keepalive: None,
+            remove: false,
+        }
+    }
+
+    pub fn remove(public_key: WgKey) -> WgPeerConfig {
+        WgPeerConfig {
+            remove: true,
+            ..WgPeerConfig::new(public_key)
+        }
+    }
+}
+
+/// The settings to apply to a device, the equivalent of one `wg set` invocation
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WgDeviceConfig {
+    pub private_key: Option<WgKey>,
+    pub listen_port: Option<u16>,
+    /// Drop every peer that isn't in this config
+    pub replace_peers: bool,
+    pub peers: Vec<WgPeerConfig>,
+}
+
+/// Parses a base64 WireGuard key as printed by `wg` and stored in our settings
+pub fn parse_key(key: &str) -> Result<WgKey, Error> {
+    match WgKey::from_str(key.trim()) {
+        Ok(key) => Ok(key),
+        Err(e) => bail!("Invalid WireGuard key {}: {:?}", key, e),
+    }
+}
+
+/// Reads a private key file in the format `wg set private-key` expects
+pub fn read_key_file(path: &Path) -> Result<WgKey, Error> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    parse_key(&contents)
+}
+
+fn parse_key_bytes(value: &[u8]) -> Option<WgKey> {
+    if value.len() != 32 {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(value);
+    Some(WgKey::from(key))
+}
+
+fn parse_u64(value: &[u8]) -> Option<u64> {
+    if value.len() >= 8 {
+        Some(NativeEndian::read_u64(value))
+    } else {
+        None
+    }
+}
+
+fn parse_endpoint(value: &[u8]) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+    let family = NativeEndian::read_u16(&value[0..2]) as i32;
+    let port = BigEndian::read_u16(&value[2..4]);
+    if family == libc::AF_INET && value.len() >= 8 {
+        let ip = Ipv4Addr::new(value[4], value[5], value[6], value[7]);
+        Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+    } else if family == libc::AF_INET6 && value.len() >= 28 {
+        let flowinfo = BigEndian::read_u32(&value[4..8]);
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&value[8..24]);
+        let scope_id = NativeEndian::read_u32(&value[24..28]);
+        Some(SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::from(octets),
+            port,
+            flowinfo,
+            scope_id,
+        )))
+    } else {
+        None
+    }
+}
+
+/// Encodes an endpoint as the sockaddr_in or sockaddr_in6 the kernel expects
+fn endpoint_bytes(endpoint: &SocketAddr) -> Vec<u8> {
+    match *endpoint {
+        SocketAddr::V4(addr) => {
+            let mut buf = vec![0u8; 16];
+            NativeEndian::write_u16(&mut buf[0..2], libc::AF_INET as u16);
+            BigEndian::write_u16(&mut buf[2..4], addr.port());
+            buf[4..8].copy_from_slice(&addr.ip().octets());
+            buf
+        }
+        SocketAddr::V6(addr) => {
+            let mut buf = vec![0u8; 28];
+            NativeEndian::write_u16(&mut buf[0..2], libc::AF_INET6 as u16);
+            BigEndian::write_u16(&mut buf[2..4], addr.port());
+            BigEndian::write_u32(&mut buf[4..8], addr.flowinfo());
+            buf[8..24].copy_from_slice(&addr.ip().octets());
+            NativeEndian::write_u32(&mut buf[24..28], addr.scope_id());
+            buf
+        }
+    }
+}
+
+fn parse_handshake(value: &[u8]) -> Option<SystemTime> {
+    if value.len() < 16 {
+        return None;
+    }
+    let secs = NativeEndian::read_i64(&value[0..8]);
+    let nanos = NativeEndian::read_i64(&value[8..16]);
+    if secs <= 0 && nanos <= 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::new(secs as u64, nanos as u32))
+}
+
+fn parse_allowed_ip(value: &[u8]) -> Option<(IpAddr, u8)> {
+    let mut ip = None;
+    let mut mask = None;
+    for (kind, value) in attrs(value) {
+        match kind {
+            WGALLOWEDIP_A_IPADDR => ip = super::parse_ip(value),
+            WGALLOWEDIP_A_CIDR_MASK if !value.is_empty() => mask = Some(value[0]),
+            _ => {}
+        }
+    }
+    match (ip, mask) {
+        (Some(ip), Some(mask)) => Some((ip, mask)),
+        _ => None,
+    }
+}
+
+fn parse_peer(value: &[u8]) -> Option<WgPeer> {
+    let mut public_key = None;
+    let mut peer = WgPeer {
+        public_key: WgKey::from([0u8; 32]),
+        endpoint: None,
+        allowed_ips: Vec::new(),
+        persistent_keepalive: None,
+        last_handshake: None,
+        rx_bytes: 0,
+        tx_bytes: 0,
+    };
+    for (kind, value) in attrs(value) {
+        match kind {
+            WGPEER_A_PUBLIC_KEY => public_key = parse_key_bytes(value),
+            WGPEER_A_ENDPOINT => peer.endpoint = parse_endpoint(value),
+            WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL if value.len() >= 2 => {
+                let interval = NativeEndian::read_u16(value);
+                if interval != 0 {
+                    peer.persistent_keepalive = Some(interval);
+                }
+            }
+            WGPEER_A_LAST_HANDSHAKE_TIME => peer.last_handshake = parse_handshake(value),
+            WGPEER_A_RX_BYTES => peer.rx_bytes = parse_u64(value).unwrap_or(0),
+            WGPEER_A_TX_BYTES => peer.tx_bytes = parse_u64(value).unwrap_or(0),
+            WGPEER_A_ALLOWEDIPS => {
+                for (_, allowed_ip) in attrs(value) {
+                    if let Some(allowed_ip) = parse_allowed_ip(allowed_ip) {
+                        peer.allowed_ips.push(allowed_ip);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    public_key.map(|key| {
+        peer.public_key = key;
+        peer
+    })
+}
+
+/// Folds the messages of a get device dump into one device. A peer with many allowed ips may
+/// be split across messages, in which case it is repeated at the start of the next one.
+fn parse_device(messages: &[Vec<u8>]) -> Result<WgDevice, Error> {
+    let mut device = WgDevice {
+        name: String::new(),
+        public_key: None,
+        listen_port: 0,
+        peers: Vec::new(),
+    };
+    for payload in messages {
+        if payload.len() < GENL_HDRLEN {
+            return Err(NetlinkError::Malformed("truncated genlmsghdr".to_string()).into());
+        }
+        for (kind, value) in attrs(&payload[GENL_HDRLEN..]) {
+            match kind {
+                WGDEVICE_A_IFNAME => device.name = parse_string(value),
+                WGDEVICE_A_PUBLIC_KEY => device.public_key = parse_key_bytes(value),
+                WGDEVICE_A_LISTEN_PORT if value.len() >= 2 => {
+                    device.listen_port = NativeEndian::read_u16(value)
+                }
+                WGDEVICE_A_PEERS => {
+                    for (_, peer) in attrs(value) {
+                        let peer = match parse_peer(peer) {
+                            Some(peer) => peer,
+                            None => continue,
+                        };
+                        let continued = match device.peers.last() {
+                            Some(last) => last.public_key == peer.public_key,
+                            None => false,
+                        };
+                        if continued {
+                            let last = device.peers.last_mut().unwrap();
+                            last.allowed_ips.extend(peer.allowed_ips);
+                        } else {
+                            device.peers.push(peer);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(device)
+}
+
+fn genl_header(cmd: u8, version: u8) -> [u8; GENL_HDRLEN] {
+    [cmd, version, 0, 0]
+}
+
+fn push_peer(request: &mut Request, peer: &WgPeerConfig) {
+    let start = request.nest_start(0);
+    request.attr(WGPEER_A_PUBLIC_KEY, peer.public_key.as_ref());
+    if peer.remove {
+        request.attr_u32(WGPEER_A_FLAGS, WGPEER_F_REMOVE_ME);
+        request.nest_end(start);
+        return;
+    }
+    if peer.allowed_ips.is_some() {
+        request.attr_u32(WGPEER_A_FLAGS, WGPEER_F_REPLACE_ALLOWEDIPS);
+    }
+    if let Some(ref endpoint) = peer.endpoint {
+        request.attr(WGPEER_A_ENDPOINT, &endpoint_bytes(endpoint));
+    }
+    if let Some(keepalive) = peer.persistent_keepalive {
+        request.attr_u16(WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL, keepalive);
+    }
+    if let Some(ref allowed_ips) = peer.allowed_ips {
+        let ips_start = request.nest_start(WGPEER_A_ALLOWEDIPS);
+        for &(ref ip, mask) in allowed_ips {
+            let ip_start = request.nest_start(0);
+            let family = match *ip {
+                IpAddr::V4(_) => libc::AF_INET as u16,
+                IpAddr::V6(_) => libc::AF_INET6 as u16,
+            };
+            request.attr_u16(WGALLOWEDIP_A_FAMILY, family);
+            request.attr_ip(WGALLOWEDIP_A_IPADDR, ip);
+            request.attr(WGALLOWEDIP_A_CIDR_MASK, &[mask]);
+            request.nest_end(ip_start);
+        }
+        request.nest_end(ips_start);
+    }
+    request.nest_end(start);
+}
+
+/// A generic netlink socket bound to the wireguard family, the family id is looked up on first
+/// use since the module may only be loaded once the first wireguard link is created
+pub struct WireGuard {
+    socket: Mutex<(Socket, Option<u16>)>,
+}
+
+impl WireGuard {
+    pub fn new() -> Result<WireGuard, Error> {
+        Ok(WireGuard {
+            socket: Mutex::new((Socket::open(libc::NETLINK_GENERIC)?, None)),
+        })
+    }
+
+    fn family(socket: &mut Socket, family: &mut Option<u16>) -> Result<u16, Error> {
+        if let Some(id) = *family {
+            return Ok(id);
+        }
+        let mut request = Request::new(GENL_ID_CTRL, NLM_F_ACK);
+        request.push(&genl_header(CTRL_CMD_GETFAMILY, 1));
+        request.attr_str(CTRL_ATTR_FAMILY_NAME, WG_GENL_NAME);
+        let replies = match socket.request(request) {
+            Ok(replies) => replies,
+            Err(e) => match errno_of(&e) {
+                Some(libc::ENOENT) => bail!("The wireguard kernel module is not loaded"),
+                _ => return Err(e),
+            },
+        };
+        for (_, payload) in replies {
+            if payload.len() < GENL_HDRLEN {
+                continue;
+            }
+            for (kind, value) in attrs(&payload[GENL_HDRLEN..]) {
+                if kind == CTRL_ATTR_FAMILY_ID && value.len() >= 2 {
+                    let id = NativeEndian::read_u16(value);
+                    *family = Some(id);
+                    return Ok(id);
+                }
+            }
+        }
+        Err(NetlinkError::Malformed("no family id in ctrl reply".to_string()).into())
+    }
+
+    /// Reads the full state of a WireGuard interface, like `wg show <interface> dump`
+    pub fn get_device(&self, interface: &str) -> Result<WgDevice, Error> {
+        let mut guard = self.socket.lock().unwrap();
+        let (ref mut socket, ref mut family) = *guard;
+        let family = WireGuard::family(socket, family)?;
+
+        let mut request = Request::new(family, NLM_F_DUMP);
+        request.push(&genl_header(WG_CMD_GET_DEVICE, WG_GENL_VERSION));
+        request.attr_str(WGDEVICE_A_IFNAME, interface);
+        let messages: Vec<Vec<u8>> = socket
+            .request(request)?
+            .into_iter()
+            .filter(|&(kind, _)| kind == family)
+            .map(|(_, payload)| payload)
+            .collect();
+        parse_device(&messages)
+    }
+
+    /// Applies a configuration to a WireGuard interface, like `wg set`. Large peer lists are
+    /// sent over several requests, only the first carries the device settings.
+    pub fn set_device(&self, interface: &str, config: &WgDeviceConfig) -> Result<(), Error> {
+        let mut guard = self.socket.lock().unwrap();
+        let (ref mut socket, ref mut family) = *guard;
+        let family = WireGuard::family(socket, family)?;
+
+        let new_request = |first: bool| {
+            let mut request = Request::new(family, NLM_F_ACK);
+            request.push(&genl_header(WG_CMD_SET_DEVICE, WG_GENL_VERSION));
+            request.attr_str(WGDEVICE_A_IFNAME, interface);
+            if first {
+                if let Some(ref key) = config.private_key {
+                    request.attr(WGDEVICE_A_PRIVATE_KEY, key.as_ref());
+                }
+                if let Some(port) = config.listen_port {
+                    request.attr_u16(WGDEVICE_A_LISTEN_PORT, port);
+                }
+                if config.replace_peers {
+                    request.attr_u32(WGDEVICE_A_FLAGS, WGDEVICE_F_REPLACE_PEERS);
+                }
+            }
+            request
+        };
+
+        let mut request = new_request(true);
+        let mut peers = request.nest_start(WGDEVICE_A_PEERS);
+        for peer in config.peers.iter() {
+            if request.len() > MAX_SET_REQUEST {
+                request.nest_end(peers);
+                socket.request(request)?;
+                request = new_request(false);
+                peers = request.nest_start(WGDEVICE_A_PEERS);
+            }
+            push_peer(&mut request, peer);
+        }
+        request.nest_end(peers);
+        socket.request(request)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_device() {
+    use super::{NLMSG_HDRLEN, RTM_NEWLINK};
+
+    let key_a = parse_key("jkIodvXKgij/rAEQXFEPJpls6ooxXJEC5XlWA1uUPUg=").unwrap();
+    let key_b = parse_key("8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk=").unwrap();
+    let endpoint = SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 60000, 0, 2));
+
+    let mut first = Request::new(RTM_NEWLINK, 0);
+    first.push(&genl_header(WG_CMD_GET_DEVICE, WG_GENL_VERSION));
+    first.attr_str(WGDEVICE_A_IFNAME, "wg_exit");
+    first.attr_u16(WGDEVICE_A_LISTEN_PORT, 59999);
+    let peers = first.nest_start(WGDEVICE_A_PEERS);
+    let peer = first.nest_start(0);
+    first.attr(WGPEER_A_PUBLIC_KEY, key_a.as_ref());
+    first.attr(WGPEER_A_ENDPOINT, &endpoint_bytes(&endpoint));
+    first.attr(WGPEER_A_RX_BYTES, &[1, 0, 0, 0, 0, 0, 0, 0]);
+    let ips = first.nest_start(WGPEER_A_ALLOWEDIPS);
+    let ip = first.nest_start(0);
+    first.attr_u16(WGALLOWEDIP_A_FAMILY, libc::AF_INET as u16);
+    first.attr_ip(WGALLOWEDIP_A_IPADDR, &"172.168.1.2".parse().unwrap());
+    first.attr(WGALLOWEDIP_A_CIDR_MASK, &[32]);
+    first.nest_end(ip);
+    first.nest_end(ips);
+    first.nest_end(peer);
+    first.nest_end(peers);
+
+    // the second message continues key_a's allowed ips, then adds key_b
+    let mut second = Request::new(RTM_NEWLINK, 0);
+    second.push(&genl_header(WG_CMD_GET_DEVICE, WG_GENL_VERSION));
+    let peers = second.nest_start(WGDEVICE_A_PEERS);
+    push_peer(
+        &mut second,
+        &WgPeerConfig {
+            allowed_ips: Some(vec![("172.168.1.3".parse().unwrap(), 32)]),
+            ..WgPeerConfig::new(key_a.clone())
+        },
+    );
+    push_peer(&mut second, &WgPeerConfig::new(key_b.clone()));
+    second.nest_end(peers);
+
+    let messages = vec![
+        first.finish(1)[NLMSG_HDRLEN..].to_vec(),
+        second.finish(1)[NLMSG_HDRLEN..].to_vec(),
+    ];
+    let device = parse_device(&messages).unwrap();
+
+    assert_eq!(device.name, "wg_exit");
+    assert_eq!(device.listen_port, 59999);
+    assert_eq!(device.peers.len(), 2);
+    assert_eq!(device.peers[0].public_key, key_a);
+    assert_eq!(device.peers[0].endpoint, Some(endpoint));
+    assert_eq!(device.peers[0].rx_bytes, 1);
+    assert_eq!(device.peers[0].last_handshake, None);
+    assert_eq!(
+        device.peers[0].allowed_ips,
+        vec![
+            ("172.168.1.2".parse().unwrap(), 32),
+            ("172.168.1.3".parse().unwrap(), 32),
+        ]
+    );
+    assert_eq!(device.peers[1].public_key, key_b);
+}
@@ -0,0 +1,345 @@
+//! nftables version of the counters in counter.rs. Each FilterTarget gets its own ip6 table with
+//! a dynamic set of every (destination, wg interface) pair the chain sees, the rule adding an
+//! element gives it a counter so even the first packet to a new destination is counted. Elements
+//! expire once a destination has been quiet for a while, so the set doesn't grow forever.
+//!
+//! Old kernels can't reset set element counters, so instead of zeroing them each read returns the
+//! growth since the previous read of the same target.
+
+use super::{FilterTarget, KernelInterface, KernelInterfaceError, ASYNC_COMMAND_TIMEOUT_SECS};
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::process::Output;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::Future;
+
+use serde_json::{self, Value};
+
+use failure::Error;
+
+const CHAIN: &str = "rita";
+const DESTINATIONS_SET: &str = "destinations";
+
+/// How long a destination stays in the set without traffic, this has to be well above the
+/// interval counters are read at so an element can't expire and come back between two reads
+const ELEMENT_TIMEOUT_SECS: u64 = 120;
+
+/// packets and bytes of one set element
+type ElementCounter = (u64, u64);
+
+lazy_static! {
+    // the element counters seen by the last read, by table
+    static ref LAST_READ: Mutex<HashMap<String, HashMap<(IpAddr, String), ElementCounter>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn nft_objects(input: &str) -> Result<Vec<Value>, Error> {
+    let mut value: Value = serde_json::from_str(input)?;
+    match value["nftables"].take() {
+        Value::Array(objects) => Ok(objects),
+        _ => bail!("nft output has no nftables array {}", input),
+    }
+}
+
+/// Parses the elements and their counters out of `nft -j list set ..` for a set of
+/// ipv6_addr . ifname
+fn parse_nft_set(input: &str) -> Result<HashMap<(IpAddr, String), ElementCounter>, Error> {
+    let mut elements = HashMap::new();
+
+    // example element `{"elem": {"val": {"concat": ["fd00::1", "wg0"]}, "timeout": 120000,
+    // "expires": 119000, "counter": {"packets": 28, "bytes": 2212}}}`
+
+    for object in nft_objects(input)? {
+        let elems = match object["set"]["elem"].as_array() {
+            Some(elems) => elems.clone(),
+            None => continue,
+        };
+        for elem in elems {
+            let concat = match elem["elem"]["val"]["concat"].as_array() {
+                Some(concat) => concat.clone(),
+                None => bail!("Unexpected nftables set element {}", elem),
+            };
+            let key = match (
+                concat.get(0).and_then(|v| v.as_str()),
+                concat.get(1).and_then(|v| v.as_str()),
+            ) {
+                (Some(addr), Some(iface)) => (IpAddr::from_str(addr)?, iface.to_string()),
+                _ => bail!("Unexpected nftables set element {}", elem),
+            };
+            let counter = &elem["elem"]["counter"];
+            match (counter["packets"].as_u64(), counter["bytes"].as_u64()) {
+                (Some(packets), Some(bytes)) => {
+                    elements.insert(key, (packets, bytes));
+                }
+                _ => bail!("nftables set element {} has no counter", elem),
+            }
+        }
+    }
+    Ok(elements)
+}
+
+/// The traffic each element saw since `last`, in the same form as parse_ipset. An element whose
+/// counter went backwards expired and was added again, so all of its count is new
+fn counter_growth(
+    last: &HashMap<(IpAddr, String), ElementCounter>,
+    current: &HashMap<(IpAddr, String), ElementCounter>,
+) -> HashMap<(IpAddr, String), u64> {
+    let mut map = HashMap::new();
+    for (key, &(packets, bytes)) in current {
+        let (packets, bytes) = match last.get(key) {
+            Some(&(last_packets, last_bytes)) if packets >= last_packets && bytes >= last_bytes => {
+                (packets - last_packets, bytes - last_bytes)
+            }
+            _ => (packets, bytes),
+        };
+        map.insert(key.clone(), bytes + packets * 40);
+    }
+    map
+}
+
+/// Records this read for the target and returns what grew since the last one, elements that
+/// expired are dropped along the way
+fn take_growth(
+    target: &FilterTarget,
+    current: HashMap<(IpAddr, String), ElementCounter>,
+) -> HashMap<(IpAddr, String), u64> {
+    let mut last_read = LAST_READ.lock().unwrap();
+    let last = last_read
+        .entry(target.set_name().to_string())
+        .or_insert_with(HashMap::new);
+    let res = counter_growth(last, &current);
+    *last = current;
+    res
+}
+
+fn nft_stdout<S: AsRef<str>>(args: &[S], output: Output) -> Result<String, Error> {
+    if !output.status.success() {
+        let args: Vec<&str> = args.iter().map(|a| a.as_ref()).collect();
+        return Err(KernelInterfaceError::from_output("nft", &args, &output).into());
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+impl KernelInterface {
+    fn run_nft(&self, args: &[&str]) -> Result<String, Error> {
+        let output = self.run_command("nft", args)?;
+        nft_stdout(args, output)
+    }
+
+    fn run_nft_async(&self, args: &[&str]) -> Box<Future<Item = String, Error = Error>> {
+        let timeout = Duration::from_secs(ASYNC_COMMAND_TIMEOUT_SECS);
+        let owned: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        Box::new(
+            self.run_command_async("nft", args, timeout)
+                .and_then(move |output| nft_stdout(&owned, output)),
+        )
+    }
+
+    /// Recreates the counter table for this target from scratch, the whole table is replaced in
+    /// one nft transaction so a restart never leaves a half built table behind
+    pub fn init_nft_counter(&self, target: &FilterTarget) -> Result<(), Error> {
+        let table = format!("ip6 {}", target.set_name());
+        let script = [
+            // add before delete so the delete can't fail on the first start
+            format!("add table {}", table),
+            format!("delete table {}", table),
+            format!("add table {}", table),
+            format!(
+                "add chain {} {} {{ type filter hook {} priority 0; policy accept; }}",
+                table,
+                CHAIN,
+                target.hook()
+            ),
+            format!(
+                "add set {} {} {{ type ipv6_addr . ifname; flags dynamic, timeout; timeout {}s; }}",
+                table, DESTINATIONS_SET, ELEMENT_TIMEOUT_SECS
+            ),
+            // only tunnel traffic is billed, lan and wan traffic never reaches the set
+            format!(
+                "add rule {} {} {} \"wg*\" update @{} {{ ip6 daddr . {} counter }}",
+                table,
+                CHAIN,
+                target.iface_key(),
+                DESTINATIONS_SET,
+                target.iface_key()
+            ),
+        ].join("; ");
+        self.run_nft(&[&script])?;
+        LAST_READ.lock().unwrap().remove(target.set_name());
+        Ok(())
+    }
+
+    /// Returns the traffic for this target since the last read
+    pub fn read_nft_counters(
+        &self,
+        target: &FilterTarget,
+    ) -> Result<HashMap<(IpAddr, String), u64>, Error> {
+        let output = self.run_nft(&[
+            "-j",
+            "list",
+            "set",
+            "ip6",
+            target.set_name(),
+            DESTINATIONS_SET,
+        ])?;
+        let res = take_growth(target, parse_nft_set(&output)?);
+        trace!("nft counters parsed into {:?}", res);
+        Ok(res)
+    }
+
+    /// Same as `read_nft_counters` but runs the command in the background
+    pub fn read_nft_counters_async(
+        &'static self,
+        target: FilterTarget,
+    ) -> Box<Future<Item = HashMap<(IpAddr, String), u64>, Error = Error>> {
+        Box::new(
+            self.run_nft_async(&[
+                "-j",
+                "list",
+                "set",
+                "ip6",
+                target.set_name(),
+                DESTINATIONS_SET,
+            ]).and_then(move |output| {
+                let res = take_growth(&target, parse_nft_set(&output)?);
+                trace!("nft counters parsed into {:?}", res);
+                Ok(res)
+            }),
+        )
+    }
+}
+
+#[test]
+fn test_parse_nft_set() {
+    let data = r#"{"nftables": [{"metainfo": {"version": "1.0.2", "release_name": "Lester Gooch", "json_schema_version": 1}}, {"set": {"family": "ip6", "name": "destinations", "table": "rita_input", "type": ["ipv6_addr", "ifname"], "handle": 2, "flags": ["dynamic", "timeout"], "timeout": 120, "elem": [{"elem": {"val": {"concat": ["1234:5678:9801:2345:6789:123:4567:8901", "wg42"]}, "timeout": 120000, "expires": 119000, "counter": {"packets": 123456789, "bytes": 987654321}}}, {"elem": {"val": {"concat": ["fd00::1", "wg0"]}, "timeout": 120000, "expires": 20000, "counter": {"packets": 0, "bytes": 0}}}]}}]}"#;
+    let result = parse_nft_set(data).expect("Unable to parse set");
+    assert_eq!(result.len(), 2);
+    assert_eq!(
+        result[&(
+            "1234:5678:9801:2345:6789:123:4567:8901".parse().unwrap(),
+            "wg42".to_string()
+        )],
+        (123456789, 987654321)
+    );
+    assert_eq!(
+        result[&("fd00::1".parse().unwrap(), "wg0".to_string())],
+        (0, 0)
+    );
+
+    let no_counter = r#"{"nftables": [{"set": {"family": "ip6", "name": "destinations", "table": "rita_input", "elem": [{"elem": {"val": {"concat": ["fd00::1", "wg0"]}, "expires": 20000}}]}}]}"#;
+    assert!(parse_nft_set(no_counter).is_err());
+}
+
+#[test]
+fn test_counter_growth() {
+    let kept = ("fd00::1".parse().unwrap(), "wg0".to_string());
+    let readded = ("fd00::2".parse().unwrap(), "wg1".to_string());
+    let new = ("fd00::3".parse().unwrap(), "wg2".to_string());
+    let expired = ("fd00::4".parse().unwrap(), "wg3".to_string());
+
+    let mut last = HashMap::new();
+    last.insert(kept.clone(), (10, 1000));
+    last.insert(readded.clone(), (50, 5000));
+    last.insert(expired.clone(), (1, 100));
+
+    let mut current = HashMap::new();
+    current.insert(kept.clone(), (15, 1500));
+    current.insert(readded.clone(), (2, 200));
+    current.insert(new.clone(), (1, 80));
+
+    let growth = counter_growth(&last, &current);
+    assert_eq!(growth.len(), 3);
+    assert_eq!(growth[&kept], 500 + 5 * 40);
+    assert_eq!(growth[&readded], 200 + 2 * 40);
+    // the first packet to a destination is counted
+    assert_eq!(growth[&new], 80 + 40);
+}
+
+#[test]
+fn test_init_nft_counter() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    use KI;
+
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        match counter {
+            1 => {
+                assert_eq!(program, "nft");
+                assert_eq!(
+                    args,
+                    vec![
+                        "add table ip6 rita_output; \
+                         delete table ip6 rita_output; \
+                         add table ip6 rita_output; \
+                         add chain ip6 rita_output rita { type filter hook output priority 0; policy accept; }; \
+                         add set ip6 rita_output destinations { type ipv6_addr . ifname; flags dynamic, timeout; timeout 120s; }; \
+                         add rule ip6 rita_output rita oifname \"wg*\" update @destinations { ip6 daddr . oifname counter }",
+                    ]
+                );
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(0),
+                })
+            }
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        }
+    }));
+    KI.init_nft_counter(&FilterTarget::Output)
+        .expect("Unable to init counter");
+}
+
+#[test]
+fn test_read_nft_counters() {
+    use std::net::Ipv6Addr;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    use KI;
+
+    let mut counter = 0;
+
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        assert_eq!(program, "nft");
+        assert_eq!(
+            args,
+            vec!["-j", "list", "set", "ip6", "rita_input", "destinations"]
+        );
+        let stdout = match counter {
+            1 => br#"{"nftables": [{"set": {"family": "ip6", "name": "destinations", "table": "rita_input", "elem": [{"elem": {"val": {"concat": ["fd00::dead:beef", "wg42"]}, "expires": 119000, "counter": {"packets": 111, "bytes": 222}}}]}}]}"#.to_vec(),
+            2 => br#"{"nftables": [{"set": {"family": "ip6", "name": "destinations", "table": "rita_input", "elem": [{"elem": {"val": {"concat": ["fd00::dead:beef", "wg42"]}, "expires": 119000, "counter": {"packets": 112, "bytes": 322}}}]}}]}"#.to_vec(),
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        };
+        Ok(Output {
+            stdout,
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+    let key = (
+        IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0xdead, 0xbeef)),
+        "wg42".to_string(),
+    );
+
+    let result = KI
+        .read_nft_counters(&FilterTarget::Input)
+        .expect("Unable to read values");
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[&key], 222u64 + 111u64 * 40);
+
+    let result = KI
+        .read_nft_counters(&FilterTarget::Input)
+        .expect("Unable to read values");
+    assert_eq!(result[&key], 100u64 + 40);
+}
@@ -1,29 +1,39 @@
+use super::netlink::wireguard::{parse_key, read_key_file, WgDeviceConfig, WgPeerConfig};
 use super::{KernelInterface, KernelInterfaceError};
 
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::path::Path;
 
 use failure::Error;
 
-fn to_wg_local(ip: &IpAddr) -> IpAddr {
-    match ip {
-        &IpAddr::V6(ip) => {
-            let mut seg = ip.segments();
-            assert_eq!((seg[0] & 0xfd00), 0xfd00);
-            IpAddr::V6(Ipv6Addr::new(
+/// The link local ip a node gives itself on every tunnel, None if `mesh_ip` isn't a mesh ip
+pub fn tunnel_link_local(mesh_ip: IpAddr) -> Option<IpAddr> {
+    match mesh_ip {
+        IpAddr::V6(ip) => {
+            let seg = ip.segments();
+            if (seg[0] & 0xfd00) != 0xfd00 {
+                return None;
+            }
+            Some(IpAddr::V6(Ipv6Addr::new(
                 0xfe80, 0x0, 0x0, 0x0, seg[4], seg[5], seg[6], seg[7],
-            ))
+            )))
         }
-        _ => unreachable!(),
+        IpAddr::V4(_) => None,
     }
 }
 
+fn to_wg_local(ip: &IpAddr) -> IpAddr {
+    tunnel_link_local(*ip).expect("Our mesh ip must be in fd00::/8")
+}
+
 #[test]
 fn test_to_wg_local() {
     assert_eq!(
         to_wg_local(&"fd00::1".parse().unwrap()),
         "fe80::1".parse::<IpAddr>().unwrap()
-    )
+    );
+    assert_eq!(tunnel_link_local("2001:db8::1".parse().unwrap()), None);
+    assert_eq!(tunnel_link_local("10.0.0.1".parse().unwrap()), None);
 }
 
 fn is_link_local(ip: IpAddr) -> bool {
@@ -52,7 +62,49 @@ fn socket_to_string(endpoint: &SocketAddr, interface_name: Option<String>) -> St
     }
 }
 
+/// The peer settings both ends of a mesh tunnel use, the endpoint is only known to the side
+/// that opens the tunnel
+fn tunnel_config(
+    port: u16,
+    endpoint: Option<SocketAddr>,
+    remote_pub_key: &String,
+    private_key_path: &Path,
+) -> Result<WgDeviceConfig, Error> {
+    Ok(WgDeviceConfig {
+        private_key: Some(read_key_file(private_key_path)?),
+        listen_port: Some(port),
+        replace_peers: false,
+        peers: vec![WgPeerConfig {
+            endpoint,
+            allowed_ips: Some(vec![("::".parse()?, 0)]),
+            persistent_keepalive: Some(5),
+            ..WgPeerConfig::new(parse_key(remote_pub_key)?)
+        }],
+    })
+}
+
 impl KernelInterface {
+    /// Netlink equivalent of socket_to_string, link local endpoints need the index of the
+    /// interface they are reachable over as their scope id
+    fn wg_endpoint(
+        &self,
+        endpoint: &SocketAddr,
+        interface_name: Option<String>,
+    ) -> Result<SocketAddr, Error> {
+        match endpoint {
+            &SocketAddr::V6(endpoint) if is_link_local(IpAddr::V6(endpoint.ip().clone())) => {
+                let interface_name = interface_name.expect("Link local without interface");
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    endpoint.ip().clone(),
+                    endpoint.port(),
+                    0,
+                    self.get_iface_index(&interface_name)?,
+                )))
+            }
+            _ => Ok(*endpoint),
+        }
+    }
+
     pub fn open_tunnel(
         &self,
         interface: &String,
@@ -76,59 +128,85 @@ impl KernelInterface {
                 external_nic
             }
         };
-        let socket_connect_str = socket_to_string(endpoint, phy_name);
-        trace!("socket conenct string: {}", socket_connect_str);
-        let output = self.run_command(
-            "wg",
-            &[
-                "set",
-                &interface,
-                "listen-port",
-                &format!("{}", port),
-                "private-key",
-                &format!("{}", private_key_path.to_str().unwrap()),
-                "peer",
-                &format!("{}", remote_pub_key),
-                "endpoint",
-                &socket_connect_str,
-                "allowed-ips",
-                "::/0",
-                "persistent-keepalive",
-                "5",
-            ],
-        )?;
-        if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error from wg command: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+        let transaction = self.transaction();
+        if let Some(wg) = self.wireguard() {
+            let wg_endpoint = self.wg_endpoint(endpoint, phy_name.clone())?;
+            trace!("wg endpoint: {}", wg_endpoint);
+            wg.set_device(
+                interface,
+                &tunnel_config(port, Some(wg_endpoint), remote_pub_key, private_key_path)?,
+            ).map_err(|e| KernelInterfaceError::from_netlink("received error from wg netlink", e))?;
+            self.journal_wg_peer(interface, parse_key(remote_pub_key)?);
+        } else {
+            self.wg_set_tunnel(
+                interface,
+                port,
+                Some(socket_to_string(endpoint, phy_name)),
+                remote_pub_key,
+                private_key_path,
+            )?;
+            self.journal("wg", &["set", interface, "peer", remote_pub_key, "remove"]);
         }
-        let _output = self.run_command(
-            "ip",
-            &["address", "add", &format!("{}", own_ip), "dev", &interface],
-        )?;
-
-        self.run_command(
-            "ip",
-            &[
-                "address",
-                "add",
-                &format!("{}/64", to_wg_local(own_ip)),
-                "dev",
-                &interface,
-            ],
-        )?;
+
+        self.add_tunnel_addresses(interface, own_ip)?;
 
         if external_peer {
             self.manual_peers_route(&endpoint.ip(), settings_default_route)?;
         }
 
-        let output = self.run_command("ip", &["link", "set", "dev", &interface, "up"])?;
+        let args: &[&str] = &["link", "set", "dev", &interface, "up"];
+        let output = self.run_command("ip", args)?;
+        if !output.stderr.is_empty() {
+            return Err(KernelInterfaceError::from_output("ip", args, &output).into());
+        }
+        transaction.commit();
+        Ok(())
+    }
+
+    /// Adds our mesh ip and the matching link local ip to a tunnel, addresses the tunnel
+    /// already had are not journaled so a rollback leaves them in place
+    fn add_tunnel_addresses(&self, interface: &String, own_ip: &IpAddr) -> Result<(), Error> {
+        for address in &[format!("{}", own_ip), format!("{}/64", to_wg_local(own_ip))] {
+            let output = self.run_command("ip", &["address", "add", address, "dev", &interface])?;
+            if output.status.success() {
+                self.journal("ip", &["address", "del", address, "dev", &interface]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `wg set` for a single tunnel peer
+    fn wg_set_tunnel(
+        &self,
+        interface: &String,
+        port: u16,
+        socket_connect_str: Option<String>,
+        remote_pub_key: &String,
+        private_key_path: &Path,
+    ) -> Result<(), Error> {
+        let port = format!("{}", port);
+        let private_key_path = format!("{}", private_key_path.to_str().unwrap());
+        let remote_pub_key = format!("{}", remote_pub_key);
+        let mut args = vec![
+            "set",
+            interface.as_str(),
+            "listen-port",
+            port.as_str(),
+            "private-key",
+            private_key_path.as_str(),
+            "peer",
+            remote_pub_key.as_str(),
+        ];
+        if let Some(ref socket_connect_str) = socket_connect_str {
+            trace!("socket conenct string: {}", socket_connect_str);
+            args.push("endpoint");
+            args.push(socket_connect_str.as_str());
+        }
+        args.extend(&["allowed-ips", "::/0", "persistent-keepalive", "5"]);
+
+        let output = self.run_command("wg", &args)?;
         if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error setting wg interface up: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+            return Err(KernelInterfaceError::from_output("wg", &args, &output).into());
         }
         Ok(())
     }
@@ -141,52 +219,26 @@ impl KernelInterface {
         private_key_path: &Path,
         own_ip: &IpAddr,
     ) -> Result<(), Error> {
-        let output = self.run_command(
-            "wg",
-            &[
-                "set",
-                &interface,
-                "listen-port",
-                &format!("{}", port),
-                "private-key",
-                &format!("{}", private_key_path.to_str().unwrap()),
-                "peer",
-                &format!("{}", remote_pub_key),
-                "allowed-ips",
-                "::/0",
-                "persistent-keepalive",
-                "5",
-            ],
-        )?;
-        if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error from wg command: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+        let transaction = self.transaction();
+        if let Some(wg) = self.wireguard() {
+            wg.set_device(
+                interface,
+                &tunnel_config(port, None, remote_pub_key, private_key_path)?,
+            ).map_err(|e| KernelInterfaceError::from_netlink("received error from wg netlink", e))?;
+            self.journal_wg_peer(interface, parse_key(remote_pub_key)?);
+        } else {
+            self.wg_set_tunnel(interface, port, None, remote_pub_key, private_key_path)?;
+            self.journal("wg", &["set", interface, "peer", remote_pub_key, "remove"]);
         }
-        let _output = self.run_command(
-            "ip",
-            &["address", "add", &format!("{}", own_ip), "dev", &interface],
-        )?;
-
-        self.run_command(
-            "ip",
-            &[
-                "address",
-                "add",
-                &format!("{}/64", to_wg_local(own_ip)),
-                "dev",
-                &interface,
-            ],
-        )?;
-
-        let output = self.run_command("ip", &["link", "set", "dev", &interface, "up"])?;
+
+        self.add_tunnel_addresses(interface, own_ip)?;
+
+        let args: &[&str] = &["link", "set", "dev", &interface, "up"];
+        let output = self.run_command("ip", args)?;
         if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error setting wg interface up: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+            return Err(KernelInterfaceError::from_output("ip", args, &output).into());
         }
+        transaction.commit();
         Ok(())
     }
 }
@@ -302,3 +354,72 @@ fe80::433:25ff:fe8c:e1ea dev eth0 lladdr 1a:32:06:78:05:0a STALE
         &mut vec![],
     ).unwrap();
 }
+
+#[test]
+fn test_open_tunnel_manual_peer_replay() {
+    use super::ReplayCommandRunner;
+
+    let replay =
+        ReplayCommandRunner::from_jsonl(include_str!("../fixtures/open_tunnel_manual_peer.jsonl"))
+            .unwrap();
+    let mut default_route = Vec::new();
+    {
+        let ki: &KernelInterface = &replay;
+        ki.open_tunnel(
+            &String::from("wg3"),
+            60003,
+            &"203.0.113.10:60001".parse().unwrap(),
+            &String::from("x8AcR9wI4t97aowYFlis077BDBk9SLdq6khMiixuTsQ="),
+            Path::new("/tmp/priv"),
+            &"fd00::aa".parse().unwrap(),
+            Some(String::from("eth0")),
+            &mut default_route,
+        ).unwrap();
+    }
+    replay.assert_done();
+    assert_eq!(
+        default_route,
+        vec![
+            "default",
+            "via",
+            "192.168.8.1",
+            "dev",
+            "eth0",
+            "proto",
+            "static",
+            "src",
+            "192.168.8.186",
+            "metric",
+            "10",
+        ]
+    );
+}
+
+#[test]
+fn test_open_tunnel_rollback() {
+    use super::ReplayCommandRunner;
+
+    // the interface fails to come up, everything applied before that is undone newest first
+    let replay =
+        ReplayCommandRunner::from_jsonl(include_str!("../fixtures/open_tunnel_rollback.jsonl"))
+            .unwrap();
+    {
+        let ki: &KernelInterface = &replay;
+        let e = ki
+            .open_tunnel(
+                &String::from("wg3"),
+                60003,
+                &"203.0.113.10:60001".parse().unwrap(),
+                &String::from("x8AcR9wI4t97aowYFlis077BDBk9SLdq6khMiixuTsQ="),
+                Path::new("/tmp/priv"),
+                &"fd00::aa".parse().unwrap(),
+                Some(String::from("eth0")),
+                &mut Vec::new(),
+            ).unwrap_err();
+        match e.downcast_ref::<KernelInterfaceError>() {
+            Some(&KernelInterfaceError::InterfaceNotFound(_)) => {}
+            _ => panic!("Unexpected error {:?}", e),
+        }
+    }
+    replay.assert_done();
+}
@@ -1,7 +1,228 @@
-use super::KernelInterface;
+//! Typed wrappers around the ubus calls OpenWrt answers status queries with. The structs only
+//! hold the fields we use and almost all of them are optional, which fields netifd and iwinfo
+//! fill in depends on the OpenWrt release and on the state of the interface.
+
+use super::{KernelInterface, KernelInterfaceError};
+
+use std::collections::HashMap;
+
+use serde::de::DeserializeOwned;
+use serde_json;
 
 use failure::Error;
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UbusAddress {
+    pub address: String,
+    pub mask: u8,
+}
+
+/// A logical interface from `network.interface dump`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UbusInterface {
+    pub interface: String,
+    pub up: bool,
+    #[serde(default)]
+    pub pending: bool,
+    #[serde(default)]
+    pub available: bool,
+    /// Seconds since the interface came up
+    #[serde(default)]
+    pub uptime: Option<u64>,
+    #[serde(default)]
+    pub proto: String,
+    /// The device the interface is bound to, br-lan for the lan bridge
+    #[serde(default)]
+    pub device: Option<String>,
+    /// The device that actually carries the traffic, differs from `device` for tunnels
+    #[serde(default)]
+    pub l3_device: Option<String>,
+    #[serde(default, rename = "ipv4-address")]
+    pub ipv4_address: Vec<UbusAddress>,
+    #[serde(default, rename = "ipv6-address")]
+    pub ipv6_address: Vec<UbusAddress>,
+    #[serde(default, rename = "dns-server")]
+    pub dns_server: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InterfaceDump {
+    interface: Vec<UbusInterface>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UbusDeviceStatistics {
+    #[serde(default)]
+    pub rx_bytes: u64,
+    #[serde(default)]
+    pub rx_packets: u64,
+    #[serde(default)]
+    pub rx_errors: u64,
+    #[serde(default)]
+    pub tx_bytes: u64,
+    #[serde(default)]
+    pub tx_packets: u64,
+    #[serde(default)]
+    pub tx_errors: u64,
+}
+
+/// A device from `network.device status`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UbusDeviceStatus {
+    #[serde(rename = "type")]
+    pub device_type: String,
+    #[serde(default)]
+    pub present: bool,
+    #[serde(default)]
+    pub up: bool,
+    /// Whether a cable is plugged in, only reported for devices that are up
+    #[serde(default)]
+    pub carrier: Option<bool>,
+    /// Link speed and duplex like 1000F, missing when there's no carrier
+    #[serde(default)]
+    pub speed: Option<String>,
+    #[serde(default)]
+    pub mtu: Option<u32>,
+    #[serde(default)]
+    pub macaddr: Option<String>,
+    #[serde(default, rename = "bridge-members")]
+    pub bridge_members: Vec<String>,
+    #[serde(default)]
+    pub statistics: UbusDeviceStatistics,
+}
+
+/// A wireless interface from `iwinfo info`, signal and noise are in dBm and missing while the
+/// radio isn't associated
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IwinfoInfo {
+    #[serde(default)]
+    pub phy: Option<String>,
+    #[serde(default)]
+    pub ssid: Option<String>,
+    #[serde(default)]
+    pub bssid: Option<String>,
+    #[serde(default)]
+    pub country: Option<String>,
+    pub mode: String,
+    #[serde(default)]
+    pub channel: Option<u16>,
+    /// In MHz
+    #[serde(default)]
+    pub frequency: Option<u32>,
+    /// In dBm
+    #[serde(default)]
+    pub txpower: Option<i32>,
+    #[serde(default)]
+    pub signal: Option<i32>,
+    #[serde(default)]
+    pub noise: Option<i32>,
+    #[serde(default)]
+    pub hwmodes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IwinfoRate {
+    /// In kbit/s
+    pub rate: u64,
+    #[serde(default)]
+    pub mcs: Option<u8>,
+}
+
+/// A station associated with one of our access points, from `iwinfo assoclist`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IwinfoStation {
+    pub mac: String,
+    /// In dBm
+    pub signal: i32,
+    #[serde(default)]
+    pub noise: Option<i32>,
+    /// Milliseconds since we last heard from the station
+    #[serde(default)]
+    pub inactive: u64,
+    pub rx: IwinfoRate,
+    pub tx: IwinfoRate,
+}
+
+#[derive(Debug, Deserialize)]
+struct Assoclist {
+    results: Vec<IwinfoStation>,
+}
+
+/// A nearby BSS from `iwinfo scan`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IwinfoBss {
+    /// None for hidden networks
+    #[serde(default)]
+    pub ssid: Option<String>,
+    pub bssid: String,
+    /// Master for access points, Ad-Hoc or Mesh Point for mesh radios
+    pub mode: String,
+    pub channel: u16,
+    /// In dBm
+    pub signal: i32,
+    /// A mesh radio using one of the mesh ids configured on this router
+    #[serde(default)]
+    pub althea_mesh: bool,
+}
+
+impl IwinfoBss {
+    pub fn is_mesh(&self) -> bool {
+        self.mode == "Ad-Hoc" || self.mode == "Mesh Point"
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ScanResults {
+    results: Vec<IwinfoBss>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoardRelease {
+    pub distribution: String,
+    pub version: String,
+    #[serde(default)]
+    pub revision: Option<String>,
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+/// What `system board` knows about the hardware and the firmware on it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoardInfo {
+    pub kernel: String,
+    pub hostname: String,
+    #[serde(default)]
+    pub system: Option<String>,
+    /// The human readable model, like "GL.iNet GL-B1300"
+    pub model: String,
+    /// The device tree name, like "glinet,gl-b1300"
+    #[serde(default)]
+    pub board_name: Option<String>,
+    pub release: BoardRelease,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SystemMemory {
+    pub total: u64,
+    pub free: u64,
+    #[serde(default)]
+    pub shared: u64,
+    #[serde(default)]
+    pub buffered: u64,
+}
+
+/// Runtime state from `system info`, memory is in bytes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SystemInfo {
+    pub localtime: u64,
+    pub uptime: u64,
+    /// The load averages scaled by 65536 like the kernel keeps them
+    pub load: Vec<u64>,
+    pub memory: SystemMemory,
+}
+
 impl KernelInterface {
     /// calls a ubus rpc
     pub fn ubus_call(
@@ -16,4 +237,154 @@ impl KernelInterface {
         )?;
         Ok(output)
     }
+
+    /// Makes a ubus call and deserializes the reply, unlike `ubus_call` a failed call is an
+    /// error instead of an empty reply
+    fn ubus_call_typed<T: DeserializeOwned>(
+        &self,
+        namespace: &str,
+        function: &str,
+        argument: &str,
+    ) -> Result<T, Error> {
+        let args = &["call", namespace, function, argument];
+        let output = self.run_command("ubus", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("ubus", args, &output).into());
+        }
+        Ok(serde_json::from_slice(&output.stdout)?)
+    }
+
+    /// Every logical interface netifd knows about
+    pub fn ubus_interface_dump(&self) -> Result<Vec<UbusInterface>, Error> {
+        let dump: InterfaceDump = self.ubus_call_typed("network.interface", "dump", "{}")?;
+        Ok(dump.interface)
+    }
+
+    pub fn ubus_device_status(&self, device: &str) -> Result<UbusDeviceStatus, Error> {
+        let argument = format!("{{\"name\":{}}}", serde_json::to_string(device)?);
+        self.ubus_call_typed("network.device", "status", &argument)
+    }
+
+    /// The status of every device, indexed by device name
+    pub fn ubus_all_device_status(&self) -> Result<HashMap<String, UbusDeviceStatus>, Error> {
+        self.ubus_call_typed("network.device", "status", "{}")
+    }
+
+    pub fn iwinfo_info(&self, device: &str) -> Result<IwinfoInfo, Error> {
+        let argument = format!("{{\"device\":{}}}", serde_json::to_string(device)?);
+        self.ubus_call_typed("iwinfo", "info", &argument)
+    }
+
+    pub fn iwinfo_assoclist(&self, device: &str) -> Result<Vec<IwinfoStation>, Error> {
+        let argument = format!("{{\"device\":{}}}", serde_json::to_string(device)?);
+        let assoclist: Assoclist = self.ubus_call_typed("iwinfo", "assoclist", &argument)?;
+        Ok(assoclist.results)
+    }
+
+    /// Scans for nearby networks on a wireless interface, this takes a few seconds and briefly
+    /// interrupts traffic on the radio
+    pub fn iwinfo_scan(&self, device: &str) -> Result<Vec<IwinfoBss>, Error> {
+        let argument = format!("{{\"device\":{}}}", serde_json::to_string(device)?);
+        let scan: ScanResults = self.ubus_call_typed("iwinfo", "scan", &argument)?;
+        Ok(scan.results)
+    }
+
+    /// Scans like `iwinfo_scan` and marks the mesh radios that share a mesh id with one of
+    /// ours, those are the Althea nodes we can peer with
+    pub fn wifi_scan(&self, device: &str) -> Result<Vec<IwinfoBss>, Error> {
+        let wireless = self.uci_get_package("wireless")?;
+        let mut mesh_ids = Vec::new();
+        for section in wireless.sections_of_type("wifi-iface") {
+            let option = |name| section.get(name).and_then(|v| v.values().first());
+            match option("mode").map(|m| m.as_str()) {
+                Some("adhoc") => mesh_ids.extend(option("ssid")),
+                Some("mesh") => mesh_ids.extend(option("mesh_id")),
+                _ => {}
+            }
+        }
+
+        let mut results = self.iwinfo_scan(device)?;
+        for bss in results.iter_mut() {
+            let known_id = match bss.ssid {
+                Some(ref ssid) => mesh_ids.contains(&ssid),
+                None => false,
+            };
+            bss.althea_mesh = bss.is_mesh() && known_id;
+        }
+        Ok(results)
+    }
+
+    pub fn ubus_system_board(&self) -> Result<BoardInfo, Error> {
+        self.ubus_call_typed("system", "board", "{}")
+    }
+
+    pub fn ubus_system_info(&self) -> Result<SystemInfo, Error> {
+        self.ubus_call_typed("system", "info", "{}")
+    }
+}
+
+#[test]
+fn test_ubus_typed_calls() {
+    use super::ReplayCommandRunner;
+
+    let replay =
+        ReplayCommandRunner::from_jsonl(include_str!("../fixtures/ubus_status.jsonl")).unwrap();
+    {
+        let ki: &KernelInterface = &replay;
+
+        let interfaces = ki.ubus_interface_dump().unwrap();
+        assert_eq!(interfaces.len(), 3);
+        assert_eq!(interfaces[0].interface, "lan");
+        assert_eq!(interfaces[0].l3_device, Some("br-lan".to_string()));
+        assert_eq!(
+            interfaces[0].ipv4_address,
+            vec![UbusAddress {
+                address: "192.168.10.1".to_string(),
+                mask: 24,
+            }]
+        );
+        // down interfaces leave out most fields
+        assert!(!interfaces[2].up);
+        assert_eq!(interfaces[2].device, None);
+
+        let eth1 = ki.ubus_device_status("eth1").unwrap();
+        assert!(eth1.up);
+        assert_eq!(eth1.carrier, Some(true));
+        assert_eq!(eth1.speed, Some("1000F".to_string()));
+        assert_eq!(eth1.statistics.rx_bytes, 1215098);
+        assert!(ki.ubus_device_status("eth9").is_err());
+
+        let info = ki.iwinfo_info("wlan0").unwrap();
+        assert_eq!(info.mode, "Master");
+        assert_eq!(info.channel, Some(11));
+        assert_eq!(info.hwmodes, vec!["b", "g", "n"]);
+
+        let stations = ki.iwinfo_assoclist("wlan0").unwrap();
+        assert_eq!(stations.len(), 1);
+        assert_eq!(stations[0].mac, "58:EF:68:12:34:56");
+        assert_eq!(stations[0].signal, -47);
+        assert_eq!(stations[0].tx.rate, 72200);
+
+        let results = ki.wifi_scan("wlan0").unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].ssid, Some("AltheaHome".to_string()));
+        assert!(!results[0].althea_mesh);
+        // meshes under our mesh ids
+        assert!(results[1].althea_mesh);
+        assert!(results[2].althea_mesh);
+        // a mesh, but not one we can join
+        assert!(results[3].is_mesh());
+        assert!(!results[3].althea_mesh);
+
+        let board = ki.ubus_system_board().unwrap();
+        assert_eq!(board.model, "GL.iNet GL-B1300");
+        assert_eq!(board.board_name, Some("glinet,gl-b1300".to_string()));
+        assert_eq!(board.release.version, "18.06.1");
+
+        let info = ki.ubus_system_info().unwrap();
+        assert_eq!(info.uptime, 3526);
+        assert_eq!(info.load.len(), 3);
+        assert_eq!(info.memory.total, 255324160);
+    }
+    replay.assert_done();
 }
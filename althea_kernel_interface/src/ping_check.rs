@@ -1,6 +1,99 @@
-use super::KernelInterface;
+use super::{KernelInterface, KernelInterfaceError};
 use failure::Error;
+use futures::Future;
+use regex::Regex;
 use std::net::IpAddr;
+use std::process::Output;
+use std::time::Duration;
+
+/// Summary of a run of pings to a single address, times are in milliseconds and are None when
+/// not enough replies came back to work them out
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProbeResult {
+    pub sent: u32,
+    pub received: u32,
+    /// Percentage of the probes that got no reply
+    pub loss: f32,
+    pub rtt_min: Option<f32>,
+    pub rtt_avg: Option<f32>,
+    pub rtt_max: Option<f32>,
+    /// Mean difference between the rtts of consecutive replies
+    pub jitter: Option<f32>,
+}
+
+/// Parses the output of both busybox and iputils ping. The replies are read one by one rather
+/// than taking the summary line because busybox doesn't print the deviation
+fn parse_ping_output(output: &str) -> Result<ProbeResult, Error> {
+    lazy_static! {
+        static ref REPLY: Regex =
+            Regex::new(r"time=([0-9.]+) ms").expect("Unable to compile regular expression");
+        static ref SUMMARY: Regex =
+            Regex::new(r"([0-9]+) packets transmitted, ([0-9]+) (packets )?received")
+                .expect("Unable to compile regular expression");
+    }
+
+    let summary = match SUMMARY.captures(output) {
+        Some(summary) => summary,
+        None => {
+            return Err(KernelInterfaceError::ParseError(format!(
+                "No summary in ping output {:?}",
+                output
+            )).into())
+        }
+    };
+    let sent: u32 = summary[1].parse()?;
+    let received: u32 = summary[2].parse()?;
+
+    let mut rtts = Vec::new();
+    for line in output.lines().filter(|line| !line.contains("DUP!")) {
+        if let Some(reply) = REPLY.captures(line) {
+            rtts.push(reply[1].parse::<f32>()?);
+        }
+    }
+
+    let (rtt_min, rtt_avg, rtt_max) = if rtts.is_empty() {
+        (None, None, None)
+    } else {
+        let min = rtts.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = rtts.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let avg = rtts.iter().sum::<f32>() / rtts.len() as f32;
+        (Some(min), Some(avg), Some(max))
+    };
+    let jitter = if rtts.len() < 2 {
+        None
+    } else {
+        let diffs: f32 = rtts.windows(2).map(|pair| (pair[1] - pair[0]).abs()).sum();
+        Some(diffs / (rtts.len() - 1) as f32)
+    };
+    let loss = if sent == 0 {
+        100.0
+    } else {
+        sent.saturating_sub(received) as f32 * 100.0 / sent as f32
+    };
+
+    Ok(ProbeResult {
+        sent,
+        received,
+        loss,
+        rtt_min,
+        rtt_avg,
+        rtt_max,
+        jitter,
+    })
+}
+
+/// Ping exits with 1 when nothing came back, that's still a result, anything without a summary
+/// means ping itself failed
+fn probe_result(program: &str, args: &[&str], output: &Output) -> Result<ProbeResult, Error> {
+    let stdout = String::from_utf8(output.stdout.clone())?;
+    match parse_ping_output(&stdout) {
+        Ok(result) => Ok(result),
+        Err(_) if !output.status.success() => {
+            Err(KernelInterfaceError::from_output(program, args, output).into())
+        }
+        Err(e) => Err(e),
+    }
+}
 
 impl KernelInterface {
     //Pings a ipv6 address to determine if it's online
@@ -13,4 +106,128 @@ impl KernelInterface {
         let result = self.run_command("ping", &["-w1", "-W1", "-c1", &ip.to_string()]);
         Ok(result?.status.success())
     }
+
+    /// Sends `count` pings a second apart and reports the loss and rtts, without blocking the
+    /// thread. A host that doesn't answer at all is 100% loss rather than an error. The pings
+    /// go out of `iface` when it's given, which link local ips need
+    pub fn probe_async(
+        &self,
+        ip: IpAddr,
+        iface: Option<&str>,
+        count: u32,
+    ) -> Box<Future<Item = ProbeResult, Error = Error>> {
+        let program = match ip {
+            IpAddr::V4(_) => "ping",
+            IpAddr::V6(_) => "ping6",
+        };
+        // the deadline leaves a second for the last reply, the timeout a bit more on top for
+        // ping to start up and exit
+        let deadline = u64::from(count) + 1;
+        let timeout = Duration::from_secs(deadline + 2);
+        let mut args = vec![
+            "-c".to_string(),
+            count.to_string(),
+            "-W1".to_string(),
+            "-w".to_string(),
+            deadline.to_string(),
+        ];
+        if let Some(iface) = iface {
+            args.push("-I".to_string());
+            args.push(iface.to_string());
+        }
+        args.push(ip.to_string());
+
+        let arg_refs: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+        Box::new(
+            self.run_command_async(program, &arg_refs, timeout)
+                .and_then(move |output| {
+                    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+                    probe_result(program, &args, &output)
+                }),
+        )
+    }
+}
+
+#[test]
+fn test_parse_ping_output() {
+    let busybox = "PING 10.0.0.1 (10.0.0.1): 56 data bytes
+64 bytes from 10.0.0.1: seq=0 ttl=64 time=1.000 ms
+64 bytes from 10.0.0.1: seq=1 ttl=64 time=3.000 ms
+64 bytes from 10.0.0.1: seq=1 ttl=64 time=9.000 ms (DUP!)
+64 bytes from 10.0.0.1: seq=3 ttl=64 time=2.000 ms
+
+--- 10.0.0.1 ping statistics ---
+4 packets transmitted, 3 packets received, 25% packet loss
+round-trip min/avg/max = 1.000/2.000/3.000 ms
+";
+    assert_eq!(
+        parse_ping_output(busybox).unwrap(),
+        ProbeResult {
+            sent: 4,
+            received: 3,
+            loss: 25.0,
+            rtt_min: Some(1.0),
+            rtt_avg: Some(2.0),
+            rtt_max: Some(3.0),
+            jitter: Some(1.5),
+        }
+    );
+
+    let iputils = "PING fd00::1(fd00::1) 56 data bytes
+64 bytes from fd00::1: icmp_seq=1 ttl=64 time=0.500 ms
+
+--- fd00::1 ping statistics ---
+2 packets transmitted, 1 received, 50% packet loss, time 1001ms
+rtt min/avg/max/mdev = 0.500/0.500/0.500/0.000 ms
+";
+    let result = parse_ping_output(iputils).unwrap();
+    assert_eq!(result.loss, 50.0);
+    assert_eq!(result.rtt_avg, Some(0.5));
+    assert_eq!(result.jitter, None);
+
+    assert!(parse_ping_output("ping: bad address 'nowhere'\n").is_err());
+}
+
+#[test]
+fn test_probe() {
+    use KI;
+
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    KI.set_mock(Box::new(move |program, args| {
+        assert_eq!(program, "ping6");
+        assert_eq!(args, &["-c", "3", "-W1", "-w", "4", "-I", "wg0", "fe80::1"]);
+
+        Ok(Output {
+            stdout: b"PING fe80::1 (fe80::1): 56 data bytes
+
+--- fe80::1 ping statistics ---
+3 packets transmitted, 0 packets received, 100% packet loss
+"
+                .to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(256),
+        })
+    }));
+
+    let result = KI
+        .probe_async("fe80::1".parse().unwrap(), Some("wg0"), 3)
+        .wait()
+        .unwrap();
+    assert_eq!(result.received, 0);
+    assert_eq!(result.loss, 100.0);
+    assert_eq!(result.rtt_min, None);
+
+    KI.set_mock(Box::new(move |_program, _args| {
+        Ok(Output {
+            stdout: b"".to_vec(),
+            stderr: b"ping: sendto: Network unreachable\n".to_vec(),
+            status: ExitStatus::from_raw(512),
+        })
+    }));
+    assert!(KI
+        .probe_async("10.0.0.1".parse().unwrap(), None, 3)
+        .wait()
+        .is_err());
 }
@@ -0,0 +1,370 @@
+//! Command runners for turning a real router session into deterministic tests.
+//! RecordingCommandRunner wraps LinuxCommandRunner and saves every command it runs along with
+//! the output to a fixture file with one json object per line, ReplayCommandRunner serves those
+//! fixtures back in order and panics as soon as a command doesn't match the recording.
+
+use super::{CommandRunner, LinuxCommandRunner};
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Output};
+use std::sync::Mutex;
+
+use libc;
+use serde_json;
+
+use failure::Error;
+
+fn default_status() -> Option<i32> {
+    Some(0)
+}
+
+/// A single command and everything it returned
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandFixture {
+    pub program: String,
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
+    /// The exit code, None if the command was killed by a signal
+    #[serde(default = "default_status")]
+    pub status: Option<i32>,
+    /// What was written to the command's stdin, if anything
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdin: Option<String>,
+}
+
+impl CommandFixture {
+    pub fn new(program: &str, args: &[&str], output: &Output) -> CommandFixture {
+        CommandFixture {
+            program: program.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            status: output.status.code(),
+            stdin: None,
+        }
+    }
+
+    pub fn output(&self) -> Output {
+        Output {
+            stdout: self.stdout.clone().into_bytes(),
+            stderr: self.stderr.clone().into_bytes(),
+            status: match self.status {
+                // from_raw takes a wait status, the exit code lives in the second byte
+                Some(code) => ExitStatus::from_raw(code << 8),
+                None => ExitStatus::from_raw(libc::SIGKILL),
+            },
+        }
+    }
+}
+
+/// Parses fixtures saved one per line, blank lines are skipped
+pub fn parse_fixtures(jsonl: &str) -> Result<Vec<CommandFixture>, Error> {
+    let mut fixtures = Vec::new();
+    for line in jsonl.lines().filter(|line| !line.trim().is_empty()) {
+        fixtures.push(serde_json::from_str(line)?);
+    }
+    Ok(fixtures)
+}
+
+pub fn load_fixtures<P: AsRef<Path>>(path: P) -> Result<Vec<CommandFixture>, Error> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    parse_fixtures(&contents)
+}
+
+/// Adds one fixture to the end of the file as a single line
+pub fn append_fixture<P: AsRef<Path>>(path: P, fixture: &CommandFixture) -> Result<(), Error> {
+    let mut line = serde_json::to_string(fixture)?;
+    line.push('\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Runs commands like LinuxCommandRunner and records each one to a fixture file. Async commands
+/// are run synchronously too so the recording has them in the order they were started
+pub struct RecordingCommandRunner {
+    runner: Box<CommandRunner + Sync>,
+    path: PathBuf,
+    fixtures: Mutex<Vec<CommandFixture>>,
+}
+
+impl RecordingCommandRunner {
+    /// Starts a new recording, anything already at `path` is replaced
+    pub fn new<P: Into<PathBuf>>(path: P) -> RecordingCommandRunner {
+        RecordingCommandRunner::with_runner(path, Box::new(LinuxCommandRunner {}))
+    }
+
+    /// Records the commands run by `runner` instead, for recording against a fake system
+    pub fn with_runner<P: Into<PathBuf>>(
+        path: P,
+        runner: Box<CommandRunner + Sync>,
+    ) -> RecordingCommandRunner {
+        let path = path.into();
+        if let Err(e) = File::create(&path) {
+            error!("Failed to create command fixture file {:?} {:?}", path, e);
+        }
+        RecordingCommandRunner {
+            runner,
+            path,
+            fixtures: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Everything recorded so far
+    pub fn fixtures(&self) -> Vec<CommandFixture> {
+        self.fixtures.lock().unwrap().clone()
+    }
+
+    fn record(&self, fixture: CommandFixture) {
+        // the lock is held while appending so lines from different threads can't interleave,
+        // every line is complete on its own so the file is usable whenever rita is stopped
+        let mut fixtures = self.fixtures.lock().unwrap();
+        if let Err(e) = append_fixture(&self.path, &fixture) {
+            error!("Failed to save command fixture to {:?} {:?}", self.path, e);
+        }
+        fixtures.push(fixture);
+    }
+}
+
+impl CommandRunner for RecordingCommandRunner {
+    fn run_command(&self, program: &str, args: &[&str]) -> Result<Output, Error> {
+        // commands that couldn't be started at all aren't recorded, a replay will then fail on
+        // them as unexpected which is what we want
+        let output = self.runner.run_command(program, args)?;
+        self.record(CommandFixture::new(program, args, &output));
+        Ok(output)
+    }
+
+    fn run_command_with_input(
+        &self,
+        program: &str,
+        args: &[&str],
+        input: &str,
+    ) -> Result<Output, Error> {
+        let output = self.runner.run_command_with_input(program, args, input)?;
+        self.record(CommandFixture {
+            stdin: Some(input.to_string()),
+            ..CommandFixture::new(program, args, &output)
+        });
+        Ok(output)
+    }
+
+    fn set_mock(&self, mock: Box<FnMut(String, Vec<String>) -> Result<Output, Error> + Send>) {
+        self.runner.set_mock(mock)
+    }
+}
+
+/// Serves recorded fixtures back in order, any command that isn't the next one in the
+/// recording is a test failure
+pub struct ReplayCommandRunner {
+    fixtures: Mutex<VecDeque<CommandFixture>>,
+}
+
+impl ReplayCommandRunner {
+    pub fn new(fixtures: Vec<CommandFixture>) -> ReplayCommandRunner {
+        ReplayCommandRunner {
+            fixtures: Mutex::new(fixtures.into_iter().collect()),
+        }
+    }
+
+    pub fn from_jsonl(jsonl: &str) -> Result<ReplayCommandRunner, Error> {
+        Ok(ReplayCommandRunner::new(parse_fixtures(jsonl)?))
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ReplayCommandRunner, Error> {
+        Ok(ReplayCommandRunner::new(load_fixtures(path)?))
+    }
+
+    /// Panics if part of the recording was never replayed
+    pub fn assert_done(&self) {
+        let fixtures = self.fixtures.lock().unwrap();
+        if let Some(next) = fixtures.front() {
+            panic!(
+                "{} recorded commands were never run, next is {} {:?}",
+                fixtures.len(),
+                next.program,
+                next.args
+            );
+        }
+    }
+}
+
+impl ReplayCommandRunner {
+    fn replay(&self, program: &str, args: &[&str], input: Option<&str>) -> Output {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let expected = match self.fixtures.lock().unwrap().pop_front() {
+            Some(expected) => expected,
+            None => panic!(
+                "Unexpected command {} {:?} after the recording ended",
+                program, args
+            ),
+        };
+        if expected.program != program || expected.args != args {
+            panic!(
+                "Unexpected command {} {:?}, the recording has {} {:?}",
+                program, args, expected.program, expected.args
+            );
+        }
+        if expected.stdin.as_ref().map(|s| s.as_str()) != input {
+            panic!(
+                "Unexpected input {:?} to {} {:?}, the recording has {:?}",
+                input, program, args, expected.stdin
+            );
+        }
+        trace!("Replaying {:?}", expected);
+        expected.output()
+    }
+}
+
+impl CommandRunner for ReplayCommandRunner {
+    fn run_command(&self, program: &str, args: &[&str]) -> Result<Output, Error> {
+        Ok(self.replay(program, args, None))
+    }
+
+    fn run_command_with_input(
+        &self,
+        program: &str,
+        args: &[&str],
+        input: &str,
+    ) -> Result<Output, Error> {
+        Ok(self.replay(program, args, Some(input)))
+    }
+
+    fn set_mock(&self, _mock: Box<FnMut(String, Vec<String>) -> Result<Output, Error> + Send>) {
+        unimplemented!()
+    }
+}
+
+#[test]
+fn test_fixture_output() {
+    let fixtures = parse_fixtures(
+        r#"{"program": "uci", "args": ["get", "network.lan.ipaddr"], "stdout": "192.168.10.1\n"}
+{"program": "uci", "args": ["get", "a.b"], "stderr": "uci: Entry not found\n", "status": 1}
+
+{"program": "wg", "args": ["show"], "status": null}
+"#,
+    ).unwrap();
+    assert_eq!(fixtures.len(), 3);
+
+    let output = fixtures[0].output();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"192.168.10.1\n");
+    assert!(output.stderr.is_empty());
+
+    let output = fixtures[1].output();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(output.stderr, b"uci: Entry not found\n");
+
+    let output = fixtures[2].output();
+    assert_eq!(output.status.code(), None);
+    assert_eq!(output.status.signal(), Some(libc::SIGKILL));
+
+    for fixture in fixtures {
+        let args: Vec<&str> = fixture.args.iter().map(|a| a.as_str()).collect();
+        let recorded = CommandFixture::new(&fixture.program, &args, &fixture.output());
+        assert_eq!(recorded, fixture);
+    }
+}
+
+#[test]
+fn test_record_and_replay() {
+    use std::env;
+    use std::fs;
+
+    let path = env::temp_dir().join("althea_kernel_interface_test_record.jsonl");
+    let recorder = RecordingCommandRunner::new(path.clone());
+    let recorded = recorder.run_command("echo", &["hello", "world"]).unwrap();
+    assert_eq!(recorded.stdout, b"hello world\n");
+    let second = recorder.run_command("echo", &["again"]).unwrap();
+    let piped = recorder
+        .run_command_with_input("cat", &[], "piped\n")
+        .unwrap();
+    assert_eq!(piped.stdout, b"piped\n");
+
+    let mut contents = String::new();
+    File::open(&path)
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents.lines().count(), 3);
+
+    let replay = ReplayCommandRunner::from_file(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(replay.fixtures.lock().unwrap().len(), 3);
+    let replayed = replay.run_command("echo", &["hello", "world"]).unwrap();
+    assert_eq!(replayed, recorded);
+    assert_eq!(replay.run_command("echo", &["again"]).unwrap(), second);
+    assert_eq!(
+        replay
+            .run_command_with_input("cat", &[], "piped\n")
+            .unwrap(),
+        piped
+    );
+    replay.assert_done();
+}
+
+#[test]
+fn test_record_mocked_commands() {
+    use super::TestCommandRunner;
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+
+    let path = env::temp_dir().join("althea_kernel_interface_test_record_mock.jsonl");
+    let system = TestCommandRunner {
+        run_command: Arc::new(Mutex::new(Box::new(|_program, _args| {
+            panic!("mock not set");
+        }))),
+    };
+    let recorder = RecordingCommandRunner::with_runner(path.clone(), Box::new(system));
+    recorder.set_mock(Box::new(|_program, _args| {
+        Ok(Output {
+            stdout: b"wg0\n".to_vec(),
+            stderr: Vec::new(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+    let output = recorder.run_command("wg", &["show", "interfaces"]).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(
+        recorder.fixtures(),
+        vec![CommandFixture::new("wg", &["show", "interfaces"], &output)]
+    );
+}
+
+#[test]
+#[should_panic(expected = "Unexpected command ip")]
+fn test_replay_unexpected_command() {
+    let replay = ReplayCommandRunner::new(vec![CommandFixture {
+        program: "ip".to_string(),
+        args: vec!["neighbor".to_string()],
+        stdout: String::new(),
+        stderr: String::new(),
+        status: Some(0),
+        stdin: None,
+    }]);
+    let _ = replay.run_command("ip", &["route"]);
+}
+
+#[test]
+#[should_panic(expected = "recorded commands were never run")]
+fn test_replay_unfinished() {
+    let replay = ReplayCommandRunner::new(vec![CommandFixture {
+        program: "ip".to_string(),
+        args: vec!["neighbor".to_string()],
+        stdout: String::new(),
+        stderr: String::new(),
+        status: Some(0),
+        stdin: None,
+    }]);
+    replay.assert_done();
+}
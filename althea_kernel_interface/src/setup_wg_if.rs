@@ -1,12 +1,44 @@
-use super::{KernelInterface, KernelInterfaceError};
+use super::netlink;
+use super::{KernelInterface, KernelInterfaceError, ASYNC_COMMAND_TIMEOUT_SECS};
 use failure::err_msg;
+use futures::{future, Future};
+use libc;
 use std::str::from_utf8;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use failure::Error;
 
+/// Counts the peers in `wg show <interface> latest-handshakes` output that have shaken hands
+/// in the last 10 minutes
+fn count_recent_handshakes(out: &str) -> Result<u32, Error> {
+    let mut num: u32 = 0;
+    for line in out.lines() {
+        let content: Vec<&str> = line.split("\t").collect();
+        let mut itr = content.iter();
+        itr.next();
+        let timestamp = itr
+            .next()
+            .ok_or(err_msg("Option did not contain a value."))?;
+        let d = UNIX_EPOCH + Duration::from_secs(timestamp.parse()?);
+
+        if SystemTime::now().duration_since(d)? < Duration::new(600, 0) {
+            num += 1;
+        }
+    }
+    Ok(num)
+}
+
 impl KernelInterface {
     pub fn get_peers(&self, iface_name: &str) -> Result<Vec<String>, Error> {
+        if let Some(wg) = self.wireguard() {
+            return Ok(wg
+                .get_device(iface_name)?
+                .peers
+                .iter()
+                .map(|p| p.public_key.to_string())
+                .collect());
+        }
+
         let output = self.run_command("wg", &["show", iface_name, "peers"])?;
 
         let output = from_utf8(&output.stdout)?;
@@ -23,6 +55,17 @@ impl KernelInterface {
     /// checks the existing interfaces to find an interface name that isn't in use.
     /// then calls iproute2 to set up a new interface.
     pub fn setup_wg_if(&self) -> Result<String, Error> {
+        if let Some(nl) = self.netlink() {
+            let links = nl.links()?;
+            let mut if_num = 0;
+            while links.iter().any(|l| l.name == format!("wg{}", if_num)) {
+                if_num += 1;
+            }
+            let interface = format!("wg{}", if_num);
+            self.setup_wg_if_named(&interface)?;
+            return Ok(interface);
+        }
+
         //call "ip links" to get a list of currently set up links
         let links = String::from_utf8(self.run_command("ip", &["link"])?.stdout)?;
         let mut if_num = 0;
@@ -35,42 +78,71 @@ impl KernelInterface {
         Ok(interface)
     }
 
-    /// calls iproute2 to set up a new interface with a given name.
+    /// calls iproute2 to set up a new interface with a given name, an interface that already
+    /// exists is left as is and isn't journaled
     pub fn setup_wg_if_named(&self, name: &str) -> Result<(), Error> {
-        let output = self.run_command("ip", &["link", "add", &name, "type", "wireguard"])?;
-        let stderr = String::from_utf8(output.stderr)?;
-        if !stderr.is_empty() {
-            if stderr.contains("exists") {
+        if let Some(nl) = self.netlink() {
+            return match nl.add_link(name, "wireguard") {
+                Ok(()) => {
+                    self.journal_link(name);
+                    Ok(())
+                }
+                Err(e) => match netlink::errno_of(&e) {
+                    Some(libc::EEXIST) => Ok(()),
+                    _ => Err(
+                        KernelInterfaceError::from_netlink("received error adding wg link", e)
+                            .into(),
+                    ),
+                },
+            };
+        }
+
+        let args: &[&str] = &["link", "add", &name, "type", "wireguard"];
+        let output = self.run_command("ip", args)?;
+        if !output.stderr.is_empty() {
+            if String::from_utf8(output.stderr.clone())?.contains("exists") {
                 return Ok(());
             } else {
-                return Err(KernelInterfaceError::RuntimeError(format!(
-                    "received error adding wg link: {}",
-                    stderr
-                )).into());
+                return Err(KernelInterfaceError::from_output("ip", args, &output).into());
             }
         }
+        self.journal("ip", &["link", "del", "dev", name]);
         Ok(())
     }
 
     /// Returns the number of clients that are active on the wg_exit tunnel
     pub fn get_wg_exit_clients_online(&self) -> Result<u32, Error> {
-        let output = self.run_command("wg", &["show", "wg_exit", "latest-handshakes"])?;
-        let mut num: u32 = 0;
-        let out = String::from_utf8(output.stdout)?;
-        for line in out.lines() {
-            let content: Vec<&str> = line.split("\t").collect();
-            let mut itr = content.iter();
-            itr.next();
-            let timestamp = itr
-                .next()
-                .ok_or(err_msg("Option did not contain a value."))?;
-            let d = UNIX_EPOCH + Duration::from_secs(timestamp.parse()?);
-
-            if SystemTime::now().duration_since(d)? < Duration::new(600, 0) {
-                num += 1;
+        if let Some(wg) = self.wireguard() {
+            let mut num: u32 = 0;
+            for peer in wg.get_device("wg_exit")?.peers {
+                if let Some(handshake) = peer.last_handshake {
+                    // a handshake slightly in the future due to clock adjustment still counts
+                    let age = SystemTime::now()
+                        .duration_since(handshake)
+                        .unwrap_or(Duration::new(0, 0));
+                    if age < Duration::new(600, 0) {
+                        num += 1;
+                    }
+                }
             }
+            return Ok(num);
         }
-        Ok(num)
+
+        let output = self.run_command("wg", &["show", "wg_exit", "latest-handshakes"])?;
+        count_recent_handshakes(&String::from_utf8(output.stdout)?)
+    }
+
+    /// Same as `get_wg_exit_clients_online` but doesn't block on `wg`
+    pub fn get_wg_exit_clients_online_async(&self) -> Box<Future<Item = u32, Error = Error>> {
+        if self.wireguard().is_some() {
+            return Box::new(future::result(self.get_wg_exit_clients_online()));
+        }
+
+        let timeout = Duration::from_secs(ASYNC_COMMAND_TIMEOUT_SECS);
+        Box::new(
+            self.run_command_async("wg", &["show", "wg_exit", "latest-handshakes"], timeout)
+                .and_then(|output| count_recent_handshakes(&String::from_utf8(output.stdout)?)),
+        )
     }
 }
 
@@ -1,63 +1,329 @@
-use super::KernelInterface;
+//! Typed readers for the system statistics in /proc. Each file has a parser that works on the
+//! file's contents so it can be tested against captured output, the KernelInterface methods just
+//! read the file and hand it over.
 
-use failure::Error;
+use super::{KernelInterface, KernelInterfaceError};
+
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
+use std::net::Ipv4Addr;
 
-impl KernelInterface {
-    fn read_file(&self, path_str: &str) -> Result<String, Error> {
-        let mut f = File::open(path_str)?;
-        let mut contents = String::new();
-        f.read_to_string(&mut contents)?;
+use althea_types::{InterfaceStats, LoadAvg, MemInfo, RouteEntry};
+
+use failure::Error;
 
-        Ok(contents)
+/// Time the cpus have spent busy and in total since boot, in jiffies. Only the difference
+/// between two samples means anything, see `usage_since`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTimes {
+    pub busy: u64,
+    pub total: u64,
+}
+
+impl CpuTimes {
+    /// The percentage of time the cpus were busy between an earlier sample and this one, None
+    /// if no time passed or the counters went backwards
+    pub fn usage_since(&self, earlier: &CpuTimes) -> Option<f32> {
+        let total = self.total.checked_sub(earlier.total)?;
+        if total == 0 {
+            return None;
+        }
+        let busy = self.busy.saturating_sub(earlier.busy);
+        Some(busy as f32 * 100.0 / total as f32)
     }
+}
 
-    pub fn get_proc_stat(&self) -> Result<String, Error> {
-        debug!("getting proc stat");
+fn read_file(path: &str) -> Result<String, Error> {
+    let mut f = File::open(path)?;
+    let mut contents = String::new();
+    f.read_to_string(&mut contents)?;
+    Ok(contents)
+}
 
-        let contents = self.read_file("/proc/stat")?;
+fn parse_error(file: &str, line: &str) -> Error {
+    KernelInterfaceError::ParseError(format!("Unexpected line in {}: {:?}", file, line)).into()
+}
 
-        Ok(contents)
+/// Reads the summary `cpu` line of /proc/stat, idle and iowait count as idle and everything
+/// else up to steal as busy. Guest time is already part of user time so it's left out
+fn parse_proc_stat(input: &str) -> Result<CpuTimes, Error> {
+    let line = match input.lines().find(|line| line.starts_with("cpu ")) {
+        Some(line) => line,
+        None => bail!("No cpu line in /proc/stat"),
+    };
+    let fields = line
+        .split_whitespace()
+        .skip(1)
+        .take(8)
+        .map(|f| f.parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()?;
+    if fields.len() < 4 {
+        return Err(parse_error("/proc/stat", line));
     }
 
-    pub fn get_proc_load_avg(&self) -> Result<String, Error> {
-        debug!("getting proc loadavg");
+    let total: u64 = fields.iter().sum();
+    // iowait is missing on very old kernels
+    let idle = fields[3] + fields.get(4).unwrap_or(&0);
+    Ok(CpuTimes {
+        busy: total - idle,
+        total,
+    })
+}
 
-        let contents = self.read_file("/proc/loadavg")?;
+fn parse_loadavg(input: &str) -> Result<LoadAvg, Error> {
+    let fields: Vec<&str> = input.split_whitespace().collect();
+    if fields.len() < 3 {
+        return Err(parse_error("/proc/loadavg", input));
+    }
+    Ok(LoadAvg {
+        one_minute: fields[0].parse()?,
+        five_minute: fields[1].parse()?,
+        fifteen_minute: fields[2].parse()?,
+    })
+}
 
-        Ok(contents)
+/// Parses /proc/net/dev, after the two header lines every line is an interface name followed
+/// by eight receive and eight transmit counters
+fn parse_net_dev(input: &str) -> Result<HashMap<String, InterfaceStats>, Error> {
+    let mut interfaces = HashMap::new();
+    for line in input.lines().skip(2) {
+        let mut parts = line.splitn(2, ':');
+        let (name, counters) = match (parts.next(), parts.next()) {
+            (Some(name), Some(counters)) => (name.trim(), counters),
+            _ => return Err(parse_error("/proc/net/dev", line)),
+        };
+        let counters = counters
+            .split_whitespace()
+            .map(|c| c.parse::<u64>())
+            .collect::<Result<Vec<u64>, _>>()?;
+        if counters.len() < 16 {
+            return Err(parse_error("/proc/net/dev", line));
+        }
+        interfaces.insert(
+            name.to_string(),
+            InterfaceStats {
+                rx_bytes: counters[0],
+                rx_packets: counters[1],
+                rx_errors: counters[2],
+                rx_dropped: counters[3],
+                tx_bytes: counters[8],
+                tx_packets: counters[9],
+                tx_errors: counters[10],
+                tx_dropped: counters[11],
+            },
+        );
     }
+    Ok(interfaces)
+}
 
-    pub fn get_device_stats(&self) -> Result<String, Error> {
-        debug!("getting device stats");
+fn parse_meminfo(input: &str) -> Result<MemInfo, Error> {
+    let mut values = HashMap::new();
+    for line in input.lines() {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => {
+                values.insert(key.trim_right_matches(':'), value.parse::<u64>()?);
+            }
+            _ => return Err(parse_error("/proc/meminfo", line)),
+        }
+    }
 
-        let contents = self.read_file("/proc/net/dev")?;
+    let get = |key: &str| match values.get(key) {
+        Some(value) => Ok(*value),
+        None => Err(KernelInterfaceError::ParseError(format!(
+            "No {} in /proc/meminfo",
+            key
+        ))),
+    };
+    let free = get("MemFree")?;
+    // kernels before 3.14 don't estimate this, free plus the page cache is close enough
+    let available = match get("MemAvailable") {
+        Ok(available) => available,
+        Err(_) => free + get("Buffers")? + get("Cached")?,
+    };
+    Ok(MemInfo {
+        total: get("MemTotal")?,
+        free,
+        available,
+    })
+}
 
-        Ok(contents)
-    }
+/// Addresses in /proc/net/route are the raw network order bytes printed as a native integer
+fn parse_route_addr(field: &str) -> Result<Ipv4Addr, Error> {
+    Ok(Ipv4Addr::from(u32::from_be(u32::from_str_radix(field, 16)?)))
+}
 
-    pub fn get_meminfo_stats(&self) -> Result<String, Error> {
-        debug!("getting meminfo");
+fn parse_route_table(input: &str) -> Result<Vec<RouteEntry>, Error> {
+    let mut routes = Vec::new();
+    for line in input.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 8 {
+            return Err(parse_error("/proc/net/route", line));
+        }
+        routes.push(RouteEntry {
+            iface: fields[0].to_string(),
+            destination: parse_route_addr(fields[1])?,
+            gateway: parse_route_addr(fields[2])?,
+            metric: fields[6].parse()?,
+            mask: parse_route_addr(fields[7])?,
+        });
+    }
+    Ok(routes)
+}
 
-        let contents = self.read_file("/proc/meminfo")?;
+impl KernelInterface {
+    pub fn get_cpu_times(&self) -> Result<CpuTimes, Error> {
+        parse_proc_stat(&read_file("/proc/stat")?)
+    }
 
-        Ok(contents)
+    pub fn get_load_avg(&self) -> Result<LoadAvg, Error> {
+        parse_loadavg(&read_file("/proc/loadavg")?)
     }
 
-    pub fn get_cpuinfo_stats(&self) -> Result<String, Error> {
-        debug!("getting cpuinfo");
+    /// Counters for every interface, indexed by interface name
+    pub fn get_interface_stats(&self) -> Result<HashMap<String, InterfaceStats>, Error> {
+        parse_net_dev(&read_file("/proc/net/dev")?)
+    }
 
-        let contents = self.read_file("/proc/cpuinfo")?;
+    pub fn get_memory_info(&self) -> Result<MemInfo, Error> {
+        parse_meminfo(&read_file("/proc/meminfo")?)
+    }
 
-        Ok(contents)
+    /// The main ipv4 routing table
+    pub fn get_route_table(&self) -> Result<Vec<RouteEntry>, Error> {
+        parse_route_table(&read_file("/proc/net/route")?)
     }
+}
 
-    pub fn get_route_stats(&self) -> Result<String, Error> {
-        debug!("getting route stats");
+#[test]
+fn test_parse_proc_stat() {
+    let input = "cpu  4705 150 1120 16250 520 0 45 0 0 0
+cpu0 2355 75 560 8125 260 0 23 0 0 0
+cpu1 2350 75 560 8125 260 0 22 0 0 0
+intr 114930548 113199788 3 0 5 263 0 4 [... lots more numbers ...]
+ctxt 1990473
+btime 1062191376
+";
+    let times = parse_proc_stat(input).unwrap();
+    assert_eq!(
+        times,
+        CpuTimes {
+            busy: 4705 + 150 + 1120 + 45,
+            total: 4705 + 150 + 1120 + 16250 + 520 + 45,
+        }
+    );
 
-        let contents = self.read_file("/proc/net/route")?;
+    let later = CpuTimes {
+        busy: times.busy + 25,
+        total: times.total + 100,
+    };
+    assert_eq!(later.usage_since(&times), Some(25.0));
+    assert_eq!(times.usage_since(&times), None);
+    assert_eq!(times.usage_since(&later), None);
 
-        Ok(contents)
-    }
+    assert!(parse_proc_stat("intr 1 2 3\n").is_err());
+}
+
+#[test]
+fn test_parse_loadavg() {
+    assert_eq!(
+        parse_loadavg("0.20 0.18 0.12 1/80 11206\n").unwrap(),
+        LoadAvg {
+            one_minute: 0.20,
+            five_minute: 0.18,
+            fifteen_minute: 0.12,
+        }
+    );
+    assert!(parse_loadavg("").is_err());
+}
+
+#[test]
+fn test_parse_net_dev() {
+    let input = "Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:   15840     192    0    0    0     0          0         0    15840     192    0    0    0     0       0          0
+  eth0: 1215098   11051    3    7    0     0          0       328  2395412    8470    1    2    0     0       0          0
+wg_exit:     148       1    0    0    0     0          0         0      180       2    0    0    0     0       0          0
+";
+    let interfaces = parse_net_dev(input).unwrap();
+    assert_eq!(interfaces.len(), 3);
+    assert_eq!(
+        interfaces["eth0"],
+        InterfaceStats {
+            rx_bytes: 1215098,
+            rx_packets: 11051,
+            rx_errors: 3,
+            rx_dropped: 7,
+            tx_bytes: 2395412,
+            tx_packets: 8470,
+            tx_errors: 1,
+            tx_dropped: 2,
+        }
+    );
+    assert_eq!(interfaces["wg_exit"].tx_bytes, 180);
+}
+
+#[test]
+fn test_parse_meminfo() {
+    let input = "MemTotal:         124724 kB
+MemFree:           58912 kB
+MemAvailable:      67136 kB
+Buffers:            3904 kB
+Cached:            14364 kB
+HugePages_Total:       0
+";
+    assert_eq!(
+        parse_meminfo(input).unwrap(),
+        MemInfo {
+            total: 124724,
+            free: 58912,
+            available: 67136,
+        }
+    );
+
+    let old_kernel = "MemTotal:         124724 kB
+MemFree:           58912 kB
+Buffers:            3904 kB
+Cached:            14364 kB
+";
+    assert_eq!(parse_meminfo(old_kernel).unwrap().available, 58912 + 3904 + 14364);
+    assert!(parse_meminfo("MemFree: 10 kB\n").is_err());
+}
+
+#[test]
+fn test_parse_route_table() {
+    let destination = Ipv4Addr::new(192, 168, 1, 0);
+    let gateway = Ipv4Addr::new(192, 168, 1, 1);
+    let mask = Ipv4Addr::new(255, 255, 255, 0);
+    let native = |addr: Ipv4Addr| format!("{:08X}", u32::from_be(u32::from(addr)));
+
+    let input = format!(
+        "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\t{}\t0003\t0\t0\t10\t00000000\t0\t0\t0
+eth0\t{}\t00000000\t0001\t0\t0\t0\t{}\t0\t0\t0
+",
+        native(gateway),
+        native(destination),
+        native(mask)
+    );
+    assert_eq!(
+        parse_route_table(&input).unwrap(),
+        vec![
+            RouteEntry {
+                iface: "eth0".to_string(),
+                destination: Ipv4Addr::new(0, 0, 0, 0),
+                gateway,
+                mask: Ipv4Addr::new(0, 0, 0, 0),
+                metric: 10,
+            },
+            RouteEntry {
+                iface: "eth0".to_string(),
+                destination,
+                gateway: Ipv4Addr::new(0, 0, 0, 0),
+                mask,
+                metric: 0,
+            },
+        ]
+    );
 }
@@ -0,0 +1,458 @@
+//! Per client bandwidth limits on the exit. wg_exit gets an HTB root qdisc with a single flow
+//! filter that maps every packet to a class picked from the low bits of its destination, so a
+//! client is limited by adding a class for its internal ip and unlimited again by deleting it.
+//! Packets for clients without a class fall through to the HTB direct queue and aren't shaped.
+//! Ipv6 addresses are too long for the flow filter, so a client's ipv6 prefix gets its own u32
+//! filter pointing at the same class as its internal ip.
+//!
+//! Only traffic going to clients is shaped, client uploads would need an ingress redirect to an
+//! ifb device first.
+
+use super::{KernelInterface, KernelInterfaceError};
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv6Addr};
+
+use regex::Regex;
+
+use failure::Error;
+
+/// The download limit for a single client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientRate {
+    /// kbit/s
+    pub rate: u64,
+    /// The ipv6 prefix delegated to the client and its length, limited along with its ipv4
+    pub internal_ipv6: Option<(IpAddr, u8)>,
+}
+
+/// An ipv6 u32 filter as (class id, [(offset, value, mask)]), the form `tc filter show` prints
+type Ipv6Filter = (u16, Vec<(u32, u32, u32)>);
+
+/// The HTB class a client ends up in. The flow filter adds its destination's low 16 bits to
+/// the base class 1:1 and the kernel wraps the sum to 16 bits, a client ending in 255.255 would
+/// land on 1:0 which is the qdisc itself, so it can't be limited
+pub fn client_class_id(internal_ip: IpAddr) -> Option<u16> {
+    match internal_ip {
+        IpAddr::V4(address) => {
+            let class_id = (u32::from(address) & 0xffff) as u16;
+            class_id.checked_add(1)
+        }
+        IpAddr::V6(_) => None,
+    }
+}
+
+/// Parses the rate `tc` prints, like 1500Kbit or 8Mbit, into kbit/s
+fn parse_rate(value: &str, unit: &str) -> Result<u64, Error> {
+    let value: f64 = value.parse()?;
+    let kbit = match unit {
+        "bit" => value / 1000.0,
+        "Kbit" => value,
+        "Mbit" => value * 1000.0,
+        "Gbit" => value * 1_000_000.0,
+        "Tbit" => value * 1_000_000_000.0,
+        _ => {
+            return Err(
+                KernelInterfaceError::ParseError(format!("Unknown rate unit {}", unit)).into(),
+            )
+        }
+    };
+    Ok(kbit.round() as u64)
+}
+
+/// The u32 keys matching a destination in `prefix`, one 32 bit word of the destination address
+/// per key starting at offset 24 of the ipv6 header
+fn ipv6_prefix_keys(prefix: Ipv6Addr, len: u8) -> Vec<(u32, u32, u32)> {
+    let octets = prefix.octets();
+    let len = u32::from(len.min(128));
+    let mut keys = Vec::new();
+    for word in 0..(len + 31) / 32 {
+        let start = (word * 4) as usize;
+        let value = (u32::from(octets[start]) << 24)
+            | (u32::from(octets[start + 1]) << 16)
+            | (u32::from(octets[start + 2]) << 8)
+            | u32::from(octets[start + 3]);
+        let bits = (len - word * 32).min(32);
+        let mask = !0u32 << (32 - bits);
+        keys.push((24 + word * 4, value & mask, mask));
+    }
+    keys
+}
+
+/// Whether `tc qdisc show` lists the HTB root that setup_traffic_shaping installs
+fn has_htb_root(input: &str) -> bool {
+    input
+        .lines()
+        .any(|line| line.starts_with("qdisc htb 1: root"))
+}
+
+impl KernelInterface {
+    /// Installs the HTB root and the flow filter on an interface unless the root is already
+    /// there. An existing HTB root can't be replaced, HTB has no change operation, so this
+    /// leaves it and its classes alone
+    pub fn setup_traffic_shaping(&self, iface: &str) -> Result<(), Error> {
+        let args = &["qdisc", "show", "dev", iface];
+        let output = self.run_command("tc", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("tc", args, &output).into());
+        }
+        if has_htb_root(&String::from_utf8(output.stdout)?) {
+            return Ok(());
+        }
+
+        let args = &[
+            "qdisc", "replace", "dev", iface, "root", "handle", "1:", "htb", "default", "0",
+        ];
+        let output = self.run_command("tc", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("tc", args, &output).into());
+        }
+
+        let args = &[
+            "filter",
+            "replace",
+            "dev",
+            iface,
+            "parent",
+            "1:",
+            "protocol",
+            "ip",
+            "prio",
+            "1",
+            "handle",
+            "1",
+            "flow",
+            "map",
+            "key",
+            "dst",
+            "and",
+            "0xffff",
+            "baseclass",
+            "1:1",
+        ];
+        let output = self.run_command("tc", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("tc", args, &output).into());
+        }
+
+        Ok(())
+    }
+
+    /// The ipv6 filters on an interface, sorted by class id
+    fn get_ipv6_filters(&self, iface: &str) -> Result<Vec<Ipv6Filter>, Error> {
+        let args = &["filter", "show", "dev", iface, "parent", "1:"];
+        let output = self.run_command("tc", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("tc", args, &output).into());
+        }
+
+        lazy_static! {
+            static ref FILTER_RE: Regex =
+                Regex::new(r"^filter .*protocol ipv6 .*flowid 1:(?P<class>[0-9a-f]+)")
+                    .expect("Unable to compile regular expression");
+            static ref KEY_RE: Regex = Regex::new(
+                r"^\s+match (?P<value>[0-9a-f]{8})/(?P<mask>[0-9a-f]{8}) at (?P<offset>[0-9]+)"
+            )
+            .expect("Unable to compile regular expression");
+        }
+
+        let mut filters: Vec<Ipv6Filter> = Vec::new();
+        // whether the match lines that follow belong to the last filter in `filters`
+        let mut in_filter = false;
+        for line in String::from_utf8(output.stdout)?.lines() {
+            if let Some(item) = FILTER_RE.captures(line) {
+                filters.push((u16::from_str_radix(&item["class"], 16)?, Vec::new()));
+                in_filter = true;
+            } else if let Some(item) = KEY_RE.captures(line) {
+                if !in_filter {
+                    continue;
+                }
+                if let Some(filter) = filters.last_mut() {
+                    filter.1.push((
+                        item["offset"].parse()?,
+                        u32::from_str_radix(&item["value"], 16)?,
+                        u32::from_str_radix(&item["mask"], 16)?,
+                    ));
+                }
+            } else {
+                in_filter = false;
+            }
+        }
+        for filter in filters.iter_mut() {
+            filter.1.sort();
+        }
+        filters.sort();
+        Ok(filters)
+    }
+
+    /// Sends traffic for `prefix` to a client's class
+    fn add_ipv6_filter(
+        &self,
+        iface: &str,
+        class_id: u16,
+        prefix: Ipv6Addr,
+        len: u8,
+    ) -> Result<(), Error> {
+        let class_id = format!("1:{:x}", class_id);
+        let prefix = format!("{}/{}", prefix, len);
+        let args = &[
+            "filter", "add", "dev", iface, "parent", "1:", "protocol", "ipv6", "prio", "2", "u32",
+            "match", "ip6", "dst", &prefix, "flowid", &class_id,
+        ];
+        let output = self.run_command("tc", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("tc", args, &output).into());
+        }
+        Ok(())
+    }
+
+    fn remove_ipv6_filters(&self, iface: &str) -> Result<(), Error> {
+        let args = &[
+            "filter", "del", "dev", iface, "parent", "1:", "protocol", "ipv6", "prio", "2",
+        ];
+        let output = self.run_command("tc", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("tc", args, &output).into());
+        }
+        Ok(())
+    }
+
+    /// The current limits on an interface in kbit/s, indexed by class id
+    pub fn get_client_rates(&self, iface: &str) -> Result<HashMap<u16, u64>, Error> {
+        let args = &["class", "show", "dev", iface];
+        let output = self.run_command("tc", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("tc", args, &output).into());
+        }
+
+        lazy_static! {
+            static ref RE: Regex = Regex::new(
+                r"class htb 1:(?P<class>[0-9a-f]+) .*?rate (?P<rate>[0-9.]+)(?P<unit>[KMGT]?bit)"
+            )
+            .expect("Unable to compile regular expression");
+        }
+
+        let mut rates = HashMap::new();
+        for item in RE.captures_iter(&String::from_utf8(output.stdout)?) {
+            let class_id = u16::from_str_radix(&item["class"], 16)?;
+            rates.insert(class_id, parse_rate(&item["rate"], &item["unit"])?);
+        }
+        Ok(rates)
+    }
+
+    /// Limits a single client to `rate` kbit/s in both the guaranteed rate and the ceiling
+    pub fn set_client_rate(&self, iface: &str, class_id: u16, rate: u64) -> Result<(), Error> {
+        let class_id = format!("1:{:x}", class_id);
+        let rate = format!("{}kbit", rate);
+        let args = &[
+            "class", "replace", "dev", iface, "parent", "1:", "classid", &class_id, "htb", "rate",
+            &rate, "ceil", &rate,
+        ];
+        let output = self.run_command("tc", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("tc", args, &output).into());
+        }
+        Ok(())
+    }
+
+    pub fn remove_client_rate(&self, iface: &str, class_id: u16) -> Result<(), Error> {
+        let class_id = format!("1:{:x}", class_id);
+        let args = &["class", "del", "dev", iface, "classid", &class_id];
+        let output = self.run_command("tc", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("tc", args, &output).into());
+        }
+        Ok(())
+    }
+
+    /// Brings the limits on an interface in line with `rates`, a map of client internal ips to
+    /// their limits. Clients that aren't in the map are left unlimited and only classes that
+    /// changed are touched, so this is cheap enough to run every tick
+    pub fn sync_client_rates(
+        &self,
+        iface: &str,
+        rates: &HashMap<IpAddr, ClientRate>,
+    ) -> Result<(), Error> {
+        self.setup_traffic_shaping(iface)?;
+
+        // ordered so the commands are the same each run
+        let mut wanted: BTreeMap<u16, (IpAddr, &ClientRate)> = BTreeMap::new();
+        for (ip, rate) in rates.iter() {
+            match client_class_id(*ip) {
+                Some(class_id) => {
+                    if let Some((other, _)) = wanted.insert(class_id, (*ip, rate)) {
+                        warn!(
+                            "{} and {} share traffic class 1:{:x}, limiting only one of them",
+                            ip, other, class_id
+                        );
+                    }
+                }
+                None => warn!("Can't limit the rate of {}, it has no traffic class", ip),
+            }
+        }
+
+        let mut prefixes: Vec<(u16, Ipv6Addr, u8)> = Vec::new();
+        for (class_id, &(ip, rate)) in wanted.iter() {
+            match rate.internal_ipv6 {
+                Some((IpAddr::V6(prefix), len)) => prefixes.push((*class_id, prefix, len)),
+                Some((prefix, _)) => warn!("Can't limit {}, {} isn't ipv6", ip, prefix),
+                None => {}
+            }
+        }
+        let mut wanted_filters: Vec<Ipv6Filter> = prefixes
+            .iter()
+            .map(|&(class_id, prefix, len)| {
+                let mut keys = ipv6_prefix_keys(prefix, len);
+                keys.sort();
+                (class_id, keys)
+            })
+            .collect();
+        wanted_filters.sort();
+
+        // filters are all replaced when any changed, and removed before the classes because a
+        // class with filters pointing at it can't be deleted
+        let current_filters = self.get_ipv6_filters(iface)?;
+        let filters_changed = current_filters != wanted_filters;
+        if filters_changed && !current_filters.is_empty() {
+            self.remove_ipv6_filters(iface)?;
+        }
+
+        let current = self.get_client_rates(iface)?;
+        let mut stale: Vec<u16> = current
+            .keys()
+            .filter(|class_id| !wanted.contains_key(class_id))
+            .cloned()
+            .collect();
+        stale.sort();
+        for class_id in stale {
+            self.remove_client_rate(iface, class_id)?;
+        }
+        for (class_id, &(ip, rate)) in wanted.iter() {
+            if current.get(class_id) != Some(&rate.rate) {
+                trace!("Limiting {} to {}kbit", ip, rate.rate);
+                self.set_client_rate(iface, *class_id, rate.rate)?;
+            }
+        }
+
+        if filters_changed {
+            for (class_id, prefix, len) in prefixes {
+                self.add_ipv6_filter(iface, class_id, prefix, len)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_client_class_id() {
+    assert_eq!(client_class_id("172.16.0.5".parse().unwrap()), Some(6));
+    assert_eq!(client_class_id("172.16.1.0".parse().unwrap()), Some(0x101));
+    assert_eq!(
+        client_class_id("172.16.255.254".parse().unwrap()),
+        Some(0xffff)
+    );
+    assert_eq!(client_class_id("172.16.255.255".parse().unwrap()), None);
+    assert_eq!(client_class_id("2001:db8::5".parse().unwrap()), None);
+}
+
+#[test]
+fn test_has_htb_root() {
+    assert!(has_htb_root(
+        "qdisc htb 1: root refcnt 2 r2q 10 default 0 direct_packets_stat 12 direct_qlen 1000\n"
+    ));
+    assert!(!has_htb_root("qdisc noqueue 0: root refcnt 2 \n"));
+    assert!(!has_htb_root(
+        "qdisc noqueue 0: root refcnt 2 \nqdisc htb 1: parent 2:1 r2q 10 default 0\n"
+    ));
+}
+
+#[test]
+fn test_ipv6_prefix_keys() {
+    assert_eq!(
+        ipv6_prefix_keys("2001:db8:0:3::".parse().unwrap(), 64),
+        vec![(24, 0x20010db8, 0xffffffff), (28, 0x00000003, 0xffffffff)]
+    );
+    assert_eq!(
+        ipv6_prefix_keys("2001:db8:ff00::".parse().unwrap(), 40),
+        vec![(24, 0x20010db8, 0xffffffff), (28, 0xff000000, 0xff000000)]
+    );
+    assert_eq!(ipv6_prefix_keys("::".parse().unwrap(), 0), vec![]);
+}
+
+#[test]
+fn test_parse_rate() {
+    assert_eq!(parse_rate("500", "Kbit").unwrap(), 500);
+    assert_eq!(parse_rate("8", "Mbit").unwrap(), 8000);
+    assert_eq!(parse_rate("1.5", "Mbit").unwrap(), 1500);
+    assert_eq!(parse_rate("64000", "bit").unwrap(), 64);
+    assert!(parse_rate("1", "Pbit").is_err());
+}
+
+#[test]
+fn test_sync_client_rates_existing_root() {
+    use super::ReplayCommandRunner;
+
+    // the root and filters from an earlier tick are left alone, only the classes are synced
+    let replay = ReplayCommandRunner::from_jsonl(include_str!(
+        "../fixtures/sync_client_rates_existing_root.jsonl"
+    )).unwrap();
+    let mut rates = HashMap::new();
+    rates.insert(
+        "172.168.1.2".parse().unwrap(),
+        ClientRate {
+            rate: 8000,
+            internal_ipv6: Some(("2001:db8:0:2::".parse().unwrap(), 64)),
+        },
+    );
+    rates.insert(
+        "172.168.1.4".parse().unwrap(),
+        ClientRate {
+            rate: 500,
+            internal_ipv6: None,
+        },
+    );
+    {
+        let ki: &KernelInterface = &replay;
+        ki.sync_client_rates("wg_exit", &rates).unwrap();
+    }
+    replay.assert_done();
+}
+
+#[test]
+fn test_sync_client_rates() {
+    use super::ReplayCommandRunner;
+
+    let replay =
+        ReplayCommandRunner::from_jsonl(include_str!("../fixtures/sync_client_rates.jsonl")).unwrap();
+    let mut rates = HashMap::new();
+    // already limited to this rate, nothing to do
+    rates.insert(
+        "172.168.1.2".parse().unwrap(),
+        ClientRate {
+            rate: 8000,
+            internal_ipv6: None,
+        },
+    );
+    // limited to a different rate and its ipv6 prefix newly limited
+    rates.insert(
+        "172.168.1.3".parse().unwrap(),
+        ClientRate {
+            rate: 1500,
+            internal_ipv6: Some(("2001:db8:0:3::".parse().unwrap(), 64)),
+        },
+    );
+    // newly limited
+    rates.insert(
+        "172.168.1.4".parse().unwrap(),
+        ClientRate {
+            rate: 500,
+            internal_ipv6: None,
+        },
+    );
+    {
+        let ki: &KernelInterface = &replay;
+        ki.sync_client_rates("wg_exit", &rates).unwrap();
+    }
+    replay.assert_done();
+}
@@ -0,0 +1,294 @@
+//! Journaled kernel changes. Operations that are made of several commands open a transaction
+//! and every step that changes the system registers the command that undoes it, if the
+//! transaction is dropped without being committed the applied steps are undone newest first.
+//! This keeps a failure halfway through tunnel setup from leaving a half configured interface
+//! or an orphaned route behind.
+//!
+//! A step is undone through the same backend that applied it, a peer added over WireGuard
+//! netlink is removed over netlink rather than with `wg`.
+
+use super::netlink::wireguard::{WgDeviceConfig, WgPeerConfig};
+use super::{FirewallRule, KernelInterface, UciBatch};
+
+use althea_types::WgKey;
+
+use std::cell::RefCell;
+use std::thread;
+
+/// What reverts a single step
+#[derive(Debug)]
+enum Undo {
+    Command {
+        program: String,
+        args: Vec<String>,
+    },
+    /// Declares what the owner had before, see firewall.rs
+    FirewallRules {
+        owner: String,
+        rules: Vec<FirewallRule>,
+    },
+    /// Removes a peer with the WireGuard netlink backend
+    WgPeer {
+        interface: String,
+        public_key: WgKey,
+    },
+    /// Deletes a link with the rtnetlink backend
+    Link { name: String },
+    /// Puts uci options back, then reloads the services that read them
+    Uci {
+        batch: UciBatch,
+        services: Vec<String>,
+    },
+}
+
+thread_local! {
+    // one journal per open transaction, innermost last. Kernel interface calls are synchronous
+    // so a transaction never spans threads
+    static JOURNALS: RefCell<Vec<Vec<Undo>>> = RefCell::new(Vec::new());
+}
+
+/// An open transaction, rolls back on drop unless committed
+pub struct Transaction<'a> {
+    ki: &'a (KernelInterface + 'static),
+    depth: usize,
+    finished: bool,
+}
+
+impl<'a> Transaction<'a> {
+    /// Keeps the applied steps. A nested transaction hands its journal to the one it was
+    /// opened in so a failure further out still undoes them
+    pub fn commit(mut self) {
+        self.finished = true;
+        let journal = self.pop();
+        JOURNALS.with(|journals| {
+            if let Some(parent) = journals.borrow_mut().last_mut() {
+                parent.extend(journal);
+            }
+        });
+    }
+
+    /// Undoes every applied step, newest first
+    pub fn rollback(mut self) {
+        self.finished = true;
+        self.undo();
+    }
+
+    fn pop(&self) -> Vec<Undo> {
+        JOURNALS.with(|journals| {
+            let mut journals = journals.borrow_mut();
+            assert_eq!(
+                journals.len(),
+                self.depth,
+                "Transactions must be finished in the reverse order they were opened"
+            );
+            journals.pop().unwrap()
+        })
+    }
+
+    fn undo(&self) {
+        let journal = self.pop();
+        // running more commands while a panic unwinds through a mocked runner would abort
+        if thread::panicking() {
+            return;
+        }
+        for step in journal.into_iter().rev() {
+            match step {
+                Undo::Command { program, args } => self.undo_command(&program, &args),
+                Undo::WgPeer {
+                    interface,
+                    public_key,
+                } => self.undo_wg_peer(&interface, public_key),
+                Undo::Link { name } => self.undo_link(&name),
+                Undo::Uci { batch, services } => self.undo_uci(&batch, &services),
+                Undo::FirewallRules { owner, rules } => {
+                    info!("Rolling back the firewall rules of {}", owner);
+                    if let Err(e) = self.ki.restore_firewall_rules(&owner, rules) {
+                        error!(
+                            "Rolling back the firewall rules of {} failed with {:?}",
+                            owner, e
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn undo_wg_peer(&self, interface: &str, public_key: WgKey) {
+        info!("Rolling back by removing peer {:?} from {}", public_key, interface);
+        let wg = match self.ki.wireguard() {
+            Some(wg) => wg,
+            None => {
+                error!("Can't remove a peer from {} without WireGuard netlink", interface);
+                return;
+            }
+        };
+        let config = WgDeviceConfig {
+            peers: vec![WgPeerConfig::remove(public_key)],
+            ..WgDeviceConfig::default()
+        };
+        if let Err(e) = wg.set_device(interface, &config) {
+            error!("Rolling back a peer on {} failed with {:?}", interface, e);
+        }
+    }
+
+    fn undo_link(&self, name: &str) {
+        info!("Rolling back by deleting link {}", name);
+        let nl = match self.ki.netlink() {
+            Some(nl) => nl,
+            None => {
+                error!("Can't delete link {} without rtnetlink", name);
+                return;
+            }
+        };
+        if let Err(e) = nl.del_link(name) {
+            error!("Rolling back link {} failed with {:?}", name, e);
+        }
+    }
+
+    fn undo_uci(&self, batch: &UciBatch, services: &[String]) {
+        info!("Rolling back uci with\n{}", batch.script());
+        if let Err(e) = self.ki.uci_apply(batch) {
+            error!("Rolling back uci failed with {:?}", e);
+            return;
+        }
+        for service in services {
+            if let Err(e) = self.ki.refresh_initd(service) {
+                error!("Reloading {} for a rollback failed with {:?}", service, e);
+            }
+        }
+    }
+
+    fn undo_command(&self, program: &str, args: &[String]) {
+        info!("Rolling back with {} {:?}", program, args);
+        let str_args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+        match self.ki.run_command(program, &str_args) {
+            Ok(ref output) if output.status.success() => {}
+            Ok(output) => error!(
+                "Rollback step {} {:?} failed with {}",
+                program,
+                args,
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            Err(e) => error!("Rollback step {} {:?} failed with {:?}", program, args, e),
+        }
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.undo();
+        }
+    }
+}
+
+/// Does nothing outside of a transaction
+fn push_undo(undo: Undo) {
+    JOURNALS.with(|journals| {
+        if let Some(journal) = journals.borrow_mut().last_mut() {
+            journal.push(undo);
+        }
+    });
+}
+
+impl KernelInterface {
+    /// Opens a transaction, steps journaled until it's committed are undone if it's dropped
+    /// instead, which is what happens when a `?` returns early
+    pub fn transaction(&self) -> Transaction {
+        let depth = JOURNALS.with(|journals| {
+            let mut journals = journals.borrow_mut();
+            journals.push(Vec::new());
+            journals.len()
+        });
+        Transaction {
+            ki: self,
+            depth,
+            finished: false,
+        }
+    }
+
+    /// Registers the command that undoes a step that was just applied, does nothing outside
+    /// of a transaction
+    pub fn journal(&self, program: &str, args: &[&str]) {
+        push_undo(Undo::Command {
+            program: program.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+        });
+    }
+
+    /// Registers the removal of a peer that was just added over WireGuard netlink
+    pub(crate) fn journal_wg_peer(&self, interface: &str, public_key: WgKey) {
+        push_undo(Undo::WgPeer {
+            interface: interface.to_string(),
+            public_key,
+        });
+    }
+
+    /// Registers the deletion of a link that was just created over rtnetlink
+    pub(crate) fn journal_link(&self, name: &str) {
+        push_undo(Undo::Link {
+            name: name.to_string(),
+        });
+    }
+
+    /// Registers the batch that undoes a uci change that was just applied, along with the init
+    /// scripts to reload once it's undone
+    pub(crate) fn journal_uci(&self, batch: UciBatch, services: &[&str]) {
+        push_undo(Undo::Uci {
+            batch,
+            services: services.iter().map(|s| s.to_string()).collect(),
+        });
+    }
+
+    /// Registers the declaration a firewall owner had before it was replaced
+    pub(crate) fn journal_firewall_rules(&self, owner: &str, rules: Vec<FirewallRule>) {
+        push_undo(Undo::FirewallRules {
+            owner: owner.to_string(),
+            rules,
+        });
+    }
+}
+
+#[test]
+fn test_nested_transaction() {
+    use super::{CommandFixture, ReplayCommandRunner};
+
+    let fixture = |program: &str, args: &[&str]| CommandFixture {
+        program: program.to_string(),
+        args: args.iter().map(|a| a.to_string()).collect(),
+        stdout: String::new(),
+        stderr: String::new(),
+        status: Some(0),
+        stdin: None,
+    };
+    let replay = ReplayCommandRunner::new(vec![
+        fixture("ip", &["link", "del", "dev", "wg_test"]),
+        fixture("ip", &["route", "del", "10.0.0.1"]),
+        fixture("ip", &["address", "del", "10.0.0.2", "dev", "wg_test"]),
+    ]);
+    {
+        let ki: &KernelInterface = &replay;
+        let outer = ki.transaction();
+        ki.journal("ip", &["address", "del", "10.0.0.2", "dev", "wg_test"]);
+        {
+            let inner = ki.transaction();
+            ki.journal("ip", &["route", "del", "10.0.0.1"]);
+            inner.commit();
+        }
+        {
+            // rolled back on its own, the outer transaction never sees this step
+            let _inner = ki.transaction();
+            ki.journal("ip", &["link", "del", "dev", "wg_test"]);
+        }
+        outer.rollback();
+    }
+    replay.assert_done();
+
+    // committing the outermost transaction forgets the journal
+    let ki: &KernelInterface = &replay;
+    let transaction = ki.transaction();
+    ki.journal("ip", &["route", "del", "10.0.0.1"]);
+    transaction.commit();
+    ki.journal("ip", &["route", "del", "10.0.0.1"]);
+    JOURNALS.with(|journals| assert!(journals.borrow().is_empty()));
+}
@@ -0,0 +1,431 @@
+//! A typed view of UCI configuration and batched changes to it. A package is read with
+//! `uci show` into its sections and options, anonymous sections keep the `@type[index]` name
+//! uci shows them under so they can be addressed in changes like any other section.
+//!
+//! Changes are collected in a UciBatch and applied with a single `uci batch` run, if any command
+//! in it fails every package the batch touched is reverted, otherwise they're all committed. So a
+//! change made of several steps is either applied completely or not at all.
+
+use super::{KernelInterface, KernelInterfaceError};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::slice;
+
+use failure::Error;
+
+/// The value of an option. `uci show` prints a list with a single item exactly like a plain
+/// option, so those read back as `Option`, `values` treats both the same
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UciValue {
+    Option(String),
+    List(Vec<String>),
+}
+
+impl UciValue {
+    pub fn values(&self) -> &[String] {
+        match *self {
+            UciValue::Option(ref value) => slice::from_ref(value),
+            UciValue::List(ref values) => values,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UciSection {
+    /// The name used to address the section, `@type[index]` for anonymous sections
+    pub name: String,
+    pub anonymous: bool,
+    pub section_type: String,
+    pub options: BTreeMap<String, UciValue>,
+}
+
+impl UciSection {
+    pub fn get(&self, option: &str) -> Option<&UciValue> {
+        self.options.get(option)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UciPackage {
+    pub name: String,
+    /// In the order they appear in the config file
+    pub sections: Vec<UciSection>,
+}
+
+impl UciPackage {
+    pub fn section(&self, name: &str) -> Option<&UciSection> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    /// Shorthand for looking up a single option
+    pub fn get(&self, section: &str, option: &str) -> Option<&UciValue> {
+        self.section(section).and_then(|s| s.get(option))
+    }
+
+    pub fn sections_of_type<'a>(
+        &'a self,
+        section_type: &'a str,
+    ) -> impl Iterator<Item = &'a UciSection> + 'a {
+        self.sections
+            .iter()
+            .filter(move |s| s.section_type == section_type)
+    }
+}
+
+/// Splits a value the way uci quotes it, 'a' 'b' is a list and 'it'\''s' a single value
+fn parse_uci_words(input: &str) -> Result<Vec<String>, Error> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            '\\' if !quoted => match chars.next() {
+                Some(escaped) => {
+                    word.push(escaped);
+                    in_word = true;
+                }
+                None => break,
+            },
+            ' ' if !quoted => {
+                if in_word {
+                    words.push(word.clone());
+                    word.clear();
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quoted {
+        return Err(KernelInterfaceError::ParseError(format!(
+            "Unterminated quote in uci value {:?}",
+            input
+        ))
+        .into());
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Builds the typed package from `uci show <package>` output
+pub(crate) fn parse_uci_show(package: &str, input: &str) -> Result<UciPackage, Error> {
+    let mut sections: Vec<UciSection> = Vec::new();
+    for line in input.lines() {
+        let parse_error =
+            || KernelInterfaceError::ParseError(format!("Unexpected line in uci show: {:?}", line));
+        let mut parts = line.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key, value),
+            _ => return Err(parse_error().into()),
+        };
+        let path: Vec<&str> = key.split('.').collect();
+        if path[0] != package {
+            return Err(parse_error().into());
+        }
+
+        match path.len() {
+            2 => sections.push(UciSection {
+                name: path[1].to_string(),
+                anonymous: path[1].starts_with('@'),
+                section_type: value.to_string(),
+                options: BTreeMap::new(),
+            }),
+            3 => {
+                let section = match sections.iter_mut().rev().find(|s| s.name == path[1]) {
+                    Some(section) => section,
+                    None => return Err(parse_error().into()),
+                };
+                let mut words = parse_uci_words(value)?;
+                let value = if words.len() == 1 {
+                    UciValue::Option(words.remove(0))
+                } else {
+                    UciValue::List(words)
+                };
+                section.options.insert(path[2].to_string(), value);
+            }
+            _ => return Err(parse_error().into()),
+        }
+    }
+    Ok(UciPackage {
+        name: package.to_string(),
+        sections,
+    })
+}
+
+/// Quotes an argument for `uci batch`, which splits lines like a shell would
+fn quote_uci(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// A set of changes to apply together with `uci_apply`. Paths are the usual
+/// `package.section.option`, deleting something that doesn't exist fails the whole batch so
+/// check the package first
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UciBatch {
+    commands: Vec<String>,
+    packages: BTreeSet<String>,
+}
+
+impl UciBatch {
+    pub fn new() -> UciBatch {
+        UciBatch::default()
+    }
+
+    fn push(&mut self, command: &str, path: &str, arg: String) -> &mut UciBatch {
+        let package = path.split('.').next().unwrap_or(path);
+        self.packages.insert(package.to_string());
+        self.commands.push(format!("{} {}", command, arg));
+        self
+    }
+
+    /// Sets an option, or creates a named section when `path` is `package.section`
+    pub fn set(&mut self, path: &str, value: &str) -> &mut UciBatch {
+        self.push("set", path, quote_uci(&format!("{}={}", path, value)))
+    }
+
+    /// Adds an anonymous section, it becomes the last `@section_type` of the package
+    pub fn add(&mut self, package: &str, section_type: &str) -> &mut UciBatch {
+        let arg = format!("{} {}", quote_uci(package), quote_uci(section_type));
+        self.push("add", package, arg)
+    }
+
+    pub fn add_list(&mut self, path: &str, value: &str) -> &mut UciBatch {
+        self.push("add_list", path, quote_uci(&format!("{}={}", path, value)))
+    }
+
+    pub fn del_list(&mut self, path: &str, value: &str) -> &mut UciBatch {
+        self.push("del_list", path, quote_uci(&format!("{}={}", path, value)))
+    }
+
+    /// Deletes a section or an option
+    pub fn delete(&mut self, path: &str) -> &mut UciBatch {
+        self.push("delete", path, quote_uci(path))
+    }
+
+    /// Puts an option back to a value read before it was changed, or deletes it if it didn't
+    /// exist then
+    pub fn restore(&mut self, path: &str, previous: Option<&UciValue>) -> &mut UciBatch {
+        match previous {
+            Some(&UciValue::Option(ref value)) => self.set(path, value),
+            Some(&UciValue::List(ref values)) => {
+                self.delete(path);
+                for value in values {
+                    self.add_list(path, value);
+                }
+                self
+            }
+            None => self.delete(path),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Every package this batch changes
+    pub fn packages(&self) -> Vec<&str> {
+        self.packages.iter().map(|p| p.as_str()).collect()
+    }
+
+    /// The input for `uci batch`
+    pub fn script(&self) -> String {
+        let mut script = String::new();
+        for command in self.commands.iter() {
+            script.push_str(command);
+            script.push('\n');
+        }
+        script
+    }
+}
+
+impl KernelInterface {
+    /// Reads a whole package, like `uci_show` but keeping its structure
+    pub fn uci_get_package(&self, package: &str) -> Result<UciPackage, Error> {
+        let args = &["show", package];
+        let output = self.run_command("uci", args)?;
+        if !output.status.success() {
+            return Err(KernelInterfaceError::from_output("uci", args, &output).into());
+        }
+        parse_uci_show(package, &String::from_utf8(output.stdout)?)
+    }
+
+    /// Runs a batch and commits every package it touched, or reverts them all if any of the
+    /// batch failed. Commits can't be undone, if committing a later package fails the earlier
+    /// ones stay committed and the rest are reverted
+    pub fn uci_apply(&self, batch: &UciBatch) -> Result<(), Error> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let script = batch.script();
+        trace!("Applying uci batch:\n{}", script);
+
+        let args = &["batch"];
+        let output = self.run_command_with_input("uci", args, &script)?;
+        // uci batch keeps going after a failed command and only complains on stderr
+        if !output.status.success() || !output.stderr.is_empty() {
+            let e = KernelInterfaceError::from_output("uci", args, &output);
+            self.uci_revert_all(&batch.packages());
+            return Err(e.into());
+        }
+
+        let packages = batch.packages();
+        for (index, package) in packages.iter().enumerate() {
+            if let Err(e) = self.uci_commit(package) {
+                self.uci_revert_all(&packages[index..]);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn uci_revert_all(&self, packages: &[&str]) {
+        for package in packages {
+            if let Err(e) = self.uci_revert(package) {
+                error!("Failed to revert uci package {}: {:?}", package, e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_uci_words() {
+    assert_eq!(parse_uci_words("'eth0.1'").unwrap(), vec!["eth0.1"]);
+    assert_eq!(
+        parse_uci_words("'1.1.1.1' '8.8.8.8'").unwrap(),
+        vec!["1.1.1.1", "8.8.8.8"]
+    );
+    assert_eq!(
+        parse_uci_words("'it'\\''s mine'").unwrap(),
+        vec!["it's mine"]
+    );
+    assert_eq!(parse_uci_words("''").unwrap(), vec![""]);
+    assert!(parse_uci_words("'open").is_err());
+}
+
+#[test]
+fn test_parse_uci_show() {
+    let input = "network.loopback=interface
+network.loopback.ifname='lo'
+network.lan=interface
+network.lan.type='bridge'
+network.lan.ifname='eth0.1 eth1'
+network.lan.dns='1.1.1.1' '8.8.8.8'
+network.@switch[0]=switch
+network.@switch[0].name='switch0'
+network.@switch_vlan[0]=switch_vlan
+network.@switch_vlan[0].vlan='1'
+network.@switch_vlan[1]=switch_vlan
+network.@switch_vlan[1].vlan='2'
+";
+    let network = parse_uci_show("network", input).unwrap();
+    assert_eq!(network.sections.len(), 5);
+    assert_eq!(
+        network.get("lan", "ifname"),
+        Some(&UciValue::Option("eth0.1 eth1".to_string()))
+    );
+    assert_eq!(
+        network.get("lan", "dns").unwrap().values(),
+        &["1.1.1.1".to_string(), "8.8.8.8".to_string()]
+    );
+
+    let switch = network.section("@switch[0]").unwrap();
+    assert!(switch.anonymous);
+    assert_eq!(switch.section_type, "switch");
+    assert!(!network.section("lan").unwrap().anonymous);
+    assert_eq!(network.sections_of_type("switch_vlan").count(), 2);
+    assert_eq!(
+        network.get("@switch_vlan[1]", "vlan"),
+        Some(&UciValue::Option("2".to_string()))
+    );
+
+    assert!(parse_uci_show("network", "wireless.radio0=wifi-device\n").is_err());
+    assert!(parse_uci_show("network", "network.lan.proto='static'\n").is_err());
+}
+
+#[test]
+fn test_uci_batch_script() {
+    let mut batch = UciBatch::new();
+    batch
+        .delete("network.rita_eth1")
+        .set("network.backhaul", "interface")
+        .set("network.backhaul.ifname", "eth1")
+        .add("firewall", "rule")
+        .add_list("dhcp.@dnsmasq[0].server", "1.1.1.1")
+        .del_list("dhcp.@dnsmasq[0].server", "8.8.8.8")
+        .set("wireless.default_radio0.ssid", "Bob's Network");
+    assert_eq!(
+        batch.packages(),
+        vec!["dhcp", "firewall", "network", "wireless"]
+    );
+    assert_eq!(
+        batch.script(),
+        "delete 'network.rita_eth1'
+set 'network.backhaul=interface'
+set 'network.backhaul.ifname=eth1'
+add 'firewall' 'rule'
+add_list 'dhcp.@dnsmasq[0].server=1.1.1.1'
+del_list 'dhcp.@dnsmasq[0].server=8.8.8.8'
+set 'wireless.default_radio0.ssid=Bob'\\''s Network'
+"
+    );
+    assert!(UciBatch::new().is_empty());
+
+    let dns = UciValue::List(vec!["1.1.1.1".to_string(), "8.8.8.8".to_string()]);
+    let mut batch = UciBatch::new();
+    batch
+        .restore("dhcp.lan.ra", Some(&UciValue::Option("relay".to_string())))
+        .restore("dhcp.lan.dhcpv6", None)
+        .restore("network.lan.dns", Some(&dns));
+    assert_eq!(
+        batch.script(),
+        "set 'dhcp.lan.ra=relay'
+delete 'dhcp.lan.dhcpv6'
+delete 'network.lan.dns'
+add_list 'network.lan.dns=1.1.1.1'
+add_list 'network.lan.dns=8.8.8.8'
+"
+    );
+}
+
+#[test]
+fn test_uci_apply() {
+    use super::ReplayCommandRunner;
+
+    let replay =
+        ReplayCommandRunner::from_jsonl(include_str!("../fixtures/uci_apply.jsonl")).unwrap();
+    {
+        let ki: &KernelInterface = &replay;
+        let network = ki.uci_get_package("network").unwrap();
+        assert_eq!(
+            network.get("lan", "ifname").unwrap().values(),
+            &["eth0.1".to_string()]
+        );
+
+        let mut batch = UciBatch::new();
+        batch
+            .set("network.lan.ifname", "eth0.1 eth1")
+            .set("wireless.default_radio0.disabled", "0");
+        ki.uci_apply(&batch).unwrap();
+
+        // the missing section fails the batch and both packages are reverted
+        let mut batch = UciBatch::new();
+        batch
+            .delete("network.rita_eth1")
+            .set("wireless.default_radio0.disabled", "1");
+        assert!(ki.uci_apply(&batch).is_err());
+
+        ki.uci_apply(&UciBatch::new()).unwrap();
+    }
+    replay.assert_done();
+}
@@ -6,9 +6,9 @@ use std::u16;
 use super::KernelInterfaceError;
 use failure::Error;
 
-/// Returns a kernel interface runtime error with the given message.
-fn runtime_error<T>(msg: &str) -> Result<T, Error> {
-    Err(KernelInterfaceError::RuntimeError(msg.to_string()).into())
+/// Returns a kernel interface parse error with the given message.
+fn parse_error<T>(msg: &str) -> Result<T, Error> {
+    Err(KernelInterfaceError::ParseError(msg.to_string()).into())
 }
 
 /// Helper function for parsing out port number from local_address column
@@ -16,17 +16,17 @@ fn parse_local_port(s: &str) -> Result<u16, Error> {
     // second column in table contains local_address
     let local_addr = match s.split_whitespace().nth(1) {
         Some(addr) => addr,
-        None => return runtime_error("Error parsing local_address column!"),
+        None => return parse_error("Error parsing local_address column!"),
     };
     // having a format like "00000000:14E9"
     let port = match local_addr.split(":").nth(1) {
         Some(port) => port,
-        None => return runtime_error("Error parsing local_address column!"),
+        None => return parse_error("Error parsing local_address column!"),
     };
 
     match u16::from_str_radix(port, 16) {
         Ok(port) => Ok(port),
-        Err(_) => runtime_error("Error parsing port from local_address column!"),
+        Err(_) => parse_error("Error parsing port from local_address column!"),
     }
 }
 
@@ -3,11 +3,13 @@
 //! a single destination and a single price.
 
 use failure::Error;
+use futures::{future, Future};
 use regex::Regex;
 
 use std::collections::HashMap;
+use std::time::Duration;
 
-use super::{KernelInterface, KernelInterfaceError};
+use super::{KernelInterface, KernelInterfaceError, ASYNC_COMMAND_TIMEOUT_SECS};
 
 #[derive(Clone, Debug)]
 pub struct WgUsage {
@@ -15,34 +17,73 @@ pub struct WgUsage {
     pub download: u64,
 }
 
+/// Parses the output of `wg show <interface> transfer`
+fn parse_wg_transfer(input: &str) -> Result<HashMap<String, WgUsage>, Error> {
+    lazy_static! {
+        static ref RE: Regex =
+            Regex::new(r"(?P<key>[/=0-9a-zA-Z]+)\t(?P<download>[0-9]+)\t(?P<upload>[0-9]+)\n*")
+                .expect("Unable to compile regular expression");
+    }
+
+    let mut result = HashMap::new();
+    for item in RE.captures_iter(input) {
+        let usage = WgUsage {
+            upload: item["upload"].parse()?,
+            download: item["download"].parse()?,
+        };
+        result.insert(item["key"].to_string(), usage);
+    }
+
+    Ok(result)
+}
+
 impl KernelInterface {
     /// Takes a wg interface name and provides upload and download since creation in bytes
     /// in a hashmap indexed by peer WireGuard key
     pub fn read_wg_counters(&self, wg_name: &str) -> Result<HashMap<String, WgUsage>, Error> {
-        let output = self.run_command("wg", &["show", wg_name, "transfer"])?;
-        if !output.stderr.is_empty() {
-            return Err(KernelInterfaceError::RuntimeError(format!(
-                "received error from wg command: {}",
-                String::from_utf8(output.stderr)?
-            )).into());
+        if let Some(wg) = self.wireguard() {
+            let mut result = HashMap::new();
+            for peer in wg.get_device(wg_name)?.peers {
+                let usage = WgUsage {
+                    upload: peer.tx_bytes,
+                    download: peer.rx_bytes,
+                };
+                result.insert(peer.public_key.to_string(), usage);
+            }
+            return Ok(result);
         }
 
-        lazy_static! {
-            static ref RE: Regex =
-                Regex::new(r"(?P<key>[/=0-9a-zA-Z]+)\t(?P<download>[0-9]+)\t(?P<upload>[0-9]+)\n*")
-                    .expect("Unable to compile regular expression");
+        let args = &["show", wg_name, "transfer"];
+        let output = self.run_command("wg", args)?;
+        if !output.stderr.is_empty() {
+            return Err(KernelInterfaceError::from_output("wg", args, &output).into());
         }
 
-        let mut result = HashMap::new();
-        for item in RE.captures_iter(&String::from_utf8(output.stdout)?) {
-            let usage = WgUsage {
-                upload: item["upload"].parse()?,
-                download: item["download"].parse()?,
-            };
-            result.insert(item["key"].to_string(), usage);
+        parse_wg_transfer(&String::from_utf8(output.stdout)?)
+    }
+
+    /// Same as `read_wg_counters` but doesn't block on `wg`, the netlink read is a single
+    /// request to the kernel so that one still happens inline
+    pub fn read_wg_counters_async(
+        &self,
+        wg_name: &str,
+    ) -> Box<Future<Item = HashMap<String, WgUsage>, Error = Error>> {
+        if self.wireguard().is_some() {
+            return Box::new(future::result(self.read_wg_counters(wg_name)));
         }
 
-        Ok(result)
+        let timeout = Duration::from_secs(ASYNC_COMMAND_TIMEOUT_SECS);
+        let wg_name = wg_name.to_string();
+        Box::new(
+            self.run_command_async("wg", &["show", &wg_name, "transfer"], timeout)
+                .and_then(move |output| {
+                    if !output.stderr.is_empty() {
+                        let args = &["show", &wg_name, "transfer"];
+                        return Err(KernelInterfaceError::from_output("wg", args, &output).into());
+                    }
+                    parse_wg_transfer(&String::from_utf8(output.stdout)?)
+                }),
+        )
     }
 }
 
@@ -1,5 +1,6 @@
 use num256::Uint256;
-use std::net::IpAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
 use wg_key::WgKey;
 use EthAddress;
 
@@ -148,6 +149,11 @@ pub struct ExitDetails {
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 pub struct ExitClientDetails {
     pub client_internal_ip: IpAddr,
+    /// The ipv6 prefix delegated to this client, None if the exit doesn't offer ipv6
+    #[serde(default)]
+    pub client_ipv6_prefix: Option<IpAddr>,
+    #[serde(default)]
+    pub client_ipv6_prefix_len: u8,
 }
 
 #[cfg(feature = "actix")]
@@ -177,13 +183,55 @@ pub struct PaymentTx {
     pub amount: Uint256,
 }
 
-/// This contains all the info we need to send the the stats server
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Load averages over the last 1, 5 and 15 minutes, from /proc/loadavg
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct LoadAvg {
+    pub one_minute: f32,
+    pub five_minute: f32,
+    pub fifteen_minute: f32,
+}
+
+/// Counters for a single interface since it came up, from /proc/net/dev
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InterfaceStats {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_errors: u64,
+    pub tx_dropped: u64,
+}
+
+/// Memory usage in kB, from /proc/meminfo
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct MemInfo {
+    pub total: u64,
+    pub free: u64,
+    /// Free memory plus what the kernel can reclaim from caches, what's left for new programs
+    pub available: u64,
+}
+
+/// An ipv4 route from /proc/net/route
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteEntry {
+    pub iface: String,
+    pub destination: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub mask: Ipv4Addr,
+    pub metric: u32,
+}
+
+/// A sample of the router's health
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Stats {
-    pub proc_stat: String,
-    pub proc_load_avg: String,
-    pub devices: String,
-    pub routes: String,
-    pub meminfo: String,
-    pub cpuinfo: String,
+    /// When the sample was taken, in seconds since the unix epoch
+    pub timestamp: u64,
+    /// Percentage of cpu time spent busy since the previous sample, None for the first sample
+    pub cpu_usage: Option<f32>,
+    pub load_avg: LoadAvg,
+    pub interfaces: HashMap<String, InterfaceStats>,
+    pub memory: MemInfo,
+    pub routes: Vec<RouteEntry>,
 }
@@ -282,4 +282,28 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn exit_client_details_deserialize() {
+        // exits without ipv6 support don't send the prefix at all
+        let s = "{\"client_internal_ip\":\"172.16.0.5\"}";
+        assert_eq!(
+            serde_json::from_str::<ExitClientDetails>(s).unwrap(),
+            ExitClientDetails {
+                client_internal_ip: "172.16.0.5".parse().unwrap(),
+                client_ipv6_prefix: None,
+                client_ipv6_prefix_len: 0,
+            }
+        );
+
+        let s = "{\"client_internal_ip\":\"172.16.0.5\",\"client_ipv6_prefix\":\"2001:db8:0:5::\",\"client_ipv6_prefix_len\":64}";
+        assert_eq!(
+            serde_json::from_str::<ExitClientDetails>(s).unwrap(),
+            ExitClientDetails {
+                client_internal_ip: "172.16.0.5".parse().unwrap(),
+                client_ipv6_prefix: Some("2001:db8:0:5::".parse().unwrap()),
+                client_ipv6_prefix_len: 64,
+            }
+        );
+    }
 }
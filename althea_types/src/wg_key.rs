@@ -13,6 +13,12 @@ impl AsRef<[u8]> for WgKey {
     }
 }
 
+impl From<[u8; 32]> for WgKey {
+    fn from(key: [u8; 32]) -> WgKey {
+        WgKey(key)
+    }
+}
+
 impl fmt::Display for WgKey {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", base64::encode(&self))
@@ -0,0 +1,416 @@
+//! An async connection to babeld for use from inside an event loop. Every read has a deadline
+//! so a hung babeld fails the command instead of blocking forever, and a connection that has
+//! gone away, like when babeld restarts during an upgrade, is reopened and handshaked again the
+//! next time a command is sent.
+//!
+//! The client is passed by value and handed back with each reply, the same way tokio's own
+//! combinators hand back a stream. Several commands can be sent in one go with `commands`,
+//! babeld answers them in order so they don't each wait on a round trip.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use failure::Error;
+use futures::{future, stream, Future, Sink, Stream};
+use tokio::net::TcpStream;
+use tokio::timer::Timeout;
+use tokio_codec::{Framed, LinesCodec};
+
+use parser::{parse_line, BabelDump, BabelLine};
+use preamble::{Capability, Preamble};
+use tuning::InterfaceTuning;
+use BabelMonitorError::*;
+
+type Lines = Framed<TcpStream, LinesCodec>;
+
+/// The reply to one command, a command babeld refuses fails on its own without taking the
+/// connection down with it
+pub type Reply = Result<String, Error>;
+
+fn timed<F>(fut: F, timeout: Duration) -> Box<Future<Item = F::Item, Error = Error>>
+where
+    F: Future<Error = Error> + 'static,
+{
+    Box::new(Timeout::new(fut, timeout).map_err(|e| {
+        if e.is_elapsed() {
+            TimedOut.into()
+        } else if e.is_inner() {
+            e.into_inner().expect("Checked is_inner")
+        } else {
+            format_err!("Babel timer failed: {:?}", e)
+        }
+    }))
+}
+
+fn read_line(
+    lines: Lines,
+    timeout: Duration,
+) -> Box<Future<Item = (String, Lines), Error = Error>> {
+    let read = lines.into_future().then(|res| match res {
+        Ok((Some(line), lines)) => Ok((line, lines)),
+        Ok((None, _)) => Err(ConnectionClosed.into()),
+        Err((e, _)) => Err(e.into()),
+    });
+    timed(read, timeout)
+}
+
+/// Reads up to and including the ok, no or bad that ends a reply
+fn read_reply(
+    lines: Lines,
+    timeout: Duration,
+) -> Box<Future<Item = (Reply, Lines), Error = Error>> {
+    Box::new(future::loop_fn(
+        (lines, String::new()),
+        move |(lines, mut output)| {
+            read_line(lines, timeout).map(|(line, lines)| {
+                output.push_str(&line);
+                output.push('\n');
+                match parse_line(&line) {
+                    Ok(BabelLine::Ok) => {
+                        trace!("Babel returned ok; full output:\n{}", output);
+                        future::Loop::Break((Ok(output), lines))
+                    }
+                    Ok(BabelLine::No(_)) | Ok(BabelLine::Bad(_)) => {
+                        warn!("Babel returned bad/no; full output:\n{}", output);
+                        future::Loop::Break((Err(ReadFailed(output).into()), lines))
+                    }
+                    _ => future::Loop::Continue((lines, output)),
+                }
+            })
+        },
+    ))
+}
+
+fn connect(
+    addr: SocketAddr,
+    timeout: Duration,
+) -> Box<Future<Item = (Lines, Preamble), Error = Error>> {
+    let stream = TcpStream::connect(&addr).from_err();
+    Box::new(
+        timed(stream, timeout)
+            .and_then(move |stream| read_reply(Framed::new(stream, LinesCodec::new()), timeout))
+            .and_then(|(preamble, lines)| {
+                let preamble = preamble?;
+                let parsed = Preamble::parse(&preamble)?;
+                info!(
+                    "Attached OK to Babel with preamble: {}capabilities {:?}",
+                    preamble,
+                    parsed.capabilities()
+                );
+                Ok((lines, parsed))
+            }),
+    )
+}
+
+/// Opens a new connection and sends the commands over it
+fn reconnect_exchange(
+    addr: SocketAddr,
+    commands: Vec<String>,
+    timeout: Duration,
+) -> Box<Future<Item = (Lines, Preamble, Vec<Reply>), Error = Error>> {
+    Box::new(connect(addr, timeout).and_then(move |(lines, preamble)| {
+        exchange(lines, commands, timeout).map(move |(lines, replies)| (lines, preamble, replies))
+    }))
+}
+
+/// Sends every command and then reads every reply
+fn exchange(
+    lines: Lines,
+    commands: Vec<String>,
+    timeout: Duration,
+) -> Box<Future<Item = (Lines, Vec<Reply>), Error = Error>> {
+    let count = commands.len();
+    trace!("Sending {:?} to babel", commands);
+    let sent = lines
+        .send_all(stream::iter_ok::<_, ::std::io::Error>(commands))
+        .from_err()
+        .map(|(lines, _)| lines);
+    Box::new(timed(sent, timeout).and_then(move |lines| {
+        stream::iter_ok::<_, Error>(0..count).fold(
+            (lines, Vec::with_capacity(count)),
+            move |(lines, mut replies), _| {
+                read_reply(lines, timeout).map(|(reply, lines)| {
+                    replies.push(reply);
+                    (lines, replies)
+                })
+            },
+        )
+    }))
+}
+
+pub struct BabelClient {
+    addr: SocketAddr,
+    timeout: Duration,
+    lines: Option<Lines>,
+    preamble: Option<Preamble>,
+}
+
+impl BabelClient {
+    /// Nothing is opened until the first command, `timeout` is how long to wait for each line
+    /// babeld sends back
+    pub fn new(addr: SocketAddr, timeout: Duration) -> BabelClient {
+        BabelClient {
+            addr,
+            timeout,
+            lines: None,
+            preamble: None,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.lines.is_some()
+    }
+
+    /// The preamble from the last time the connection was opened
+    pub fn preamble(&self) -> Option<&Preamble> {
+        self.preamble.as_ref()
+    }
+
+    /// False until the connection has been opened
+    pub fn supports(&self, cap: Capability) -> bool {
+        self.preamble
+            .as_ref()
+            .map(|preamble| preamble.supports(cap))
+            .unwrap_or(false)
+    }
+
+    /// Opens the connection and checks the preamble if it isn't open already
+    pub fn connect(self) -> Box<Future<Item = BabelClient, Error = Error>> {
+        let BabelClient {
+            addr,
+            timeout,
+            lines,
+            preamble,
+        } = self;
+        let connection = match (lines, preamble) {
+            (Some(lines), Some(preamble)) => Box::new(future::ok((lines, preamble)))
+                as Box<Future<Item = (Lines, Preamble), Error = Error>>,
+            _ => connect(addr, timeout),
+        };
+        Box::new(connection.map(move |(lines, preamble)| BabelClient {
+            addr,
+            timeout,
+            lines: Some(lines),
+            preamble: Some(preamble),
+        }))
+    }
+
+    /// Sends all the commands without waiting on each reply, the replies come back in the same
+    /// order. If a connection that was already open fails it's reopened and all the commands
+    /// are sent again, so they should be ones that are safe to repeat
+    pub fn commands(
+        self,
+        commands: Vec<String>,
+    ) -> Box<Future<Item = (BabelClient, Vec<Reply>), Error = Error>> {
+        let BabelClient {
+            addr,
+            timeout,
+            lines,
+            preamble,
+        } = self;
+        let replies = match (lines, preamble) {
+            (Some(lines), Some(preamble)) => {
+                let retry = commands.clone();
+                Box::new(
+                    exchange(lines, commands, timeout)
+                        .map(move |(lines, replies)| (lines, preamble, replies))
+                        .or_else(move |e| {
+                            warn!("Babel connection failed with {:?}, reconnecting", e);
+                            reconnect_exchange(addr, retry, timeout)
+                        }),
+                )
+                    as Box<Future<Item = (Lines, Preamble, Vec<Reply>), Error = Error>>
+            }
+            _ => reconnect_exchange(addr, commands, timeout),
+        };
+        Box::new(replies.map(move |(lines, preamble, replies)| {
+            let client = BabelClient {
+                addr,
+                timeout,
+                lines: Some(lines),
+                preamble: Some(preamble),
+            };
+            (client, replies)
+        }))
+    }
+
+    pub fn command(self, cmd: &str) -> Box<Future<Item = (BabelClient, String), Error = Error>> {
+        let cmd = cmd.to_string();
+        Box::new(
+            self.commands(vec![cmd.clone()])
+                .and_then(move |(client, mut replies)| match replies.pop() {
+                    Some(Ok(output)) => Ok((client, output)),
+                    Some(Err(e)) => Err(CommandFailed(cmd, e.to_string()).into()),
+                    None => Err(CommandFailed(cmd, "No reply".to_string()).into()),
+                }),
+        )
+    }
+
+    fn set(self, cmd: String) -> Box<Future<Item = BabelClient, Error = Error>> {
+        Box::new(self.command(&cmd).map(|(client, _)| client))
+    }
+
+    /// Sends the command once the preamble says babeld supports `cap`
+    fn set_with(
+        self,
+        cap: Capability,
+        cmd: String,
+    ) -> Box<Future<Item = BabelClient, Error = Error>> {
+        Box::new(self.connect().and_then(move |client| {
+            match super::require(client.preamble(), cap) {
+                Ok(()) => client.set(cmd),
+                Err(e) => Box::new(future::err(e)),
+            }
+        }))
+    }
+
+    pub fn dump(self) -> Box<Future<Item = (BabelClient, BabelDump), Error = Error>> {
+        Box::new(
+            self.command("dump")
+                .map(|(client, output)| (client, BabelDump::parse(&output))),
+        )
+    }
+
+    pub fn set_local_fee(self, new_fee: u32) -> Box<Future<Item = BabelClient, Error = Error>> {
+        self.set_with(Capability::Price, format!("fee {}", new_fee))
+    }
+
+    pub fn set_metric_factor(
+        self,
+        new_factor: u32,
+    ) -> Box<Future<Item = BabelClient, Error = Error>> {
+        self.set_with(
+            Capability::MetricFactor,
+            format!("metric-factor {}", new_factor),
+        )
+    }
+
+    pub fn monitor(self, iface: &str) -> Box<Future<Item = BabelClient, Error = Error>> {
+        let iface = iface.to_string();
+        Box::new(self.connect().and_then(move |client| {
+            let command = super::monitor_command(&iface, client.preamble());
+            client.set(command)
+        }))
+    }
+
+    pub fn unmonitor(self, iface: &str) -> Box<Future<Item = BabelClient, Error = Error>> {
+        self.set(format!("flush interface {}", iface))
+    }
+
+    /// Changes babeld's settings for an interface it's monitoring
+    pub fn tune(
+        self,
+        iface: &str,
+        tuning: &InterfaceTuning,
+    ) -> Box<Future<Item = BabelClient, Error = Error>> {
+        if let Err(e) = tuning.validate() {
+            return Box::new(future::err(e));
+        }
+        match tuning.command(iface) {
+            Some(command) => self.set(command),
+            None => Box::new(future::ok(self)),
+        }
+    }
+
+    pub fn redistribute_ip(
+        self,
+        ip: &IpAddr,
+        allow: bool,
+    ) -> Box<Future<Item = BabelClient, Error = Error>> {
+        self.set(format!(
+            "redistribute ip {}/128 {}",
+            ip,
+            if allow { "allow" } else { "deny" }
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio::runtime::current_thread::Runtime;
+
+    static PREAMBLE: &'static str =
+        "ALTHEA 0.1\nversion babeld-1.8.0-24-g6335378\nhost raspberrypi\nmy-id \
+         ba:27:eb:ff:fe:09:06:dd\nok\n";
+
+    /// Accepts a connection per script, sends the preamble and answers each command it reads
+    /// with the next reply. A None reply hangs up
+    fn fake_babel(scripts: Vec<Vec<Option<&'static str>>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for script in scripts {
+                let (mut stream, _) = listener.accept().unwrap();
+                stream.write_all(PREAMBLE.as_bytes()).unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                for reply in script {
+                    let mut command = String::new();
+                    reader.read_line(&mut command).unwrap();
+                    match reply {
+                        Some(reply) => stream.write_all(reply.as_bytes()).unwrap(),
+                        None => break,
+                    }
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_pipelined_commands() {
+        let addr = fake_babel(vec![vec![
+            Some("ok\n"),
+            Some("no\n"),
+            Some("local fee 1024\nok\n"),
+        ]]);
+        let client = BabelClient::new(addr, Duration::from_secs(5));
+        let commands = vec![
+            "fee 1024".to_string(),
+            "bogus".to_string(),
+            "dump".to_string(),
+        ];
+        let (client, replies) = Runtime::new()
+            .unwrap()
+            .block_on(client.commands(commands))
+            .unwrap();
+        assert!(client.is_connected());
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0].as_ref().unwrap(), "ok\n");
+        assert!(replies[1].is_err());
+        assert_eq!(replies[2].as_ref().unwrap(), "local fee 1024\nok\n");
+    }
+
+    #[test]
+    fn test_reconnect() {
+        // babeld restarts after the first command
+        let addr = fake_babel(vec![vec![Some("ok\n"), None], vec![Some("ok\n")]]);
+        let mut runtime = Runtime::new().unwrap();
+        let client = BabelClient::new(addr, Duration::from_secs(5));
+        let client = runtime.block_on(client.set_local_fee(10)).unwrap();
+        let client = runtime.block_on(client.set_local_fee(20)).unwrap();
+        assert!(client.is_connected());
+    }
+
+    #[test]
+    fn test_timeout() {
+        // a babeld that's stuck after sending its preamble
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(PREAMBLE.as_bytes()).unwrap();
+            thread::sleep(Duration::from_secs(5));
+        });
+
+        let client = BabelClient::new(addr, Duration::from_millis(200));
+        let res = Runtime::new().unwrap().block_on(client.dump());
+        match res {
+            Err(e) => assert!(e.to_string().contains("Timed out")),
+            Ok(_) => panic!("Expected a timeout"),
+        }
+    }
+}
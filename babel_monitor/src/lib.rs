@@ -1,10 +1,16 @@
 extern crate bufstream;
 #[macro_use]
 extern crate failure;
+extern crate futures;
 extern crate ipnetwork;
 #[macro_use]
 extern crate log;
 extern crate mockstream;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate tokio;
+extern crate tokio_codec;
 
 use std::collections::VecDeque;
 use std::io::{BufRead, Read, Write};
@@ -16,12 +22,30 @@ use bufstream::BufStream;
 use failure::Error;
 use ipnetwork::IpNetwork;
 
+mod client;
+mod parser;
+mod preamble;
+mod table;
+mod tuning;
+
+pub use client::BabelClient;
+pub use parser::{parse_line, BabelDump, BabelLine, Interface, Neighbor, Route, Verb, XRoute};
+pub use preamble::{Capability, Preamble};
+pub use table::BabelTable;
+pub use tuning::{Channel, InterfaceTuning, LinkType};
+
 #[derive(Debug, Fail)]
 pub enum BabelMonitorError {
-    #[fail(display = "variable '{}' not found in '{}'", _0, _1)]
-    VariableNotFound(String, String),
+    #[fail(display = "Could not parse '{}': {}", _0, _1)]
+    ParseFailed(String, String),
     #[fail(display = "Invalid preamble: {}", _0)]
     InvalidPreamble(String),
+    #[fail(display = "Unsupported babel protocol version {}.{}", _0, _1)]
+    UnsupportedProtocol(u32, u32),
+    #[fail(display = "Babel doesn't support {}", _0)]
+    Unsupported(Capability),
+    #[fail(display = "Invalid interface setting, {}", _0)]
+    InvalidTuning(String),
     #[fail(display = "Could not find local fee in '{}'", _0)]
     LocalFeeNotFound(String),
     #[fail(display = "Command '{}' failed. {}", _0, _1)]
@@ -32,80 +56,74 @@ pub enum BabelMonitorError {
     NoTerminator(String),
     #[fail(display = "No Neighbor was found matching address:\n{}", _0)]
     NoNeighbor(String),
+    #[fail(display = "Timed out waiting for Babel")]
+    TimedOut,
+    #[fail(display = "Babel closed the connection")]
+    ConnectionClosed,
 }
 
 use BabelMonitorError::*;
 
-// If a function doesn't need internal state of the Babel object
-// we don't want to place it as a member function.
-fn find_babel_val(val: &str, line: &str) -> Result<String, Error> {
-    let mut iter = line.split(" ");
-    while let Some(entry) = iter.next() {
-        if entry.to_string().contains(val) {
-            match iter.next() {
-                Some(v) => return Ok(v.to_string()),
-                None => continue,
-            }
+/// The command that starts monitoring an interface, with rtt timestamps if babeld has them
+fn monitor_command(iface: &str, preamble: Option<&Preamble>) -> String {
+    match preamble {
+        Some(preamble) if !preamble.supports(Capability::RttTimestamps) => {
+            format!("interface {}", iface)
         }
+        _ => format!("interface {} enable-timestamps true", iface),
     }
-    warn!("find_babel_val warn! Can not find {} in {}", val, line);
-    return Err(VariableNotFound(String::from(val), String::from(line)).into());
-}
-
-#[derive(Debug, Clone)]
-pub struct Route {
-    pub id: String,
-    pub iface: String,
-    pub xroute: bool,
-    pub installed: bool,
-    pub neigh_ip: IpAddr,
-    pub prefix: IpNetwork,
-    pub metric: u16,
-    pub refmetric: u16,
-    pub full_path_rtt: f32,
-    pub price: u32,
-    pub fee: u32,
 }
 
-#[derive(Debug, Clone)]
-pub struct Neighbor {
-    pub id: String,
-    pub address: IpAddr,
-    pub iface: String,
-    pub reach: u16,
-    pub txcost: u16,
-    pub rxcost: u16,
-    pub rtt: f32,
-    pub rttcost: u16,
-    pub cost: u16,
+/// Only fails once the preamble has been read and it lacks `cap`, babeld itself refuses
+/// commands it doesn't know before that
+fn require(preamble: Option<&Preamble>, cap: Capability) -> Result<(), Error> {
+    match preamble {
+        Some(preamble) => preamble.require(cap),
+        None => Ok(()),
+    }
 }
 
 pub struct Babel<T: Read + Write> {
     stream: BufStream<T>,
+    preamble: Option<Preamble>,
 }
 
 impl<T: Read + Write> Babel<T> {
     pub fn new(stream: T) -> Babel<T> {
         Babel {
             stream: BufStream::new(stream),
+            preamble: None,
         }
     }
 
+    /// The preamble read by `start_connection`
+    pub fn preamble(&self) -> Option<&Preamble> {
+        self.preamble.as_ref()
+    }
+
+    /// False until `start_connection` has read the preamble
+    pub fn supports(&self, cap: Capability) -> bool {
+        self.preamble
+            .as_ref()
+            .map(|preamble| preamble.supports(cap))
+            .unwrap_or(false)
+    }
+
     fn read_babel(&mut self) -> Result<String, Error> {
         let mut ret = String::new();
         for line in Read::by_ref(&mut self.stream).lines() {
             let line = &line?;
             ret.push_str(line);
             ret.push_str("\n");
-            match line.as_str().trim() {
-                "ok" => {
+            match parse_line(line) {
+                Ok(BabelLine::Ok) => {
                     trace!(
                         "Babel returned ok; full output:\n{}\nEND OF BABEL OUTPUT",
                         ret
                     );
                     return Ok(ret);
                 }
-                "bad" | "no" => {
+                Ok(BabelLine::Bad(_)) | Ok(BabelLine::No(_)) => {
                     warn!(
                         "Babel returned bad/no; full output:\n{}\nEND OF BABEL OUTPUT",
                         ret
@@ -136,48 +154,55 @@ impl<T: Read + Write> Babel<T> {
     // Consumes the automated Preamble and validates configuration api version
     pub fn start_connection(&mut self) -> Result<(), Error> {
         let preamble = self.read_babel()?;
-        // Note you have changed the config interface, bump to 1.1 in babel
-        if preamble.contains("ALTHEA 0.1") {
-            info!("Attached OK to Babel with preamble: {}", preamble);
-            return Ok(());
-        } else {
-            return Err(InvalidPreamble(preamble).into());
-        }
+        let parsed = Preamble::parse(&preamble)?;
+        info!(
+            "Attached OK to Babel with preamble: {}capabilities {:?}",
+            preamble,
+            parsed.capabilities()
+        );
+        self.preamble = Some(parsed);
+        Ok(())
     }
 
     pub fn get_local_fee(&mut self) -> Result<u32, Error> {
         let babel_output = self.command("dump")?;
-        let fee_entry = match babel_output.split("\n").nth(0) {
-            Some(entry) => entry,
-            // Even an empty string wouldn't yield None
-            None => return Err(LocalFeeNotFound(String::from("<Babel output is None>")).into()),
-        };
-
-        if fee_entry.contains("local fee") {
-            let fee = find_babel_val("fee", fee_entry)?.parse()?;
-            trace!("Retrieved a local fee of {}", fee);
-            return Ok(fee);
+        match BabelDump::parse(&babel_output).local_fee {
+            Some(fee) => {
+                trace!("Retrieved a local fee of {}", fee);
+                Ok(fee)
+            }
+            None => Err(LocalFeeNotFound(babel_output).into()),
         }
-
-        Err(LocalFeeNotFound(String::from(fee_entry)).into())
     }
 
     pub fn set_local_fee(&mut self, new_fee: u32) -> Result<(), Error> {
+        require(self.preamble(), Capability::Price)?;
         let _babel_output = self.command(&format!("fee {}", new_fee))?;
         Ok(())
     }
 
     pub fn set_metric_factor(&mut self, new_factor: u32) -> Result<(), Error> {
+        require(self.preamble(), Capability::MetricFactor)?;
         let _babel_output = self.command(&format!("metric-factor {}", new_factor))?;
         Ok(())
     }
 
     pub fn monitor(&mut self, iface: &str) -> Result<(), Error> {
-        let _ = self.command(&format!("interface {} enable-timestamps true", iface))?;
+        let command = monitor_command(iface, self.preamble());
+        let _ = self.command(&command)?;
         info!("Babel started monitoring: {}", iface);
         Ok(())
     }
 
+    /// Changes babeld's settings for an interface it's monitoring
+    pub fn tune(&mut self, iface: &str, tuning: &InterfaceTuning) -> Result<(), Error> {
+        tuning.validate()?;
+        if let Some(command) = tuning.command(iface) {
+            self.command(&command)?;
+        }
+        Ok(())
+    }
+
     pub fn redistribute_ip(&mut self, ip: &IpAddr, allow: bool) -> Result<(), Error> {
         let commmand = format!(
             "redistribute ip {}/128 {}",
@@ -194,254 +219,107 @@ impl<T: Read + Write> Babel<T> {
         Ok(())
     }
 
-    pub fn parse_neighs(&mut self) -> Result<VecDeque<Neighbor>, Error> {
-        let mut vector: VecDeque<Neighbor> = VecDeque::with_capacity(5);
-        let mut found_neigh = false;
-        for entry in self.command("dump")?.split("\n") {
-            if entry.contains("add neighbour") {
-                found_neigh = true;
-                let neigh = Neighbor {
-                    id: match find_babel_val("neighbour", entry) {
-                        Ok(val) => val,
-                        Err(_) => continue,
-                    },
-                    address: match find_babel_val("address", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing address for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    iface: match find_babel_val("if", entry) {
-                        Ok(val) => val,
-                        Err(_) => continue,
-                    },
-                    reach: match find_babel_val("reach", entry) {
-                        Ok(val) => match u16::from_str_radix(&val, 16) {
-                            Ok(val) => val,
-                            Err(e) => {
-                                warn!("Failed to convert reach {:?} {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    txcost: match find_babel_val("txcost", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing txcost for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    rxcost: match find_babel_val("rxcost", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing rxcost for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    rtt: match find_babel_val("rtt", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing rtt for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        // it's possible that our neigh does not have rtt enabled, handle
-                        Err(_) => 0.0,
-                    },
-                    rttcost: match find_babel_val("rttcost", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing rtt for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        // it's possible that our neigh does not have rtt enabled, handle
-                        Err(_) => 0,
-                    },
-                    cost: match find_babel_val("cost", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing cost for neigh {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                };
-                vector.push_back(neigh);
-            }
+    /// Everything babeld knows about, interfaces, neighbors, routes, xroutes and the local fee
+    pub fn dump(&mut self) -> Result<BabelDump, Error> {
+        let babel_out = self.command("dump")?;
+        trace!("Got from babel dump: {}", babel_out);
+        Ok(BabelDump::parse(&babel_out))
+    }
+
+    pub fn parse_interfaces(&mut self) -> Result<Vec<Interface>, Error> {
+        let dump = self.dump()?;
+        if dump.all_failed("interface", dump.interfaces.len()) {
+            bail!("All Babel interface parsing failed!")
         }
-        if vector.len() == 0 && found_neigh {
+        Ok(dump.interfaces)
+    }
+
+    pub fn parse_neighs(&mut self) -> Result<VecDeque<Neighbor>, Error> {
+        let dump = self.dump()?;
+        if dump.all_failed("neighbour", dump.neighbors.len()) {
             bail!("All Babel neigh parsing failed!")
         }
-        Ok(vector)
+        Ok(dump.neighbors.into_iter().collect())
     }
 
     pub fn parse_routes(&mut self) -> Result<VecDeque<Route>, Error> {
-        let mut vector: VecDeque<Route> = VecDeque::with_capacity(20);
-        let babel_out = self.command("dump")?;
-        let mut found_route = false;
-        trace!("Got from babel dump: {}", babel_out);
-
-        for entry in babel_out.split("\n") {
-            if entry.contains("add route") {
-                trace!("Parsing 'add route' entry: {}", entry);
-                found_route = true;
-                let route = Route {
-                    id: match find_babel_val("route", entry) {
-                        Ok(entry) => entry,
-                        Err(_) => continue,
-                    },
-                    iface: match find_babel_val("if", entry) {
-                        Ok(entry) => entry,
-                        Err(_) => continue,
-                    },
-                    xroute: false,
-                    installed: match find_babel_val("installed", entry) {
-                        Ok(entry) => entry.contains("yes"),
-                        Err(_) => continue,
-                    },
-                    neigh_ip: match find_babel_val("via", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing neigh_ip for route {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    prefix: match find_babel_val("prefix", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing prefix for route {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    metric: match find_babel_val("metric", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing metric for route {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    refmetric: match find_babel_val("refmetric", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing refmetric {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    full_path_rtt: match find_babel_val("full-path-rtt", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing full_path_rtt {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    price: match find_babel_val("price", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing price {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                    fee: match find_babel_val("fee", entry) {
-                        Ok(entry) => match entry.parse() {
-                            Ok(parsed_data) => parsed_data,
-                            Err(e) => {
-                                warn!("Error parsing fee {:?} from {}", e, entry);
-                                continue;
-                            }
-                        },
-                        Err(_) => continue,
-                    },
-                };
-
-                vector.push_back(route);
-            }
-        }
-        if vector.len() == 0 && found_route {
+        let dump = self.dump()?;
+        if dump.all_failed("route", dump.routes.len()) {
             bail!("All Babel route parsing failed!")
         }
-        Ok(vector)
+        Ok(dump.routes.into_iter().collect())
+    }
+
+    /// The routes this node originates
+    pub fn parse_xroutes(&mut self) -> Result<Vec<XRoute>, Error> {
+        let dump = self.dump()?;
+        if dump.all_failed("xroute", dump.xroutes.len()) {
+            bail!("All Babel xroute parsing failed!")
+        }
+        Ok(dump.xroutes)
     }
 
-    /// In this function we take a route snapshot then loop over the routes list twice
-    /// to find the neighbor local address and then the route to the destination
-    /// via that neighbor. This could be dramatically more efficient if we had the neighbors
-    /// local ip lying around somewhere.
+    /// See the free function of the same name
     pub fn get_route_via_neigh(
         &mut self,
         neigh_mesh_ip: IpAddr,
         dest_mesh_ip: IpAddr,
         routes: &VecDeque<Route>,
     ) -> Result<Route, Error> {
-        // First find the neighbors route to itself to get the local address
-        for neigh_route in routes.iter() {
-            // This will fail on v4 babel routes etc
-            if let IpNetwork::V6(ref ip) = neigh_route.prefix {
-                if ip.ip() == neigh_mesh_ip {
-                    let neigh_local_ip = neigh_route.neigh_ip;
-                    // Now we take the neigh_local_ip and search for a route via that
-                    for route in routes.iter() {
-                        if let IpNetwork::V6(ref ip) = route.prefix {
-                            if ip.ip() == dest_mesh_ip && route.neigh_ip == neigh_local_ip {
-                                return Ok(route.clone());
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        Err(NoNeighbor(neigh_mesh_ip.to_string()).into())
+        get_route_via_neigh(neigh_mesh_ip, dest_mesh_ip, routes)
     }
 
-    /// Checks if Babel has an installed route to the given destination
+    /// See the free function of the same name
     pub fn do_we_have_route(
         &mut self,
         mesh_ip: &IpAddr,
         routes: &VecDeque<Route>,
     ) -> Result<bool, Error> {
-        for route in routes.iter() {
-            if let IpNetwork::V6(ref ip) = route.prefix {
-                if ip.ip() == *mesh_ip && route.installed {
-                    return Ok(true);
+        Ok(do_we_have_route(mesh_ip, routes))
+    }
+}
+
+/// In this function we take a route snapshot then loop over the routes list twice
+/// to find the neighbor local address and then the route to the destination
+/// via that neighbor. This could be dramatically more efficient if we had the neighbors
+/// local ip lying around somewhere.
+pub fn get_route_via_neigh(
+    neigh_mesh_ip: IpAddr,
+    dest_mesh_ip: IpAddr,
+    routes: &VecDeque<Route>,
+) -> Result<Route, Error> {
+    // First find the neighbors route to itself to get the local address
+    for neigh_route in routes.iter() {
+        // This will fail on v4 babel routes etc
+        if let IpNetwork::V6(ref ip) = neigh_route.prefix {
+            if ip.ip() == neigh_mesh_ip {
+                let neigh_local_ip = neigh_route.neigh_ip;
+                // Now we take the neigh_local_ip and search for a route via that
+                for route in routes.iter() {
+                    if let IpNetwork::V6(ref ip) = route.prefix {
+                        if ip.ip() == dest_mesh_ip && route.neigh_ip == neigh_local_ip {
+                            return Ok(route.clone());
+                        }
+                    }
                 }
             }
         }
-        Ok(false)
     }
+    Err(NoNeighbor(neigh_mesh_ip.to_string()).into())
+}
+
+/// Checks if Babel has an installed route to the given destination
+pub fn do_we_have_route(mesh_ip: &IpAddr, routes: &VecDeque<Route>) -> bool {
+    for route in routes.iter() {
+        if let IpNetwork::V6(ref ip) = route.prefix {
+            if ip.ip() == *mesh_ip && route.installed {
+                return true;
+            }
+        }
+    }
+    false
 }
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -460,13 +338,13 @@ rtt 18.674 rttcost 473 cost 817\n\
 add neighbour 14f0488 address fe80::e914:2335:a76:bda3 if wlan0 reach feff rxcost 258 txcost 256 \
 rtt 22.805 rttcost 698 cost 956\n\
 add xroute 10.28.119.131/32-::/0 prefix 10.28.119.131/32 from ::/0 metric 0\n\
-add route 14f0820 prefix 10.28.7.7/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:5b:fe:c7\
+add route 14f0820 prefix 10.28.7.7/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:5b:fe:c7 \
 metric 1596 price 3072 fee 3072 refmetric 638 full-path-rtt 22.805 via fe80::e914:2335:a76:bda3 if wlan0\n\
-add route 14f07a0 prefix 10.28.7.7/32 from 0.0.0.0/0 installed no id ba:27:eb:ff:fe:5b:fe:c7\
+add route 14f07a0 prefix 10.28.7.7/32 from 0.0.0.0/0 installed no id ba:27:eb:ff:fe:5b:fe:c7 \
 metric 1569 price 5032 fee 5032 refmetric 752 full-path-rtt 42.805 via fe80::e9d0:498f:6c61:be29 if wlan0\n\
-add route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:c1:2d:d5\
+add route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:c1:2d:d5 \
 metric 817 price 4008 fee 4008 refmetric 0 full-path-rtt 18.674 via fe80::e9d0:498f:6c61:be29 if wlan0\n\
-add route 14f0548 prefix 10.28.244.138/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:d1:3e:ba\
+add route 14f0548 prefix 10.28.244.138/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:d1:3e:ba \
 metric 958 price 2048 fee 2048 refmetric 0 full-path-rtt 56.805 via fe80::e914:2335:a76:bda3 if wlan0\n\
 ok\n";
 
@@ -479,7 +357,7 @@ ok\n";
 
     static ROUTE_LINE: &'static str =
         "add route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed yes id \
-         ba:27:eb:ff:fe:c1:2d:d5 metric 1306 price 4008 refmetric 0 full-path-rtt 18.674 via \
+         ba:27:eb:ff:fe:c1:2d:d5 metric 1306 price 4008 fee 4008 refmetric 0 full-path-rtt 18.674 via \
          fe80::e9d0:498f:6c61:be29 if wlan0";
 
     static NEIGH_LINE: &'static str =
@@ -511,23 +389,59 @@ ok\n";
 
     #[test]
     fn line_parse() {
-        assert_eq!(find_babel_val("metric", XROUTE_LINE).unwrap(), "0");
-        assert_eq!(
-            find_babel_val("prefix", XROUTE_LINE).unwrap(),
-            "10.28.119.131/32"
-        );
-        assert_eq!(find_babel_val("route", ROUTE_LINE).unwrap(), "14f06d8");
-        assert_eq!(find_babel_val("if", ROUTE_LINE).unwrap(), "wlan0");
+        match parse_line(XROUTE_LINE).unwrap() {
+            BabelLine::XRoute(Verb::Add, xroute) => {
+                assert_eq!(xroute.metric, 0);
+                assert_eq!(xroute.prefix, "10.28.119.131/32".parse().unwrap());
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        match parse_line(ROUTE_LINE).unwrap() {
+            BabelLine::Route(Verb::Add, route) => {
+                assert_eq!(route.id, "14f06d8");
+                assert_eq!(route.iface, "wlan0");
+                assert_eq!(
+                    route.neigh_ip,
+                    "fe80::e9d0:498f:6c61:be29".parse::<IpAddr>().unwrap()
+                );
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        match parse_line(NEIGH_LINE).unwrap() {
+            BabelLine::Neighbor(Verb::Add, neigh) => {
+                assert_eq!(neigh.reach, 0xffff);
+                assert_eq!(neigh.rxcost, 256);
+                assert_eq!(neigh.rtt, 29.264);
+                assert_eq!(neigh.cost, 1306);
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        match parse_line(IFACE_LINE).unwrap() {
+            BabelLine::Interface(Verb::Add, iface) => {
+                assert_eq!(iface.name, "wlan0");
+                assert_eq!(iface.ipv4, Some("10.28.119.131".parse().unwrap()));
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
         assert_eq!(
-            find_babel_val("via", ROUTE_LINE).unwrap(),
-            "fe80::e9d0:498f:6c61:be29"
+            parse_line(PRICE_LINE).unwrap(),
+            BabelLine::Local("price".to_string(), "1024".to_string())
         );
-        assert_eq!(find_babel_val("reach", NEIGH_LINE).unwrap(), "ffff");
-        assert_eq!(find_babel_val("rxcost", NEIGH_LINE).unwrap(), "256");
-        assert_eq!(find_babel_val("rtt", NEIGH_LINE).unwrap(), "29.264");
-        assert_eq!(find_babel_val("interface", IFACE_LINE).unwrap(), "wlan0");
-        assert_eq!(find_babel_val("ipv4", IFACE_LINE).unwrap(), "10.28.119.131");
-        assert_eq!(find_babel_val("price", PRICE_LINE).unwrap(), "1024");
+    }
+
+    #[test]
+    fn dump_parse() {
+        let mut s = SharedMockStream::new();
+        s.push_bytes_to_read(TABLE.as_bytes());
+        let mut b = Babel::new(s);
+
+        let dump = b.dump().unwrap();
+        assert_eq!(dump.local_fee, Some(1024));
+        assert_eq!(dump.interfaces.len(), 2);
+        assert_eq!(dump.neighbors.len(), 4);
+        assert_eq!(dump.xroutes.len(), 1);
+        assert_eq!(dump.routes.len(), 4);
+        assert!(dump.failed.is_empty());
     }
 
     #[test]
@@ -593,4 +507,45 @@ ok\n";
         let mut b = Babel::new(s);
         b.command("interface wg0").unwrap();
     }
+
+    #[test]
+    fn mock_capabilities() {
+        let mut s = SharedMockStream::new();
+        s.push_bytes_to_read(b"ALTHEA 0.2\ncapabilities price\nok\n");
+        s.push_bytes_to_read(b"ok\n");
+
+        let mut b = Babel::new(s.clone());
+        assert!(!b.supports(Capability::Price));
+        b.start_connection().unwrap();
+        assert!(b.supports(Capability::Price));
+
+        // refused without bothering babeld
+        let e = b.set_metric_factor(1900).unwrap_err();
+        assert_eq!(e.to_string(), "Babel doesn't support metric-factor");
+        assert!(s.pop_bytes_written().is_empty());
+
+        b.monitor("wg0").unwrap();
+        assert_eq!(s.pop_bytes_written(), b"interface wg0\n".to_vec());
+    }
+
+    #[test]
+    fn mock_tune() {
+        let mut s = SharedMockStream::new();
+        s.push_bytes_to_read(b"ok\n");
+
+        let mut b = Babel::new(s.clone());
+        b.tune("wg0", &InterfaceTuning::default()).unwrap();
+        assert!(s.pop_bytes_written().is_empty());
+
+        let tuning = InterfaceTuning {
+            link_cost: Some(2048),
+            link_type: Some(LinkType::Tunnel),
+            ..InterfaceTuning::default()
+        };
+        b.tune("wg0", &tuning).unwrap();
+        assert_eq!(
+            s.pop_bytes_written(),
+            b"interface wg0 rxcost 2048 type tunnel\n".to_vec()
+        );
+    }
 }
@@ -0,0 +1,498 @@
+//! A tokenizer and typed model for the lines babeld sends over its config protocol, both in
+//! `dump` output and in monitor mode.
+//!
+//! Apart from `local` lines, the preamble and the `ok`, `no` and `bad` replies every line is
+//! `<verb> <object> <id>` followed by key value pairs. Keys are matched exactly and keys we
+//! don't know are skipped, so a babeld that reports more than we expect still parses.
+
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use failure::Error;
+use ipnetwork::IpNetwork;
+
+use BabelMonitorError::ParseFailed;
+
+/// What happened to the object a line describes, a dump only has `Add`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verb {
+    Add,
+    Change,
+    Flush,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interface {
+    pub name: String,
+    pub up: bool,
+    pub ipv6: Option<IpAddr>,
+    pub ipv4: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Neighbor {
+    pub id: String,
+    pub address: IpAddr,
+    pub iface: String,
+    pub reach: u16,
+    pub txcost: u16,
+    pub rxcost: u16,
+    /// Zero if the neighbor doesn't have timestamps enabled
+    pub rtt: f32,
+    pub rttcost: u16,
+    pub cost: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub id: String,
+    pub iface: String,
+    pub installed: bool,
+    pub neigh_ip: IpAddr,
+    pub prefix: IpNetwork,
+    pub metric: u16,
+    pub refmetric: u16,
+    pub full_path_rtt: f32,
+    /// Zero if babeld doesn't support prices
+    pub price: u32,
+    pub fee: u32,
+}
+
+/// A route we originate and redistribute to our neighbors
+#[derive(Debug, Clone, PartialEq)]
+pub struct XRoute {
+    pub id: String,
+    pub prefix: IpNetwork,
+    pub from: IpNetwork,
+    pub metric: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BabelLine {
+    LocalFee(u32),
+    /// Any other `local` line, the key and the rest of the line
+    Local(String, String),
+    Interface(Verb, Interface),
+    Neighbor(Verb, Neighbor),
+    Route(Verb, Route),
+    XRoute(Verb, XRoute),
+    Ok,
+    /// The command failed, babeld may say why
+    No(Option<String>),
+    /// The command wasn't understood
+    Bad(Option<String>),
+    /// Lines with nothing for us to parse, like the preamble's version and host
+    Other(String),
+}
+
+impl BabelLine {
+    /// Whether this line ends the reply to a command
+    pub fn is_terminator(&self) -> bool {
+        match *self {
+            BabelLine::Ok | BabelLine::No(_) | BabelLine::Bad(_) => true,
+            _ => false,
+        }
+    }
+}
+
+fn parse_error(line: &str, reason: String) -> Error {
+    ParseFailed(line.to_string(), reason).into()
+}
+
+/// The key value pairs that follow a line's id
+struct Fields<'a> {
+    line: &'a str,
+    pairs: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(line: &'a str, tokens: &[&'a str]) -> Result<Fields<'a>, Error> {
+        if tokens.len() % 2 != 0 {
+            let key = tokens[tokens.len() - 1];
+            return Err(parse_error(line, format!("no value for '{}'", key)));
+        }
+        Ok(Fields {
+            line,
+            pairs: tokens.chunks(2).map(|pair| (pair[0], pair[1])).collect(),
+        })
+    }
+
+    /// The value of a key, the last one wins if babeld repeats it
+    fn get(&self, key: &str) -> Option<&'a str> {
+        self.pairs
+            .iter()
+            .rev()
+            .find(|pair| pair.0 == key)
+            .map(|pair| pair.1)
+    }
+
+    fn parse<T>(&self, key: &str, value: &str) -> Result<T, Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        value.parse().map_err(|e| {
+            parse_error(
+                self.line,
+                format!("invalid value '{}' for '{}': {}", value, key, e),
+            )
+        })
+    }
+
+    fn optional<T>(&self, key: &str) -> Result<Option<T>, Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.get(key) {
+            Some(value) => Ok(Some(self.parse(key, value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn required<T>(&self, key: &str) -> Result<T, Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.optional(key)? {
+            Some(value) => Ok(value),
+            None => Err(parse_error(self.line, format!("missing '{}'", key))),
+        }
+    }
+
+    /// babeld says both yes/no and true/false
+    fn flag(&self, key: &str) -> Result<Option<bool>, Error> {
+        match self.get(key) {
+            Some("yes") | Some("true") => Ok(Some(true)),
+            Some("no") | Some("false") => Ok(Some(false)),
+            Some(value) => Err(parse_error(
+                self.line,
+                format!("invalid value '{}' for '{}'", value, key),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Reach is the bitmap of recent hellos printed in hex
+    fn hex(&self, key: &str) -> Result<u16, Error> {
+        match self.get(key) {
+            Some(value) => u16::from_str_radix(value, 16).map_err(|e| {
+                parse_error(
+                    self.line,
+                    format!("invalid value '{}' for '{}': {}", value, key, e),
+                )
+            }),
+            None => Err(parse_error(self.line, format!("missing '{}'", key))),
+        }
+    }
+}
+
+fn parse_interface(name: &str, fields: &Fields) -> Result<Interface, Error> {
+    Ok(Interface {
+        name: name.to_string(),
+        // a flushed interface is sent without any fields
+        up: fields.flag("up")?.unwrap_or(false),
+        ipv6: fields.optional("ipv6")?,
+        ipv4: fields.optional("ipv4")?,
+    })
+}
+
+fn parse_neighbor(id: &str, fields: &Fields) -> Result<Neighbor, Error> {
+    Ok(Neighbor {
+        id: id.to_string(),
+        address: fields.required("address")?,
+        iface: fields.required("if")?,
+        reach: fields.hex("reach")?,
+        txcost: fields.required("txcost")?,
+        rxcost: fields.required("rxcost")?,
+        rtt: fields.optional("rtt")?.unwrap_or(0.0),
+        rttcost: fields.optional("rttcost")?.unwrap_or(0),
+        cost: fields.required("cost")?,
+    })
+}
+
+fn parse_route(id: &str, fields: &Fields) -> Result<Route, Error> {
+    Ok(Route {
+        id: id.to_string(),
+        iface: fields.required("if")?,
+        installed: match fields.flag("installed")? {
+            Some(installed) => installed,
+            None => return Err(parse_error(fields.line, "missing 'installed'".to_string())),
+        },
+        neigh_ip: fields.required("via")?,
+        prefix: fields.required("prefix")?,
+        metric: fields.required("metric")?,
+        refmetric: fields.required("refmetric")?,
+        full_path_rtt: fields.required("full-path-rtt")?,
+        price: fields.optional("price")?.unwrap_or(0),
+        fee: fields.optional("fee")?.unwrap_or(0),
+    })
+}
+
+fn parse_xroute(id: &str, fields: &Fields) -> Result<XRoute, Error> {
+    Ok(XRoute {
+        id: id.to_string(),
+        prefix: fields.required("prefix")?,
+        from: fields.required("from")?,
+        metric: fields.required("metric")?,
+    })
+}
+
+/// The rest of a reply line, None if there isn't any
+fn reply_message(tokens: &[&str]) -> Option<String> {
+    match tokens.len() {
+        0 => None,
+        _ => Some(tokens.join(" ")),
+    }
+}
+
+/// Parses a single line, an error means the line was meant for us but is malformed
+pub fn parse_line(line: &str) -> Result<BabelLine, Error> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    if tokens.is_empty() {
+        return Ok(BabelLine::Other(String::new()));
+    }
+
+    let verb = match tokens[0] {
+        "ok" => return Ok(BabelLine::Ok),
+        "no" => return Ok(BabelLine::No(reply_message(&tokens[1..]))),
+        "bad" => return Ok(BabelLine::Bad(reply_message(&tokens[1..]))),
+        "local" => {
+            return match (tokens.get(1), tokens.get(2)) {
+                (Some(&"fee"), Some(fee)) => match fee.parse() {
+                    Ok(fee) => Ok(BabelLine::LocalFee(fee)),
+                    Err(e) => Err(parse_error(
+                        line,
+                        format!("invalid value '{}' for 'fee': {}", fee, e),
+                    )),
+                },
+                (Some(key), _) => Ok(BabelLine::Local(key.to_string(), tokens[2..].join(" "))),
+                (None, _) => Err(parse_error(line, "nothing after 'local'".to_string())),
+            }
+        }
+        "add" => Verb::Add,
+        "change" => Verb::Change,
+        "flush" => Verb::Flush,
+        _ => return Ok(BabelLine::Other(line.to_string())),
+    };
+
+    let object = match tokens.get(1) {
+        Some(object) => *object,
+        None => return Err(parse_error(line, "missing object".to_string())),
+    };
+    let id = match tokens.get(2) {
+        Some(id) => *id,
+        None => return Err(parse_error(line, format!("missing {} id", object))),
+    };
+    let fields = Fields::new(line, &tokens[3..])?;
+
+    Ok(match object {
+        "interface" => BabelLine::Interface(verb, parse_interface(id, &fields)?),
+        "neighbour" => BabelLine::Neighbor(verb, parse_neighbor(id, &fields)?),
+        "route" => BabelLine::Route(verb, parse_route(id, &fields)?),
+        "xroute" => BabelLine::XRoute(verb, parse_xroute(id, &fields)?),
+        _ => BabelLine::Other(line.to_string()),
+    })
+}
+
+/// Everything in the output of `dump`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BabelDump {
+    pub local_fee: Option<u32>,
+    pub interfaces: Vec<Interface>,
+    pub neighbors: Vec<Neighbor>,
+    pub routes: Vec<Route>,
+    pub xroutes: Vec<XRoute>,
+    /// The object type of every line that failed to parse, a bad line is logged and skipped so
+    /// it doesn't take the rest of the dump with it
+    pub failed: Vec<String>,
+}
+
+impl BabelDump {
+    pub fn parse(output: &str) -> BabelDump {
+        let mut dump = BabelDump::default();
+        for line in output.lines() {
+            match parse_line(line) {
+                Ok(BabelLine::LocalFee(fee)) => dump.local_fee = Some(fee),
+                Ok(BabelLine::Interface(_, interface)) => dump.interfaces.push(interface),
+                Ok(BabelLine::Neighbor(_, neighbor)) => dump.neighbors.push(neighbor),
+                Ok(BabelLine::Route(_, route)) => dump.routes.push(route),
+                Ok(BabelLine::XRoute(_, xroute)) => dump.xroutes.push(xroute),
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Skipping babel line {:?}", e);
+                    let object = line.split_whitespace().nth(1).unwrap_or("");
+                    dump.failed.push(object.to_string());
+                }
+            }
+        }
+        dump
+    }
+
+    /// Whether lines for `object` were there but none of them could be parsed
+    pub fn all_failed(&self, object: &str, parsed: usize) -> bool {
+        parsed == 0 && self.failed.iter().any(|failed| failed == object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lines() {
+        assert_eq!(
+            parse_line(
+                "add interface wlan0 up true ipv6 fe80::1a8b:ec1:8542:1bd8 ipv4 10.28.119.131"
+            )
+            .unwrap(),
+            BabelLine::Interface(
+                Verb::Add,
+                Interface {
+                    name: "wlan0".to_string(),
+                    up: true,
+                    ipv6: Some("fe80::1a8b:ec1:8542:1bd8".parse().unwrap()),
+                    ipv4: Some("10.28.119.131".parse().unwrap()),
+                }
+            )
+        );
+        assert_eq!(
+            parse_line("flush interface wg3").unwrap(),
+            BabelLine::Interface(
+                Verb::Flush,
+                Interface {
+                    name: "wg3".to_string(),
+                    up: false,
+                    ipv6: None,
+                    ipv4: None,
+                }
+            )
+        );
+
+        // ureach is newer than us, rtt is missing without timestamps
+        assert_eq!(
+            parse_line(
+                "change neighbour 14f05f0 address fe80::e9d0:498f:6c61:be29 if wlan0 reach ffff \
+                 ureach 0000 rxcost 256 txcost 341 cost 1306"
+            )
+            .unwrap(),
+            BabelLine::Neighbor(
+                Verb::Change,
+                Neighbor {
+                    id: "14f05f0".to_string(),
+                    address: "fe80::e9d0:498f:6c61:be29".parse().unwrap(),
+                    iface: "wlan0".to_string(),
+                    reach: 0xffff,
+                    txcost: 341,
+                    rxcost: 256,
+                    rtt: 0.0,
+                    rttcost: 0,
+                    cost: 1306,
+                }
+            )
+        );
+
+        assert_eq!(
+            parse_line(
+                "add xroute 10.28.119.131/32-::/0 prefix 10.28.119.131/32 from ::/0 metric 0"
+            )
+            .unwrap(),
+            BabelLine::XRoute(
+                Verb::Add,
+                XRoute {
+                    id: "10.28.119.131/32-::/0".to_string(),
+                    prefix: "10.28.119.131/32".parse().unwrap(),
+                    from: "::/0".parse().unwrap(),
+                    metric: 0,
+                }
+            )
+        );
+
+        assert_eq!(
+            parse_line("local fee 1024").unwrap(),
+            BabelLine::LocalFee(1024)
+        );
+        assert_eq!(
+            parse_line("local price 1024").unwrap(),
+            BabelLine::Local("price".to_string(), "1024".to_string())
+        );
+        assert_eq!(parse_line("ok").unwrap(), BabelLine::Ok);
+        assert_eq!(parse_line("no").unwrap(), BabelLine::No(None));
+        assert_eq!(
+            parse_line("bad unknown command").unwrap(),
+            BabelLine::Bad(Some("unknown command".to_string()))
+        );
+        assert_eq!(
+            parse_line("host raspberrypi").unwrap(),
+            BabelLine::Other("host raspberrypi".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_route() {
+        let line = "add route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed yes id \
+                    ba:27:eb:ff:fe:c1:2d:d5 metric 1306 price 4008 fee 4008 refmetric 0 \
+                    full-path-rtt 18.674 via fe80::e9d0:498f:6c61:be29 if wlan0";
+        let route = match parse_line(line).unwrap() {
+            BabelLine::Route(Verb::Add, route) => route,
+            other => panic!("Unexpected {:?}", other),
+        };
+        assert_eq!(route.id, "14f06d8");
+        assert_eq!(route.iface, "wlan0");
+        assert!(route.installed);
+        assert_eq!(route.metric, 1306);
+        assert_eq!(route.refmetric, 0);
+        assert_eq!(route.price, 4008);
+        assert_eq!(route.full_path_rtt, 18.674);
+
+        let line = "add route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed no id \
+                    ba:27:eb:ff:fe:c1:2d:d5 metric 1306 refmetric 0 full-path-rtt 18.674 via \
+                    fe80::e9d0:498f:6c61:be29 if wlan0";
+        let route = match parse_line(line).unwrap() {
+            BabelLine::Route(Verb::Add, route) => route,
+            other => panic!("Unexpected {:?}", other),
+        };
+        assert!(!route.installed);
+        assert_eq!(route.price, 0);
+        assert_eq!(route.fee, 0);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let message = |line: &str| parse_line(line).unwrap_err().to_string();
+
+        // the old parser found "cost" inside "txcost" and "rttcost"
+        assert!(message("add neighbour 1 address fe80::1 if wg0 reach ffff rxcost 256 txcost 256 rtt 1.0 rttcost 10")
+            .ends_with("missing 'cost'"));
+        assert!(message(
+            "add neighbour 1 address fe80::1 if wg0 reach fffff rxcost 256 txcost 256 cost 1"
+        )
+        .contains("invalid value 'fffff' for 'reach'"));
+        assert!(
+            message("add route 1 prefix 10.0.0.1/32 installed maybe if wg0")
+                .contains("'installed'")
+        );
+        assert!(message("add interface wg0 up").ends_with("no value for 'up'"));
+        assert!(message("add route").ends_with("missing route id"));
+        assert!(message("local fee lots").contains("invalid value 'lots' for 'fee'"));
+    }
+
+    #[test]
+    fn test_parse_dump() {
+        let dump = BabelDump::parse(
+            "local fee 1024\n\
+             add interface wg0 up true ipv6 fe80::2cee:2fff:7380:8354\n\
+             add neighbour 14f19a8 address fe80::2cee:2fff:648:8796 if wg0 reach ffff rxcost 256\n\
+             add xroute 10.28.119.131/32-::/0 prefix 10.28.119.131/32 from ::/0 metric 0\n\
+             ok\n",
+        );
+        assert_eq!(dump.local_fee, Some(1024));
+        assert_eq!(dump.interfaces.len(), 1);
+        assert_eq!(dump.xroutes.len(), 1);
+        assert!(dump.neighbors.is_empty());
+        assert!(dump.all_failed("neighbour", dump.neighbors.len()));
+        assert!(!dump.all_failed("route", dump.routes.len()));
+    }
+}
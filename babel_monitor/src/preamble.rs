@@ -0,0 +1,199 @@
+//! The preamble babeld sends when a connection opens. The first line names the protocol and its
+//! version, after that babeld sends its own version, host name and router id. Newer versions of
+//! our babeld also list the optional parts of the config protocol they speak on a
+//! `capabilities` line, for older ones the capabilities are worked out from the version.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use failure::Error;
+
+use BabelMonitorError::*;
+
+/// Only Althea's babeld speaks the parts of the config protocol we need
+const PROTOCOL: &'static str = "ALTHEA";
+/// A new major version may change the commands we already use, minor versions only add to them
+const PROTOCOL_MAJOR: u32 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Capability {
+    /// Routes carry a price and fee, and `fee` sets ours
+    Price,
+    /// Interfaces can be monitored with `enable-timestamps` so neighbors have an rtt
+    RttTimestamps,
+    /// `metric-factor` sets how price is weighed against the metric
+    MetricFactor,
+    /// Each interface can have its own fee
+    InterfaceFee,
+}
+
+impl Capability {
+    fn all() -> Vec<Capability> {
+        vec![
+            Capability::Price,
+            Capability::RttTimestamps,
+            Capability::MetricFactor,
+            Capability::InterfaceFee,
+        ]
+    }
+
+    /// The name babeld uses on the capabilities line
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Capability::Price => "price",
+            Capability::RttTimestamps => "rtt-timestamps",
+            Capability::MetricFactor => "metric-factor",
+            Capability::InterfaceFee => "interface-fee",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Capability> {
+        Capability::all().into_iter().find(|cap| cap.name() == name)
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// What a babeld that predates the capabilities line can do, all of it shipped with 0.1
+fn implied_capabilities() -> BTreeSet<Capability> {
+    vec![
+        Capability::Price,
+        Capability::RttTimestamps,
+        Capability::MetricFactor,
+    ]
+    .into_iter()
+    .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preamble {
+    pub major: u32,
+    pub minor: u32,
+    /// babeld's own version, like babeld-1.8.0-24-g6335378
+    pub version: Option<String>,
+    pub host: Option<String>,
+    pub my_id: Option<String>,
+    capabilities: BTreeSet<Capability>,
+}
+
+impl Preamble {
+    /// Parses everything up to the ok that ends the preamble, fails if it isn't a protocol
+    /// version we can speak. Capabilities we don't know about are skipped
+    pub fn parse(preamble: &str) -> Result<Preamble, Error> {
+        let mut lines = preamble.lines();
+        let mut first = lines.next().unwrap_or("").split_whitespace();
+        if first.next() != Some(PROTOCOL) {
+            return Err(InvalidPreamble(preamble.to_string()).into());
+        }
+        let (major, minor) = match first.next().map(parse_version) {
+            Some(Some(version)) => version,
+            _ => return Err(InvalidPreamble(preamble.to_string()).into()),
+        };
+        if major != PROTOCOL_MAJOR {
+            return Err(UnsupportedProtocol(major, minor).into());
+        }
+
+        let mut parsed = Preamble {
+            major,
+            minor,
+            version: None,
+            host: None,
+            my_id: None,
+            capabilities: implied_capabilities(),
+        };
+        for line in lines {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("version") => parsed.version = words.next().map(String::from),
+                Some("host") => parsed.host = words.next().map(String::from),
+                Some("my-id") => parsed.my_id = words.next().map(String::from),
+                Some("capabilities") => {
+                    parsed.capabilities = words
+                        .filter_map(|name| {
+                            let cap = Capability::from_name(name);
+                            if cap.is_none() {
+                                trace!("Skipping unknown babel capability {}", name);
+                            }
+                            cap
+                        })
+                        .collect()
+                }
+                Some("ok") => break,
+                _ => trace!("Skipping babel preamble line {}", line),
+            }
+        }
+        Ok(parsed)
+    }
+
+    pub fn supports(&self, cap: Capability) -> bool {
+        self.capabilities.contains(&cap)
+    }
+
+    pub fn capabilities(&self) -> Vec<Capability> {
+        self.capabilities.iter().cloned().collect()
+    }
+
+    /// Fails with `Unsupported` if babeld can't do `cap`
+    pub fn require(&self, cap: Capability) -> Result<(), Error> {
+        if self.supports(cap) {
+            Ok(())
+        } else {
+            Err(Unsupported(cap).into())
+        }
+    }
+}
+
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.splitn(2, '.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_preamble() {
+        let preamble = Preamble::parse(
+            "ALTHEA 0.1\nversion babeld-1.8.0-24-g6335378\nhost raspberrypi\nmy-id \
+             ba:27:eb:ff:fe:09:06:dd\nok\n",
+        )
+        .unwrap();
+        assert_eq!((preamble.major, preamble.minor), (0, 1));
+        assert_eq!(preamble.version.unwrap(), "babeld-1.8.0-24-g6335378");
+        assert_eq!(preamble.host.unwrap(), "raspberrypi");
+        assert_eq!(preamble.my_id.unwrap(), "ba:27:eb:ff:fe:09:06:dd");
+
+        let preamble =
+            Preamble::parse("ALTHEA 0.1\nversion babeld-1.8.0-24-g6335378\nok\n").unwrap();
+        assert!(preamble.supports(Capability::Price));
+        assert!(preamble.supports(Capability::MetricFactor));
+        assert!(!preamble.supports(Capability::InterfaceFee));
+
+        let preamble = Preamble::parse(
+            "ALTHEA 0.3\nversion babeld-1.9.0\ncapabilities price interface-fee \
+             source-routing\nok\n",
+        )
+        .unwrap();
+        assert_eq!(
+            preamble.capabilities(),
+            vec![Capability::Price, Capability::InterfaceFee]
+        );
+        assert!(preamble.require(Capability::RttTimestamps).is_err());
+    }
+
+    #[test]
+    fn test_parse_preamble_errors() {
+        assert!(Preamble::parse("BABEL 1.0\nok\n").is_err());
+        assert!(Preamble::parse("ALTHEA\nok\n").is_err());
+        assert!(Preamble::parse("ALTHEA one.two\nok\n").is_err());
+        let e = Preamble::parse("ALTHEA 1.0\nok\n").unwrap_err();
+        assert_eq!(e.to_string(), "Unsupported babel protocol version 1.0");
+    }
+}
@@ -0,0 +1,152 @@
+//! babeld's state kept up to date from the lines `monitor` sends, everything babeld knows about
+//! as `add` lines followed by `add`, `change` and `flush` lines as things happen.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use parser::{BabelDump, BabelLine, Interface, Neighbor, Route, Verb, XRoute};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BabelTable {
+    pub local_fee: Option<u32>,
+    interfaces: BTreeMap<String, Interface>,
+    neighbors: BTreeMap<String, Neighbor>,
+    routes: BTreeMap<String, Route>,
+    xroutes: BTreeMap<String, XRoute>,
+}
+
+fn update<T>(entries: &mut BTreeMap<String, T>, verb: Verb, id: &str, entry: T) {
+    match verb {
+        Verb::Add | Verb::Change => {
+            entries.insert(id.to_string(), entry);
+        }
+        Verb::Flush => {
+            entries.remove(id);
+        }
+    }
+}
+
+impl BabelTable {
+    pub fn new() -> BabelTable {
+        BabelTable::default()
+    }
+
+    /// Applies a line from babeld, returns false if it wasn't one that changes the table
+    pub fn apply(&mut self, line: BabelLine) -> bool {
+        match line {
+            BabelLine::LocalFee(fee) => self.local_fee = Some(fee),
+            BabelLine::Interface(verb, interface) => {
+                let name = interface.name.clone();
+                update(&mut self.interfaces, verb, &name, interface)
+            }
+            BabelLine::Neighbor(verb, neighbor) => {
+                let id = neighbor.id.clone();
+                update(&mut self.neighbors, verb, &id, neighbor)
+            }
+            BabelLine::Route(verb, route) => {
+                let id = route.id.clone();
+                update(&mut self.routes, verb, &id, route)
+            }
+            BabelLine::XRoute(verb, xroute) => {
+                let id = xroute.id.clone();
+                update(&mut self.xroutes, verb, &id, xroute)
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn interfaces(&self) -> Vec<Interface> {
+        self.interfaces.values().cloned().collect()
+    }
+
+    pub fn neighbors(&self) -> VecDeque<Neighbor> {
+        self.neighbors.values().cloned().collect()
+    }
+
+    pub fn routes(&self) -> VecDeque<Route> {
+        self.routes.values().cloned().collect()
+    }
+
+    pub fn xroutes(&self) -> Vec<XRoute> {
+        self.xroutes.values().cloned().collect()
+    }
+}
+
+/// A table from a single dump, for when there's no monitor connection to keep one up to date
+impl From<BabelDump> for BabelTable {
+    fn from(dump: BabelDump) -> BabelTable {
+        let mut table = BabelTable::new();
+        table.local_fee = dump.local_fee;
+        for interface in dump.interfaces {
+            table.apply(BabelLine::Interface(Verb::Add, interface));
+        }
+        for neighbor in dump.neighbors {
+            table.apply(BabelLine::Neighbor(Verb::Add, neighbor));
+        }
+        for route in dump.routes {
+            table.apply(BabelLine::Route(Verb::Add, route));
+        }
+        for xroute in dump.xroutes {
+            table.apply(BabelLine::XRoute(Verb::Add, xroute));
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::parse_line;
+
+    fn apply(table: &mut BabelTable, line: &str) -> bool {
+        table.apply(parse_line(line).unwrap())
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut table = BabelTable::new();
+        assert!(!apply(&mut table, "ALTHEA 0.1"));
+        assert!(apply(&mut table, "local fee 1024"));
+        assert!(apply(
+            &mut table,
+            "add interface wg0 up true ipv6 fe80::2cee:2fff:7380:8354"
+        ));
+        assert!(apply(
+            &mut table,
+            "add neighbour 14f19a8 address fe80::2cee:2fff:648:8796 if wg0 reach ffff rxcost 256 \
+             txcost 256 rtt 26.723 rttcost 912 cost 1168"
+        ));
+        assert!(apply(
+            &mut table,
+            "add route 14f0820 prefix 10.28.7.7/32 from 0.0.0.0/0 installed yes id \
+             ba:27:eb:ff:fe:5b:fe:c7 metric 1596 price 3072 fee 3072 refmetric 638 full-path-rtt \
+             22.805 via fe80::2cee:2fff:648:8796 if wg0"
+        ));
+        assert!(!apply(&mut table, "ok"));
+        assert_eq!(table.local_fee, Some(1024));
+        assert_eq!(table.interfaces().len(), 1);
+        assert_eq!(table.neighbors().len(), 1);
+        assert_eq!(table.routes()[0].price, 3072);
+
+        apply(
+            &mut table,
+            "change route 14f0820 prefix 10.28.7.7/32 from 0.0.0.0/0 installed no id \
+             ba:27:eb:ff:fe:5b:fe:c7 metric 1596 price 4096 fee 4096 refmetric 638 full-path-rtt \
+             22.805 via fe80::2cee:2fff:648:8796 if wg0",
+        );
+        assert_eq!(table.routes().len(), 1);
+        assert_eq!(table.routes()[0].price, 4096);
+        assert!(!table.routes()[0].installed);
+
+        apply(
+            &mut table,
+            "flush route 14f0820 prefix 10.28.7.7/32 from 0.0.0.0/0 installed no id \
+             ba:27:eb:ff:fe:5b:fe:c7 metric 65535 price 4096 fee 4096 refmetric 638 \
+             full-path-rtt 22.805 via fe80::2cee:2fff:648:8796 if wg0",
+        );
+        apply(&mut table, "flush interface wg0");
+        assert!(table.routes().is_empty());
+        assert!(table.interfaces().is_empty());
+        assert_eq!(table.neighbors().len(), 1);
+    }
+}
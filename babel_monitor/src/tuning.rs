@@ -0,0 +1,219 @@
+//! The per interface settings babeld.conf has, sent to babeld on an `interface` line instead. Each
+//! one that's left unset keeps what babeld already has, which is babeld's default unless it was
+//! set before. babeld merges what it's sent into the interface's settings and there's no way to
+//! unset one, short of flushing the interface and adding it again.
+
+use failure::Error;
+
+use BabelMonitorError::*;
+
+/// babeld sends intervals in centiseconds in a 16 bit field
+const MIN_INTERVAL_MS: u32 = 10;
+const MAX_INTERVAL_MS: u32 = 655_350;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkType {
+    Wired,
+    Wireless,
+    Tunnel,
+    /// babeld guesses from the interface
+    Auto,
+}
+
+impl LinkType {
+    fn name(&self) -> &'static str {
+        match *self {
+            LinkType::Wired => "wired",
+            LinkType::Wireless => "wireless",
+            LinkType::Tunnel => "tunnel",
+            LinkType::Auto => "auto",
+        }
+    }
+}
+
+/// babeld picks routes that don't hop back and forth on one radio channel, links on the same
+/// channel interfere with each other
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    /// 1 to 254
+    Number(u8),
+    /// Interferes with every other link, like a single radio doing everything
+    Interfering,
+    /// Interferes with nothing, like wires and tunnels
+    NonInterfering,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterfaceTuning {
+    /// babeld's rxcost, the cost of receiving over the link. Routes over links that cost more are
+    /// less attractive
+    pub link_cost: Option<u16>,
+    pub hello_interval_ms: Option<u32>,
+    pub update_interval_ms: Option<u32>,
+    pub split_horizon: Option<bool>,
+    pub link_type: Option<LinkType>,
+    pub channel: Option<Channel>,
+    /// babeld's max-rtt-penalty, the most a link's rtt can add to its cost
+    pub max_rtt_penalty: Option<u16>,
+}
+
+/// babeld takes intervals in seconds
+fn seconds(ms: u32) -> String {
+    format!("{}.{:03}", ms / 1000, ms % 1000)
+}
+
+impl InterfaceTuning {
+    pub fn is_empty(&self) -> bool {
+        *self == InterfaceTuning::default()
+    }
+
+    /// Fails with InvalidTuning on a value babeld would refuse
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.link_cost == Some(0) {
+            return Err(InvalidTuning("link cost must be at least 1".to_string()).into());
+        }
+        for &(name, interval) in &[
+            ("hello interval", self.hello_interval_ms),
+            ("update interval", self.update_interval_ms),
+        ] {
+            match interval {
+                Some(ms) if ms < MIN_INTERVAL_MS || ms > MAX_INTERVAL_MS => {
+                    return Err(InvalidTuning(format!(
+                        "{} must be between {}ms and {}ms",
+                        name, MIN_INTERVAL_MS, MAX_INTERVAL_MS
+                    ))
+                    .into());
+                }
+                _ => {}
+            }
+        }
+        match self.channel {
+            Some(Channel::Number(0)) | Some(Channel::Number(255)) => {
+                Err(InvalidTuning("channel must be between 1 and 254".to_string()).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The settings the way babeld takes them after the interface name
+    pub fn options(&self) -> Vec<String> {
+        let mut options = Vec::new();
+        if let Some(cost) = self.link_cost {
+            options.push(format!("rxcost {}", cost));
+        }
+        if let Some(ms) = self.hello_interval_ms {
+            options.push(format!("hello-interval {}", seconds(ms)));
+        }
+        if let Some(ms) = self.update_interval_ms {
+            options.push(format!("update-interval {}", seconds(ms)));
+        }
+        if let Some(split_horizon) = self.split_horizon {
+            options.push(format!("split-horizon {}", split_horizon));
+        }
+        if let Some(link_type) = self.link_type {
+            options.push(format!("type {}", link_type.name()));
+        }
+        match self.channel {
+            Some(Channel::Number(channel)) => options.push(format!("channel {}", channel)),
+            Some(Channel::Interfering) => options.push("channel interfering".to_string()),
+            Some(Channel::NonInterfering) => options.push("channel noninterfering".to_string()),
+            None => {}
+        }
+        if let Some(penalty) = self.max_rtt_penalty {
+            options.push(format!("max-rtt-penalty {}", penalty));
+        }
+        options
+    }
+
+    /// The command that applies the settings, None if none are set. babeld starts monitoring an
+    /// interface it's tuned for if it wasn't already
+    pub fn command(&self, iface: &str) -> Option<String> {
+        let options = self.options();
+        if options.is_empty() {
+            None
+        } else {
+            Some(format!("interface {} {}", iface, options.join(" ")))
+        }
+    }
+
+    fn set_fields(&self) -> [bool; 7] {
+        [
+            self.link_cost.is_some(),
+            self.hello_interval_ms.is_some(),
+            self.update_interval_ms.is_some(),
+            self.split_horizon.is_some(),
+            self.link_type.is_some(),
+            self.channel.is_some(),
+            self.max_rtt_penalty.is_some(),
+        ]
+    }
+
+    /// Whether going from `old` to these settings leaves something unset that was set, babeld
+    /// only goes back to its default after the interface is flushed
+    pub fn unsets(&self, old: &InterfaceTuning) -> bool {
+        old.set_fields()
+            .iter()
+            .zip(self.set_fields().iter())
+            .any(|(&was, &is)| was && !is)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tuning_command() {
+        assert_eq!(InterfaceTuning::default().command("wg0"), None);
+
+        let tuning = InterfaceTuning {
+            link_cost: Some(512),
+            hello_interval_ms: Some(4000),
+            update_interval_ms: Some(16500),
+            split_horizon: Some(false),
+            link_type: Some(LinkType::Wireless),
+            channel: Some(Channel::NonInterfering),
+            max_rtt_penalty: Some(96),
+        };
+        tuning.validate().unwrap();
+        assert_eq!(
+            tuning.command("wg0").unwrap(),
+            "interface wg0 rxcost 512 hello-interval 4.000 update-interval 16.500 split-horizon \
+             false type wireless channel noninterfering max-rtt-penalty 96"
+        );
+
+        let less = InterfaceTuning {
+            link_cost: Some(1024),
+            ..InterfaceTuning::default()
+        };
+        assert!(less.unsets(&tuning));
+        assert!(!tuning.unsets(&less));
+    }
+
+    #[test]
+    fn test_tuning_validate() {
+        let bad = vec![
+            InterfaceTuning {
+                link_cost: Some(0),
+                ..InterfaceTuning::default()
+            },
+            InterfaceTuning {
+                hello_interval_ms: Some(5),
+                ..InterfaceTuning::default()
+            },
+            InterfaceTuning {
+                update_interval_ms: Some(MAX_INTERVAL_MS + 1),
+                ..InterfaceTuning::default()
+            },
+            InterfaceTuning {
+                channel: Some(Channel::Number(0)),
+                ..InterfaceTuning::default()
+            },
+        ];
+        for tuning in bad {
+            assert!(tuning.validate().is_err(), "{:?} should be refused", tuning);
+        }
+    }
+}
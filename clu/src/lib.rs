@@ -33,7 +33,7 @@ extern crate althea_types;
 extern crate babel_monitor;
 extern crate regex;
 
-use babel_monitor::Babel;
+use babel_monitor::{Babel, Capability};
 
 #[derive(Debug, Fail)]
 pub enum CluError {
@@ -149,7 +149,21 @@ fn linux_init(config: Arc<RwLock<settings::RitaSettingsStruct>>) -> Result<(), E
             }
 
             if network_settings.device.is_none() {
-                warn!("Device name could not be read from {}", release_file_path);
+                warn!(
+                    "Device name could not be read from {}, asking ubus",
+                    release_file_path
+                );
+                // board names are vendor,device like glinet,gl-b1300, the firmware release
+                // file only names the device
+                match KI.ubus_system_board() {
+                    Ok(board) => {
+                        let board_name = board.board_name.unwrap_or(board.model);
+                        let device = board_name.rsplit(',').next().unwrap_or(&board_name);
+                        info!("Device name is {} according to ubus", device);
+                        network_settings.device = Some(device.to_string());
+                    }
+                    Err(e) => warn!("Device name could not be read from ubus {:?}", e),
+                }
             }
         }
     }
@@ -284,12 +298,20 @@ fn linux_exit_init(config: Arc<RwLock<settings::RitaExitSettingsStruct>>) -> Res
 
     babel.start_connection()?;
 
-    babel.set_local_fee(local_fee)?;
+    if babel.supports(Capability::Price) {
+        babel.set_local_fee(local_fee)?;
+    } else {
+        warn!("Babel doesn't support prices, clients will be routed to us for free");
+    }
     if local_fee == 0 {
         warn!("THIS NODE IS GIVING BANDWIDTH AWAY FOR FREE. PLEASE SET local_fee TO A NON-ZERO VALUE TO DISABLE THIS WARNING.");
     }
 
-    babel.set_metric_factor(metric_factor)?;
+    if babel.supports(Capability::MetricFactor) {
+        babel.set_metric_factor(metric_factor)?;
+    } else {
+        warn!("Babel doesn't support metric-factor, routes will be picked on quality alone");
+    }
     if metric_factor == 0 {
         warn!("THIS NODE DOESN'T PAY ATTENTION TO ROUTE QUALITY - IT'LL CHOOSE THE CHEAPEST ROUTE EVEN IF IT'S THE WORST LINK AROUND. PLEASE SET metric_factor TO A NON-ZERO VALUE TO DISABLE THIS WARNING.");
     }
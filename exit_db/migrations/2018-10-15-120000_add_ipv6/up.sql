@@ -0,0 +1,2 @@
+ALTER TABLE clients
+  ADD internal_ipv6 VARCHAR DEFAULT "" NOT NULL;
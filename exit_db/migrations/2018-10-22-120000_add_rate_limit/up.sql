@@ -0,0 +1,2 @@
+ALTER TABLE clients
+  ADD rate_limit INTEGER DEFAULT 0 NOT NULL;
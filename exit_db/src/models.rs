@@ -13,4 +13,8 @@ pub struct Client {
     pub verified: bool,
     // TODO change before 2038; it's left that way because diesel cannot do `Insertable` for i64
     pub email_sent_time: i32,
+    /// The ipv6 prefix delegated to this client, empty if it doesn't have one
+    pub internal_ipv6: String,
+    /// Download limit in kbit/s set by the operator, 0 for unlimited
+    pub rate_limit: i32,
 }
@@ -9,5 +9,7 @@ table! {
         email_code -> Text,
         verified -> Bool,
         email_sent_time -> Integer,
+        internal_ipv6 -> Text,
+        rate_limit -> Integer,
     }
 }
@@ -0,0 +1,312 @@
+//! A fake babeld for tests and local development. It listens on TCP and speaks enough of the
+//! config protocol for rita and clu, the preamble, `dump`, `fee`, `metric-factor`, `interface`,
+//! `flush interface`, `redistribute`, `monitor` and `unmonitor`. What it reports comes from a
+//! `Topology`, and changes made to that while it runs are announced to monitoring connections
+//! the way babeld announces changes to the mesh.
+
+extern crate babel_monitor;
+#[macro_use]
+extern crate failure;
+extern crate ipnetwork;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use failure::Error;
+
+mod topology;
+
+pub use topology::Topology;
+
+struct Shared {
+    topology: Topology,
+    /// The connections in monitor mode, by connection number
+    monitors: Vec<(usize, TcpStream)>,
+    commands: Vec<String>,
+    stopped: bool,
+}
+
+impl Shared {
+    /// Sends the lines to every monitor, ones that have gone away are dropped
+    fn announce(&mut self, lines: &[String]) {
+        if lines.is_empty() {
+            return;
+        }
+        let mut out = lines.join("\n");
+        out.push('\n');
+        self.monitors
+            .retain(|&(_, ref stream)| (&*stream).write_all(out.as_bytes()).is_ok());
+    }
+
+    /// Changes the topology and announces what changed
+    fn change<F: FnOnce(&mut Topology)>(&mut self, change: F) {
+        let old = self.topology.clone();
+        change(&mut self.topology);
+        let lines = self.topology.changes(&old);
+        self.announce(&lines);
+    }
+
+    /// The reply to a command, ending in ok, no or bad
+    fn handle(&mut self, conn: usize, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let ok = "ok\n".to_string();
+        let bad = "bad\n".to_string();
+        match (words.get(0).cloned(), words.get(1).cloned()) {
+            (Some("dump"), None) => dump(&self.topology),
+            (Some("monitor"), None) => {
+                self.monitors.retain(|&(c, _)| c != conn);
+                dump(&self.topology)
+            }
+            (Some("unmonitor"), None) | (Some("quit"), None) => {
+                self.monitors.retain(|&(c, _)| c != conn);
+                ok
+            }
+            (Some("fee"), Some(fee)) if self.topology.supports("price") => match fee.parse() {
+                Ok(fee) => {
+                    // babeld doesn't announce fee changes either
+                    self.topology.local_fee = fee;
+                    ok
+                }
+                Err(_) => bad,
+            },
+            (Some("metric-factor"), Some(factor)) if self.topology.supports("metric-factor") => {
+                match factor.parse() {
+                    Ok(factor) => {
+                        self.topology.metric_factor = factor;
+                        ok
+                    }
+                    Err(_) => bad,
+                }
+            }
+            (Some("interface"), Some(name)) => {
+                self.change(|topology| topology.add_interface(name));
+                ok
+            }
+            (Some("flush"), Some("interface")) => match words.get(2) {
+                Some(name) if self.topology.interfaces.contains_key(*name) => {
+                    self.change(|topology| topology.remove_interface(name));
+                    ok
+                }
+                _ => "no\n".to_string(),
+            },
+            (Some("redistribute"), Some(_)) => {
+                self.topology.redistribute.push(words[1..].join(" "));
+                ok
+            }
+            _ => bad,
+        }
+    }
+}
+
+fn dump(topology: &Topology) -> String {
+    let mut out = String::new();
+    for line in topology.dump() {
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out.push_str("ok\n");
+    out
+}
+
+/// Answers one connection until it's closed
+fn serve(conn: usize, stream: TcpStream, shared: &Mutex<Shared>) -> Result<(), Error> {
+    let mut writer = stream.try_clone()?;
+    {
+        let shared = shared.lock().unwrap();
+        writer.write_all(shared.topology.preamble().as_bytes())?;
+    }
+    for command in BufReader::new(stream).lines() {
+        let command = command?;
+        trace!("Fake babel got {}", command);
+        let mut shared = shared.lock().unwrap();
+        if shared.stopped {
+            break;
+        }
+        shared.commands.push(command.clone());
+        let reply = shared.handle(conn, &command);
+        writer.write_all(reply.as_bytes())?;
+
+        match command.trim() {
+            // only once the dump is out, so changes come after it
+            "monitor" => shared.monitors.push((conn, writer.try_clone()?)),
+            "quit" => break,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+pub struct FakeBabel {
+    addr: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl FakeBabel {
+    /// Listens on a free port on localhost
+    pub fn start(topology: Topology) -> Result<FakeBabel, Error> {
+        FakeBabel::bind("127.0.0.1:0".parse()?, topology)
+    }
+
+    pub fn bind(addr: SocketAddr, topology: Topology) -> Result<FakeBabel, Error> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Mutex::new(Shared {
+            topology,
+            monitors: Vec::new(),
+            commands: Vec::new(),
+            stopped: false,
+        }));
+
+        let accepting = shared.clone();
+        thread::spawn(move || {
+            for (conn, stream) in listener.incoming().enumerate() {
+                if accepting.lock().unwrap().stopped {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let shared = accepting.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = serve(conn, stream, &shared) {
+                                trace!("Fake babel connection ended with {:?}", e);
+                            }
+                            shared.lock().unwrap().monitors.retain(|&(c, _)| c != conn);
+                        });
+                    }
+                    Err(e) => warn!("Fake babel failed to accept with {:?}", e),
+                }
+            }
+        });
+
+        Ok(FakeBabel { addr, shared })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// A copy of the topology as it is now, including what commands have changed
+    pub fn topology(&self) -> Topology {
+        self.shared.lock().unwrap().topology.clone()
+    }
+
+    /// Every command received so far on any connection
+    pub fn commands(&self) -> Vec<String> {
+        self.shared.lock().unwrap().commands.clone()
+    }
+
+    /// Changes the topology, monitors are sent what changed
+    pub fn update<F: FnOnce(&mut Topology)>(&self, change: F) {
+        self.shared.lock().unwrap().change(change)
+    }
+}
+
+impl Drop for FakeBabel {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().stopped = true;
+        // wakes the accept loop up so it sees it has stopped
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use babel_monitor::{parse_line, Babel, BabelLine, BabelTable};
+    use std::time::Duration;
+
+    fn topology() -> Topology {
+        Topology::new()
+            .local_fee(10)
+            .neighbor("wg0", "fe80::1", 256)
+            .route("fd00::2/128", "fe80::1", 512, 100)
+    }
+
+    #[test]
+    fn test_commands() {
+        let fake = FakeBabel::start(topology()).unwrap();
+        let mut babel = Babel::new(TcpStream::connect(fake.addr()).unwrap());
+        babel.start_connection().unwrap();
+
+        assert_eq!(babel.get_local_fee().unwrap(), 10);
+        babel.set_local_fee(20).unwrap();
+        babel.set_metric_factor(1000).unwrap();
+        babel.monitor("wg1").unwrap();
+        assert_eq!(babel.parse_routes().unwrap().len(), 1);
+        babel.unmonitor("wg0").unwrap();
+        assert!(babel.parse_routes().unwrap().is_empty());
+
+        let topology = fake.topology();
+        assert_eq!(topology.local_fee, 20);
+        assert_eq!(topology.metric_factor, 1000);
+        assert!(topology.interfaces.contains_key("wg1"));
+        assert!(!topology.interfaces.contains_key("wg0"));
+        assert_eq!(fake.commands()[1], "fee 20");
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let fake = FakeBabel::start(topology().capabilities(&["price"])).unwrap();
+        let mut babel = Babel::new(TcpStream::connect(fake.addr()).unwrap());
+        babel.start_connection().unwrap();
+        assert!(babel.set_metric_factor(1000).is_err());
+        babel.monitor("wg1").unwrap();
+        assert_eq!(fake.commands(), vec!["interface wg1"]);
+    }
+
+    /// A connection that's sent `command` and reads lines back
+    fn send(fake: &FakeBabel, command: &str) -> Box<Iterator<Item = String>> {
+        let mut stream = TcpStream::connect(fake.addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(command.as_bytes()).unwrap();
+        Box::new(BufReader::new(stream).lines().map(|line| line.unwrap()))
+    }
+
+    #[test]
+    fn test_redistribute() {
+        let fake = FakeBabel::start(topology()).unwrap();
+        let replies: Vec<String> = send(
+            &fake,
+            "redistribute ip fd00::1/128 allow\nredistribute local deny\nquit\n",
+        ).filter(|line| line == "ok")
+        .collect();
+        // the preamble's and one per command
+        assert_eq!(replies.len(), 4);
+        assert_eq!(
+            fake.topology().redistribute,
+            vec!["ip fd00::1/128 allow", "local deny"]
+        );
+    }
+
+    #[test]
+    fn test_monitor() {
+        let fake = FakeBabel::start(topology()).unwrap();
+        let mut lines = send(&fake, "monitor\n");
+
+        // the preamble, then the dump
+        let mut table = BabelTable::new();
+        let mut oks = 0;
+        while oks < 2 {
+            match parse_line(&lines.next().unwrap()).unwrap() {
+                BabelLine::Ok => oks += 1,
+                line => {
+                    table.apply(line);
+                }
+            }
+        }
+        assert_eq!(table.routes()[0].price, 100);
+
+        fake.update(|topology| topology.route_mut("fd00::2/128").unwrap().price = 300);
+        table.apply(parse_line(&lines.next().unwrap()).unwrap());
+        assert_eq!(table.routes()[0].price, 300);
+    }
+}
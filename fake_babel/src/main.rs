@@ -0,0 +1,54 @@
+//! Runs a fake babeld for working on rita without a mesh, `fake_babel <topology.toml> [port]`.
+//! The port defaults to the one rita expects babeld on.
+
+extern crate env_logger;
+extern crate fake_babel;
+
+use std::env;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::exit;
+use std::thread;
+
+use fake_babel::{FakeBabel, Topology};
+
+/// rita's default babel_port
+const DEFAULT_PORT: u16 = 6872;
+
+fn main() {
+    env_logger::init();
+
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("Usage: {} <topology.toml> [port]", args[0]);
+        exit(1);
+    }
+    let topology = match Topology::from_file(Path::new(&args[1])) {
+        Ok(topology) => topology,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", args[1], e);
+            exit(1);
+        }
+    };
+    let port = match args.get(2).map(|port| port.parse()) {
+        Some(Ok(port)) => port,
+        Some(Err(e)) => {
+            eprintln!("Invalid port {}: {}", args[2], e);
+            exit(1);
+        }
+        None => DEFAULT_PORT,
+    };
+
+    let addr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port));
+    let _babel = match FakeBabel::bind(addr, topology) {
+        Ok(babel) => babel,
+        Err(e) => {
+            eprintln!("Failed to listen on {}: {}", addr, e);
+            exit(1);
+        }
+    };
+    println!("Fake babeld listening on {}", addr);
+    loop {
+        thread::park();
+    }
+}
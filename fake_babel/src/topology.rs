@@ -0,0 +1,570 @@
+//! What the fake babeld reports, the interfaces, neighbors and routes of a pretend mesh along
+//! with our fee and metric factor. It's built in code or read from a TOML file like
+//!
+//! ```toml
+//! local_fee = 10
+//!
+//! [[neighbor]]
+//! iface = "wg0"
+//! address = "fe80::1"
+//! cost = 256
+//!
+//! [[route]]
+//! prefix = "fd00::2/128"
+//! via = "fe80::1"
+//! metric = 512
+//! price = 100
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::Path;
+
+use babel_monitor::{Interface, Neighbor, Route, Verb, XRoute};
+use failure::Error;
+use ipnetwork::IpNetwork;
+use toml;
+
+/// babeld's default, see the dashboard's metric factor
+const DEFAULT_METRIC_FACTOR: u32 = 1900;
+/// What babeld reports for a wired link that hears every hello
+const LINK_COST: u16 = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topology {
+    pub version: String,
+    pub host: String,
+    pub my_id: String,
+    /// Sent on the preamble's capabilities line, with None there isn't one like on babeld from
+    /// before there was
+    pub capabilities: Option<Vec<String>>,
+    pub local_fee: u32,
+    pub metric_factor: u32,
+    pub interfaces: BTreeMap<String, Interface>,
+    pub neighbors: BTreeMap<String, Neighbor>,
+    pub routes: BTreeMap<String, Route>,
+    pub xroutes: BTreeMap<String, XRoute>,
+    /// Every redistribute rule we've been sent, in order
+    pub redistribute: Vec<String>,
+    next_id: u32,
+}
+
+impl Default for Topology {
+    fn default() -> Topology {
+        Topology {
+            version: "babeld-1.8.0-fake".to_string(),
+            host: "fake-babeld".to_string(),
+            my_id: "ba:27:eb:ff:fe:00:00:01".to_string(),
+            capabilities: None,
+            local_fee: 0,
+            metric_factor: DEFAULT_METRIC_FACTOR,
+            interfaces: BTreeMap::new(),
+            neighbors: BTreeMap::new(),
+            routes: BTreeMap::new(),
+            xroutes: BTreeMap::new(),
+            redistribute: Vec::new(),
+            next_id: 0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TopologyFile {
+    my_id: Option<String>,
+    host: Option<String>,
+    capabilities: Option<Vec<String>>,
+    #[serde(default)]
+    local_fee: u32,
+    metric_factor: Option<u32>,
+    #[serde(default)]
+    interface: Vec<InterfaceFile>,
+    #[serde(default)]
+    neighbor: Vec<NeighborFile>,
+    #[serde(default)]
+    route: Vec<RouteFile>,
+    #[serde(default)]
+    xroute: Vec<XRouteFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InterfaceFile {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NeighborFile {
+    iface: String,
+    address: String,
+    cost: Option<u16>,
+    rtt: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RouteFile {
+    prefix: String,
+    via: String,
+    metric: u16,
+    price: u32,
+    installed: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct XRouteFile {
+    prefix: String,
+    metric: Option<u16>,
+}
+
+/// Builder methods panic on addresses that don't parse or routes via neighbors that haven't been
+/// added, they're meant for tests. Files are checked and fail with an error instead
+impl Topology {
+    pub fn new() -> Topology {
+        Topology::default()
+    }
+
+    pub fn from_file(path: &Path) -> Result<Topology, Error> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        Topology::from_toml(&contents)
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Topology, Error> {
+        let file: TopologyFile = toml::from_str(contents)?;
+        let mut topology = Topology::new();
+        if let Some(my_id) = file.my_id {
+            topology.my_id = my_id;
+        }
+        if let Some(host) = file.host {
+            topology.host = host;
+        }
+        topology.capabilities = file.capabilities;
+        topology.local_fee = file.local_fee;
+        if let Some(metric_factor) = file.metric_factor {
+            topology.metric_factor = metric_factor;
+        }
+        for interface in file.interface {
+            topology.add_interface(&interface.name);
+        }
+        for neighbor in file.neighbor {
+            topology.add_neighbor(
+                &neighbor.iface,
+                neighbor.address.parse()?,
+                neighbor.cost.unwrap_or(LINK_COST),
+                neighbor.rtt.unwrap_or(0.0),
+            );
+        }
+        for route in file.route {
+            topology.add_route(
+                route.prefix.parse()?,
+                route.via.parse()?,
+                route.metric,
+                route.price,
+                route.installed.unwrap_or(true),
+            )?;
+        }
+        for xroute in file.xroute {
+            topology.add_xroute(xroute.prefix.parse()?, xroute.metric.unwrap_or(0));
+        }
+        Ok(topology)
+    }
+
+    pub fn local_fee(mut self, fee: u32) -> Topology {
+        self.local_fee = fee;
+        self
+    }
+
+    pub fn metric_factor(mut self, factor: u32) -> Topology {
+        self.metric_factor = factor;
+        self
+    }
+
+    pub fn capabilities(mut self, capabilities: &[&str]) -> Topology {
+        self.capabilities = Some(capabilities.iter().map(|cap| cap.to_string()).collect());
+        self
+    }
+
+    pub fn interface(mut self, name: &str) -> Topology {
+        self.add_interface(name);
+        self
+    }
+
+    /// A neighbor heard on every hello over `iface`, which is added if it's new
+    pub fn neighbor(mut self, iface: &str, address: &str, cost: u16) -> Topology {
+        let address = address.parse().expect("Invalid neighbor address");
+        self.add_neighbor(iface, address, cost, 0.0);
+        self
+    }
+
+    /// An installed route through the neighbor at `via`
+    pub fn route(mut self, prefix: &str, via: &str, metric: u16, price: u32) -> Topology {
+        let prefix = prefix.parse().expect("Invalid route prefix");
+        let via = via.parse().expect("Invalid route next hop");
+        self.add_route(prefix, via, metric, price, true)
+            .expect("No neighbor for route");
+        self
+    }
+
+    pub fn xroute(mut self, prefix: &str, metric: u16) -> Topology {
+        let prefix = prefix.parse().expect("Invalid xroute prefix");
+        self.add_xroute(prefix, metric);
+        self
+    }
+
+    /// babeld's ids are the addresses of its structs, any unique hex will do
+    fn next_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("{:x}", 0x14f_0000 + self.next_id)
+    }
+
+    pub fn add_interface(&mut self, name: &str) {
+        self.interfaces
+            .entry(name.to_string())
+            .or_insert_with(|| Interface {
+                name: name.to_string(),
+                up: true,
+                ipv6: None,
+                ipv4: None,
+            });
+    }
+
+    pub fn add_neighbor(&mut self, iface: &str, address: IpAddr, cost: u16, rtt: f32) {
+        self.add_interface(iface);
+        let id = self.next_id();
+        self.neighbors.insert(
+            id.clone(),
+            Neighbor {
+                id,
+                address,
+                iface: iface.to_string(),
+                reach: 0xffff,
+                txcost: LINK_COST,
+                rxcost: LINK_COST,
+                rtt,
+                rttcost: 0,
+                cost,
+            },
+        );
+    }
+
+    pub fn add_route(
+        &mut self,
+        prefix: IpNetwork,
+        via: IpAddr,
+        metric: u16,
+        price: u32,
+        installed: bool,
+    ) -> Result<(), Error> {
+        let (iface, cost) = match self.neighbor_at(via) {
+            Some(neighbor) => (neighbor.iface.clone(), neighbor.cost),
+            None => bail!("No neighbor at {} for the route to {}", via, prefix),
+        };
+        let id = self.next_id();
+        self.routes.insert(
+            id.clone(),
+            Route {
+                id,
+                iface,
+                installed,
+                neigh_ip: via,
+                prefix,
+                metric,
+                refmetric: metric.saturating_sub(cost),
+                full_path_rtt: 0.0,
+                price,
+                fee: price,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn add_xroute(&mut self, prefix: IpNetwork, metric: u16) {
+        let from = any_source(&prefix);
+        let id = format!("{}-{}", prefix, from);
+        self.xroutes.insert(
+            id.clone(),
+            XRoute {
+                id,
+                prefix,
+                from,
+                metric,
+            },
+        );
+    }
+
+    pub fn neighbor_at(&self, address: IpAddr) -> Option<&Neighbor> {
+        self.neighbors.values().find(|n| n.address == address)
+    }
+
+    /// The installed route to `prefix`
+    pub fn route_mut(&mut self, prefix: &str) -> Option<&mut Route> {
+        let prefix: IpNetwork = prefix.parse().ok()?;
+        self.routes
+            .values_mut()
+            .find(|route| route.prefix == prefix && route.installed)
+    }
+
+    /// Drops the neighbor and every route through it
+    pub fn remove_neighbor(&mut self, address: IpAddr) {
+        self.neighbors.retain(|_, neighbor| neighbor.address != address);
+        self.routes.retain(|_, route| route.neigh_ip != address);
+    }
+
+    /// Drops the interface along with the neighbors and routes on it, like `flush interface`
+    pub fn remove_interface(&mut self, name: &str) {
+        self.interfaces.remove(name);
+        self.neighbors.retain(|_, neighbor| neighbor.iface != name);
+        self.routes.retain(|_, route| route.iface != name);
+    }
+
+    /// Everything up to the ok that opens a connection
+    pub fn preamble(&self) -> String {
+        let mut preamble = format!(
+            "ALTHEA 0.1\nversion {}\nhost {}\nmy-id {}\n",
+            self.version, self.host, self.my_id
+        );
+        if let Some(ref capabilities) = self.capabilities {
+            preamble.push_str(&format!("capabilities {}\n", capabilities.join(" ")));
+        }
+        preamble.push_str("ok\n");
+        preamble
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        match self.capabilities {
+            Some(ref capabilities) => capabilities.iter().any(|cap| cap == capability),
+            None => true,
+        }
+    }
+
+    /// The output of `dump` without the ok
+    pub fn dump(&self) -> Vec<String> {
+        let mut lines = vec![format!("local fee {}", self.local_fee)];
+        lines.extend(self.interfaces.values().map(|i| interface_line(Verb::Add, i)));
+        lines.extend(self.neighbors.values().map(|n| neighbor_line(Verb::Add, n)));
+        lines.extend(self.xroutes.values().map(|x| xroute_line(Verb::Add, x)));
+        lines.extend(self.routes.values().map(|r| self.route_line(Verb::Add, r)));
+        lines
+    }
+
+    /// The lines babeld would send monitors as `old` became `self`, additions and changes come
+    /// before removals so a route is never announced without the interface it's on
+    pub fn changes(&self, old: &Topology) -> Vec<String> {
+        let mut lines = Vec::new();
+        diff(&old.interfaces, &self.interfaces, &mut lines, interface_line);
+        diff(&old.neighbors, &self.neighbors, &mut lines, neighbor_line);
+        diff(&old.xroutes, &self.xroutes, &mut lines, xroute_line);
+        diff(&old.routes, &self.routes, &mut lines, |verb, route| {
+            self.route_line(verb, route)
+        });
+
+        // babeld flushes routes before the neighbors and interfaces they went through
+        let (mut flushes, mut rest): (Vec<String>, Vec<String>) =
+            lines.into_iter().partition(|line| line.starts_with("flush"));
+        flushes.reverse();
+        rest.extend(flushes);
+        rest
+    }
+
+    fn route_line(&self, verb: Verb, route: &Route) -> String {
+        format!(
+            "{} route {} prefix {} from {} installed {} id {} metric {} price {} fee {} \
+             refmetric {} full-path-rtt {:.3} via {} if {}",
+            verb_name(verb),
+            route.id,
+            route.prefix,
+            any_source(&route.prefix),
+            if route.installed { "yes" } else { "no" },
+            self.my_id,
+            route.metric,
+            route.price,
+            route.fee,
+            route.refmetric,
+            route.full_path_rtt,
+            route.neigh_ip,
+            route.iface
+        )
+    }
+}
+
+fn verb_name(verb: Verb) -> &'static str {
+    match verb {
+        Verb::Add => "add",
+        Verb::Change => "change",
+        Verb::Flush => "flush",
+    }
+}
+
+/// Routes aren't source specific so they're all from anywhere
+fn any_source(prefix: &IpNetwork) -> IpNetwork {
+    match *prefix {
+        IpNetwork::V4(_) => "0.0.0.0/0".parse().unwrap(),
+        IpNetwork::V6(_) => "::/0".parse().unwrap(),
+    }
+}
+
+fn interface_line(verb: Verb, interface: &Interface) -> String {
+    if verb == Verb::Flush {
+        return format!("flush interface {}", interface.name);
+    }
+    let mut line = format!(
+        "{} interface {} up {}",
+        verb_name(verb),
+        interface.name,
+        interface.up
+    );
+    if let Some(ipv6) = interface.ipv6 {
+        line.push_str(&format!(" ipv6 {}", ipv6));
+    }
+    if let Some(ipv4) = interface.ipv4 {
+        line.push_str(&format!(" ipv4 {}", ipv4));
+    }
+    line
+}
+
+fn neighbor_line(verb: Verb, neighbor: &Neighbor) -> String {
+    format!(
+        "{} neighbour {} address {} if {} reach {:04x} rxcost {} txcost {} rtt {:.3} rttcost {} \
+         cost {}",
+        verb_name(verb),
+        neighbor.id,
+        neighbor.address,
+        neighbor.iface,
+        neighbor.reach,
+        neighbor.rxcost,
+        neighbor.txcost,
+        neighbor.rtt,
+        neighbor.rttcost,
+        neighbor.cost
+    )
+}
+
+fn xroute_line(verb: Verb, xroute: &XRoute) -> String {
+    format!(
+        "{} xroute {} prefix {} from {} metric {}",
+        verb_name(verb),
+        xroute.id,
+        xroute.prefix,
+        xroute.from,
+        xroute.metric
+    )
+}
+
+fn diff<T, F>(
+    old: &BTreeMap<String, T>,
+    new: &BTreeMap<String, T>,
+    lines: &mut Vec<String>,
+    line: F,
+) where
+    T: PartialEq,
+    F: Fn(Verb, &T) -> String,
+{
+    for (id, entry) in new {
+        match old.get(id) {
+            None => lines.push(line(Verb::Add, entry)),
+            Some(old_entry) if old_entry != entry => lines.push(line(Verb::Change, entry)),
+            Some(_) => {}
+        }
+    }
+    for (id, entry) in old {
+        if !new.contains_key(id) {
+            lines.push(line(Verb::Flush, entry));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use babel_monitor::{BabelDump, BabelLine, BabelTable};
+
+    fn topology() -> Topology {
+        Topology::new()
+            .local_fee(10)
+            .neighbor("wg0", "fe80::1", 256)
+            .neighbor("wg1", "fe80::2", 512)
+            .route("fd00::2/128", "fe80::1", 512, 100)
+            .route("fd00::3/128", "fe80::2", 1024, 200)
+            .xroute("fd00::1/128", 0)
+    }
+
+    #[test]
+    fn test_dump_parses() {
+        let topology = topology();
+        let mut dump = topology.dump().join("\n");
+        dump.push_str("\nok\n");
+        let dump = BabelDump::parse(&dump);
+        assert!(dump.failed.is_empty());
+        assert_eq!(dump.local_fee, Some(10));
+        assert_eq!(dump.interfaces.len(), 2);
+        assert_eq!(dump.neighbors.len(), 2);
+        assert_eq!(dump.xroutes.len(), 1);
+        assert_eq!(dump.routes.len(), 2);
+        assert_eq!(
+            dump.routes,
+            topology.routes.values().cloned().collect::<Vec<Route>>()
+        );
+    }
+
+    #[test]
+    fn test_changes() {
+        let old = topology();
+        let mut new = old.clone();
+        new.route_mut("fd00::2/128").unwrap().price = 150;
+        new.remove_interface("wg1");
+
+        let mut table = BabelTable::new();
+        for line in old.dump() {
+            table.apply(::babel_monitor::parse_line(&line).unwrap());
+        }
+        let changes = new.changes(&old);
+        assert!(changes[0].starts_with("change route"));
+        assert!(changes.last().unwrap().starts_with("flush interface wg1"));
+        for line in changes {
+            match ::babel_monitor::parse_line(&line).unwrap() {
+                BabelLine::Other(line) => panic!("Unparsed change {}", line),
+                line => table.apply(line),
+            };
+        }
+        assert_eq!(table.routes().len(), 1);
+        assert_eq!(table.routes()[0].price, 150);
+        assert_eq!(table.neighbors().len(), 1);
+        assert_eq!(table.interfaces().len(), 1);
+    }
+
+    #[test]
+    fn test_from_toml() {
+        let topology = Topology::from_toml(
+            r#"
+            local_fee = 10
+            capabilities = ["price", "rtt-timestamps"]
+
+            [[neighbor]]
+            iface = "wg0"
+            address = "fe80::1"
+
+            [[route]]
+            prefix = "fd00::2/128"
+            via = "fe80::1"
+            metric = 512
+            price = 100
+            "#,
+        ).unwrap();
+        assert_eq!(topology.local_fee, 10);
+        assert_eq!(topology.interfaces.len(), 1);
+        assert_eq!(topology.routes.values().next().unwrap().refmetric, 256);
+        assert!(!topology.supports("metric-factor"));
+        assert!(topology.preamble().contains("capabilities price rtt-timestamps\n"));
+
+        let missing_neighbor = Topology::from_toml(
+            r#"
+            [[route]]
+            prefix = "fd00::2/128"
+            via = "fe80::1"
+            metric = 512
+            price = 100
+            "#,
+        );
+        assert!(missing_neighbor.is_err());
+    }
+}
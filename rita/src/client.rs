@@ -96,15 +96,17 @@ struct Args {
     flag_config: String,
     flag_platform: String,
     flag_future: bool,
+    flag_dry_run: bool,
 }
 
 lazy_static! {
     static ref USAGE: String = format!(
-        "Usage: rita --config=<settings> --platform=<platform> [--future]
+        "Usage: rita --config=<settings> --platform=<platform> [--future] [--dry-run]
 Options:
     -c, --config=<settings>     Name of config file
     -p, --platform=<platform>   Platform (linux or openwrt)
     --future                    Enable B side of A/B releases
+    --dry-run                   Only plan changes to the system, see /dry_run on the dashboard
 About:
     Version {}
     git hash {}",
@@ -113,10 +115,10 @@ About:
     );
 }
 
-use althea_kernel_interface::KernelInterface;
+use althea_kernel_interface::{dry_run_plan, KernelInterface};
 
 #[cfg(not(test))]
-use althea_kernel_interface::LinuxCommandRunner;
+use althea_kernel_interface::{enable_dry_run, new_kernel_interface};
 #[cfg(test)]
 use althea_kernel_interface::TestCommandRunner;
 
@@ -131,7 +133,7 @@ lazy_static! {
 
 #[cfg(not(test))]
 lazy_static! {
-    pub static ref KI: Box<KernelInterface> = Box::new(LinuxCommandRunner {});
+    pub static ref KI: Box<KernelInterface> = new_kernel_interface();
 }
 
 #[cfg(not(test))]
@@ -144,13 +146,25 @@ lazy_static! {
         let settings_file = args.flag_config;
         let platform = args.flag_platform;
 
-        let s = RitaSettingsStruct::new_watched(&settings_file).unwrap();
+        if args.flag_dry_run {
+            enable_dry_run();
+        }
+
+        // a dry run has to leave the config file alone, so nothing watches the settings to
+        // write changes back either
+        let s = if args.flag_dry_run {
+            Arc::new(RwLock::new(RitaSettingsStruct::new(&settings_file).unwrap()))
+        } else {
+            RitaSettingsStruct::new_watched(&settings_file).unwrap()
+        };
 
         s.set_future(args.flag_future);
 
         clu::init(&platform, s.clone());
 
-        s.read().unwrap().write(&settings_file).unwrap();
+        if !args.flag_dry_run {
+            s.read().unwrap().write(&settings_file).unwrap();
+        }
         s
     };
 }
@@ -193,6 +207,13 @@ fn main() {
     );
     trace!("Starting with Identity: {:?}", SETTING.get_identity());
 
+    if args.flag_dry_run {
+        println!("Dry run, commands planned during startup:");
+        for command in dry_run_plan() {
+            println!("    {}", command);
+        }
+    }
+
     let system = actix::System::new(format!("main {:?}", SETTING.get_network().mesh_ip));
 
     assert!(rita_common::debt_keeper::DebtKeeper::from_registry().connected());
@@ -201,6 +222,10 @@ fn main() {
     assert!(rita_common::http_client::HTTPClient::from_registry().connected());
     assert!(rita_common::traffic_watcher::TrafficWatcher::from_registry().connected());
     assert!(rita_common::peer_listener::PeerListener::from_registry().connected());
+    assert!(rita_common::stats_collector::StatsCollector::from_registry().connected());
+    assert!(rita_common::prober::Prober::from_registry().connected());
+    assert!(rita_common::fraud_detector::FraudDetector::from_registry().connected());
+    assert!(rita_common::babel_listener::BabelListener::from_registry().connected());
     assert!(rita_client::exit_manager::ExitManager::from_registry().connected());
 
     // rita
@@ -237,6 +262,7 @@ fn main() {
             .route("/exits/{name}/register", Method::POST, register_to_exit)
             .route("/exits/{name}/reset", Method::POST, reset_exit)
             .route("/exits/{name}/select", Method::POST, select_exit)
+            .route("/lan_devices", Method::GET, get_lan_devices)
             .route("/local_fee", Method::GET, get_local_fee)
             .route("/local_fee/{fee}", Method::POST, set_local_fee)
             .route("/metric_factor", Method::GET, get_metric_factor)
@@ -259,11 +285,20 @@ fn main() {
                 "/remote_logging/level/{level}",
                 Method::POST,
                 remote_logging_level,
-            ).route("/settings", Method::GET, get_settings)
+            ).route("/router_status", Method::GET, get_router_status)
+            .route("/settings", Method::GET, get_settings)
             .route("/settings", Method::POST, set_settings)
+            .route("/stats", Method::GET, get_stats)
+            .route("/probes", Method::GET, get_probes)
+            .route("/fraud", Method::GET, get_fraud_suspects)
+            .route("/tunnels/mtu", Method::GET, get_tunnel_mtus)
+            .route("/tunnels/tuning", Method::GET, get_tunnel_tunings)
+            .route("/tunnels/{iface}/tuning", Method::POST, set_tunnel_tuning)
+            .route("/dry_run", Method::GET, get_dry_run_plan)
             .route("/version", Method::GET, version)
             .route("/wifi_settings/pass", Method::POST, set_wifi_pass)
             .route("/wifi_settings/ssid", Method::POST, set_wifi_ssid)
+            .route("/wifi_settings/survey", Method::GET, get_wifi_survey)
             .route("/wifi_settings", Method::GET, get_wifi_config)
             .route("/wipe", Method::POST, wipe)
     }).workers(1)
@@ -61,6 +61,7 @@ extern crate reqwest;
 extern crate serde;
 extern crate settings;
 extern crate tokio;
+extern crate tokio_codec;
 extern crate trust_dns_resolver;
 
 use settings::{RitaCommonSettings, RitaExitSettings, RitaExitSettingsStruct};
@@ -98,14 +99,16 @@ use std::sync::Mutex;
 struct Args {
     flag_config: String,
     flag_future: bool,
+    flag_dry_run: bool,
 }
 
 lazy_static! {
     static ref USAGE: String = format!(
-        "Usage: rita_exit --config=<settings>
+        "Usage: rita_exit --config=<settings> [--future] [--dry-run]
 Options:
     -c, --config=<settings>   Name of config file
     --future                    Enable B side of A/B releases
+    --dry-run                   Only plan changes to the system, see /dry_run on the dashboard
 About:
     Version {}
     git hash {}",
@@ -114,10 +117,10 @@ About:
     );
 }
 
-use althea_kernel_interface::KernelInterface;
+use althea_kernel_interface::{dry_run_plan, KernelInterface};
 
 #[cfg(not(test))]
-use althea_kernel_interface::LinuxCommandRunner;
+use althea_kernel_interface::{enable_dry_run, new_kernel_interface};
 #[cfg(test)]
 use althea_kernel_interface::TestCommandRunner;
 
@@ -132,7 +135,7 @@ lazy_static! {
 
 #[cfg(not(test))]
 lazy_static! {
-    pub static ref KI: Box<KernelInterface> = Box::new(LinuxCommandRunner {});
+    pub static ref KI: Box<KernelInterface> = new_kernel_interface();
 }
 
 #[cfg(not(test))]
@@ -144,13 +147,25 @@ lazy_static! {
 
         let settings_file = args.flag_config;
 
-        let s = RitaExitSettingsStruct::new_watched(&settings_file).unwrap();
+        if args.flag_dry_run {
+            enable_dry_run();
+        }
+
+        // a dry run has to leave the config file alone, so nothing watches the settings to
+        // write changes back either
+        let s = if args.flag_dry_run {
+            Arc::new(RwLock::new(RitaExitSettingsStruct::new(&settings_file).unwrap()))
+        } else {
+            RitaExitSettingsStruct::new_watched(&settings_file).unwrap()
+        };
 
         s.set_future(args.flag_future);
 
         clu::exit_init("linux", s.clone());
 
-        s.read().unwrap().write(&settings_file).unwrap();
+        if !args.flag_dry_run {
+            s.read().unwrap().write(&settings_file).unwrap();
+        }
 
         s
     };
@@ -191,6 +206,13 @@ fn main() {
     );
     trace!("Starting with Identity: {:?}", SETTING.get_identity());
 
+    if args.flag_dry_run {
+        println!("Dry run, commands planned during startup:");
+        for command in dry_run_plan() {
+            println!("    {}", command);
+        }
+    }
+
     let system = actix::System::new(format!("main {:?}", SETTING.get_network().mesh_ip));
 
     assert!(rita_common::debt_keeper::DebtKeeper::from_registry().connected());
@@ -199,6 +221,10 @@ fn main() {
     assert!(rita_common::http_client::HTTPClient::from_registry().connected());
     assert!(rita_common::traffic_watcher::TrafficWatcher::from_registry().connected());
     assert!(rita_common::peer_listener::PeerListener::from_registry().connected());
+    assert!(rita_common::stats_collector::StatsCollector::from_registry().connected());
+    assert!(rita_common::prober::Prober::from_registry().connected());
+    assert!(rita_common::fraud_detector::FraudDetector::from_registry().connected());
+    assert!(rita_common::babel_listener::BabelListener::from_registry().connected());
 
     assert!(rita_exit::traffic_watcher::TrafficWatcher::from_registry().connected());
     assert!(rita_exit::db_client::DbClient::from_registry().connected());
@@ -249,6 +275,13 @@ fn main() {
             .route("/metric_factor/{factor}", Method::POST, set_metric_factor)
             .route("/settings", Method::GET, get_settings)
             .route("/settings", Method::POST, set_settings)
+            .route("/stats", Method::GET, get_stats)
+            .route("/probes", Method::GET, get_probes)
+            .route("/fraud", Method::GET, get_fraud_suspects)
+            .route("/tunnels/mtu", Method::GET, get_tunnel_mtus)
+            .route("/tunnels/tuning", Method::GET, get_tunnel_tunings)
+            .route("/tunnels/{iface}/tuning", Method::POST, set_tunnel_tuning)
+            .route("/dry_run", Method::GET, get_dry_run_plan)
             .route("/version", Method::GET, version)
             .route("/wipe", Method::POST, wipe)
             .route("/database", Method::DELETE, nuke_db)
@@ -270,7 +303,7 @@ fn main() {
     let common = rita_common::rita_loop::RitaLoop::new();
     let _: Addr<_> = common.start();
 
-    let exit = rita_exit::rita_loop::RitaLoop {};
+    let exit = rita_exit::rita_loop::RitaLoop::default();
     let _: Addr<_> = exit.start();
 
     system.run();
@@ -4,9 +4,10 @@ The Exit info endpoint gathers infromation about exit status and presents it to
 
 use actix::prelude::*;
 use failure::Error;
-use std::net::{SocketAddr, TcpStream};
+use futures::Future;
 
-use babel_monitor::Babel;
+use babel_monitor::do_we_have_route;
+use rita_common::babel_listener::get_babel_table;
 use rita_common::dashboard::Dashboard;
 use settings::ExitServer;
 use settings::RitaClientSettings;
@@ -22,6 +23,8 @@ pub struct ExitInfo {
     have_route: bool,
     is_reachable: bool,
     is_tunnel_working: bool,
+    /// The mtu of wg_exit, only for the selected exit
+    mtu: Option<u32>,
 }
 
 pub struct GetExitInfo;
@@ -54,46 +57,49 @@ fn is_tunnel_working(exit: &ExitServer, current_exit: Option<&ExitServer>) -> bo
 }
 
 impl Handler<GetExitInfo> for Dashboard {
-    type Result = Result<Vec<ExitInfo>, Error>;
+    type Result = ResponseFuture<Vec<ExitInfo>, Error>;
 
     fn handle(&mut self, _msg: GetExitInfo, _ctx: &mut Self::Context) -> Self::Result {
-        let stream = TcpStream::connect::<SocketAddr>(
-            format!("[::1]:{}", SETTING.get_network().babel_port).parse()?,
-        )?;
-        let mut babel = Babel::new(stream);
-        babel.start_connection()?;
-        let route_table_sample = babel.parse_routes()?;
-
-        let mut output = Vec::new();
-
-        let exit_client = SETTING.get_exit_client();
-        let current_exit = exit_client.get_current_exit();
-
-        for exit in exit_client.exits.clone().into_iter() {
-            let selected = is_selected(&exit.1, current_exit);
-            let have_route = babel.do_we_have_route(&exit.1.id.mesh_ip, &route_table_sample)?;
-
-            // failed pings block for one second, so we should be sure it's at least reasonable
-            // to expect the pings to work before issuing them.
-            let reachable = match have_route {
-                true => KI.ping_check_v6(&exit.1.id.mesh_ip)?,
-                false => false,
-            };
-            let tunnel_working = match (have_route, selected) {
-                (true, true) => is_tunnel_working(&exit.1, current_exit),
-                _ => false,
-            };
-
-            output.push(ExitInfo {
-                nickname: exit.0,
-                exit_settings: exit.1.clone(),
-                is_selected: selected,
-                have_route: have_route,
-                is_reachable: reachable,
-                is_tunnel_working: tunnel_working,
-            })
-        }
-
-        Ok(output)
+        Box::new(get_babel_table().and_then(|babel| {
+            let route_table_sample = babel.routes();
+
+            let mut output = Vec::new();
+
+            let exit_client = SETTING.get_exit_client();
+            let current_exit = exit_client.get_current_exit();
+
+            for exit in exit_client.exits.clone().into_iter() {
+                let selected = is_selected(&exit.1, current_exit);
+                let have_route = do_we_have_route(&exit.1.id.mesh_ip, &route_table_sample);
+
+                // failed pings block for one second, so we should be sure it's at least reasonable
+                // to expect the pings to work before issuing them.
+                let reachable = match have_route {
+                    true => KI.ping_check_v6(&exit.1.id.mesh_ip)?,
+                    false => false,
+                };
+                let tunnel_working = match (have_route, selected) {
+                    (true, true) => is_tunnel_working(&exit.1, current_exit),
+                    _ => false,
+                };
+
+                let mtu = match selected {
+                    true => KI.get_mtu("wg_exit").ok(),
+                    false => None,
+                };
+
+                output.push(ExitInfo {
+                    nickname: exit.0,
+                    exit_settings: exit.1.clone(),
+                    is_selected: selected,
+                    have_route: have_route,
+                    is_reachable: reachable,
+                    is_tunnel_working: tunnel_working,
+                    mtu: mtu,
+                })
+            }
+
+            Ok(output)
+        }))
     }
 }
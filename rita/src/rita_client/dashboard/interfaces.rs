@@ -17,6 +17,8 @@ use settings::RitaCommonSettings;
 use KI;
 use SETTING;
 
+use althea_kernel_interface::{UciBatch, UciValue};
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct InterfaceToSet {
     pub interface: String,
@@ -63,19 +65,17 @@ pub fn get_interfaces() -> Result<HashMap<String, InterfaceMode>, Error> {
     let mut retval = HashMap::new();
 
     // Wired
-    for (setting_name, value) in KI.uci_show(Some("network"))? {
+    let network = KI.uci_get_package("network")?;
+    for section in network.sections.iter() {
+        let ifnames = match section.get("ifname") {
+            Some(value) => split_ifnames(value),
+            None => continue,
+        };
         // Only non-loopback non-bridge interface names should get past
-        if setting_name.contains("ifname") && !value.contains("backhaul") && value != "lo" {
-            // it's a list and we need to handle that
-            if value.contains(",") {
-                for list_member in value.split(",") {
-                    retval.insert(
-                        list_member.replace(" ", "").to_string(),
-                        ethernet2mode(&value, &setting_name)?,
-                    );
-                }
-            } else {
-                retval.insert(value.clone(), ethernet2mode(&value, &setting_name)?);
+        for ifname in ifnames {
+            if !ifname.contains("backhaul") && ifname != "lo" {
+                let mode = ethernet2mode(&ifname, &section.name)?;
+                retval.insert(ifname, mode);
             }
         }
     }
@@ -95,16 +95,28 @@ pub fn get_interfaces() -> Result<HashMap<String, InterfaceMode>, Error> {
     Ok(retval)
 }
 
-/// Find out a wired interface's mode (mesh, LAN, WAN) from the setting name
-pub fn ethernet2mode(ifname: &str, setting_name: &str) -> Result<InterfaceMode, Error> {
+/// The interface names in a network section's ifname, netifd takes either a list or a space
+/// separated option. Older versions of the dashboard wrote comma separated options
+pub fn split_ifnames(value: &UciValue) -> Vec<String> {
+    value
+        .values()
+        .iter()
+        .flat_map(|v| v.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|ifname| !ifname.is_empty())
+        .map(|ifname| ifname.to_string())
+        .collect()
+}
+
+/// Find out a wired interface's mode (mesh, LAN, WAN) from the name of its network section
+pub fn ethernet2mode(ifname: &str, section_name: &str) -> Result<InterfaceMode, Error> {
     trace!(
-        "ethernet2mode: ifname {:?}, setting_name {:?}",
+        "ethernet2mode: ifname {:?}, section_name {:?}",
         ifname,
-        setting_name
+        section_name
     );
 
     // Match parent section name
-    Ok(match &setting_name.replace(".ifname", "") {
+    Ok(match section_name {
         s if s.contains("rita_") => InterfaceMode::Mesh,
         s if s.contains("lan") => InterfaceMode::LAN,
         s if s.contains("backhaul") => InterfaceMode::WAN,
@@ -244,32 +256,34 @@ pub fn ethernet_transform_mode(
         bail!("We can't change Unknown interfaces!");
     }
 
-    // if we have edited UCI and it fails we set this var to handle cleanup later
-    let mut return_codes = Vec::new();
-    let mut mesh_add = false;
+    let network = KI.uci_get_package("network")?;
+    let lan_ifnames = match network.get("lan", "ifname") {
+        Some(value) => split_ifnames(value),
+        None => Vec::new(),
+    };
+    // leaving the old mode and entering the new one are applied together, if entering fails
+    // the interface keeps its old mode rather than being left with none
+    let mut batch = UciBatch::new();
     let filtered_ifname = format!("network.rita_{}", ifname.replace(".", ""));
 
     match a {
         // Wan is very simple, just delete it
         InterfaceMode::WAN => {
-            let ret = KI.del_uci_var("network.backhaul");
-            SETTING.get_network_mut().external_nic = None;
-            return_codes.push(ret);
+            batch.delete("network.backhaul");
         }
         // lan is a little more complicated, wifi interfaces
         // may depend on it so we only remove the ifname entry
         InterfaceMode::LAN => {
-            let list = KI.get_uci_var("network.lan.ifname")?;
-            let new_list = comma_list_remove(&list, ifname);
-            let ret = KI.set_uci_var("network.lan.ifname", &new_list);
-            return_codes.push(ret);
+            let new_list: Vec<&str> = lan_ifnames
+                .iter()
+                .map(|i| i.as_str())
+                .filter(|i| *i != ifname)
+                .collect();
+            batch.set("network.lan.ifname", &new_list.join(" "));
         }
-        // for mesh we need to send an unlisten so that Rita stops
-        // listening then we can remove the section
+        // for mesh we can just remove the section, Rita stops listening once it's applied
         InterfaceMode::Mesh => {
-            PeerListener::from_registry().do_send(UnListen(ifname.clone().to_string()));
-            let ret = KI.del_uci_var(&filtered_ifname);
-            return_codes.push(ret);
+            batch.delete(&filtered_ifname);
         }
         InterfaceMode::Meshpoint => unimplemented!(),
         InterfaceMode::Unknown => unimplemented!(),
@@ -278,78 +292,47 @@ pub fn ethernet_transform_mode(
     match b {
         // here we add back all the properties of backhaul we removed
         InterfaceMode::WAN => {
-            SETTING.get_network_mut().external_nic = Some(ifname.to_string());
-            let ret = KI.set_uci_var("network.backhaul", "interface");
-            return_codes.push(ret);
-            let ret = KI.set_uci_var("network.backhaul.ifname", ifname);
-            return_codes.push(ret);
-            let ret = KI.set_uci_var("network.backhaul.proto", "dhcp");
-            return_codes.push(ret);
+            batch
+                .set("network.backhaul", "interface")
+                .set("network.backhaul.ifname", ifname)
+                .set("network.backhaul.proto", "dhcp");
         }
         // since we left lan mostly unomidifed we just pop in the ifname
         InterfaceMode::LAN => {
-            trace!("Converting interface to lan with ifname {:?}", ifname);
-            let ret = KI.get_uci_var("network.lan.ifname");
-            match ret {
-                Ok(list) => {
-                    trace!("The existing LAN interfaces list is {:?}", list);
-                    let new_list = comma_list_add(&list, &ifname);
-                    trace!("Setting the new list {:?}", new_list);
-                    let ret = KI.set_uci_var("network.lan.ifname", &new_list);
-                    return_codes.push(ret);
-                }
-                Err(e) => {
-                    if e.to_string().contains("Entry not found") {
-                        trace!("No LAN interfaces found, setting one now");
-                        let ret = KI.set_uci_var("network.lan.ifname", &ifname);
-                        return_codes.push(ret);
-                    } else {
-                        warn!("Trying to read lan ifname returned {:?}", e);
-                        return_codes.push(Err(e));
-                    }
-                }
-            }
+            trace!("The existing LAN interfaces list is {:?}", lan_ifnames);
+            let mut new_list: Vec<&str> = lan_ifnames.iter().map(|i| i.as_str()).collect();
+            new_list.push(ifname);
+            trace!("Setting the new list {:?}", new_list);
+            batch.set("network.lan.ifname", &new_list.join(" "));
         }
-        // next we do some magic to listen on the interface after a minute
         InterfaceMode::Mesh => {
-            let ret = KI.set_uci_var(&filtered_ifname, "interface");
-            return_codes.push(ret);
-            let ret = KI.set_uci_var(&format!("{}.ifname", filtered_ifname), ifname);
-            return_codes.push(ret);
-            let ret = KI.set_uci_var(&format!("{}.proto", filtered_ifname), "static");
-            return_codes.push(ret);
-            mesh_add = true;
+            batch
+                .set(&filtered_ifname, "interface")
+                .set(&format!("{}.ifname", filtered_ifname), ifname)
+                .set(&format!("{}.proto", filtered_ifname), "static");
         }
         InterfaceMode::Meshpoint => unimplemented!(),
         InterfaceMode::Unknown => unimplemented!(),
     }
 
-    // check all of our return codes in order to handle any possible issue
-    let mut error_occured = false;
-    for ret in return_codes {
-        if ret.is_err() {
-            error_occured = true;
+    KI.uci_apply(&batch)?;
+
+    match a {
+        InterfaceMode::WAN => SETTING.get_network_mut().external_nic = None,
+        InterfaceMode::Mesh => {
+            PeerListener::from_registry().do_send(UnListen(ifname.to_string()));
         }
+        _ => {}
     }
-    if error_occured {
-        let res = KI.uci_revert("network");
-        bail!("Error running UCI commands! Revert attempted: {:?}", res);
-    } else if mesh_add {
-        let when = Instant::now() + Duration::from_millis(60000);
-        let locally_owned_ifname = ifname.clone().to_string();
-
-        let fut = Delay::new(when)
-            .map_err(|e| warn!("timer failed; err={:?}", e))
-            .and_then(move |_| {
-                trace!("Adding mesh interface {:?}", locally_owned_ifname);
-                PeerListener::from_registry().do_send(Listen(locally_owned_ifname));
-                Ok(())
-            });
-
-        Arbiter::spawn(fut);
+    match b {
+        InterfaceMode::WAN => {
+            SETTING.get_network_mut().external_nic = Some(ifname.to_string());
+        }
+        // next we do some magic to listen on the interface after a minute
+        InterfaceMode::Mesh => listen_later(ifname),
+        _ => {}
     }
 
-    KI.uci_commit(&"network")?;
     KI.openwrt_reset_network()?;
 
     // We edited disk contents, force global sync
@@ -376,10 +359,6 @@ pub fn wlan_transform_mode(ifname: &str, a: InterfaceMode, b: InterfaceMode) ->
         bail!("WAN not supported for wlan interfaces!");
     }
 
-    // if we have edited UCI and it fails we set this var to handle cleanup later
-    let mut return_codes = Vec::new();
-    let mut mesh_add = false;
-
     // we assume wlan0 => radio0 this is held true by our config
     // modifications but is not generally true for OpenWRT
     let radio = match ifname.chars().last() {
@@ -395,15 +374,18 @@ pub fn wlan_transform_mode(ifname: &str, a: InterfaceMode, b: InterfaceMode) ->
         _ => bail!("wlan name not considered in design!"),
     };
 
+    let wireless = KI.uci_get_package("wireless")?;
+    // nothing is applied until the batch is complete, so the meshpoint checks below can still
+    // bail without leaving a radio half reconfigured
+    let mut batch = UciBatch::new();
+
     match a {
         InterfaceMode::WAN => unimplemented!(),
         // nothing to do here we overwrite everything we need later
         InterfaceMode::LAN => {}
-        // for mesh we need to send an unlisten and delete the static interface we made
+        // for mesh we need to disable the meshpoint interface
         InterfaceMode::Meshpoint => {
-            let ret = KI.set_uci_var(&format!("wireless.mesh_{}.disabled", radio), "1");
-            return_codes.push(ret);
-            PeerListener::from_registry().do_send(UnListen(mesh_wlan.to_string()));
+            batch.set(&format!("wireless.mesh_{}.disabled", radio), "1");
         }
         InterfaceMode::Mesh => unimplemented!(),
         InterfaceMode::Unknown => unimplemented!(),
@@ -413,81 +395,45 @@ pub fn wlan_transform_mode(ifname: &str, a: InterfaceMode, b: InterfaceMode) ->
         InterfaceMode::WAN => unimplemented!(),
         // since we left lan mostly unomidifed we just pop in the ifname
         InterfaceMode::LAN => {
-            let ret = KI.set_uci_var(&format!("wireless.{}.network", network_section), "lan");
-            return_codes.push(ret);
-            let ret = KI.set_uci_var(&format!("wireless.{}.mode", network_section), "ap");
-            return_codes.push(ret);
-            let ret = KI.set_uci_var(&format!("wireless.{}.ssid", network_section), "AltheaHome");
-            return_codes.push(ret);
-            let ret = KI.set_uci_var(
-                &format!("wireless.{}.encryption", network_section),
-                "psk2+tkip+aes",
-            );
-            return_codes.push(ret);
-            let ret = KI.set_uci_var(&format!("wireless.{}.key", network_section), "ChangeMe");
-            return_codes.push(ret);
+            batch
+                .set(&format!("wireless.{}.network", network_section), "lan")
+                .set(&format!("wireless.{}.mode", network_section), "ap")
+                .set(&format!("wireless.{}.ssid", network_section), "AltheaHome")
+                .set(
+                    &format!("wireless.{}.encryption", network_section),
+                    "psk2+tkip+aes",
+                )
+                .set(&format!("wireless.{}.key", network_section), "ChangeMe");
         }
-        // in this section we modfiy the wlan config to mesh and then add a static logical iface
-        // that is used for things like ip assignment etc
+        // in this section we modfiy the wlan config to mesh
         InterfaceMode::Meshpoint => {
-            let val = KI.get_uci_var(&format!("wireless.mesh_{}.disabled", radio));
-            match val {
-                Ok(status) => match status.as_str() {
-                    "0" => {
-                        warn!("You can't meshpoint both wireless interfaces!");
-                        return_codes.push(Err(format_err!(
-                            "You can't meshpoint both wireless interfaces!"
-                        )));
-                    }
-                    "1" => {
-                        let ret = KI.set_uci_var(&format!("wireless.mesh_{}.disabled", radio), "0");
-                        return_codes.push(ret);
-                        mesh_add = true;
-                    }
-                    _ => {
-                        error!("Deivce is not meshpoint enabled?");
-                        return_codes.push(Err(format_err!("Device may not be meshpoint enabled!")));
-                    }
-                },
-                Err(e) => return_codes.push(Err(e)),
+            let mesh_section = format!("mesh_{}", radio);
+            match wireless.get(&mesh_section, "disabled") {
+                Some(&UciValue::Option(ref status)) if status == "0" => {
+                    bail!("You can't meshpoint both wireless interfaces!");
+                }
+                Some(&UciValue::Option(ref status)) if status == "1" => {
+                    batch.set(&format!("wireless.{}.disabled", mesh_section), "0");
+                }
+                _ => {
+                    error!("Deivce is not meshpoint enabled?");
+                    bail!("Device may not be meshpoint enabled!");
+                }
             }
         }
         InterfaceMode::Mesh => unimplemented!(),
         InterfaceMode::Unknown => unimplemented!(),
     }
 
-    // check all of our return codes in order to handle any possible issue
-    let mut error_occured = false;
-    for ret in return_codes {
-        if ret.is_err() {
-            error_occured = true;
-        }
+    KI.uci_apply(&batch)?;
+
+    if a == InterfaceMode::Meshpoint {
+        PeerListener::from_registry().do_send(UnListen(mesh_wlan.to_string()));
     }
-    if error_occured {
-        let res_a = KI.uci_revert("network");
-        let res_b = KI.uci_revert("wireless");
-        bail!(
-            "Error running UCI commands! Revert attempted: {:?} {:?}",
-            res_a,
-            res_b
-        );
-    } else if mesh_add {
-        let when = Instant::now() + Duration::from_millis(60000);
-        let locally_owned_ifname = mesh_wlan.clone().to_string();
-
-        let fut = Delay::new(when)
-            .map_err(|e| warn!("timer failed; err={:?}", e))
-            .and_then(move |_| {
-                trace!("Adding mesh interface {:?}", locally_owned_ifname);
-                PeerListener::from_registry().do_send(Listen(locally_owned_ifname));
-                Ok(())
-            });
-
-        Arbiter::spawn(fut);
+    if b == InterfaceMode::Meshpoint {
+        listen_later(mesh_wlan);
     }
 
-    KI.uci_commit(&"wireless")?;
-    KI.uci_commit(&"network")?;
     KI.openwrt_reset_network()?;
     KI.openwrt_reset_wireless()?;
 
@@ -497,38 +443,21 @@ pub fn wlan_transform_mode(ifname: &str, a: InterfaceMode, b: InterfaceMode) ->
     Ok(())
 }
 
-/// A helper function for adding entires to a comma deliminated list
-pub fn comma_list_add(list: &str, entry: &str) -> String {
-    if list.len() > 0 {
-        format!("{}, {}", list, entry)
-    } else {
-        entry.to_string()
-    }
-}
-
-/// A helper function for removing entires to a comma deliminated list
-pub fn comma_list_remove(list: &str, entry: &str) -> String {
-    if list.len() > 0 {
-        let split = list.split(",");
-        let mut new_list = "".to_string();
-        let mut first = true;
-        for item in split {
-            let filtered_item = item.trim();
-            if !item.contains(entry) {
-                trace!("{} is not {} it's on the list!", filtered_item, entry);
-                let tmp_list = new_list.to_string();
-                if first {
-                    new_list = tmp_list + &format!("{}", filtered_item);
-                    first = false;
-                } else {
-                    new_list = tmp_list + &format!(", {}", filtered_item);
-                }
-            }
-        }
-        new_list.to_string()
-    } else {
-        "".to_string()
-    }
+/// Listens for peers on an interface after a minute, giving the network restart time to
+/// bring it up
+fn listen_later(ifname: &str) {
+    let when = Instant::now() + Duration::from_millis(60000);
+    let locally_owned_ifname = ifname.to_string();
+
+    let fut = Delay::new(when)
+        .map_err(|e| warn!("timer failed; err={:?}", e))
+        .and_then(move |_| {
+            trace!("Adding mesh interface {:?}", locally_owned_ifname);
+            PeerListener::from_registry().do_send(Listen(locally_owned_ifname));
+            Ok(())
+        });
+
+    Arbiter::spawn(fut);
 }
 
 pub fn get_current_interface_mode(
@@ -550,36 +479,16 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_comma_list_remove() {
-        let a = "eth0.3, eth1, eth2, eth3, eth4";
-
-        let b = comma_list_remove(a, "eth1");
-        assert_eq!(b, "eth0.3, eth2, eth3, eth4");
-
-        let b = comma_list_remove(&b, "eth0.3");
-        assert_eq!(b, "eth2, eth3, eth4");
-
-        let b = comma_list_remove(&b, "eth4");
-        assert_eq!(b, "eth2, eth3");
-
-        let b = comma_list_remove(&b, "eth2");
-        assert_eq!(b, "eth3");
-
-        let b = comma_list_remove(&b, "eth3");
-        assert_eq!(b, "");
-    }
-
-    #[test]
-    fn test_comma_list_add() {
-        let a = "";
+    fn test_split_ifnames() {
+        let value = UciValue::Option("eth0.1 eth1".to_string());
+        assert_eq!(split_ifnames(&value), vec!["eth0.1", "eth1"]);
 
-        let b = comma_list_add(a, "eth1");
-        assert_eq!(b, "eth1");
+        let value = UciValue::Option("eth0.3, eth1, eth2".to_string());
+        assert_eq!(split_ifnames(&value), vec!["eth0.3", "eth1", "eth2"]);
 
-        let b = comma_list_add(&b, "eth0.3");
-        assert_eq!(b, "eth1, eth0.3");
+        let value = UciValue::List(vec!["eth0".to_string(), "eth2 eth3".to_string()]);
+        assert_eq!(split_ifnames(&value), vec!["eth0", "eth2", "eth3"]);
 
-        let b = comma_list_add(&b, "eth4");
-        assert_eq!(b, "eth1, eth0.3, eth4");
+        assert!(split_ifnames(&UciValue::Option("".to_string())).is_empty());
     }
 }
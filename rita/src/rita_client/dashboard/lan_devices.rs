@@ -0,0 +1,33 @@
+/*
+The lan devices endpoint lists the devices behind this router and how much each of them is using
+*/
+
+use actix::prelude::*;
+use failure::Error;
+
+use althea_kernel_interface::LanDevice;
+use rita_common::dashboard::Dashboard;
+use settings::RitaClientSettings;
+use KI;
+use SETTING;
+
+/// The lan bridge OpenWrt sets up, used when no lan_nics are configured
+static DEFAULT_LAN_NIC: &'static str = "br-lan";
+
+pub struct GetLanDevices;
+
+impl Message for GetLanDevices {
+    type Result = Result<Vec<LanDevice>, Error>;
+}
+
+impl Handler<GetLanDevices> for Dashboard {
+    type Result = Result<Vec<LanDevice>, Error>;
+    fn handle(&mut self, _msg: GetLanDevices, _ctx: &mut Self::Context) -> Self::Result {
+        let mut lan_nics: Vec<String> =
+            SETTING.get_exit_client().lan_nics.iter().cloned().collect();
+        if lan_nics.is_empty() {
+            lan_nics.push(DEFAULT_LAN_NIC.to_string());
+        }
+        KI.get_lan_devices(&lan_nics)
+    }
+}
@@ -6,6 +6,8 @@
 
 pub mod exitinfo;
 pub mod interfaces;
+pub mod lan_devices;
 pub mod network_endpoints;
 pub mod nodeinfo;
+pub mod router_status;
 pub mod wifi;
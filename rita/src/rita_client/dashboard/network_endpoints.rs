@@ -8,11 +8,16 @@ use futures::Future;
 use log::LevelFilter;
 use reqwest;
 
+use althea_kernel_interface::LanDevice;
 use althea_types::ExitState;
 use rita_client::dashboard::exitinfo::{ExitInfo, GetExitInfo};
 use rita_client::dashboard::interfaces::{GetInterfaces, InterfaceMode, InterfaceToSet};
+use rita_client::dashboard::lan_devices::GetLanDevices;
 use rita_client::dashboard::nodeinfo::{GetNodeInfo, NodeInfo};
-use rita_client::dashboard::wifi::{GetWifiConfig, WifiInterface, WifiPass, WifiSSID};
+use rita_client::dashboard::router_status::{GetRouterStatus, RouterStatus};
+use rita_client::dashboard::wifi::{
+    GetWifiConfig, GetWifiSurvey, RadioSurvey, WifiInterface, WifiPass, WifiSSID,
+};
 use rita_client::exit_manager::exit_setup_request;
 use rita_common::dashboard::Dashboard;
 use settings::{ExitServer, RitaClientSettings, RitaCommonSettings};
@@ -336,6 +341,39 @@ pub fn get_wifi_config(
         .responder()
 }
 
+pub fn get_lan_devices(
+    _req: HttpRequest,
+) -> Box<Future<Item = Json<Vec<LanDevice>>, Error = Error>> {
+    debug!("/lan_devices hit");
+    Dashboard::from_registry()
+        .send(GetLanDevices {})
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+pub fn get_router_status(
+    _req: HttpRequest,
+) -> Box<Future<Item = Json<RouterStatus>, Error = Error>> {
+    debug!("/router_status hit");
+    Dashboard::from_registry()
+        .send(GetRouterStatus {})
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+pub fn get_wifi_survey(
+    _req: HttpRequest,
+) -> Box<Future<Item = Json<Vec<RadioSurvey>>, Error = Error>> {
+    debug!("/wifi_settings/survey hit");
+    Dashboard::from_registry()
+        .send(GetWifiSurvey {})
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
 pub fn remote_logging(path: Path<bool>) -> Box<Future<Item = HttpResponse, Error = Error>> {
     let enabled = path.into_inner();
     debug!("/loging/enable/{} hit", enabled);
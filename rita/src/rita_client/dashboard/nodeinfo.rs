@@ -6,10 +6,10 @@ use actix::prelude::*;
 use failure::Error;
 use futures::Future;
 use serde_json;
-use std::net::{SocketAddr, TcpStream};
 
-use babel_monitor::Babel;
+use babel_monitor::get_route_via_neigh;
 use num256::Int256;
+use rita_common::babel_listener::get_babel_table;
 use rita_common::dashboard::Dashboard;
 use rita_common::debt_keeper::{DebtKeeper, Dump};
 use settings::RitaClientSettings;
@@ -40,14 +40,10 @@ impl Handler<GetNodeInfo> for Dashboard {
             DebtKeeper::from_registry()
                 .send(Dump {})
                 .from_err()
-                .and_then(|res| {
+                .join(get_babel_table())
+                .and_then(|(res, babel)| {
                     let res = res?;
-                    let stream = TcpStream::connect::<SocketAddr>(
-                        format!("[::1]:{}", SETTING.get_network().babel_port).parse()?,
-                    )?;
-                    let mut babel = Babel::new(stream);
-                    babel.start_connection()?;
-                    let route_table_sample = babel.parse_routes()?;
+                    let route_table_sample = babel.routes();
 
                     let mut output = Vec::new();
 
@@ -57,11 +53,8 @@ impl Handler<GetNodeInfo> for Dashboard {
                     for (identity, debt_info) in res.iter() {
                         if current_exit.is_some() {
                             let exit_ip = current_exit.unwrap().id.mesh_ip;
-                            let maybe_route = babel.get_route_via_neigh(
-                                identity.mesh_ip,
-                                exit_ip,
-                                &route_table_sample,
-                            );
+                            let maybe_route =
+                                get_route_via_neigh(identity.mesh_ip, exit_ip, &route_table_sample);
 
                             // We have a peer that is an exit, so we can't find a route
                             // from them to our selected exit. Other errors can also get
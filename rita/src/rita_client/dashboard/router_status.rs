@@ -0,0 +1,79 @@
+/*
+The router status endpoint reports link state, associated wifi stations and the board model
+straight from netifd and iwinfo over ubus
+*/
+
+use actix::prelude::*;
+use failure::Error;
+use std::collections::HashMap;
+
+use althea_kernel_interface::{BoardInfo, IwinfoStation, UbusDeviceStatus, UbusInterface};
+use rita_common::dashboard::Dashboard;
+use KI;
+
+#[derive(Serialize)]
+pub struct LinkStatus {
+    pub interface: UbusInterface,
+    /// The status of the device the interface is bound to, None while it has no device
+    pub device: Option<UbusDeviceStatus>,
+}
+
+#[derive(Serialize)]
+pub struct RouterStatus {
+    /// None on routers without the ubus system object
+    pub board: Option<BoardInfo>,
+    pub links: Vec<LinkStatus>,
+    /// The stations associated with each of our access points, indexed by wlan device
+    pub stations: HashMap<String, Vec<IwinfoStation>>,
+}
+
+pub struct GetRouterStatus;
+
+impl Message for GetRouterStatus {
+    type Result = Result<RouterStatus, Error>;
+}
+
+impl Handler<GetRouterStatus> for Dashboard {
+    type Result = Result<RouterStatus, Error>;
+    fn handle(&mut self, _msg: GetRouterStatus, _ctx: &mut Self::Context) -> Self::Result {
+        get_router_status()
+    }
+}
+
+pub fn get_router_status() -> Result<RouterStatus, Error> {
+    let board = match KI.ubus_system_board() {
+        Ok(board) => Some(board),
+        Err(e) => {
+            warn!("Unable to get the board info {:?}", e);
+            None
+        }
+    };
+
+    let devices = KI.ubus_all_device_status()?;
+    let mut links = Vec::new();
+    for interface in KI.ubus_interface_dump()? {
+        let device = match interface.device {
+            Some(ref name) => devices.get(name).cloned(),
+            None => None,
+        };
+        links.push(LinkStatus { interface, device });
+    }
+
+    // we hardcode wlan names so every wireless device starts with wlan
+    let mut stations = HashMap::new();
+    for name in devices.keys().filter(|name| name.starts_with("wlan")) {
+        match KI.iwinfo_assoclist(name) {
+            Ok(list) => {
+                stations.insert(name.clone(), list);
+            }
+            // radios that are down have no assoclist
+            Err(e) => trace!("No assoclist for {} {:?}", name, e),
+        }
+    }
+
+    Ok(RouterStatus {
+        board,
+        links,
+        stations,
+    })
+}
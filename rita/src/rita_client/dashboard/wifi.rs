@@ -8,9 +8,14 @@ use serde_json;
 use serde_json::Value;
 use std::collections::HashMap;
 
+use althea_kernel_interface::IwinfoBss;
 use rita_common::dashboard::Dashboard;
 use KI;
 
+/// The channels we suggest, on 2.4ghz only the three that don't overlap each other
+const CHANNELS_2GHZ: &[u16] = &[1, 6, 11];
+const CHANNELS_5GHZ: &[u16] = &[36, 40, 44, 48, 149, 153, 157, 161, 165];
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct WifiInterface {
     #[serde(default)]
@@ -41,6 +46,16 @@ pub struct WifiDevice {
     pub radio_type: String,
 }
 
+/// What a radio can hear, along with the channel we think it should use
+#[derive(Serialize, Clone, Debug)]
+pub struct RadioSurvey {
+    pub radio: String,
+    /// None when the radio picks its channel automatically
+    pub current_channel: Option<u16>,
+    pub suggested_channel: u16,
+    pub results: Vec<IwinfoBss>,
+}
+
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct WifiSSID {
     pub radio: String,
@@ -144,3 +159,128 @@ impl Handler<GetWifiConfig> for Dashboard {
         Ok(interfaces)
     }
 }
+
+pub struct GetWifiSurvey;
+
+impl Message for GetWifiSurvey {
+    type Result = Result<Vec<RadioSurvey>, Error>;
+}
+
+impl Handler<GetWifiSurvey> for Dashboard {
+    type Result = Result<Vec<RadioSurvey>, Error>;
+    fn handle(&mut self, _msg: GetWifiSurvey, _ctx: &mut Self::Context) -> Self::Result {
+        let wireless = KI.uci_get_package("wireless")?;
+        let mut surveys = Vec::new();
+        for device in wireless.sections_of_type("wifi-device") {
+            let option = |name| device.get(name).and_then(|v| v.values().first());
+            let current_channel = option("channel").and_then(|c| c.parse::<u16>().ok());
+            let five_ghz = match option("hwmode") {
+                Some(hwmode) => hwmode == "11a",
+                None => current_channel.map(|c| c > 14).unwrap_or(false),
+            };
+
+            // we assume wlan0 => radio0 this is held true by our config
+            // modifications but is not generally true for OpenWRT
+            let ifname = device.name.replace("radio", "wlan");
+            let results = match KI.wifi_scan(&ifname) {
+                Ok(results) => results,
+                Err(e) => {
+                    warn!("Wireless scan on {} failed {:?}", ifname, e);
+                    continue;
+                }
+            };
+            surveys.push(RadioSurvey {
+                radio: device.name.clone(),
+                current_channel,
+                suggested_channel: suggest_channel(&results, five_ghz),
+                results,
+            });
+        }
+        Ok(surveys)
+    }
+}
+
+/// Picks the channel with the least interference from what a scan heard. Every network counts
+/// by its received power so one strong neighbor outweighs a few distant ones, on 2.4ghz
+/// networks also count against channels they partially overlap. Althea mesh points are left
+/// out, a mesh radio has to share their channel to peer with them anyway
+pub fn suggest_channel(results: &[IwinfoBss], five_ghz: bool) -> u16 {
+    let candidates = if five_ghz {
+        CHANNELS_5GHZ
+    } else {
+        CHANNELS_2GHZ
+    };
+
+    let congestion = |channel: u16| -> f64 {
+        results
+            .iter()
+            .filter(|bss| !bss.althea_mesh)
+            .map(|bss| {
+                let distance = (i32::from(bss.channel) - i32::from(channel)).abs();
+                let overlap = if five_ghz {
+                    // 20mhz channels are 4 channel numbers apart and don't overlap
+                    if distance == 0 {
+                        1.0
+                    } else {
+                        0.0
+                    }
+                } else {
+                    // 2.4ghz channels are 5mhz apart and 22mhz wide
+                    (1.0 - f64::from(distance) / 5.0).max(0.0)
+                };
+                // dBm to milliwatts
+                overlap * 10f64.powf(f64::from(bss.signal) / 10.0)
+            })
+            .sum()
+    };
+
+    let mut best = candidates[0];
+    let mut best_congestion = congestion(best);
+    for channel in candidates.iter().cloned().skip(1) {
+        let channel_congestion = congestion(channel);
+        if channel_congestion < best_congestion {
+            best = channel;
+            best_congestion = channel_congestion;
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bss(channel: u16, signal: i32, althea_mesh: bool) -> IwinfoBss {
+        IwinfoBss {
+            ssid: Some("test".to_string()),
+            bssid: "02:00:00:00:00:01".to_string(),
+            mode: if althea_mesh { "Ad-Hoc" } else { "Master" }.to_string(),
+            channel,
+            signal,
+            althea_mesh,
+        }
+    }
+
+    #[test]
+    fn test_suggest_channel() {
+        assert_eq!(suggest_channel(&[], false), 1);
+        assert_eq!(suggest_channel(&[], true), 36);
+
+        // a strong network on 1 outweighs two weak ones on 6
+        let results = vec![bss(1, -40, false), bss(6, -85, false), bss(6, -85, false)];
+        assert_eq!(suggest_channel(&results, false), 11);
+        let results = vec![bss(1, -40, false), bss(6, -85, false), bss(11, -60, false)];
+        assert_eq!(suggest_channel(&results, false), 6);
+
+        // 3 overlaps both 1 and 6
+        let results = vec![bss(3, -50, false), bss(11, -70, false)];
+        assert_eq!(suggest_channel(&results, false), 11);
+
+        // mesh points don't count against their channel
+        let results = vec![bss(1, -40, true), bss(6, -70, false), bss(11, -70, false)];
+        assert_eq!(suggest_channel(&results, false), 1);
+
+        let results = vec![bss(36, -50, false), bss(40, -60, false)];
+        assert_eq!(suggest_channel(&results, true), 44);
+    }
+}
@@ -24,6 +24,8 @@ use std::net::IpAddr;
 
 use althea_types::{ExitClientIdentity, ExitState};
 
+use althea_kernel_interface::lan_ipv6_address;
+
 use settings::{ExitServer, RitaClientSettings, RitaCommonSettings};
 use SETTING;
 
@@ -42,7 +44,7 @@ use syslog::{init_udp, Facility};
 
 use failure::Error;
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use KI;
 
 /// enables remote logging if the user has configured it
@@ -69,9 +71,14 @@ fn enable_remote_logging(server_internal_ip: IpAddr) -> Result<(), LogError> {
     return res;
 }
 
-fn linux_setup_exit_tunnel() -> Result<(), Error> {
+/// Sets up wg_exit and routes all traffic over it, a failure undoes every change made so far
+/// so the next attempt starts from a clean slate
+/// Sets up wg_exit for the current exit, `mtu` is the one last discovered for it
+fn linux_setup_exit_tunnel(mtu: Option<u32>) -> Result<(), Error> {
     KI.update_settings_route(&mut SETTING.get_network_mut().default_route)?;
 
+    let transaction = KI.transaction();
+
     let exit_client = SETTING.get_exit_client();
     let current_exit = exit_client.get_current_exit().unwrap();
     let general_details = current_exit.info.general_details().unwrap();
@@ -86,14 +93,35 @@ fn linux_setup_exit_tunnel() -> Result<(), Error> {
         our_details.client_internal_ip,
         general_details.netmask,
         SETTING.get_network().rita_hello_port,
+        our_details.client_ipv6_prefix.is_some(),
+        mtu,
     )?;
     KI.set_route_to_tunnel(&general_details.server_internal_ip)?;
 
-    let lan_nics = &SETTING.get_exit_client().lan_nics;
-    for nic in lan_nics {
-        KI.add_client_nat_rules(&nic)?;
+    // sorted so each LAN gets the same share of the ipv6 prefix every time
+    let mut lan_nics: Vec<String> = SETTING.get_exit_client().lan_nics.iter().cloned().collect();
+    lan_nics.sort();
+    KI.set_client_nat_rules(&lan_nics)?;
+
+    let mut ipv6_nics = Vec::new();
+    if let Some(prefix) = our_details.client_ipv6_prefix {
+        KI.set_ipv6_route_to_tunnel()?;
+        for (index, nic) in lan_nics.iter().enumerate() {
+            match lan_ipv6_address(prefix, our_details.client_ipv6_prefix_len, index) {
+                Some(address) => {
+                    KI.add_client_ipv6_lan(&nic, address, 64)?;
+                    ipv6_nics.push(nic.clone());
+                }
+                None => warn!(
+                    "{}/{} has no room left for {}",
+                    prefix, our_details.client_ipv6_prefix_len, nic
+                ),
+            }
+        }
     }
+    KI.set_client_ipv6_rules(&ipv6_nics)?;
 
+    transaction.commit();
     Ok(())
 }
 
@@ -298,6 +326,10 @@ fn exit_status_request(exit: String) -> impl Future<Item = (), Error = Error> {
     Box::new(r)
 }
 
+/// How often the mtu of wg_exit is rediscovered, the path to the exit runs over the mesh so it
+/// changes with the routes
+const EXIT_MTU_CHECK_INTERVAL_SECS: u64 = 600;
+
 /// An actor which pays the exit
 #[derive(Default)]
 pub struct ExitManager {
@@ -307,6 +339,10 @@ pub struct ExitManager {
     // as that would cause a panic
     remote_logging_setting: bool,
     remote_logging_already_started: bool,
+    // when wg_exit's mtu was last discovered, None if it has been set up since
+    last_mtu_check: Option<Instant>,
+    // the mtu last discovered for the current exit, reapplied when wg_exit is set up again
+    wg_exit_mtu: Option<u32>,
 }
 
 impl Actor for ExitManager {
@@ -326,7 +362,7 @@ impl SystemService for ExitManager {
 impl Handler<Tick> for ExitManager {
     type Result = ResponseFuture<(), Error>;
 
-    fn handle(&mut self, _: Tick, _ctx: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, _: Tick, ctx: &mut Context<Self>) -> Self::Result {
         let exit_server = {
             SETTING
                 .get_exit_client()
@@ -347,17 +383,70 @@ impl Handler<Tick> for ExitManager {
                     && !(self.last_exit.is_some() && self.last_exit.clone().unwrap() == exit)
                 {
                     trace!("Exit change, setting up exit tunnel");
-                    linux_setup_exit_tunnel().expect("failure setting up exit tunnel");
+                    // the path to a new exit is different, its mtu is discovered once it's set up
+                    match linux_setup_exit_tunnel(None) {
+                        // on failure last_exit stays as is so we try again next tick
+                        Ok(()) => {
+                            self.last_exit = Some(exit.clone());
+                            self.last_mtu_check = None;
+                            self.wg_exit_mtu = None;
+                        }
+                        Err(e) => error!("Failed to set up exit tunnel, rolled back {:?}", e),
+                    }
+                } else if exit.info.our_details().is_some() {
+                    // checked in the background so a slow `ip route` doesn't hold up the tick
+                    ctx.spawn(KI.get_default_route_async().into_actor(self).then(
+                        |route, act, _ctx| {
+                            match route {
+                                Ok(Some(ref r)) if r.contains(&String::from("wg_exit")) => {}
+                                Ok(_) => {
+                                    trace!("DHCP overwrite setup exit tunnel again");
+                                    trace!("Exit change, setting up exit tunnel");
+                                    match linux_setup_exit_tunnel(act.wg_exit_mtu) {
+                                        // the path may have changed along with the route
+                                        Ok(()) => act.last_mtu_check = None,
+                                        Err(e) => error!(
+                                            "Failed to set up exit tunnel, rolled back {:?}",
+                                            e
+                                        ),
+                                    }
+                                }
+                                Err(e) => warn!("Failed to check the default route {:?}", e),
+                            }
+                            actix::fut::ok(())
+                        },
+                    ));
+                }
 
-                    self.last_exit = Some(exit.clone());
-                } else if exit.info.our_details().is_some() && !KI
-                    .get_default_route()
-                    .unwrap_or(Vec::new())
-                    .contains(&String::from("wg_exit"))
-                {
-                    trace!("DHCP overwrite setup exit tunnel again");
-                    trace!("Exit change, setting up exit tunnel");
-                    linux_setup_exit_tunnel().expect("failure setting up exit tunnel");
+                let mtu_due = match self.last_mtu_check {
+                    Some(checked) => {
+                        checked.elapsed() >= Duration::from_secs(EXIT_MTU_CHECK_INTERVAL_SECS)
+                    }
+                    None => true,
+                };
+                if self.last_exit.is_some() && mtu_due {
+                    self.last_mtu_check = Some(Instant::now());
+                    // wg_exit's packets go to the exit's mesh ip, so the path is every tunnel
+                    // on the way there
+                    ctx.spawn(
+                        KI.discover_tunnel_mtu_async(exit.id.mesh_ip, None)
+                            .into_actor(self)
+                            .then(|res, act, _ctx| {
+                                match res {
+                                    Ok(mtu) => {
+                                        act.wg_exit_mtu = Some(mtu);
+                                        match KI.set_mtu("wg_exit", mtu) {
+                                            Ok(()) => info!("Set the mtu of wg_exit to {}", mtu),
+                                            Err(e) => {
+                                                warn!("Failed to set the mtu of wg_exit {:?}", e)
+                                            }
+                                        }
+                                    }
+                                    Err(e) => warn!("Failed to find the mtu for wg_exit {:?}", e),
+                                }
+                                actix::fut::ok(())
+                            }),
+                    );
                 }
 
                 // enable remote logging only if it has not already been started
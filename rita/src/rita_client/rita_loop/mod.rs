@@ -10,6 +10,10 @@ use actix::prelude::*;
 use actix::registry::SystemService;
 
 use rita_client::exit_manager::ExitManager;
+use rita_common::prober::{Prober, SetExitTarget};
+
+use settings::RitaClientSettings;
+use SETTING;
 
 use failure::Error;
 
@@ -48,6 +52,15 @@ impl Handler<Tick> for RitaLoop {
                 }),
         );
 
+        let exit_ip = match SETTING.get_exit_client().get_current_exit() {
+            Some(exit) => exit
+                .info
+                .general_details()
+                .map(|details| details.server_internal_ip),
+            None => None,
+        };
+        Prober::from_registry().do_send(SetExitTarget(exit_ip));
+
         info!(
             "Rita Client loop completed in {}s {}ms",
             start.elapsed().as_secs(),
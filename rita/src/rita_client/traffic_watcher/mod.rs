@@ -12,14 +12,17 @@ use ipnetwork::IpNetwork;
 use reqwest;
 
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::net::IpAddr;
 use std::time::{Duration, SystemTime};
 
+use althea_kernel_interface::wg_iface_counter::WgUsage;
 use althea_types::{Identity, RTTimestamps};
-use babel_monitor::Babel;
+use babel_monitor::BabelTable;
+use futures::Future;
 use num256::Int256;
+use rita_common::babel_listener::get_babel_table;
 use rita_common::debt_keeper::{DebtKeeper, TrafficUpdate};
+use rita_common::fraud_detector::{ExitRound, FraudDetector};
 use settings::{RitaClientSettings, RitaCommonSettings};
 use KI;
 use SETTING;
@@ -27,6 +30,9 @@ use SETTING;
 pub struct TrafficWatcher {
     last_read_input: u64,
     last_read_output: u64,
+    /// Whether a watch is still running, two at once would both bill the exit for everything
+    /// since the same last reads
+    watching: bool,
 }
 
 impl Actor for TrafficWatcher {
@@ -45,6 +51,7 @@ impl Default for TrafficWatcher {
         TrafficWatcher {
             last_read_input: 0,
             last_read_output: 0,
+            watching: false,
         }
     }
 }
@@ -56,29 +63,51 @@ impl Message for Watch {
 }
 
 impl Handler<Watch> for TrafficWatcher {
-    type Result = Result<(), Error>;
+    type Result = ResponseActFuture<Self, (), Error>;
 
     fn handle(&mut self, msg: Watch, _: &mut Context<Self>) -> Self::Result {
-        let stream = TcpStream::connect::<SocketAddr>(
-            format!("[::1]:{}", SETTING.get_network().babel_port).parse()?,
-        )?;
-
-        watch(self, Babel::new(stream), msg.0, msg.1)
+        // wg_exit's counters only grow, so the traffic a skipped watch would have billed the
+        // exit for is billed by the next one
+        if self.watching {
+            return Box::new(actix::fut::err(format_err!(
+                "The previous watch is still running"
+            )));
+        }
+        self.watching = true;
+
+        // the routes are fetched first so the counters are read right before they're billed
+        Box::new(
+            get_babel_table()
+                .and_then(|babel| {
+                    KI.read_wg_counters_async("wg_exit")
+                        .map_err(|e| {
+                            warn!(
+                                "Error getting router client input output counters {:?} traffic has gone unaccounted!",
+                                e
+                            );
+                            e
+                        }).map(move |counters| (counters, babel))
+                }).into_actor(self)
+                .then(move |res, act, _ctx| {
+                    act.watching = false;
+                    actix::fut::result(res.and_then(|(counters, babel)| {
+                        watch(act, &babel, msg.0, msg.1, counters)
+                    }))
+                }),
+        )
     }
 }
 
 /// This traffic watcher watches how much traffic we send to the exit, and how much the exit sends
 /// back to us.
-pub fn watch<T: Read + Write>(
+pub fn watch(
     history: &mut TrafficWatcher,
-    mut babel: Babel<T>,
+    babel: &BabelTable,
     exit: Identity,
     exit_price: u64,
+    counters: HashMap<String, WgUsage>,
 ) -> Result<(), Error> {
-    babel.start_connection()?;
-
-    trace!("Getting routes");
-    let routes = babel.parse_routes()?;
+    let routes = babel.routes();
     info!("Got routes: {:?}", routes);
 
     let mut destinations = HashMap::new();
@@ -93,26 +122,15 @@ pub fn watch<T: Read + Write>(
         }
     }
 
-    let counter = match KI.read_wg_counters("wg_exit") {
-        Ok(res) => {
-            if res.len() > 1 {
-                warn!("wg_exit client tunnel has multiple peers!");
-            } else if res.len() == 0 {
-                warn!("No peers on wg_exit why is client traffic watcher running?");
-                return Err(format_err!("No peers on wg_exit"));
-            }
-            // unwrap is safe because we check that len is not equal to zero
-            // then we toss the exit's wg key as we don't need it
-            res.iter().last().unwrap().1.clone()
-        }
-        Err(e) => {
-            warn!(
-                "Error getting router client input output counters {:?} traffic has gone unaccounted!",
-                e
-            );
-            return Err(e);
-        }
-    };
+    if counters.len() > 1 {
+        warn!("wg_exit client tunnel has multiple peers!");
+    } else if counters.len() == 0 {
+        warn!("No peers on wg_exit why is client traffic watcher running?");
+        return Err(format_err!("No peers on wg_exit"));
+    }
+    // unwrap is safe because we check that len is not equal to zero
+    // then we toss the exit's wg key as we don't need it
+    let counter = counters.iter().last().unwrap().1.clone();
 
     // bandwidth usage should always increase if it doesn't the interface has been
     // deleted and recreated and we need to reset our usage, also protects from negatives
@@ -137,33 +155,18 @@ pub fn watch<T: Read + Write>(
     info!("exit price {}", exit_price);
 
     if destinations.contains_key(&exit.mesh_ip) {
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(5))
-            .build()?;
-
         let target_route = destinations[&exit.mesh_ip];
         let exit_dest_price: Int256 = Int256::from(target_route.price) + exit_price;
-        let client_tx = SystemTime::now();
-        let RTTimestamps { exit_rx, exit_tx } = client
-            .get(&format!(
-                "http://[{}]:{}/rtt",
-                exit.mesh_ip,
-                match SETTING.get_exit_client().get_current_exit() {
-                    Some(current_exit) => current_exit.registration_port,
-                    None => {
-                        return Err(format_err!(
-                            "No current exit even though an exit route is present"
-                        ));
-                    }
-                }
-            )).send()?
-            .json()?;
-        let client_rx = SystemTime::now();
+        let inner_rtt_millis = measure_inner_rtt(&exit)?;
 
-        let inner_rtt = client_rx.duration_since(client_tx)? - exit_tx.duration_since(exit_rx)?;
-        let inner_rtt_millis =
-            inner_rtt.as_secs() as f32 * 1000.0 + inner_rtt.subsec_nanos() as f32 / 1_000_000.0;
-        //                        secs -> millis                            nanos -> millis
+        // the exit answered so it's up, traffic that doesn't come back is lost on the way
+        FraudDetector::from_registry().do_send(ExitRound {
+            iface: target_route.iface.clone(),
+            advertised_rtt: target_route.full_path_rtt,
+            inner_rtt: inner_rtt_millis,
+            sent: output,
+            received: input,
+        });
 
         info!(
             "RTTs: per-hop {}ms, inner {}ms",
@@ -195,31 +198,107 @@ pub fn watch<T: Read + Write>(
     Ok(())
 }
 
+/// The rtt to the exit inside the exit tunnel in milliseconds, without the time the exit took
+/// to answer
+fn measure_inner_rtt(exit: &Identity) -> Result<f32, Error> {
+    // a new client every time, so every measurement pays for its own handshake
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()?;
+
+    let client_tx = SystemTime::now();
+    let RTTimestamps { exit_rx, exit_tx } = client
+        .get(&format!(
+            "http://[{}]:{}/rtt",
+            exit.mesh_ip,
+            match SETTING.get_exit_client().get_current_exit() {
+                Some(current_exit) => current_exit.registration_port,
+                None => {
+                    return Err(format_err!(
+                        "No current exit even though an exit route is present"
+                    ));
+                }
+            }
+        )).send()?
+        .json()?;
+    let client_rx = SystemTime::now();
+
+    one_trip_ms(
+        client_rx.duration_since(client_tx)?,
+        exit_tx.duration_since(exit_rx)?,
+    )
+}
+
+/// The rtt in milliseconds from how long a request to the exit took and how long the exit spent
+/// answering it. The TCP handshake takes a round trip and the request another, this is one of
+/// them so it compares with babel's full path rtt
+fn one_trip_ms(elapsed: Duration, exit_busy: Duration) -> Result<f32, Error> {
+    let both_trips = match elapsed.checked_sub(exit_busy) {
+        Some(both_trips) => both_trips,
+        None => bail!("The exit took longer to answer than the whole request"),
+    };
+    let inner_rtt = both_trips / 2;
+    Ok(inner_rtt.as_secs() as f32 * 1000.0 + inner_rtt.subsec_nanos() as f32 / 1_000_000.0)
+    //                   secs -> millis                            nanos -> millis
+}
+
 #[cfg(test)]
 mod tests {
-    extern crate env_logger;
+    extern crate fake_babel;
 
+    use self::fake_babel::{FakeBabel, Topology};
     use super::*;
     use althea_types::EthAddress;
-    use std::str::FromStr;
+    use babel_monitor::Babel;
+    use std::net::TcpStream;
 
     #[test]
-    #[ignore]
-    fn debug_babel_socket_client() {
-        env_logger::init();
-        let bm_stream = TcpStream::connect::<SocketAddr>("[::1]:9001".parse().unwrap()).unwrap();
-        watch(
-            &mut TrafficWatcher {
-                last_read_input: 0u64,
-                last_read_output: 0u64,
-            },
-            Babel::new(bm_stream),
-            Identity::new(
-                "0.0.0.0".parse().unwrap(),
-                EthAddress::from_str("abababababababababab").unwrap(),
-                String::from("abc0abc1abc2abc3abc4abc5abc6abc7abc8abc9"),
-            ),
-            5,
+    fn test_watch_without_exit_route() {
+        let fake = FakeBabel::start(
+            Topology::new()
+                .neighbor("wg0", "fe80::1", 256)
+                .route("fd00::2/128", "fe80::1", 256, 0),
         ).unwrap();
+        let mut babel = Babel::new(TcpStream::connect(fake.addr()).unwrap());
+        babel.start_connection().unwrap();
+        let table = BabelTable::from(babel.dump().unwrap());
+
+        // there's no route to the exit yet, so the traffic isn't billed but it is counted
+        let exit = Identity::new(
+            "fd00::ff".parse().unwrap(),
+            EthAddress::default(),
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+                .parse()
+                .unwrap(),
+        );
+        let mut history = TrafficWatcher {
+            last_read_input: 0,
+            last_read_output: 0,
+            watching: false,
+        };
+        let mut counters = HashMap::new();
+        counters.insert(
+            "exit".to_string(),
+            WgUsage {
+                upload: 1000,
+                download: 5000,
+            },
+        );
+        watch(&mut history, &table, exit.clone(), 5, counters).unwrap();
+        assert_eq!(history.last_read_input, 5000);
+        assert_eq!(history.last_read_output, 1000);
+
+        assert!(watch(&mut history, &table, exit, 5, HashMap::new()).is_err());
+    }
+    #[test]
+    fn test_one_trip_ms() {
+        // a 40ms path, the handshake and the request took two trips and the exit 3ms
+        let rtt = one_trip_ms(Duration::from_millis(83), Duration::from_millis(3)).unwrap();
+        assert_eq!(rtt, 40.0);
+
+        let rtt = one_trip_ms(Duration::from_micros(1500), Duration::from_micros(500)).unwrap();
+        assert_eq!(rtt, 0.5);
+
+        assert!(one_trip_ms(Duration::from_millis(3), Duration::from_millis(5)).is_err());
     }
 }
@@ -0,0 +1,283 @@
+//! BabelListener keeps one connection to babeld open in monitor mode and applies the add, change
+//! and flush events babeld sends to a copy of its table. The rest of rita asks this actor for a
+//! snapshot of the routes and neighbors instead of connecting to babeld and running a full
+//! `dump` each time, which is slow on a large mesh of small routers.
+//!
+//! When the connection drops the table is thrown away, on the next tick we connect again and
+//! babeld starts the new monitor session by sending everything it knows.
+
+use actix::prelude::*;
+use failure::Error;
+use futures::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::write_all;
+use tokio::net::TcpStream as TokioTcpStream;
+use tokio_codec::{FramedRead, LinesCodec};
+
+use babel_monitor::{parse_line, BabelClient, BabelLine, BabelTable, Capability, Preamble};
+
+use rita_common::rita_loop::Tick;
+
+use settings::RitaCommonSettings;
+use SETTING;
+
+/// How long a babel command waits on each line of the reply
+const BABEL_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Disconnected,
+    Connecting,
+    /// Reading the preamble, it ends with an ok
+    Preamble,
+    /// Reading the dump babeld sends when monitoring starts, it also ends with an ok
+    Syncing,
+    Synced,
+}
+
+pub struct BabelListener {
+    state: State,
+    preamble: String,
+    table: BabelTable,
+    stream: Option<SpawnHandle>,
+}
+
+impl Actor for BabelListener {
+    type Context = Context<Self>;
+}
+
+impl Supervised for BabelListener {}
+impl SystemService for BabelListener {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Babel listener started");
+    }
+}
+
+impl Default for BabelListener {
+    fn default() -> BabelListener {
+        BabelListener::new()
+    }
+}
+
+impl BabelListener {
+    pub fn new() -> BabelListener {
+        BabelListener {
+            state: State::Disconnected,
+            preamble: String::new(),
+            table: BabelTable::new(),
+            stream: None,
+        }
+    }
+
+    fn disconnect(&mut self, ctx: &mut Context<Self>) {
+        if let Some(stream) = self.stream.take() {
+            ctx.cancel_future(stream);
+        }
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.state = State::Disconnected;
+        self.preamble.clear();
+        self.table = BabelTable::new();
+    }
+
+    /// Returns false if the connection should be dropped
+    fn handle_line(&mut self, line: BabelLine) -> bool {
+        match (self.state, line) {
+            (State::Preamble, BabelLine::Ok) => {
+                let preamble = match Preamble::parse(&self.preamble) {
+                    Ok(preamble) => preamble,
+                    Err(e) => {
+                        warn!("Can't use babel's preamble {:?}", e);
+                        return false;
+                    }
+                };
+                info!("Babel supports {:?}", preamble.capabilities());
+                if !preamble.supports(Capability::Price) {
+                    warn!("Babel doesn't support prices, every route will look free");
+                }
+                self.state = State::Syncing;
+            }
+            (State::Preamble, BabelLine::Other(text)) => {
+                self.preamble.push_str(&text);
+                self.preamble.push('\n');
+            }
+            (State::Syncing, BabelLine::Ok) => {
+                info!(
+                    "Synced with babel, {} neighbors and {} routes",
+                    self.table.neighbors().len(),
+                    self.table.routes().len()
+                );
+                self.state = State::Synced;
+            }
+            (State::Preamble, ref line) | (State::Syncing, ref line) if line.is_terminator() => {
+                warn!("Babel refused to start monitoring with {:?}", line);
+                return false;
+            }
+            (_, line) => {
+                self.table.apply(line);
+            }
+        }
+        true
+    }
+}
+
+impl StreamHandler<String, io::Error> for BabelListener {
+    fn handle(&mut self, line: String, ctx: &mut Context<Self>) {
+        trace!("Babel event {}", line);
+        match parse_line(&line) {
+            Ok(line) => {
+                if !self.handle_line(line) {
+                    self.disconnect(ctx);
+                }
+            }
+            Err(e) => warn!("Failed to parse babel event {:?}", e),
+        }
+    }
+
+    fn error(&mut self, e: io::Error, _ctx: &mut Context<Self>) -> Running {
+        warn!("Lost the babel connection with {:?}", e);
+        Running::Stop
+    }
+
+    fn finished(&mut self, ctx: &mut Context<Self>) {
+        warn!("Babel closed the monitor connection");
+        self.stream = None;
+        self.disconnect(ctx);
+    }
+}
+
+impl Handler<Tick> for BabelListener {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: Tick, ctx: &mut Context<Self>) -> Self::Result {
+        if self.state != State::Disconnected {
+            return Ok(());
+        }
+        let addr: SocketAddr = format!("[::1]:{}", SETTING.get_network().babel_port).parse()?;
+
+        self.state = State::Connecting;
+        ctx.spawn(
+            TokioTcpStream::connect(&addr)
+                .and_then(|stream| write_all(stream, b"monitor\n"))
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok((stream, _)) => {
+                            act.state = State::Preamble;
+                            act.stream =
+                                Some(ctx.add_stream(FramedRead::new(stream, LinesCodec::new())));
+                        }
+                        Err(e) => {
+                            warn!("Failed to connect to babel with {:?}", e);
+                            act.state = State::Disconnected;
+                        }
+                    }
+                    actix::fut::ok(())
+                }),
+        );
+        Ok(())
+    }
+}
+
+/// A snapshot of babel's table, fails until the first full table has come in
+pub struct GetBabelTable;
+
+impl Message for GetBabelTable {
+    type Result = Result<BabelTable, Error>;
+}
+
+impl Handler<GetBabelTable> for BabelListener {
+    type Result = Result<BabelTable, Error>;
+
+    fn handle(&mut self, _: GetBabelTable, _ctx: &mut Context<Self>) -> Self::Result {
+        match self.state {
+            State::Synced => Ok(self.table.clone()),
+            _ => bail!("Not synced with babel yet"),
+        }
+    }
+}
+
+/// babeld doesn't tell monitors when the fee changes, whoever changes it updates the table
+pub struct SetLocalFee(pub u32);
+
+impl Message for SetLocalFee {
+    type Result = ();
+}
+
+impl Handler<SetLocalFee> for BabelListener {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetLocalFee, _ctx: &mut Context<Self>) -> Self::Result {
+        self.table.apply(BabelLine::LocalFee(msg.0));
+    }
+}
+
+/// Shorthand for asking the listener for a snapshot
+pub fn get_babel_table() -> Box<Future<Item = BabelTable, Error = Error>> {
+    Box::new(
+        BabelListener::from_registry()
+            .send(GetBabelTable)
+            .from_err()
+            .and_then(|res| res),
+    )
+}
+
+/// A client for sending commands to babeld, it connects when the first command is sent
+pub fn babel_client() -> BabelClient {
+    let addr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], SETTING.get_network().babel_port));
+    BabelClient::new(addr, Duration::from_secs(BABEL_TIMEOUT_SECS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(listener: &mut BabelListener, lines: &str) -> bool {
+        lines
+            .lines()
+            .all(|line| listener.handle_line(parse_line(line).unwrap()))
+    }
+
+    #[test]
+    fn test_monitor_session() {
+        let mut listener = BabelListener::new();
+        listener.state = State::Preamble;
+        assert!(feed(
+            &mut listener,
+            "ALTHEA 0.1\nversion babeld-1.8.0-24-g6335378\nhost raspberrypi\nok\n\
+             local fee 1024\n\
+             add neighbour 14f19a8 address fe80::2cee:2fff:648:8796 if wg0 reach ffff rxcost 256 \
+             txcost 256 rtt 26.723 rttcost 912 cost 1168\n",
+        ));
+        assert_eq!(listener.state, State::Syncing);
+
+        assert!(feed(
+            &mut listener,
+            "ok\n\
+             add route 14f0820 prefix fd00::1/128 from ::/0 installed yes id \
+             ba:27:eb:ff:fe:5b:fe:c7 metric 1596 price 3072 fee 3072 refmetric 638 \
+             full-path-rtt 22.805 via fe80::2cee:2fff:648:8796 if wg0\n\
+             flush neighbour 14f19a8 address fe80::2cee:2fff:648:8796 if wg0 reach 0000 \
+             rxcost 65535 txcost 65535 cost 65535\n",
+        ));
+        assert_eq!(listener.state, State::Synced);
+        assert_eq!(listener.table.local_fee, Some(1024));
+        assert_eq!(listener.table.routes().len(), 1);
+        assert!(listener.table.neighbors().is_empty());
+
+        // a session that ends takes the table with it
+        listener.reset();
+        assert!(listener.table.routes().is_empty());
+
+        listener.state = State::Preamble;
+        assert!(!feed(&mut listener, "BABEL 1.0\nok\n"));
+
+        listener.reset();
+        listener.state = State::Preamble;
+        assert!(!feed(&mut listener, "ALTHEA 0.1\nok\nbad\n"));
+    }
+}
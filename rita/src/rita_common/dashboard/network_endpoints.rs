@@ -1,22 +1,26 @@
 use actix::registry::SystemService;
 use actix_web::http::StatusCode;
 use actix_web::*;
-use althea_types::EthAddress;
+use althea_kernel_interface::{dry_run_enabled, dry_run_plan};
+use althea_types::{EthAddress, Stats};
+use babel_monitor::InterfaceTuning;
 use failure::Error;
 use futures::{future, Future};
 use serde_json;
 
-use std::{
-    boxed::Box,
-    collections::HashMap,
-    net::{SocketAddr, TcpStream},
-};
+use std::{boxed::Box, collections::HashMap};
 
 use super::{Dashboard, GetOwnInfo, OwnInfo};
-use babel_monitor::Babel;
+use rita_common::babel_listener::{babel_client, BabelListener, SetLocalFee};
 use rita_common::debt_keeper::GetDebtsList;
 use rita_common::debt_keeper::{DebtKeeper, GetDebtsResult};
+use rita_common::fraud_detector::{FraudDetector, GetSuspects, Suspicion};
 use rita_common::network_endpoints::JsonStatusResponse;
+use rita_common::prober::{GetProbes, ProbeHistory, Prober};
+use rita_common::stats_collector::{GetStats, StatsCollector};
+use rita_common::tunnel_manager::{
+    GetTunnelMtus, GetTunnelTunings, SetTunnelTuning, TunnelManager, TunnelMtu, TunnelTuning,
+};
 use settings::RitaCommonSettings;
 use SETTING;
 
@@ -43,6 +47,21 @@ pub fn set_settings(
     JsonStatusResponse::new(Ok("New settings applied".to_string()))
 }
 
+#[derive(Serialize)]
+pub struct DryRunPlan {
+    pub enabled: bool,
+    /// Every command that would have changed the system, in order
+    pub commands: Vec<String>,
+}
+
+pub fn get_dry_run_plan(_req: HttpRequest) -> Result<Json<DryRunPlan>, Error> {
+    debug!("Get dry run plan endpoint hit!");
+    Ok(Json(DryRunPlan {
+        enabled: dry_run_enabled(),
+        commands: dry_run_plan().iter().map(|c| c.to_string()).collect(),
+    }))
+}
+
 #[cfg(not(feature = "development"))]
 pub fn wipe(_req: HttpRequest) -> Result<HttpResponse, Error> {
     // This is returned on production builds.
@@ -115,6 +134,101 @@ pub fn get_debts(
         .responder()
 }
 
+/// The recent system stats samples, oldest first
+pub fn get_stats(_req: HttpRequest) -> Box<Future<Item = Json<Vec<Stats>>, Error = Error>> {
+    trace!("get_stats: Hit");
+    StatsCollector::from_registry()
+        .send(GetStats {})
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+/// Latency and loss to each probe target, oldest sample first
+pub fn get_probes(_req: HttpRequest) -> Box<Future<Item = Json<Vec<ProbeHistory>>, Error = Error>> {
+    trace!("get_probes: Hit");
+    Prober::from_registry()
+        .send(GetProbes {})
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+/// Neighbors whose routes don't match what we measure, most suspect first
+pub fn get_fraud_suspects(
+    _req: HttpRequest,
+) -> Box<Future<Item = Json<Vec<Suspicion>>, Error = Error>> {
+    trace!("get_fraud_suspects: Hit");
+    FraudDetector::from_registry()
+        .send(GetSuspects {})
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+/// The mtu discovered for each tunnel
+pub fn get_tunnel_mtus(
+    _req: HttpRequest,
+) -> Box<Future<Item = Json<Vec<TunnelMtu>>, Error = Error>> {
+    trace!("get_tunnel_mtus: Hit");
+    TunnelManager::from_registry()
+        .send(GetTunnelMtus {})
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+/// Babel's settings for each tunnel, only the ones that have been changed from babel's defaults
+pub fn get_tunnel_tunings(
+    _req: HttpRequest,
+) -> Box<Future<Item = Json<Vec<TunnelTuning>>, Error = Error>> {
+    trace!("get_tunnel_tunings: Hit");
+    TunnelManager::from_registry()
+        .send(GetTunnelTunings {})
+        .from_err()
+        .and_then(move |reply| Ok(Json(reply?)))
+        .responder()
+}
+
+pub fn set_tunnel_tuning(
+    (path, tuning): (Path<String>, Json<InterfaceTuning>),
+) -> Box<Future<Item = HttpResponse, Error = Error>> {
+    let iface_name = path.into_inner();
+    let tuning = tuning.into_inner();
+    debug!("/tunnels/{}/tuning POST hit with {:?}", iface_name, tuning);
+
+    let mut ret = HashMap::<String, String>::new();
+    if let Err(e) = tuning.validate() {
+        ret.insert("error".to_owned(), format!("{}", e));
+        return Box::new(future::ok(
+            HttpResponse::new(StatusCode::BAD_REQUEST)
+                .into_builder()
+                .json(ret),
+        ));
+    }
+
+    Box::new(
+        TunnelManager::from_registry()
+            .send(SetTunnelTuning { iface_name, tuning })
+            .from_err()
+            .and_then(move |reply| {
+                if let Err(e) = reply {
+                    error!("Failed to tune tunnel! {:?}", e);
+                    ret.insert(
+                        "error".to_owned(),
+                        "Failed to ask Babel to change the tunnel's settings".to_owned(),
+                    );
+                    ret.insert("rust_error".to_owned(), format!("{:?}", e));
+
+                    return Ok(HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+                        .into_builder()
+                        .json(ret));
+                }
+                Ok(HttpResponse::Ok().json(ret))
+            }),
+    )
+}
+
 pub fn get_dao_list(_req: HttpRequest) -> Result<Json<Vec<EthAddress>>, Error> {
     trace!("get dao list: Hit");
     Ok(Json(SETTING.get_dao().dao_addresses.clone()))
@@ -169,133 +283,72 @@ pub fn get_metric_factor(_req: HttpRequest) -> Box<Future<Item = HttpResponse, E
 pub fn set_local_fee(path: Path<u32>) -> Box<Future<Item = HttpResponse, Error = Error>> {
     let new_fee = path.into_inner();
     debug!("/local_fee/{} POST hit", new_fee);
-    let mut ret = HashMap::<String, String>::new();
-
-    let stream = match TcpStream::connect::<SocketAddr>(
-        format!("[::1]:{}", SETTING.get_network().babel_port)
-            .parse()
-            .unwrap(),
-    ) {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Failed to set local fee! {:?}", e);
-            ret.insert(
-                "error".to_owned(),
-                "Could not create a socket for connecting to Babel".to_owned(),
-            );
-            ret.insert("rust_error".to_owned(), format!("{:?}", e));
-
-            return Box::new(future::ok(
-                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                    .into_builder()
-                    .json(ret),
-            ));
-        }
-    };
-
-    let mut babel = Babel::new(stream);
-
-    if let Err(e) = babel.start_connection() {
-        error!("Failed to set local fee! {:?}", e);
-        ret.insert("error".to_owned(), "Could not connect to Babel".to_owned());
-        ret.insert("rust_error".to_owned(), format!("{:?}", e));
-
-        return Box::new(future::ok(
-            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                .into_builder()
-                .json(ret),
-        ));
-    }
 
-    if let Err(e) = babel.set_local_fee(new_fee) {
-        error!("Failed to set local fee! {:?}", e);
-        ret.insert(
-            "error".to_owned(),
-            "Failed to ask Babel to set the proposed fee".to_owned(),
-        );
-        ret.insert("rust_error".to_owned(), format!("{:?}", e));
-
-        return Box::new(future::ok(
-            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                .into_builder()
-                .json(ret),
-        ));
-    };
-
-    // Set the value in settings only after Babel successfuly accepts the passed value
-    SETTING.set_local_fee(new_fee);
-
-    if new_fee == 0 {
-        warn!("THIS NODE IS GIVING BANDWIDTH AWAY FOR FREE. PLEASE SET local_fee TO A NON-ZERO VALUE TO DISABLE THIS WARNING.");
-        ret.insert("warning".to_owned(), "THIS NODE IS GIVING BANDWIDTH AWAY FOR FREE. PLEASE SET local_fee TO A NON-ZERO VALUE TO DISABLE THIS WARNING.".to_owned());
-    }
-
-    Box::new(future::ok(HttpResponse::Ok().json(ret)))
+    Box::new(
+        babel_client()
+            .set_local_fee(new_fee)
+            .then(move |res| -> Result<HttpResponse, Error> {
+                let mut ret = HashMap::<String, String>::new();
+
+                if let Err(e) = res {
+                    error!("Failed to set local fee! {:?}", e);
+                    ret.insert(
+                        "error".to_owned(),
+                        "Failed to ask Babel to set the proposed fee".to_owned(),
+                    );
+                    ret.insert("rust_error".to_owned(), format!("{:?}", e));
+
+                    return Ok(HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+                        .into_builder()
+                        .json(ret));
+                }
+
+                // Set the value in settings only after Babel successfuly accepts the passed value
+                SETTING.set_local_fee(new_fee);
+                BabelListener::from_registry().do_send(SetLocalFee(new_fee));
+
+                if new_fee == 0 {
+                    warn!("THIS NODE IS GIVING BANDWIDTH AWAY FOR FREE. PLEASE SET local_fee TO A NON-ZERO VALUE TO DISABLE THIS WARNING.");
+                    ret.insert("warning".to_owned(), "THIS NODE IS GIVING BANDWIDTH AWAY FOR FREE. PLEASE SET local_fee TO A NON-ZERO VALUE TO DISABLE THIS WARNING.".to_owned());
+                }
+
+                Ok(HttpResponse::Ok().json(ret))
+            }),
+    )
 }
 
 pub fn set_metric_factor(path: Path<u32>) -> Box<Future<Item = HttpResponse, Error = Error>> {
     let new_factor = path.into_inner();
     debug!("/metric_factor/{} POST hit", new_factor);
-    let mut ret = HashMap::<String, String>::new();
 
-    let stream = match TcpStream::connect::<SocketAddr>(
-        format!("[::1]:{}", SETTING.get_network().babel_port)
-            .parse()
-            .unwrap(),
-    ) {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Failed to set metric factor! {:?}", e);
-            ret.insert(
-                "error".to_owned(),
-                "Could not create a socket for connecting to Babel".to_owned(),
-            );
-            ret.insert("rust_error".to_owned(), format!("{:?}", e));
-
-            return Box::new(future::ok(
-                HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                    .into_builder()
-                    .json(ret),
-            ));
-        }
-    };
-
-    let mut babel = Babel::new(stream);
-
-    if let Err(e) = babel.start_connection() {
-        error!("Failed to set metric factor! {:?}", e);
-        ret.insert("error".to_owned(), "Could not connect to Babel".to_owned());
-        ret.insert("rust_error".to_owned(), format!("{:?}", e));
-
-        return Box::new(future::ok(
-            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                .into_builder()
-                .json(ret),
-        ));
-    }
-
-    if let Err(e) = babel.set_metric_factor(new_factor) {
-        error!("Failed to set metric factor! {:?}", e);
-        ret.insert(
-            "error".to_owned(),
-            "Failed to ask Babel to set the proposed factor".to_owned(),
-        );
-        ret.insert("rust_error".to_owned(), format!("{:?}", e));
-
-        return Box::new(future::ok(
-            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
-                .into_builder()
-                .json(ret),
-        ));
-    };
-
-    // Set the value in settings only after Babel successfuly accepts the passed value
-    SETTING.set_metric_factor(new_factor);
-
-    if new_factor == 0 {
-        warn!("THIS NODE DOESN'T PAY ATTENTION TO ROUTE QUALITY - IT'LL CHOOSE THE CHEAPEST ROUTE EVEN IF IT'S THE WORST LINK AROUND. PLEASE SET metric_factor TO A NON-ZERO VALUE TO DISABLE THIS WARNING.");
-        ret.insert("warning".to_owned(), "THIS NODE DOESN'T PAY ATTENTION TO ROUTE QUALITY - IT'LL CHOOSE THE CHEAPEST ROUTE EVEN IF IT'S THE WORST LINK AROUND. PLEASE SET metric_factor TO A NON-ZERO VALUE TO DISABLE THIS WARNING.".to_owned());
-    }
-
-    Box::new(future::ok(HttpResponse::Ok().json(ret)))
+    Box::new(
+        babel_client()
+            .set_metric_factor(new_factor)
+            .then(move |res| -> Result<HttpResponse, Error> {
+                let mut ret = HashMap::<String, String>::new();
+
+                if let Err(e) = res {
+                    error!("Failed to set metric factor! {:?}", e);
+                    ret.insert(
+                        "error".to_owned(),
+                        "Failed to ask Babel to set the proposed factor".to_owned(),
+                    );
+                    ret.insert("rust_error".to_owned(), format!("{:?}", e));
+
+                    return Ok(HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
+                        .into_builder()
+                        .json(ret));
+                }
+
+                // Set the value in settings only after Babel successfuly accepts the passed value
+                SETTING.set_metric_factor(new_factor);
+
+                if new_factor == 0 {
+                    warn!("THIS NODE DOESN'T PAY ATTENTION TO ROUTE QUALITY - IT'LL CHOOSE THE CHEAPEST ROUTE EVEN IF IT'S THE WORST LINK AROUND. PLEASE SET metric_factor TO A NON-ZERO VALUE TO DISABLE THIS WARNING.");
+                    ret.insert("warning".to_owned(), "THIS NODE DOESN'T PAY ATTENTION TO ROUTE QUALITY - IT'LL CHOOSE THE CHEAPEST ROUTE EVEN IF IT'S THE WORST LINK AROUND. PLEASE SET metric_factor TO A NON-ZERO VALUE TO DISABLE THIS WARNING.".to_owned());
+                }
+
+                Ok(HttpResponse::Ok().json(ret))
+            }),
+    )
 }
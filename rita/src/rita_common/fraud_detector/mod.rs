@@ -0,0 +1,683 @@
+//! FraudDetector checks what our neighbors tell babel against what we measure ourselves. A
+//! neighbor is paid for the traffic it forwards, so it gains by making its routes look better than
+//! they are, a route with a low rtt over a link that never seems to lose a packet draws traffic
+//! the neighbor may not even deliver.
+//!
+//! Every check compares babel's rtt to each neighbor and what the neighbor claims about hearing
+//! us with the Prober's pings over its tunnel, the route to the exit with the inner rtt and
+//! traffic the client traffic watcher measured through it, and the price of each installed route
+//! with what it was at the last check. Whatever doesn't add up is added to the neighbor's
+//! suspicion score. The score decays every check so a neighbor has to keep misbehaving to stay
+//! suspect, one bad measurement on a flaky link isn't enough.
+//!
+//! Past REPORT_THRESHOLD a neighbor is logged and shown on the dashboard, past BLOCK_THRESHOLD
+//! babel stops routing over its tunnels until the score has decayed below CLEAR_THRESHOLD.
+
+use actix::prelude::*;
+use failure::Error;
+use futures::Future;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use althea_types::Identity;
+use babel_monitor::BabelTable;
+
+use rita_common::babel_listener::get_babel_table;
+use rita_common::prober::{GetProbes, ProbeHistory, ProbeKind, Prober};
+use rita_common::rita_loop::Tick;
+use rita_common::tunnel_manager::{
+    GetNeighbors, Neighbor, TunnelAction, TunnelManager, TunnelStateChange,
+};
+
+/// How often neighbors are checked, the same as the probes so each check has fresh ones
+const CHECK_INTERVAL_SECS: u64 = 30;
+/// What's left of the score after each check, at one check every 30 seconds it halves in about
+/// three and a half minutes
+const DECAY: f32 = 0.9;
+/// Scores at which a neighbor is reported, its tunnels are taken out of babel and put back. One
+/// point of evidence every check settles at 10, so it takes more than one kind of evidence or
+/// the stronger kinds to be blocked
+const REPORT_THRESHOLD: f32 = 5.0;
+const BLOCK_THRESHOLD: f32 = 12.0;
+const CLEAR_THRESHOLD: f32 = 2.0;
+/// Neighbors whose score has decayed below this are forgotten
+const FORGET_THRESHOLD: f32 = 0.1;
+/// How much evidence is kept per neighbor for the dashboard
+const EVIDENCE_LEN: usize = 20;
+/// How many exit rounds are kept while checks can't run
+const MAX_EXIT_ROUNDS: usize = 20;
+
+/// A measured rtt is allowed to be this many times the advertised one plus RTT_SLACK_MS, rtts
+/// vary and babel's are smoothed
+const RTT_TOLERANCE: f32 = 1.5;
+const RTT_SLACK_MS: f32 = 10.0;
+/// The txcost of a neighbor that says it hears every one of our hellos
+const PERFECT_TXCOST: u16 = 256;
+/// Percentage of probes lost over a link that's advertised as perfect before it's suspect
+const LOSS_THRESHOLD: f32 = 50.0;
+/// Bytes sent to the exit between checks with nothing at all coming back before it's suspect,
+/// TCP alone would bring acks back for this much
+const UNDELIVERED_BYTES: u64 = 100_000;
+/// How many times more expensive an installed route can get between checks
+const PRICE_JUMP_FACTOR: u64 = 2;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum Evidence {
+    /// babel's rtt to the neighbor is much lower than the probes over its tunnel, babel's comes
+    /// from timestamps the neighbor echoes back so it can be made up
+    LinkRttUnderstated { advertised: f32, measured: f32 },
+    /// The neighbor tells babel it hears all our hellos but the probes over its tunnel are lost
+    LossHidden { txcost: u16, loss: f32 },
+    /// The route to the exit through the neighbor claims a much lower rtt than we measure
+    ExitRttUnderstated { advertised: f32, measured: f32 },
+    /// Traffic we sent to the exit through the neighbor got nothing back
+    Undelivered { sent: u64, received: u64 },
+    /// An installed route through the neighbor got much more expensive while we were using it.
+    /// This may be a node further along the route, so it counts for little on its own
+    PriceJump { prefix: String, from: u32, to: u32 },
+}
+
+impl Evidence {
+    /// How much this adds to the score, evidence that's hard to explain away counts for more
+    fn weight(&self) -> f32 {
+        match *self {
+            Evidence::LinkRttUnderstated { .. } => 1.0,
+            Evidence::LossHidden { .. } => 1.0,
+            Evidence::PriceJump { .. } => 1.0,
+            Evidence::ExitRttUnderstated { .. } => 2.0,
+            Evidence::Undelivered { .. } => 3.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    pub timestamp: u64,
+    pub evidence: Evidence,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Suspicion {
+    pub identity: Identity,
+    pub score: f32,
+    pub reported: bool,
+    /// babel isn't routing over the neighbor's tunnels
+    pub blocked: bool,
+    /// Oldest first
+    pub findings: VecDeque<Finding>,
+}
+
+impl Suspicion {
+    fn new(identity: Identity) -> Suspicion {
+        Suspicion {
+            identity,
+            score: 0.0,
+            reported: false,
+            blocked: false,
+            findings: VecDeque::new(),
+        }
+    }
+}
+
+/// One round of traffic to the exit, sent by the client traffic watcher only when the exit
+/// answered. Traffic that doesn't come back from an exit that's down is no neighbor's doing
+#[derive(Debug, Clone)]
+pub struct ExitRound {
+    /// The tunnel babel's route to the exit goes out of
+    pub iface: String,
+    /// The route's full path rtt, zero if babel doesn't know it
+    pub advertised_rtt: f32,
+    /// The rtt we measured to the exit inside the exit tunnel
+    pub inner_rtt: f32,
+    /// Bytes sent to and received from the exit this round
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl Message for ExitRound {
+    type Result = ();
+}
+
+pub struct FraudDetector {
+    exit_rounds: Vec<ExitRound>,
+    /// The price of each installed route at the last check, by neighbor and prefix
+    prices: HashMap<(Identity, String), u32>,
+    suspects: HashMap<Identity, Suspicion>,
+    last_check: Option<Instant>,
+    checking: bool,
+}
+
+impl Actor for FraudDetector {
+    type Context = Context<Self>;
+}
+
+impl Supervised for FraudDetector {}
+impl SystemService for FraudDetector {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Fraud detector started");
+    }
+}
+
+impl Default for FraudDetector {
+    fn default() -> FraudDetector {
+        FraudDetector::new()
+    }
+}
+
+/// Whether a measured rtt is too far above the advertised one, zero is babel not knowing
+fn understated(advertised: f32, measured: f32) -> bool {
+    advertised > 0.0 && measured > advertised * RTT_TOLERANCE + RTT_SLACK_MS
+}
+
+fn median(mut values: Vec<f32>) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    Some(values[values.len() / 2])
+}
+
+/// The exit rounds through one neighbor since the last check
+#[derive(Default)]
+struct ExitTotals {
+    sent: u64,
+    received: u64,
+    advertised_rtts: Vec<f32>,
+    inner_rtts: Vec<f32>,
+}
+
+impl FraudDetector {
+    pub fn new() -> FraudDetector {
+        FraudDetector {
+            exit_rounds: Vec::new(),
+            prices: HashMap::new(),
+            suspects: HashMap::new(),
+            last_check: None,
+            checking: false,
+        }
+    }
+
+    /// What this check's measurements hold against each neighbor, at most one finding of each
+    /// kind per neighbor. Uses up the exit rounds
+    fn gather(
+        &mut self,
+        table: &BabelTable,
+        neighbors: &[Neighbor],
+        probes: &[ProbeHistory],
+    ) -> Vec<(Identity, Evidence)> {
+        let by_iface: HashMap<&str, &Identity> = neighbors
+            .iter()
+            .map(|neighbor| (neighbor.iface_name.as_str(), &neighbor.identity.global))
+            .collect();
+        let mut found = Vec::new();
+
+        for neigh in table.neighbors() {
+            let identity = match by_iface.get(neigh.iface.as_str()) {
+                Some(identity) => *identity,
+                None => continue,
+            };
+            let probe = probes
+                .iter()
+                .find(|probe| {
+                    probe.target.kind == ProbeKind::Peer
+                        && probe.target.iface.as_ref() == Some(&neigh.iface)
+                })
+                .and_then(|probe| probe.samples.last())
+                .and_then(|sample| sample.result.clone());
+            let probe = match probe {
+                Some(probe) => probe,
+                None => continue,
+            };
+
+            if neigh.txcost <= PERFECT_TXCOST && probe.loss >= LOSS_THRESHOLD {
+                found.push((
+                    identity.clone(),
+                    Evidence::LossHidden {
+                        txcost: neigh.txcost,
+                        loss: probe.loss,
+                    },
+                ));
+            }
+            if let Some(measured) = probe.rtt_avg {
+                if understated(neigh.rtt, measured) {
+                    found.push((
+                        identity.clone(),
+                        Evidence::LinkRttUnderstated {
+                            advertised: neigh.rtt,
+                            measured,
+                        },
+                    ));
+                }
+            }
+        }
+
+        // the traffic watcher sends a round every few seconds, they're judged together
+        let mut exit_totals: HashMap<Identity, ExitTotals> = HashMap::new();
+        for round in self.exit_rounds.drain(..) {
+            let identity = match by_iface.get(round.iface.as_str()) {
+                Some(identity) => *identity,
+                None => continue,
+            };
+            let totals = exit_totals
+                .entry(identity.clone())
+                .or_insert_with(ExitTotals::default);
+            totals.sent += round.sent;
+            totals.received += round.received;
+            totals.advertised_rtts.push(round.advertised_rtt);
+            totals.inner_rtts.push(round.inner_rtt);
+        }
+        for (identity, totals) in exit_totals {
+            if let (Some(advertised), Some(measured)) =
+                (median(totals.advertised_rtts), median(totals.inner_rtts))
+            {
+                if understated(advertised, measured) {
+                    found.push((
+                        identity.clone(),
+                        Evidence::ExitRttUnderstated {
+                            advertised,
+                            measured,
+                        },
+                    ));
+                }
+            }
+            if totals.sent >= UNDELIVERED_BYTES && totals.received == 0 {
+                found.push((
+                    identity,
+                    Evidence::Undelivered {
+                        sent: totals.sent,
+                        received: totals.received,
+                    },
+                ));
+            }
+        }
+
+        let mut prices = HashMap::new();
+        for route in table.routes() {
+            let identity = match by_iface.get(route.iface.as_str()) {
+                Some(identity) => *identity,
+                None => continue,
+            };
+            if !route.installed {
+                continue;
+            }
+            let key = (identity.clone(), route.prefix.to_string());
+            if let Some(&from) = self.prices.get(&key) {
+                if from > 0 && u64::from(route.price) > u64::from(from) * PRICE_JUMP_FACTOR {
+                    found.push((
+                        identity.clone(),
+                        Evidence::PriceJump {
+                            prefix: key.1.clone(),
+                            from,
+                            to: route.price,
+                        },
+                    ));
+                }
+            }
+            prices.insert(key, route.price);
+        }
+        self.prices = prices;
+
+        // a neighbor with several tunnels or routes that look wrong is still scored once for it
+        let mut seen = HashSet::new();
+        found.retain(|&(ref identity, ref evidence)| {
+            seen.insert((identity.clone(), mem::discriminant(evidence)))
+        });
+        found
+    }
+
+    /// Adds the evidence to the scores and returns the tunnel state changes to make. Blocked
+    /// neighbors are blocked again every check so tunnels they opened since are covered
+    fn score(
+        &mut self,
+        timestamp: u64,
+        evidence: Vec<(Identity, Evidence)>,
+    ) -> Vec<(Identity, TunnelAction)> {
+        for suspect in self.suspects.values_mut() {
+            suspect.score *= DECAY;
+        }
+        for (identity, evidence) in evidence {
+            let suspect = self
+                .suspects
+                .entry(identity.clone())
+                .or_insert_with(|| Suspicion::new(identity));
+            suspect.score += evidence.weight();
+            while suspect.findings.len() >= EVIDENCE_LEN {
+                suspect.findings.pop_front();
+            }
+            suspect.findings.push_back(Finding {
+                timestamp,
+                evidence,
+            });
+        }
+
+        let mut actions = Vec::new();
+        for suspect in self.suspects.values_mut() {
+            if suspect.score >= REPORT_THRESHOLD && !suspect.reported {
+                error!(
+                    "Neighbor {:?} looks fraudulent with a score of {}, recent evidence {:?}",
+                    suspect.identity, suspect.score, suspect.findings
+                );
+                suspect.reported = true;
+            }
+            if suspect.score >= BLOCK_THRESHOLD {
+                if !suspect.blocked {
+                    error!("No longer routing through {:?}", suspect.identity);
+                }
+                suspect.blocked = true;
+                actions.push((suspect.identity.clone(), TunnelAction::FraudSuspected));
+            } else if suspect.score < CLEAR_THRESHOLD {
+                if suspect.blocked {
+                    info!("Routing through {:?} again", suspect.identity);
+                    actions.push((suspect.identity.clone(), TunnelAction::FraudCleared));
+                }
+                suspect.blocked = false;
+                suspect.reported = false;
+            }
+        }
+        self.suspects
+            .retain(|_, suspect| suspect.blocked || suspect.score >= FORGET_THRESHOLD);
+        actions
+    }
+}
+
+impl Handler<Tick> for FraudDetector {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: Tick, ctx: &mut Context<Self>) -> Self::Result {
+        let due = match self.last_check {
+            Some(last_check) => last_check.elapsed() >= Duration::from_secs(CHECK_INTERVAL_SECS),
+            None => true,
+        };
+        if !due || self.checking {
+            return Ok(());
+        }
+        self.last_check = Some(Instant::now());
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        self.checking = true;
+        ctx.spawn(
+            get_babel_table()
+                .join(
+                    TunnelManager::from_registry()
+                        .send(GetNeighbors)
+                        .from_err()
+                        .and_then(|res| res),
+                ).join(
+                    Prober::from_registry()
+                        .send(GetProbes)
+                        .from_err()
+                        .and_then(|res| res),
+                ).into_actor(self)
+                .then(move |res, act, _ctx| {
+                    match res {
+                        Ok(((table, neighbors), probes)) => {
+                            let evidence = act.gather(&table, &neighbors, &probes);
+                            for (identity, action) in act.score(timestamp, evidence) {
+                                TunnelManager::from_registry()
+                                    .do_send(TunnelStateChange { identity, action });
+                            }
+                        }
+                        Err(e) => warn!("Failed to check neighbors for fraud {:?}", e),
+                    }
+                    act.checking = false;
+                    actix::fut::ok(())
+                }),
+        );
+        Ok(())
+    }
+}
+
+impl Handler<ExitRound> for FraudDetector {
+    type Result = ();
+
+    fn handle(&mut self, msg: ExitRound, _ctx: &mut Context<Self>) -> Self::Result {
+        if self.exit_rounds.len() >= MAX_EXIT_ROUNDS {
+            self.exit_rounds.remove(0);
+        }
+        self.exit_rounds.push(msg);
+    }
+}
+
+/// Every neighbor with a score, most suspect first
+pub struct GetSuspects;
+
+impl Message for GetSuspects {
+    type Result = Result<Vec<Suspicion>, Error>;
+}
+
+impl Handler<GetSuspects> for FraudDetector {
+    type Result = Result<Vec<Suspicion>, Error>;
+
+    fn handle(&mut self, _: GetSuspects, _ctx: &mut Context<Self>) -> Self::Result {
+        let mut suspects: Vec<Suspicion> = self.suspects.values().cloned().collect();
+        suspects.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+        Ok(suspects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use althea_kernel_interface::ProbeResult;
+    use althea_types::{EthAddress, LocalIdentity};
+    use babel_monitor::parse_line;
+    use rita_common::prober::{ProbeSample, ProbeTarget};
+
+    fn identity(mesh_ip: &str) -> Identity {
+        Identity::new(
+            mesh_ip.parse().unwrap(),
+            EthAddress::default(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+        )
+    }
+
+    fn neighbor(mesh_ip: &str, iface: &str) -> Neighbor {
+        Neighbor {
+            identity: LocalIdentity {
+                wg_port: 60000,
+                have_tunnel: Some(true),
+                global: identity(mesh_ip),
+            },
+            iface_name: iface.to_string(),
+            tunnel_ip: "fe80::1".parse().unwrap(),
+        }
+    }
+
+    fn probe(iface: &str, loss: f32, rtt_avg: f32) -> ProbeHistory {
+        ProbeHistory {
+            target: ProbeTarget {
+                kind: ProbeKind::Peer,
+                iface: Some(iface.to_string()),
+                ip: "fe80::1".parse().unwrap(),
+            },
+            samples: vec![ProbeSample {
+                timestamp: 0,
+                result: Some(ProbeResult {
+                    sent: 5,
+                    received: 5 - (loss / 20.0) as u32,
+                    loss,
+                    rtt_min: Some(rtt_avg),
+                    rtt_avg: Some(rtt_avg),
+                    rtt_max: Some(rtt_avg),
+                    jitter: Some(0.0),
+                }),
+            }],
+        }
+    }
+
+    fn table(lines: &str) -> BabelTable {
+        let mut table = BabelTable::new();
+        for line in lines.lines() {
+            table.apply(parse_line(line).unwrap());
+        }
+        table
+    }
+
+    const HONEST: &'static str = "add neighbour 1 address fe80::1 if wg0 reach ffff rxcost 256 \
+                                  txcost 256 rtt 20.000 rttcost 0 cost 256";
+    const LIAR: &'static str = "add neighbour 2 address fe80::2 if wg1 reach ffff rxcost 256 \
+                                txcost 256 rtt 1.000 rttcost 0 cost 256";
+
+    #[test]
+    fn test_gather() {
+        // the liar has a second tunnel that looks just as bad
+        let neighbors = vec![
+            neighbor("fd00::1", "wg0"),
+            neighbor("fd00::2", "wg1"),
+            neighbor("fd00::2", "wg2"),
+        ];
+        let probes = vec![
+            probe("wg0", 0.0, 22.0),
+            probe("wg1", 60.0, 50.0),
+            probe("wg2", 60.0, 50.0),
+        ];
+        let route = |price| {
+            format!(
+                "add route 3 prefix fd00::3/128 from ::/0 installed yes id ba:27:eb:ff:fe:5b:fe:c7 \
+                 metric 512 price {} fee 0 refmetric 256 full-path-rtt 5.000 via fe80::2 if wg1",
+                price
+            )
+        };
+
+        // a check's worth of rounds
+        let mut detector = FraudDetector::new();
+        for &inner_rtt in &[80.0, 6.0, 90.0, 75.0] {
+            detector.exit_rounds.push(ExitRound {
+                iface: "wg1".to_string(),
+                advertised_rtt: 5.0,
+                inner_rtt,
+                sent: 50_000,
+                received: 0,
+            });
+        }
+        let second = LIAR
+            .replace("neighbour 2", "neighbour 4")
+            .replace("wg1", "wg2");
+        let found = detector.gather(
+            &table(&format!("{}\n{}\n{}\n{}", HONEST, LIAR, second, route(100))),
+            &neighbors,
+            &probes,
+        );
+        let liar = identity("fd00::2");
+        assert!(found.iter().all(|&(ref identity, _)| *identity == liar));
+        let kinds: Vec<f32> = found.iter().map(|&(_, ref e)| e.weight()).collect();
+        assert_eq!(kinds, vec![1.0, 1.0, 2.0, 3.0]);
+        assert_eq!(
+            found[2].1,
+            Evidence::ExitRttUnderstated {
+                advertised: 5.0,
+                measured: 80.0,
+            }
+        );
+        assert_eq!(
+            found[3].1,
+            Evidence::Undelivered {
+                sent: 200_000,
+                received: 0,
+            }
+        );
+        assert!(detector.exit_rounds.is_empty());
+
+        let found = detector.gather(&table(&route(300)), &neighbors, &[]);
+        assert_eq!(
+            found,
+            vec![(
+                liar,
+                Evidence::PriceJump {
+                    prefix: "fd00::3/128".to_string(),
+                    from: 100,
+                    to: 300,
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn test_gather_exit_rtt() {
+        let neighbors = vec![neighbor("fd00::1", "wg0")];
+        let probes = vec![probe("wg0", 0.0, 22.0)];
+        let table = table(&format!(
+            "{}\nadd route 3 prefix fd00::3/128 from ::/0 installed yes \
+             id ba:27:eb:ff:fe:5b:fe:c7 metric 512 price 100 fee 0 refmetric 256 \
+             full-path-rtt 40.000 via fe80::1 if wg0",
+            HONEST
+        ));
+        let rounds = |inner_rtts: &[f32]| {
+            inner_rtts
+                .iter()
+                .map(|&inner_rtt| ExitRound {
+                    iface: "wg0".to_string(),
+                    advertised_rtt: 40.0,
+                    inner_rtt,
+                    sent: 50_000,
+                    received: 200_000,
+                }).collect()
+        };
+
+        // an honest 40ms path with some jitter
+        let mut detector = FraudDetector::new();
+        detector.exit_rounds = rounds(&[41.5, 38.0, 52.0, 40.5, 44.0]);
+        assert!(detector.gather(&table, &neighbors, &probes).is_empty());
+
+        // both trips of the request taken as one round trip would make it look understated
+        detector.exit_rounds = rounds(&[83.0, 76.0, 104.0, 81.0, 88.0]);
+        assert_eq!(
+            detector.gather(&table, &neighbors, &probes),
+            vec![(
+                identity("fd00::1"),
+                Evidence::ExitRttUnderstated {
+                    advertised: 40.0,
+                    measured: 83.0,
+                },
+            )]
+        );
+    }
+
+    #[test]
+    fn test_score() {
+        let liar = identity("fd00::2");
+        let undelivered = || {
+            vec![(
+                liar.clone(),
+                Evidence::Undelivered {
+                    sent: 1_000_000,
+                    received: 0,
+                },
+            )]
+        };
+        let mut detector = FraudDetector::new();
+
+        // one bad round isn't enough to act on
+        assert!(detector.score(0, undelivered()).is_empty());
+        assert!(!detector.suspects[&liar].reported);
+
+        let mut actions = Vec::new();
+        for timestamp in 1..6 {
+            actions = detector.score(timestamp, undelivered());
+        }
+        assert!(detector.suspects[&liar].reported);
+        assert_eq!(actions.len(), 1);
+        match actions[0] {
+            (ref identity, TunnelAction::FraudSuspected) => assert_eq!(*identity, liar),
+            ref action => panic!("Unexpected action {:?}", action),
+        }
+
+        // once it behaves the score decays until it's routed through again and then forgotten
+        let mut cleared = false;
+        for timestamp in 6..100 {
+            for (_, action) in detector.score(timestamp, Vec::new()) {
+                cleared = match action {
+                    TunnelAction::FraudCleared => true,
+                    _ => cleared,
+                };
+            }
+        }
+        assert!(cleared);
+        assert!(detector.suspects.is_empty());
+    }
+}
@@ -1,11 +1,15 @@
+pub mod babel_listener;
 pub mod dao_manager;
 pub mod dashboard;
 pub mod debt_keeper;
+pub mod fraud_detector;
 pub mod http_client;
 pub mod network_endpoints;
 pub mod payment_controller;
 pub mod peer_listener;
 pub mod port_emissary;
+pub mod prober;
 pub mod rita_loop;
+pub mod stats_collector;
 pub mod traffic_watcher;
 pub mod tunnel_manager;
@@ -0,0 +1,291 @@
+//! Prober pings the nodes this router depends on, every tunnel peer, the exit and a gateway's
+//! upstream, and keeps the recent latency, jitter and loss to each of them. This is measured
+//! independently of babel so it can be used to check babel's rtt and to troubleshoot links.
+//!
+//! Peers are probed once per tunnel at the link local ip they have on it, out of the tunnel's
+//! interface, so each probe measures that link rather than whatever route babel picked to the
+//! peer's mesh ip. The exit is probed at its address inside the exit tunnel.
+
+use actix::prelude::*;
+use failure::Error;
+use futures::{future, Future};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use althea_kernel_interface::{tunnel_link_local, ProbeResult};
+
+use rita_common::rita_loop::Tick;
+use rita_common::tunnel_manager::{GetNeighbors, TunnelManager};
+
+use settings::RitaCommonSettings;
+use KI;
+use SETTING;
+
+/// How often every target is probed, probes to all the targets run at the same time
+const PROBE_INTERVAL_SECS: u64 = 30;
+/// Pings sent per probe, they're a second apart
+const PROBE_COUNT: u32 = 5;
+/// How many samples are kept per target, at one every 30 seconds this is half an hour
+const HISTORY_LEN: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeKind {
+    Peer,
+    Exit,
+    Upstream,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct ProbeTarget {
+    pub kind: ProbeKind,
+    /// The tunnel a peer is probed over, None for targets that are probed over the routing table
+    pub iface: Option<String>,
+    pub ip: IpAddr,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeSample {
+    pub timestamp: u64,
+    /// None if ping couldn't be run at all
+    pub result: Option<ProbeResult>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeHistory {
+    pub target: ProbeTarget,
+    /// Oldest first
+    pub samples: Vec<ProbeSample>,
+}
+
+pub struct Prober {
+    exit: Option<IpAddr>,
+    history: HashMap<ProbeTarget, VecDeque<ProbeSample>>,
+    last_probe: Option<Instant>,
+    probing: bool,
+}
+
+impl Actor for Prober {
+    type Context = Context<Self>;
+}
+
+impl Supervised for Prober {}
+impl SystemService for Prober {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Prober started");
+    }
+}
+
+impl Default for Prober {
+    fn default() -> Prober {
+        Prober::new()
+    }
+}
+
+impl Prober {
+    pub fn new() -> Prober {
+        Prober {
+            exit: None,
+            history: HashMap::new(),
+            last_probe: None,
+            probing: false,
+        }
+    }
+
+    /// Adds a round of results, targets that weren't probed this round are gone and so is their
+    /// history
+    fn record(&mut self, timestamp: u64, results: Vec<(ProbeTarget, Option<ProbeResult>)>) {
+        let mut history = HashMap::new();
+        for (target, result) in results {
+            let mut samples = self.history.remove(&target).unwrap_or_default();
+            while samples.len() >= HISTORY_LEN {
+                samples.pop_front();
+            }
+            samples.push_back(ProbeSample { timestamp, result });
+            history.insert(target, samples);
+        }
+        self.history = history;
+    }
+}
+
+/// The next hop of a route, for the default route on a gateway this is the upstream router
+fn route_gateway(route: &[String]) -> Option<IpAddr> {
+    let via = route.iter().position(|token| token == "via")?;
+    route.get(via + 1)?.parse().ok()
+}
+
+impl Handler<Tick> for Prober {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: Tick, ctx: &mut Context<Self>) -> Self::Result {
+        let due = match self.last_probe {
+            Some(last_probe) => last_probe.elapsed() >= Duration::from_secs(PROBE_INTERVAL_SECS),
+            None => true,
+        };
+        // a round of probes can't outlast the interval, but in case one does don't start another
+        if !due || self.probing {
+            return Ok(());
+        }
+        self.last_probe = Some(Instant::now());
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        let mut targets = Vec::new();
+        if let Some(ip) = self.exit {
+            targets.push(ProbeTarget {
+                kind: ProbeKind::Exit,
+                iface: None,
+                ip,
+            });
+        }
+        // the settings keep the default route from before we routed everything to the exit
+        let upstream = if SETTING.get_network().is_gateway {
+            route_gateway(&SETTING.get_network().default_route)
+        } else {
+            None
+        };
+        if let Some(ip) = upstream {
+            targets.push(ProbeTarget {
+                kind: ProbeKind::Upstream,
+                iface: None,
+                ip,
+            });
+        }
+
+        self.probing = true;
+        ctx.spawn(
+            TunnelManager::from_registry()
+                .send(GetNeighbors)
+                .into_actor(self)
+                .then(move |res, act, _ctx| {
+                    match res {
+                        Ok(Ok(neighbors)) => {
+                            for neighbor in neighbors {
+                                let mesh_ip = neighbor.identity.global.mesh_ip;
+                                match tunnel_link_local(mesh_ip) {
+                                    Some(ip) => targets.push(ProbeTarget {
+                                        kind: ProbeKind::Peer,
+                                        iface: Some(neighbor.iface_name),
+                                        ip,
+                                    }),
+                                    None => warn!("Can't probe {}, not a mesh ip", mesh_ip),
+                                }
+                            }
+                        }
+                        Ok(Err(e)) => warn!("Failed to get neighbors to probe {:?}", e),
+                        Err(e) => warn!("Failed to get neighbors to probe {:?}", e),
+                    }
+                    targets.sort();
+                    targets.dedup();
+
+                    let probes = targets.into_iter().map(|target| {
+                        let probe = {
+                            let iface = target.iface.as_ref().map(|iface| iface.as_str());
+                            KI.probe_async(target.ip, iface, PROBE_COUNT)
+                        };
+                        probe.then(move |res| {
+                            let result = match res {
+                                Ok(result) => Some(result),
+                                Err(e) => {
+                                    warn!("Failed to probe {:?} with {:?}", target, e);
+                                    None
+                                }
+                            };
+                            Ok::<_, ()>((target, result))
+                        })
+                    });
+                    future::join_all(probes)
+                        .into_actor(act)
+                        .then(move |results, act, _ctx| {
+                            if let Ok(results) = results {
+                                act.record(timestamp, results);
+                            }
+                            act.probing = false;
+                            actix::fut::ok(())
+                        })
+                }),
+        );
+        Ok(())
+    }
+}
+
+/// The exit's ip inside the exit tunnel, None while there's no exit to probe
+pub struct SetExitTarget(pub Option<IpAddr>);
+
+impl Message for SetExitTarget {
+    type Result = ();
+}
+
+impl Handler<SetExitTarget> for Prober {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetExitTarget, _ctx: &mut Context<Self>) -> Self::Result {
+        self.exit = msg.0;
+    }
+}
+
+/// The results for every target, ordered by kind, tunnel and then ip
+pub struct GetProbes;
+
+impl Message for GetProbes {
+    type Result = Result<Vec<ProbeHistory>, Error>;
+}
+
+impl Handler<GetProbes> for Prober {
+    type Result = Result<Vec<ProbeHistory>, Error>;
+
+    fn handle(&mut self, _: GetProbes, _ctx: &mut Context<Self>) -> Self::Result {
+        let mut probes: Vec<ProbeHistory> = self
+            .history
+            .iter()
+            .map(|(target, samples)| ProbeHistory {
+                target: target.clone(),
+                samples: samples.iter().cloned().collect(),
+            }).collect();
+        probes.sort_by(|a, b| a.target.cmp(&b.target));
+        Ok(probes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(kind: ProbeKind, iface: Option<&str>, ip: &str) -> ProbeTarget {
+        ProbeTarget {
+            kind,
+            iface: iface.map(|iface| iface.to_string()),
+            ip: ip.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let exit = target(ProbeKind::Exit, None, "172.168.1.254");
+        let peer = target(ProbeKind::Peer, Some("wg0"), "fe80::2");
+        let mut prober = Prober::new();
+        for timestamp in 0..(HISTORY_LEN as u64 + 5) {
+            prober.record(timestamp, vec![(exit.clone(), None), (peer.clone(), None)]);
+        }
+        assert_eq!(prober.history[&exit].len(), HISTORY_LEN);
+        assert_eq!(prober.history[&exit].front().unwrap().timestamp, 5);
+
+        // a peer whose tunnel went away takes its history with it
+        prober.record(100, vec![(exit.clone(), None)]);
+        assert!(!prober.history.contains_key(&peer));
+        assert_eq!(prober.history[&exit].back().unwrap().timestamp, 100);
+    }
+
+    #[test]
+    fn test_route_gateway() {
+        let route: Vec<String> = "default via 192.168.1.1 dev eth0 proto static"
+            .split_whitespace()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(route_gateway(&route), Some("192.168.1.1".parse().unwrap()));
+        assert_eq!(
+            route_gateway(&["default".to_string(), "dev".to_string()]),
+            None
+        );
+    }
+}
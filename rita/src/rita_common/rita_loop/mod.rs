@@ -15,7 +15,7 @@ use actix_utils::ResolverWrapper;
 
 use KI;
 
-use rita_common::tunnel_manager::{GetNeighbors, TriggerGC, TunnelManager};
+use rita_common::tunnel_manager::{CheckMtu, GetNeighbors, TriggerGC, TunnelManager};
 
 use rita_common::traffic_watcher::{TrafficWatcher, Watch};
 
@@ -32,6 +32,11 @@ use rita_common::dao_manager::DAOManager;
 
 use rita_common::tunnel_manager::PeersToContact;
 
+use rita_common::babel_listener::BabelListener;
+use rita_common::fraud_detector::FraudDetector;
+use rita_common::prober::Prober;
+use rita_common::stats_collector::StatsCollector;
+
 use failure::Error;
 
 use futures::Future;
@@ -39,13 +44,21 @@ use futures::Future;
 use settings::RitaCommonSettings;
 use SETTING;
 
+/// How often the firewall is compared against the rules rita declared, in case something else
+/// changed it
+const FIREWALL_CHECK_INTERVAL_SECS: u64 = 60;
+
 pub struct RitaLoop {
     was_gateway: bool,
+    last_firewall_check: Option<Instant>,
 }
 
 impl RitaLoop {
     pub fn new() -> RitaLoop {
-        RitaLoop { was_gateway: false }
+        RitaLoop {
+            was_gateway: false,
+            last_firewall_check: None,
+        }
     }
 }
 
@@ -97,6 +110,20 @@ impl Handler<Tick> for RitaLoop {
             self.was_gateway = false
         }
 
+        // the first check also clears out rules left over from before a restart
+        let firewall_due = match self.last_firewall_check {
+            Some(last_check) => {
+                last_check.elapsed() > Duration::from_secs(FIREWALL_CHECK_INTERVAL_SECS)
+            }
+            None => true,
+        };
+        if firewall_due {
+            if let Err(e) = KI.reconcile_firewall() {
+                warn!("Failed to reconcile the firewall with {:?}", e);
+            }
+            self.last_firewall_check = Some(Instant::now());
+        }
+
         let start = Instant::now();
         ctx.spawn(
             TunnelManager::from_registry()
@@ -170,6 +197,8 @@ impl Handler<Tick> for RitaLoop {
                 }).then(|_| Ok(())),
         );
 
+        TunnelManager::from_registry().do_send(CheckMtu);
+
         let start = Instant::now();
         trace!("Starting PeerListener tick");
         Arbiter::spawn(
@@ -201,6 +230,11 @@ impl Handler<Tick> for RitaLoop {
                 }).then(|_| Ok(())),
         );
 
+        BabelListener::from_registry().do_send(Tick {});
+        StatsCollector::from_registry().do_send(Tick {});
+        Prober::from_registry().do_send(Tick {});
+        FraudDetector::from_registry().do_send(Tick {});
+
         Ok(())
     }
 }
@@ -0,0 +1,141 @@
+//! StatsCollector samples the router's cpu, memory, interface counters and routes from /proc
+//! every rita_loop tick and keeps the last few minutes of samples, the dashboard serves them up
+//! so the health of a router can be checked remotely.
+
+use actix::prelude::*;
+use failure::Error;
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use althea_kernel_interface::CpuTimes;
+use althea_types::Stats;
+
+use rita_common::rita_loop::Tick;
+
+use KI;
+
+/// How many samples are kept, at one sample per tick this is ten minutes
+const HISTORY_LEN: usize = 120;
+
+pub struct StatsCollector {
+    history: VecDeque<Stats>,
+    last_cpu_times: Option<CpuTimes>,
+}
+
+impl Actor for StatsCollector {
+    type Context = Context<Self>;
+}
+
+impl Supervised for StatsCollector {}
+impl SystemService for StatsCollector {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        info!("Stats collector started");
+    }
+}
+
+impl Default for StatsCollector {
+    fn default() -> StatsCollector {
+        StatsCollector::new()
+    }
+}
+
+impl StatsCollector {
+    pub fn new() -> StatsCollector {
+        StatsCollector {
+            history: VecDeque::new(),
+            last_cpu_times: None,
+        }
+    }
+
+    fn sample(&mut self) -> Result<Stats, Error> {
+        let cpu_times = KI.get_cpu_times()?;
+        let cpu_usage = match self.last_cpu_times {
+            Some(last) => cpu_times.usage_since(&last),
+            None => None,
+        };
+        self.last_cpu_times = Some(cpu_times);
+
+        Ok(Stats {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            cpu_usage,
+            load_avg: KI.get_load_avg()?,
+            interfaces: KI.get_interface_stats()?,
+            memory: KI.get_memory_info()?,
+            routes: KI.get_route_table()?,
+        })
+    }
+
+    fn record(&mut self, stats: Stats) {
+        while self.history.len() >= HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(stats);
+    }
+}
+
+impl Handler<Tick> for StatsCollector {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: Tick, _ctx: &mut Context<Self>) -> Self::Result {
+        match self.sample() {
+            Ok(stats) => self.record(stats),
+            Err(e) => warn!("Failed to collect system stats with {:?}", e),
+        }
+        Ok(())
+    }
+}
+
+/// Every sample in the history, oldest first
+pub struct GetStats;
+
+impl Message for GetStats {
+    type Result = Result<Vec<Stats>, Error>;
+}
+
+impl Handler<GetStats> for StatsCollector {
+    type Result = Result<Vec<Stats>, Error>;
+
+    fn handle(&mut self, _: GetStats, _ctx: &mut Context<Self>) -> Self::Result {
+        Ok(self.history.iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use althea_types::{LoadAvg, MemInfo};
+    use std::collections::HashMap;
+
+    fn stats(timestamp: u64) -> Stats {
+        Stats {
+            timestamp,
+            cpu_usage: None,
+            load_avg: LoadAvg {
+                one_minute: 0.0,
+                five_minute: 0.0,
+                fifteen_minute: 0.0,
+            },
+            interfaces: HashMap::new(),
+            memory: MemInfo {
+                total: 0,
+                free: 0,
+                available: 0,
+            },
+            routes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut collector = StatsCollector::new();
+        for timestamp in 0..(HISTORY_LEN as u64 + 5) {
+            collector.record(stats(timestamp));
+        }
+        assert_eq!(collector.history.len(), HISTORY_LEN);
+        assert_eq!(collector.history.front().unwrap().timestamp, 5);
+        assert_eq!(
+            collector.history.back().unwrap().timestamp,
+            HISTORY_LEN as u64 + 4
+        );
+    }
+}
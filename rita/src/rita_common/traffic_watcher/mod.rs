@@ -1,16 +1,19 @@
 //! Traffic watcher monitors system traffic by interfacing with KernelInterface to create and check
-//! iptables and ipset counters on each per hop tunnel (the WireGuard tunnel between two devices). These counts
-//! are then stored and used to compute amounts for bills.
+//! iptables and ipset counters (or nftables counters where ipset isn't installed) on each per hop
+//! tunnel (the WireGuard tunnel between two devices). These counts are then stored and used to
+//! compute amounts for bills.
 
 use actix::prelude::*;
 use rita_common::tunnel_manager::Neighbor;
 
-use althea_kernel_interface::FilterTarget;
+use althea_kernel_interface::{CounterBackend, FilterTarget};
 use KI;
 
 use althea_types::Identity;
 
-use babel_monitor::Babel;
+use babel_monitor::BabelTable;
+
+use rita_common::babel_listener::get_babel_table;
 
 use rita_common::debt_keeper;
 use rita_common::debt_keeper::DebtKeeper;
@@ -18,8 +21,7 @@ use rita_common::debt_keeper::DebtKeeper;
 use num256::Int256;
 
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::net::IpAddr;
 
 use ipnetwork::IpNetwork;
 
@@ -28,7 +30,12 @@ use SETTING;
 
 use failure::Error;
 
-pub struct TrafficWatcher;
+use futures::Future;
+
+pub struct TrafficWatcher {
+    /// Whether a watch is still reading the counters, reads of the same counters must not overlap
+    watching: bool,
+}
 
 impl Actor for TrafficWatcher {
     type Context = Context<Self>;
@@ -38,6 +45,12 @@ impl Supervised for TrafficWatcher {}
 
 impl SystemService for TrafficWatcher {
     fn service_started(&mut self, _ctx: &mut Context<Self>) {
+        let backend = KI.detect_counter_backend();
+        if backend == CounterBackend::Nftables {
+            info!("ipset is not available, using nftables traffic counters");
+        }
+        KI.set_counter_backend(backend);
+
         KI.init_counter(&FilterTarget::Input).unwrap();
         KI.init_counter(&FilterTarget::Output).unwrap();
         KI.init_counter(&FilterTarget::ForwardInput).unwrap();
@@ -49,7 +62,7 @@ impl SystemService for TrafficWatcher {
 
 impl Default for TrafficWatcher {
     fn default() -> TrafficWatcher {
-        TrafficWatcher {}
+        TrafficWatcher { watching: false }
     }
 }
 
@@ -69,17 +82,73 @@ impl Message for Watch {
 }
 
 impl Handler<Watch> for TrafficWatcher {
-    type Result = Result<(), Error>;
+    type Result = ResponseActFuture<Self, (), Error>;
 
     fn handle(&mut self, msg: Watch, _: &mut Context<Self>) -> Self::Result {
-        let stream = TcpStream::connect::<SocketAddr>(
-            format!("[::1]:{}", SETTING.get_network().babel_port).parse()?,
-        )?;
-
-        watch(Babel::new(stream), &msg.neighbors)
+        // the traffic is left in the counters for the next watch to pick up
+        if self.watching {
+            return Box::new(actix::fut::err(format_err!(
+                "The previous watch is still running"
+            )));
+        }
+        self.watching = true;
+
+        // reading zeroes the counters, so they're only read once there are routes to price the
+        // traffic with. Without a babel table the traffic waits in the kernel for the next watch
+        Box::new(
+            get_babel_table()
+                .and_then(|babel| read_counters().map(move |counters| (counters, babel)))
+                .into_actor(self)
+                .then(move |res, act, _ctx| {
+                    act.watching = false;
+                    actix::fut::result(
+                        res.and_then(|(counters, babel)| watch(&babel, &msg.neighbors, counters)),
+                    )
+                }),
+        )
     }
 }
 
+/// One read of every traffic counter, reading zeroes them
+pub struct Counters {
+    pub input: HashMap<(IpAddr, String), u64>,
+    pub output: HashMap<(IpAddr, String), u64>,
+    pub fwd_input: HashMap<(IpAddr, String), u64>,
+    pub fwd_output: HashMap<(IpAddr, String), u64>,
+}
+
+/// Reads all the counters without blocking the actor. A read that fails leaves its traffic in
+/// the kernel for the next one, so one failure doesn't throw away the reads that succeeded
+fn read_counters() -> Box<Future<Item = Counters, Error = Error>> {
+    let read = |target: FilterTarget| {
+        KI.read_counters_async(target).then(
+            move |res| -> Result<HashMap<(IpAddr, String), u64>, Error> {
+                Ok(res.unwrap_or_else(|e| {
+                    warn!(
+                        "Error getting {:?} counters {:?} they'll be read next round",
+                        target, e
+                    );
+                    HashMap::new()
+                }))
+            },
+        )
+    };
+
+    Box::new(
+        read(FilterTarget::Input)
+            .join4(
+                read(FilterTarget::Output),
+                read(FilterTarget::ForwardInput),
+                read(FilterTarget::ForwardOutput),
+            ).map(|(input, output, fwd_input, fwd_output)| Counters {
+                input,
+                output,
+                fwd_input,
+                fwd_output,
+            }),
+    )
+}
+
 /// This traffic watcher watches how much traffic each neighbor sends to each destination
 /// between the last time watch was run, (This does _not_ block the thread)
 /// It also gathers the price to each destination from Babel and uses this information
@@ -87,11 +156,51 @@ impl Handler<Watch> for TrafficWatcher {
 ///
 /// This first time this is run, it will create the rules and then immediately read and zero them.
 /// (should return 0)
-pub fn watch<T: Read + Write>(mut babel: Babel<T>, neighbors: &Vec<Neighbor>) -> Result<(), Error> {
-    babel.start_connection()?;
+pub fn watch(
+    babel: &BabelTable,
+    neighbors: &Vec<Neighbor>,
+    counters: Counters,
+) -> Result<(), Error> {
+    let our_mesh_ip = match SETTING.get_network().mesh_ip {
+        Some(ip) => ip,
+        None => bail!("No mesh IP configured yet"),
+    };
+    let debts = collate_debts(babel, neighbors, counters, our_mesh_ip)?;
+
+    for (from, amount) in debts {
+        trace!("collated debt for {} is {}", from.mesh_ip, amount);
+
+        let update = debt_keeper::TrafficUpdate {
+            from: from.clone(),
+            amount,
+        };
+
+        DebtKeeper::from_registry().do_send(update);
+    }
+
+    // check if we are a gateway
+    let gateway = match SETTING.get_network().external_nic {
+        Some(ref external_nic) => match KI.is_iface_up(external_nic) {
+            Some(val) => val,
+            None => false,
+        },
+        None => false,
+    };
+
+    trace!("We are a Gateway: {}", gateway);
+    SETTING.get_network_mut().is_gateway = gateway;
 
-    trace!("Getting routes");
-    let routes = babel.parse_routes()?;
+    Ok(())
+}
+
+/// What each neighbor owes for the traffic in the counters, priced with the routes in `babel`
+fn collate_debts(
+    babel: &BabelTable,
+    neighbors: &Vec<Neighbor>,
+    counters: Counters,
+    our_mesh_ip: IpAddr,
+) -> Result<HashMap<Identity, Int256>, Error> {
+    let routes = babel.routes();
     info!("Got routes: {:?}", routes);
 
     let mut identities: HashMap<IpAddr, Identity> = HashMap::new();
@@ -107,7 +216,10 @@ pub fn watch<T: Read + Write>(mut babel: Babel<T>, neighbors: &Vec<Neighbor>) ->
     }
 
     let mut destinations = HashMap::new();
-    let local_fee = babel.get_local_fee().unwrap();
+    let local_fee = match babel.local_fee {
+        Some(fee) => fee,
+        None => bail!("No local fee from babel"),
+    };
 
     for route in &routes {
         // Only ip6
@@ -119,62 +231,16 @@ pub fn watch<T: Read + Write>(mut babel: Babel<T>, neighbors: &Vec<Neighbor>) ->
         }
     }
 
-    destinations.insert(
-        match SETTING.get_network().mesh_ip {
-            Some(ip) => ip,
-            None => bail!("No mesh IP configured yet"),
-        },
-        Int256::from(0),
-    );
+    destinations.insert(our_mesh_ip, Int256::from(0));
 
-    trace!("Getting input counters");
-    let input_counters = match KI.read_counters(&FilterTarget::Input) {
-        Ok(res) => res,
-        Err(e) => {
-            warn!(
-                "Error getting input counters {:?} traffic has gone unaccounted!",
-                e
-            );
-            return Err(e);
-        }
-    };
+    let Counters {
+        input: input_counters,
+        output: output_counters,
+        fwd_input: fwd_input_counters,
+        fwd_output: fwd_output_counters,
+    } = counters;
     trace!("Got input counters: {:?}", input_counters);
-
-    trace!("Getting ouput counters");
-    let output_counters = match KI.read_counters(&FilterTarget::Output) {
-        Ok(res) => res,
-        Err(e) => {
-            warn!(
-                "Error getting output counters {:?} traffic has gone unaccounted!",
-                e
-            );
-            return Err(e);
-        }
-    };
     trace!("Got output counters: {:?}", output_counters);
-
-    trace!("Getting fwd counters");
-    let fwd_input_counters = match KI.read_counters(&FilterTarget::ForwardInput) {
-        Ok(res) => res,
-        Err(e) => {
-            warn!(
-                "Error getting input counters {:?} traffic has gone unaccounted!",
-                e
-            );
-            return Err(e);
-        }
-    };
-    let fwd_output_counters = match KI.read_counters(&FilterTarget::ForwardOutput) {
-        Ok(res) => res,
-        Err(e) => {
-            warn!(
-                "Error getting input counters {:?} traffic has gone unaccounted!",
-                e
-            );
-            return Err(e);
-        }
-    };
-
     info!(
         "Got fwd counters: {:?}",
         (&fwd_input_counters, &fwd_output_counters)
@@ -286,43 +352,104 @@ pub fn watch<T: Read + Write>(mut babel: Babel<T>, neighbors: &Vec<Neighbor>) ->
         total_income
     );
 
-    for (from, amount) in debts {
-        trace!("collated debt for {} is {}", from.mesh_ip, amount);
-
-        let update = debt_keeper::TrafficUpdate {
-            from: from.clone(),
-            amount,
-        };
-
-        DebtKeeper::from_registry().do_send(update);
-    }
-
-    // check if we are a gateway
-    let gateway = match SETTING.get_network().external_nic {
-        Some(ref external_nic) => match KI.is_iface_up(external_nic) {
-            Some(val) => val,
-            None => false,
-        },
-        None => false,
-    };
-
-    trace!("We are a Gateway: {}", gateway);
-    SETTING.get_network_mut().is_gateway = gateway;
-
-    Ok(())
+    Ok(debts)
 }
 
 #[cfg(test)]
 mod tests {
-    extern crate env_logger;
+    extern crate fake_babel;
 
+    use self::fake_babel::{FakeBabel, Topology};
     use super::*;
+    use althea_types::{EthAddress, LocalIdentity};
+    use babel_monitor::Babel;
+    use futures::future::{self, Loop};
+    use rita_common::babel_listener::BabelListener;
+    use rita_common::rita_loop::Tick;
+    use std::net::TcpStream;
+    use std::time::{Duration, Instant};
+    use tokio::timer::Delay;
+
+    /// A peer at fd00::2 over wg0 with a route through it to fd00::3
+    fn topology() -> Topology {
+        Topology::new()
+            .local_fee(10)
+            .neighbor("wg0", "fe80::1", 256)
+            .route("fd00::2/128", "fe80::1", 256, 0)
+            .route("fd00::3/128", "fe80::1", 512, 100)
+    }
+
+    /// Bills some traffic with the routes from `topology`
+    fn check_debts(table: &BabelTable) {
+        let peer = Identity::new(
+            "fd00::2".parse().unwrap(),
+            EthAddress::default(),
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+                .parse()
+                .unwrap(),
+        );
+        let neighbors = vec![Neighbor {
+            identity: LocalIdentity {
+                wg_port: 60000,
+                have_tunnel: None,
+                global: peer.clone(),
+            },
+            iface_name: "wg0".to_string(),
+            tunnel_ip: "fe80::1".parse().unwrap(),
+        }];
+
+        let our_ip: IpAddr = "fd00::1".parse().unwrap();
+        let far_ip: IpAddr = "fd00::3".parse().unwrap();
+        let mut counters = Counters {
+            input: HashMap::new(),
+            output: HashMap::new(),
+            fwd_input: HashMap::new(),
+            fwd_output: HashMap::new(),
+        };
+        // traffic for us is free, traffic out through the peer is priced at the route and
+        // traffic forwarded in from it at the route plus our fee
+        counters.input.insert((our_ip, "wg0".to_string()), 5000);
+        counters.output.insert((far_ip, "wg0".to_string()), 1000);
+        counters.fwd_input.insert((far_ip, "wg0".to_string()), 10);
+
+        let debts = collate_debts(table, &neighbors, counters, our_ip).unwrap();
+        assert_eq!(debts.len(), 1);
+        assert_eq!(debts[&peer], Int256::from(100 * 1000 - 110 * 10));
+    }
+
+    #[test]
+    fn test_collate_debts() {
+        let fake = FakeBabel::start(topology()).unwrap();
+        let mut babel = Babel::new(TcpStream::connect(fake.addr()).unwrap());
+        babel.start_connection().unwrap();
+        check_debts(&BabelTable::from(babel.dump().unwrap()));
+    }
 
+    /// The table the watchers are given, built by BabelListener from a monitor session
     #[test]
-    #[ignore]
-    fn debug_babel_socket_common() {
-        env_logger::init();
-        let bm_stream = TcpStream::connect::<SocketAddr>("[::1]:9001".parse().unwrap()).unwrap();
-        watch(Babel::new(bm_stream), &Vec::new()).unwrap();
+    fn test_collate_debts_from_listener() {
+        let fake = FakeBabel::bind("[::1]:0".parse().unwrap(), topology()).unwrap();
+        SETTING.get_network_mut().babel_port = fake.addr().port();
+
+        let mut system = System::new("test_collate_debts_from_listener");
+        let table = system
+            .block_on(future::lazy(|| {
+                BabelListener::from_registry().do_send(Tick);
+                // the listener fails until it has synced with babel
+                future::loop_fn(0, |tries| {
+                    Delay::new(Instant::now() + Duration::from_millis(10))
+                        .from_err()
+                        .and_then(|_| get_babel_table())
+                        .then(move |res| match res {
+                            Ok(table) => Ok(Loop::Break(table)),
+                            Err(_) if tries < 100 => Ok(Loop::Continue(tries + 1)),
+                            Err(e) => Err(e),
+                        })
+                })
+            }))
+            .unwrap();
+
+        assert_eq!(table.local_fee, Some(10));
+        check_debts(&table);
     }
 }
@@ -4,8 +4,8 @@
 //! up tunnels if they respond, likewise if someone calls us their hello goes through network_endpoints
 //! then into TunnelManager to open a tunnel for them.
 
-use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::path::Path;
 use std::time::{Duration, Instant};
 
@@ -14,13 +14,15 @@ use actix::prelude::*;
 
 use futures::Future;
 
+use althea_kernel_interface::KernelInterfaceError;
 use althea_types::Identity;
 use althea_types::LocalIdentity;
 use KI;
 
-use babel_monitor::{Babel, Route};
+use babel_monitor::{BabelClient, InterfaceTuning, Route};
 
 use rita_common;
+use rita_common::babel_listener::{babel_client, get_babel_table};
 use rita_common::http_client::Hello;
 use rita_common::peer_listener::Peer;
 
@@ -33,7 +35,6 @@ use failure::Error;
 use actix::actors::mocker::Mocker;
 use ipnetwork::IpNetwork;
 use std::fmt;
-use std::io::{Read, Write};
 
 #[cfg(test)]
 type HTTPClient = Mocker<rita_common::http_client::HTTPClient>;
@@ -47,6 +48,12 @@ type Resolver = Mocker<resolver::Resolver>;
 #[cfg(not(test))]
 type Resolver = resolver::Resolver;
 
+/// How many other ports to try when opening a tunnel finds its port already in use
+const MAX_PORT_RETRIES: u8 = 3;
+
+/// How often each tunnel's path mtu is rediscovered, links can change underneath a tunnel
+const MTU_CHECK_INTERVAL_SECS: u64 = 600;
+
 #[derive(Debug, Fail)]
 pub enum TunnelManagerError {
     #[fail(display = "Port Error: {:?}", _0)]
@@ -62,6 +69,10 @@ pub enum TunnelAction {
     MembershipConfirmed,
     /// Membership expired for an identity
     MembershipExpired,
+    /// FraudDetector doesn't trust the identity's routes
+    FraudSuspected,
+    /// FraudDetector trusts the identity again
+    FraudCleared,
 }
 
 impl fmt::Display for TunnelAction {
@@ -75,12 +86,15 @@ impl fmt::Display for TunnelAction {
 ///
 /// State changes:
 /// NotRegistered -> MembershipConfirmed(not implemented therefore not added) -> Registered
+/// NotRegistered or Registered -> FraudSuspected -> Suspected -> FraudCleared -> NotRegistered
 #[derive(PartialEq, Debug, Clone)]
 pub enum TunnelState {
     /// Tunnel is not registered
     NotRegistered,
     /// Tunnel is registered (default)
     Registered,
+    /// Tunnel is up but babel isn't routing over it whatever the membership
+    Suspected,
 }
 
 impl fmt::Display for TunnelState {
@@ -93,6 +107,7 @@ impl fmt::Display for TunnelState {
 fn test_tunnel_state() {
     assert_eq!(TunnelState::NotRegistered.to_string(), "NotRegistered");
     assert_eq!(TunnelState::Registered.to_string(), "Registered");
+    assert_eq!(TunnelState::Suspected.to_string(), "Suspected");
 }
 
 #[derive(Debug, Clone)]
@@ -103,7 +118,12 @@ pub struct Tunnel {
     pub listen_port: u16,        // the local port this tunnel is listening on
     pub neigh_id: LocalIdentity, // the identity of the counterparty tunnel
     pub last_contact: Instant,   // When's the last we heard from the other end of this tunnel?
+    pub mtu: Option<u32>,        // the mtu we discovered and set, None until the first check
+    pub tuning: InterfaceTuning, // babel's settings for the tunnel, applied when it's monitored
+    mtu_checked: Option<Instant>,
     state: TunnelState,
+    // the state babel is being moved to, other changes wait until it gets there
+    pending: Option<TunnelState>,
 }
 
 impl Tunnel {
@@ -121,8 +141,12 @@ impl Tunnel {
             listen_port: our_listen_port,
             neigh_id: their_id.clone(),
             last_contact: Instant::now(),
+            mtu: None,
+            tuning: InterfaceTuning::default(),
+            mtu_checked: None,
             // By default new tunnels are in Registered state
             state: TunnelState::Registered,
+            pending: None,
         }
     }
 
@@ -145,20 +169,20 @@ impl Tunnel {
     }
 
     /// Register this tunnel into Babel monitor
-    pub fn monitor<T: Read + Write>(&self, stream: T) -> Result<(), Error> {
+    pub fn monitor(&self, babel: BabelClient) -> Box<Future<Item = BabelClient, Error = Error>> {
         info!("Monitoring tunnel {}", self.iface_name);
-        let mut babel = Babel::new(stream);
-        babel.start_connection()?;
-        babel.monitor(&self.iface_name)?;
-        Ok(())
+        let iface_name = self.iface_name.clone();
+        let tuning = self.tuning.clone();
+        Box::new(
+            babel
+                .monitor(&self.iface_name)
+                .and_then(move |client| client.tune(&iface_name, &tuning)),
+        )
     }
 
-    pub fn unmonitor<T: Read + Write>(&self, stream: T) -> Result<(), Error> {
+    pub fn unmonitor(&self, babel: BabelClient) -> Box<Future<Item = BabelClient, Error = Error>> {
         warn!("Unmonitoring tunnel {}", self.iface_name);
-        let mut babel = Babel::new(stream);
-        babel.start_connection()?;
-        babel.unmonitor(&self.iface_name)?;
-        Ok(())
+        babel.unmonitor(&self.iface_name)
     }
 }
 
@@ -167,6 +191,14 @@ pub struct TunnelManager {
     tunnels: HashMap<Identity, HashMap<u32, Tunnel>>,
     // maintained UDP port list (maps to true if free, else false)
     ports: HashMap<u16, bool>,
+    // babel settings for the tunnels to a node over each physical interface, kept so they're
+    // applied again to the tunnels that replace them. They don't survive a restart
+    tunings: HashMap<(Identity, u32), InterfaceTuning>,
+    // the nodes FraudDetector doesn't trust, kept so the tunnels that replace theirs start out
+    // Suspected too instead of being routed over until the next check
+    suspects: HashSet<Identity>,
+    // the connection to babeld, None while a command is using it
+    babel: Option<BabelClient>,
 }
 
 impl Actor for TunnelManager {
@@ -218,7 +250,7 @@ impl Message for IdentityCallback {
 impl Handler<IdentityCallback> for TunnelManager {
     type Result = Option<(Tunnel, bool)>;
 
-    fn handle(&mut self, msg: IdentityCallback, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: IdentityCallback, ctx: &mut Context<Self>) -> Self::Result {
         let our_port = match msg.our_port {
             Some(port) => port,
             _ => match self.get_port() {
@@ -230,7 +262,7 @@ impl Handler<IdentityCallback> for TunnelManager {
             },
         };
 
-        let res = self.open_tunnel(msg.local_identity, msg.peer, our_port);
+        let res = self.open_tunnel(msg.local_identity, msg.peer, our_port, ctx);
         match res {
             Ok(res) => Some(res),
             Err(e) => {
@@ -263,39 +295,30 @@ impl Message for GetPhyIpFromMeshIp {
     type Result = Result<IpAddr, Error>;
 }
 
-fn make_babel_stream() -> Result<TcpStream, Error> {
-    let stream = TcpStream::connect::<SocketAddr>(
-        format!("[::1]:{}", SETTING.get_network().babel_port).parse()?,
-    )?;
-    Ok(stream)
-}
-
 impl Handler<GetPhyIpFromMeshIp> for TunnelManager {
-    type Result = Result<IpAddr, Error>;
+    type Result = ResponseFuture<IpAddr, Error>;
 
     fn handle(&mut self, mesh_ip: GetPhyIpFromMeshIp, _: &mut Context<Self>) -> Self::Result {
-        let mut babel = Babel::new(make_babel_stream()?);
-        babel.start_connection()?;
-        let routes = babel.parse_routes()?;
-
-        let mut route_to_des: Option<Route> = None;
-
-        for route in routes {
-            // Only ip6
-            if let IpNetwork::V6(ref ip) = route.prefix {
-                // Only host addresses and installed routes
-                if ip.prefix() == 128 && route.installed {
-                    if IpAddr::V6(ip.ip()) == mesh_ip.0 {
-                        route_to_des = Some(route.clone());
+        Box::new(get_babel_table().and_then(move |babel| {
+            let mut route_to_des: Option<Route> = None;
+
+            for route in babel.routes() {
+                // Only ip6
+                if let IpNetwork::V6(ref ip) = route.prefix {
+                    // Only host addresses and installed routes
+                    if ip.prefix() == 128 && route.installed {
+                        if IpAddr::V6(ip.ip()) == mesh_ip.0 {
+                            route_to_des = Some(route.clone());
+                        }
                     }
                 }
             }
-        }
 
-        match route_to_des {
-            Some(route) => Ok(KI.get_wg_remote_ip(&route.iface)?),
-            None => bail!("No route found for mesh ip: {:?}", mesh_ip),
-        }
+            match route_to_des {
+                Some(route) => Ok(KI.get_wg_remote_ip(&route.iface)?),
+                None => bail!("No route found for mesh ip: {:?}", mesh_ip),
+            }
+        }))
     }
 }
 
@@ -348,21 +371,7 @@ impl Message for TriggerGC {
 
 impl Handler<TriggerGC> for TunnelManager {
     type Result = Result<(), Error>;
-    fn handle(&mut self, msg: TriggerGC, _ctx: &mut Context<Self>) -> Self::Result {
-        let stream = match make_babel_stream() {
-            Ok(stream) => stream,
-            Err(e) => {
-                warn!("Tunnel GC failed to open babel stream with {:?}", e);
-                return Err(e);
-            }
-        };
-        let mut babel = Babel::new(stream);
-        let res = babel.start_connection();
-        if res.is_err() {
-            warn!("Failed to start Babel RPC connection! {:?}", res);
-            bail!("Failed to start Babel RPC connection!");
-        }
-
+    fn handle(&mut self, msg: TriggerGC, ctx: &mut Context<Self>) -> Self::Result {
         let mut good: HashMap<Identity, HashMap<u32, Tunnel>> = HashMap::new();
         let mut timed_out: HashMap<Identity, HashMap<u32, Tunnel>> = HashMap::new();
         // Split entries into good and timed out rebuilding the double hashmap strucutre
@@ -410,23 +419,258 @@ impl Handler<TriggerGC> for TunnelManager {
         // would lead to nasty bugs in case del_interface() goes wrong for whatever reason.
         self.tunnels = good;
 
+        let mut flushes = Vec::new();
         for (_ident, tunnels) in timed_out {
             for (_ifidx, tunnel) in tunnels {
+                flushes.push(format!("flush interface {}", tunnel.iface_name));
                 // In the same spirit, we return the port to the free port pool only after tunnel
                 // deletion goes well.
-                let res = babel.unmonitor(&tunnel.iface_name);
-                if res.is_err() {
-                    warn!("Failed to unmonitor {} with {:?}", tunnel.iface_name, res);
-                }
                 KI.del_interface(&tunnel.iface_name)?;
                 self.ports.insert(tunnel.listen_port, true);
             }
         }
 
+        // babeld stops using an interface that's gone on its own, flushing it just saves
+        // waiting on that, so a babeld that's restarting doesn't hold up the GC
+        if !flushes.is_empty() {
+            let flush = self.babel_command(move |client| {
+                Box::new(client.commands(flushes).map(|(client, replies)| {
+                    for reply in replies {
+                        if let Err(e) = reply {
+                            warn!("Failed to unmonitor a tunnel with {:?}", e);
+                        }
+                    }
+                    client
+                }))
+            });
+            ctx.spawn(flush.then(|res, _act, _ctx| {
+                if let Err(e) = res {
+                    warn!("Failed to unmonitor tunnels with {:?}", e);
+                }
+                actix::fut::ok(())
+            }));
+        }
+
         Ok(())
     }
 }
 
+/// Rediscovers the mtu of every tunnel that's due a check and sets it on the tunnel, new
+/// tunnels are due straight away. Discovery runs in the background and a tunnel whose
+/// discovery fails keeps the mtu it has
+pub struct CheckMtu;
+
+impl Message for CheckMtu {
+    type Result = ();
+}
+
+impl Handler<CheckMtu> for TunnelManager {
+    type Result = ();
+
+    fn handle(&mut self, _: CheckMtu, ctx: &mut Context<Self>) -> Self::Result {
+        let interval = Duration::from_secs(MTU_CHECK_INTERVAL_SECS);
+        let mut due = Vec::new();
+        for tunnels in self.tunnels.values_mut() {
+            for tunnel in tunnels.values_mut() {
+                match tunnel.mtu_checked {
+                    Some(checked) if checked.elapsed() < interval => {}
+                    _ => {
+                        tunnel.mtu_checked = Some(Instant::now());
+                        due.push((tunnel.iface_name.clone(), tunnel.ip));
+                    }
+                }
+            }
+        }
+
+        for (iface_name, ip) in due {
+            // peers found over the internet have no neighbor entry, the route picks the device
+            let dev = KI.get_device_name(ip).ok();
+            let discovery = KI.discover_tunnel_mtu_async(ip, dev);
+            ctx.spawn(discovery.into_actor(self).then(move |res, act, _ctx| {
+                match res {
+                    Ok(mtu) => act.set_tunnel_mtu(&iface_name, mtu),
+                    Err(e) => warn!("Failed to find the mtu for {} with {:?}", iface_name, e),
+                }
+                actix::fut::ok(())
+            }));
+        }
+    }
+}
+
+impl TunnelManager {
+    /// Sets a discovered mtu, unless the tunnel closed while we were looking
+    fn set_tunnel_mtu(&mut self, iface_name: &str, mtu: u32) {
+        for tunnels in self.tunnels.values_mut() {
+            for tunnel in tunnels.values_mut() {
+                if tunnel.iface_name != iface_name || tunnel.mtu == Some(mtu) {
+                    continue;
+                }
+                match KI.set_mtu(iface_name, mtu) {
+                    Ok(()) => {
+                        info!("Set the mtu of {} to {}", iface_name, mtu);
+                        tunnel.mtu = Some(mtu);
+                    }
+                    Err(e) => warn!("Failed to set the mtu of {} with {:?}", iface_name, e),
+                }
+            }
+        }
+    }
+}
+
+/// The interface, far end and mtu of a tunnel
+#[derive(Debug, Serialize)]
+pub struct TunnelMtu {
+    pub iface_name: String,
+    pub ip: IpAddr,
+    pub mtu: Option<u32>,
+}
+
+pub struct GetTunnelMtus;
+
+impl Message for GetTunnelMtus {
+    type Result = Result<Vec<TunnelMtu>, Error>;
+}
+
+impl Handler<GetTunnelMtus> for TunnelManager {
+    type Result = Result<Vec<TunnelMtu>, Error>;
+
+    fn handle(&mut self, _: GetTunnelMtus, _: &mut Context<Self>) -> Self::Result {
+        let mut res = Vec::new();
+        for tunnels in self.tunnels.values() {
+            for tunnel in tunnels.values() {
+                res.push(TunnelMtu {
+                    iface_name: tunnel.iface_name.clone(),
+                    ip: tunnel.ip,
+                    mtu: tunnel.mtu,
+                });
+            }
+        }
+        res.sort_by(|a, b| a.iface_name.cmp(&b.iface_name));
+        Ok(res)
+    }
+}
+
+/// Changes babel's settings for a tunnel, what isn't set goes back to babel's default
+pub struct SetTunnelTuning {
+    pub iface_name: String,
+    pub tuning: InterfaceTuning,
+}
+
+impl Message for SetTunnelTuning {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<SetTunnelTuning> for TunnelManager {
+    type Result = ResponseActFuture<Self, (), Error>;
+
+    fn handle(&mut self, msg: SetTunnelTuning, _: &mut Context<Self>) -> Self::Result {
+        if let Err(e) = msg.tuning.validate() {
+            return Box::new(actix::fut::err(e));
+        }
+        let tunnel = self
+            .tunnels
+            .values()
+            .flat_map(|tunnels| tunnels.values())
+            .find(|tunnel| tunnel.iface_name == msg.iface_name)
+            .cloned();
+        let tunnel = match tunnel {
+            Some(tunnel) => tunnel,
+            None => return Box::new(actix::fut::err(format_err!("No tunnel {}", msg.iface_name))),
+        };
+        if let Some(ref pending) = tunnel.pending {
+            return Box::new(actix::fut::err(format_err!(
+                "{} is still changing to {}",
+                msg.iface_name,
+                pending
+            )));
+        }
+
+        let identity = tunnel.neigh_id.global.clone();
+        let ifidx = tunnel.listen_ifidx;
+        let change: Box<ActorFuture<Item = (), Error = Error, Actor = Self>> =
+            if tunnel.state != TunnelState::Registered {
+                // babel isn't monitoring it, the settings are applied once it is
+                Box::new(actix::fut::ok(()))
+            } else if msg.tuning.unsets(&tunnel.tuning) {
+                // babel only goes back to its defaults for an interface it's told about again.
+                // A tunnel that was unmonitored but couldn't be monitored again isn't routed
+                // over, so it's taken down like one add_tunnel couldn't monitor
+                let mut retuned = tunnel.clone();
+                retuned.tuning = msg.tuning.clone();
+                let remove = RemoveTunnel {
+                    identity: identity.clone(),
+                    ifidx,
+                    iface_name: tunnel.iface_name.clone(),
+                };
+                let unmonitor = self.babel_command(move |client| tunnel.unmonitor(client));
+                Box::new(unmonitor.then(move |res, act, _ctx| {
+                    let monitor: Box<ActorFuture<Item = (), Error = Error, Actor = Self>> =
+                        match res {
+                            Ok(_) => Box::new(
+                                act.babel_command(move |client| retuned.monitor(client))
+                                    .then(move |res, _act, _ctx| {
+                                        if let Err(ref e) = res {
+                                            error!(
+                                                "Unable to monitor {} again after retuning: {}",
+                                                remove.iface_name, e
+                                            );
+                                            TunnelManager::from_registry().do_send(remove);
+                                        }
+                                        actix::fut::result(res)
+                                    }),
+                            ),
+                            Err(e) => Box::new(actix::fut::err(e)),
+                        };
+                    monitor
+                }))
+            } else {
+                let tuning = msg.tuning.clone();
+                self.babel_command(move |client| client.tune(&tunnel.iface_name, &tuning))
+            };
+
+        Box::new(change.map(move |_, act, _ctx| {
+            info!("Tuned {} with {:?}", msg.iface_name, msg.tuning);
+            if let Some(tunnel) = act.tunnel_mut(&identity, ifidx, &msg.iface_name) {
+                tunnel.tuning = msg.tuning.clone();
+            }
+            act.tunings.insert((identity, ifidx), msg.tuning);
+        }))
+    }
+}
+
+/// The interface, far end and babel settings of a tunnel
+#[derive(Debug, Serialize)]
+pub struct TunnelTuning {
+    pub iface_name: String,
+    pub ip: IpAddr,
+    pub tuning: InterfaceTuning,
+}
+
+pub struct GetTunnelTunings;
+
+impl Message for GetTunnelTunings {
+    type Result = Result<Vec<TunnelTuning>, Error>;
+}
+
+impl Handler<GetTunnelTunings> for TunnelManager {
+    type Result = Result<Vec<TunnelTuning>, Error>;
+
+    fn handle(&mut self, _: GetTunnelTunings, _: &mut Context<Self>) -> Self::Result {
+        let mut res = Vec::new();
+        for tunnels in self.tunnels.values() {
+            for tunnel in tunnels.values() {
+                res.push(TunnelTuning {
+                    iface_name: tunnel.iface_name.clone(),
+                    ip: tunnel.ip,
+                    tuning: tunnel.tuning.clone(),
+                });
+            }
+        }
+        res.sort_by(|a, b| a.iface_name.cmp(&b.iface_name));
+        Ok(res)
+    }
+}
+
 pub struct PeersToContact {
     pub peers: HashMap<IpAddr, Peer>,
 }
@@ -525,7 +769,66 @@ impl TunnelManager {
 
         let tunnels = HashMap::new();
 
-        TunnelManager { ports, tunnels }
+        TunnelManager {
+            ports,
+            tunnels,
+            tunings: HashMap::new(),
+            suspects: HashSet::new(),
+            babel: None,
+        }
+    }
+
+    /// Runs a command on the tunnel manager's babel connection and keeps the connection for the
+    /// next one. A command that starts while another is using it gets a connection of its own,
+    /// which is only kept if the shared one was lost
+    fn babel_command<F>(
+        &mut self,
+        command: F,
+    ) -> Box<ActorFuture<Item = (), Error = Error, Actor = Self>>
+    where
+        F: FnOnce(BabelClient) -> Box<Future<Item = BabelClient, Error = Error>>,
+    {
+        let client = self.babel.take().unwrap_or_else(babel_client);
+        Box::new(command(client).into_actor(self).map(|client, act, _ctx| {
+            if act.babel.is_none() {
+                act.babel = Some(client);
+            }
+        }))
+    }
+
+    /// The tunnel to `identity` over `ifidx`, unless it has been replaced by one on another
+    /// interface since `iface_name` was looked up
+    fn tunnel_mut(
+        &mut self,
+        identity: &Identity,
+        ifidx: u32,
+        iface_name: &str,
+    ) -> Option<&mut Tunnel> {
+        self.tunnels
+            .get_mut(identity)
+            .and_then(|tunnels| tunnels.get_mut(&ifidx))
+            .and_then(|tunnel| {
+                if tunnel.iface_name == iface_name {
+                    Some(tunnel)
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Tells babel about a tunnel's new state, babel monitors the registered tunnels and only
+    /// those
+    fn babel_transition(
+        &mut self,
+        tunnel: Tunnel,
+        new_state: &TunnelState,
+    ) -> Box<ActorFuture<Item = (), Error = Error, Actor = Self>> {
+        let monitored = tunnel.state == TunnelState::Registered;
+        match (monitored, *new_state == TunnelState::Registered) {
+            (false, true) => self.babel_command(move |client| tunnel.monitor(client)),
+            (true, false) => self.babel_command(move |client| tunnel.unmonitor(client)),
+            _ => Box::new(actix::fut::ok(())),
+        }
     }
 
     /// Attempts to find a free unused UDP port by querying OS.
@@ -650,6 +953,7 @@ impl TunnelManager {
         their_localid: LocalIdentity,
         peer: Peer,
         our_port: u16,
+        ctx: &mut Context<Self>,
     ) -> Result<(Tunnel, bool), Error> {
         trace!("getting existing tunnel or opening a new one");
         // ifidx must be a part of the key so that we can open multiple tunnels
@@ -744,37 +1048,144 @@ impl TunnelManager {
             peer.ifidx,
         );
         // Create new tunnel
-        let tunnel = Tunnel::new(
+        let mut tunnel = Tunnel::new(
             peer.contact_socket.ip(),
             KI.setup_wg_if().unwrap(),
             our_port,
             peer.ifidx,
             their_localid.clone(),
         );
-        // Open tunnel
-        match tunnel.open() {
-            Ok(_) => trace!("Tunnel {:?} is open", tunnel),
-            Err(e) => {
+        if let Some(tuning) = self.tunings.get(&(key.clone(), peer.ifidx)) {
+            tunnel.tuning = tuning.clone();
+        }
+        // Open tunnel, the port we picked may have been taken by something else since we
+        // checked. Wireguard roaming lets the peer follow us to a new one
+        let mut port_retries = 0;
+        loop {
+            let e = match tunnel.open() {
+                Ok(_) => {
+                    trace!("Tunnel {:?} is open", tunnel);
+                    break;
+                }
+                Err(e) => e,
+            };
+            let address_in_use = match e.downcast_ref::<KernelInterfaceError>() {
+                Some(&KernelInterfaceError::AddressInUse(_)) => true,
+                _ => false,
+            };
+            if !address_in_use || port_retries >= MAX_PORT_RETRIES {
                 error!("Unable to open tunnel {:?}: {}", tunnel, e);
                 return Err(e);
             }
+            // the busy port stays marked as used so it isn't handed out again
+            let new_port = match self.port_query() {
+                Some(p) => p,
+                None => {
+                    return Err(
+                        TunnelManagerError::PortError("No remaining ports!".to_string()).into(),
+                    )
+                }
+            };
+            warn!(
+                "Port {} is in use, retrying tunnel {} on port {}",
+                tunnel.listen_port, tunnel.iface_name, new_port
+            );
+            tunnel.listen_port = new_port;
+            port_retries += 1;
         }
-        match tunnel.monitor(make_babel_stream()?) {
-            Ok(_) => {
-                let new_key = tunnel.neigh_id.global.clone();
-                // Add a tunnel to internal map based on identity, and interface index.
-                self.tunnels
-                    .entry(new_key)
-                    .or_insert(HashMap::new())
-                    .insert(tunnel.listen_ifidx.clone(), tunnel.clone());
-                Ok((tunnel, return_bool))
+        let tunnel = self.add_tunnel(tunnel, ctx);
+        Ok((tunnel, return_bool))
+    }
+
+    /// Stores a newly opened tunnel and has babel monitor it, unless it's to a suspect
+    fn add_tunnel(&mut self, mut tunnel: Tunnel, ctx: &mut Context<Self>) -> Tunnel {
+        let new_key = tunnel.neigh_id.global.clone();
+        if self.suspects.contains(&new_key) {
+            tunnel.state = TunnelState::Suspected;
+        }
+        let monitor = tunnel.state == TunnelState::Registered;
+        if monitor {
+            tunnel.pending = Some(TunnelState::Registered);
+        }
+        // Add a tunnel to internal map based on identity, and interface index.
+        self.tunnels
+            .entry(new_key.clone())
+            .or_insert(HashMap::new())
+            .insert(tunnel.listen_ifidx.clone(), tunnel.clone());
+        if !monitor {
+            trace!("Not monitoring suspected tunnel {}", tunnel.iface_name);
+            return tunnel;
+        }
+
+        // babel won't route over a tunnel it isn't monitoring, so one it couldn't be told about
+        // is taken down again and set up from scratch on the peer's next hello
+        let ifidx = tunnel.listen_ifidx;
+        let iface_name = tunnel.iface_name.clone();
+        let monitored = tunnel.clone();
+        let monitor = self.babel_command(move |client| monitored.monitor(client));
+        ctx.spawn(monitor.then(move |res, act, _ctx| {
+            match res {
+                Ok(_) => {
+                    if let Some(tunnel) = act.tunnel_mut(&new_key, ifidx, &iface_name) {
+                        tunnel.pending = None;
+                    }
+                }
+                Err(e) => {
+                    error!(
+                        "Unable to execute babel monitor on tunnel {}: {}",
+                        iface_name, e
+                    );
+                    TunnelManager::from_registry().do_send(RemoveTunnel {
+                        identity: new_key,
+                        ifidx,
+                        iface_name,
+                    });
+                }
             }
-            Err(e) => {
-                error!(
-                    "Unable to execute babel monitor on tunnel {:?}: {}",
-                    tunnel, e
-                );
-                Err(e)
+            actix::fut::ok(())
+        }));
+        tunnel
+    }
+}
+
+/// Takes down a tunnel babel couldn't be told to monitor
+struct RemoveTunnel {
+    identity: Identity,
+    ifidx: u32,
+    iface_name: String,
+}
+
+impl Message for RemoveTunnel {
+    type Result = ();
+}
+
+impl Handler<RemoveTunnel> for TunnelManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveTunnel, _: &mut Context<Self>) -> Self::Result {
+        let mut removed = None;
+        let mut now_empty = false;
+        if let Some(tunnels) = self.tunnels.get_mut(&msg.identity) {
+            // the tunnel may have been replaced by a new one in the meantime
+            let same = tunnels
+                .get(&msg.ifidx)
+                .map(|tunnel| tunnel.iface_name == msg.iface_name)
+                .unwrap_or(false);
+            if same {
+                removed = tunnels.remove(&msg.ifidx);
+            }
+            now_empty = tunnels.is_empty();
+        }
+        if now_empty {
+            self.tunnels.remove(&msg.identity);
+        }
+
+        if let Some(tunnel) = removed {
+            match KI.del_interface(&tunnel.iface_name) {
+                Ok(_) => {
+                    self.ports.insert(tunnel.listen_port, true);
+                }
+                Err(e) => warn!("Failed to remove tunnel {} with {:?}", tunnel.iface_name, e),
             }
         }
     }
@@ -789,59 +1200,103 @@ impl Message for TunnelStateChange {
     type Result = Result<(), Error>;
 }
 
-// Called by DAOManager to notify TunnelManager about the registration state of a given peer
+// Called by DAOManager to notify TunnelManager about the registration state of a given peer, and
+// by FraudDetector when it stops or starts trusting one
 impl Handler<TunnelStateChange> for TunnelManager {
     type Result = Result<(), Error>;
 
-    fn handle(&mut self, msg: TunnelStateChange, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: TunnelStateChange, ctx: &mut Context<Self>) -> Self::Result {
         trace!(
             "Tunnel state change request for {:?} with action {:?}",
             msg.identity,
             msg.action
         );
-        // Find a tunnel
-        match self.tunnels.get_mut(&msg.identity) {
-            Some(tunnels) => {
-                for (_, tunnel) in tunnels.iter_mut() {
-                    trace!("Handle action {} on tunnel {:?}", msg.action, tunnel);
-                    match msg.action {
-                        TunnelAction::MembershipConfirmed => {
-                            trace!(
-                                "Membership confirmed for identity {:?} returned tunnel {:?}",
-                                msg.identity,
-                                tunnel
-                            );
-                            match tunnel.state {
-                                TunnelState::NotRegistered => {
-                                    tunnel.monitor(make_babel_stream()?)?;
-                                    tunnel.state = TunnelState::Registered;
-                                }
-                                TunnelState::Registered => {
-                                    trace!("Tunnel {:?} already in registered state", tunnel);
-                                    continue;
-                                }
-                            }
-                        }
-                        TunnelAction::MembershipExpired => {
-                            trace!("Membership for identity {:?} is expired", msg.identity);
-                            match tunnel.state {
-                                TunnelState::Registered => {
-                                    tunnel.unmonitor(make_babel_stream()?)?;
-                                    tunnel.state = TunnelState::NotRegistered;
-                                }
-                                TunnelState::NotRegistered => {
-                                    trace!("Tunnel {:?} already in not registered state.", tunnel);
-                                    continue;
-                                }
-                            }
-                        }
-                    }
-                }
+        match msg.action {
+            TunnelAction::FraudSuspected => {
+                self.suspects.insert(msg.identity.clone());
             }
+            TunnelAction::FraudCleared => {
+                self.suspects.remove(&msg.identity);
+            }
+            _ => {}
+        }
+        // Find a tunnel
+        let tunnels = match self.tunnels.get(&msg.identity) {
+            Some(tunnels) => tunnels.clone(),
             None => {
                 // TODO: This should probably return error
                 warn!("Couldn't find tunnel for identity {:?}", msg.identity);
+                return Ok(());
+            }
+        };
+        let mut changes = Vec::new();
+        for (ifidx, tunnel) in tunnels.iter() {
+            trace!("Handle action {} on tunnel {:?}", msg.action, tunnel);
+            // changes to babel for one tunnel are made one at a time, the action is sent again on
+            // the next check if it's still needed once this one is done
+            if let Some(ref pending) = tunnel.pending {
+                trace!(
+                    "Tunnel {} is still changing to {}, ignoring {}",
+                    tunnel.iface_name,
+                    pending,
+                    msg.action
+                );
+                continue;
             }
+            let new_state = match (&msg.action, &tunnel.state) {
+                (TunnelAction::MembershipConfirmed, TunnelState::NotRegistered) => {
+                    trace!(
+                        "Membership confirmed for identity {:?} returned tunnel {:?}",
+                        msg.identity,
+                        tunnel
+                    );
+                    TunnelState::Registered
+                }
+                (TunnelAction::MembershipExpired, TunnelState::Registered) => {
+                    trace!("Membership for identity {:?} is expired", msg.identity);
+                    TunnelState::NotRegistered
+                }
+                (TunnelAction::FraudSuspected, TunnelState::Registered) => {
+                    warn!("Not routing over {} until it's trusted", tunnel.iface_name);
+                    TunnelState::Suspected
+                }
+                // babel isn't routing over it already, the state keeps membership from changing
+                // that
+                (TunnelAction::FraudSuspected, TunnelState::NotRegistered) => {
+                    TunnelState::Suspected
+                }
+                // the next membership check decides whether babel routes over it again
+                (TunnelAction::FraudCleared, TunnelState::Suspected) => TunnelState::NotRegistered,
+                (_, state) => {
+                    trace!("Tunnel {:?} already in {} state", tunnel, state);
+                    continue;
+                }
+            };
+            changes.push((*ifidx, tunnel.clone(), new_state));
+        }
+
+        for (ifidx, tunnel, new_state) in changes {
+            let identity = msg.identity.clone();
+            let iface_name = tunnel.iface_name.clone();
+            let change = self.babel_transition(tunnel, &new_state);
+            if let Some(tunnel) = self.tunnel_mut(&identity, ifidx, &iface_name) {
+                tunnel.pending = Some(new_state.clone());
+            }
+
+            // the state only moves once babel has, so a change that failed is tried again the
+            // next time the membership is checked
+            ctx.spawn(change.then(move |res, act, _ctx| {
+                if let Err(ref e) = res {
+                    warn!("Failed to change the state of {} with {:?}", iface_name, e);
+                }
+                if let Some(tunnel) = act.tunnel_mut(&identity, ifidx, &iface_name) {
+                    tunnel.pending = None;
+                    if res.is_ok() {
+                        tunnel.state = new_state;
+                    }
+                }
+                actix::fut::ok(())
+            }));
         }
         Ok(())
     }
@@ -912,3 +1367,64 @@ pub fn test_tunnel_manager_lookup() {
         assert_eq!(existing_tunnel.state, TunnelState::NotRegistered);
     }
 }
+
+#[test]
+pub fn test_tunnel_manager_reopen_suspect() {
+    use althea_types::EthAddress;
+    use futures::future;
+    use std::str::FromStr;
+
+    let id = Identity::new(
+        "0.0.0.0".parse().unwrap(),
+        EthAddress::from_str("ffffffffffffffffffffffffffffffffffffffff").unwrap(),
+        String::from("abc0abc1abc2abc3abc4abc5abc6abc7abc8abc9"),
+    );
+    let their_id = LocalIdentity {
+        wg_port: 65535,
+        have_tunnel: Some(false),
+        global: id.clone(),
+    };
+
+    let mut system = System::new("test_tunnel_manager_reopen_suspect");
+    system
+        .block_on(future::lazy(move || {
+            TunnelManager::create(move |ctx| {
+                let mut tunnel_manager = TunnelManager::new();
+                let mut tunnel = Tunnel::new(
+                    "0.0.0.0".parse().unwrap(),
+                    "wg0".into(),
+                    65535,
+                    0,
+                    their_id.clone(),
+                );
+                // not monitored, so blocking it doesn't need babel
+                tunnel.state = TunnelState::NotRegistered;
+                tunnel_manager
+                    .tunnels
+                    .entry(id.clone())
+                    .or_insert(HashMap::new())
+                    .insert(0, tunnel);
+                tunnel_manager
+                    .handle(
+                        TunnelStateChange {
+                            identity: id.clone(),
+                            action: TunnelAction::FraudSuspected,
+                        },
+                        ctx,
+                    ).unwrap();
+
+                // a hello without a tunnel drops the old one and opens another in its place
+                tunnel_manager.tunnels.remove(&id);
+                let reopened = tunnel_manager.add_tunnel(
+                    Tunnel::new("0.0.0.0".parse().unwrap(), "wg1".into(), 65535, 0, their_id),
+                    ctx,
+                );
+                assert_eq!(reopened.state, TunnelState::Suspected);
+                // babel was never told to monitor it
+                assert_eq!(reopened.pending, None);
+                assert_eq!(tunnel_manager.tunnels[&id][&0].state, TunnelState::Suspected);
+                tunnel_manager
+            });
+            Ok::<(), ()>(())
+        })).unwrap();
+}
@@ -14,7 +14,7 @@ use diesel::select;
 
 use reqwest;
 
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use lettre::{
@@ -83,13 +83,58 @@ impl Handler<ListClients> for DbClient {
     }
 }
 
-fn increment(address: IpAddr) -> Result<IpAddr, Error> {
-    if let IpAddr::V4(address) = address {
-        let mut oct = address.octets();
-        oct[3] += 1;
-        return Ok(oct.into());
+/// Steps to the next network of the given prefix length, for a v4 /32 that's just the next
+/// address, for a v6 /64 it's the next /64
+fn increment(address: IpAddr, prefix_len: u8) -> Result<IpAddr, Error> {
+    match address {
+        IpAddr::V4(address) => {
+            if prefix_len == 0 || prefix_len > 32 {
+                bail!("Invalid ipv4 prefix length {}", prefix_len)
+            }
+            let step = 1u32 << (32 - prefix_len);
+            match u32::from(address).checked_add(step) {
+                Some(next) => Ok(Ipv4Addr::from(next).into()),
+                None => bail!("Ran out of ipv4 addresses after {}", address),
+            }
+        }
+        IpAddr::V6(address) => {
+            if prefix_len == 0 || prefix_len > 128 {
+                bail!("Invalid ipv6 prefix length {}", prefix_len)
+            }
+            let step = 1u128 << (128 - prefix_len);
+            match u128::from(address).checked_add(step) {
+                Some(next) => Ok(Ipv6Addr::from(next).into()),
+                None => bail!("Ran out of ipv6 prefixes after {}", address),
+            }
+        }
     }
-    bail!("Not ipv4 addr")
+}
+
+#[test]
+fn test_increment() {
+    assert_eq!(
+        increment("172.16.0.5".parse().unwrap(), 32).unwrap(),
+        "172.16.0.6".parse::<IpAddr>().unwrap()
+    );
+    // rolls over into the next octet instead of overflowing
+    assert_eq!(
+        increment("172.16.0.255".parse().unwrap(), 32).unwrap(),
+        "172.16.1.0".parse::<IpAddr>().unwrap()
+    );
+    assert!(increment("255.255.255.255".parse().unwrap(), 32).is_err());
+    assert_eq!(
+        increment("2001:db8::".parse().unwrap(), 64).unwrap(),
+        "2001:db8:0:1::".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(
+        increment("2001:db8:0:ffff::".parse().unwrap(), 64).unwrap(),
+        "2001:db8:1::".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(
+        increment("2001:db8::".parse().unwrap(), 56).unwrap(),
+        "2001:db8:0:100::".parse::<IpAddr>().unwrap()
+    );
+    assert!(increment("2001:db8::".parse().unwrap(), 0).is_err());
 }
 
 #[derive(Deserialize, Debug)]
@@ -157,6 +202,9 @@ fn add_dummy(conn: &SqliteConnection) -> Result<(), Error> {
     let mut dummy = models::Client::default();
 
     dummy.internal_ip = SETTING.get_exit_network().exit_start_ip.to_string();
+    if let Some((_, start_ipv6)) = SETTING.get_exit_network().ipv6() {
+        dummy.internal_ipv6 = start_ipv6.to_string();
+    }
     dummy.mesh_ip = "0.0.0.0".to_string();
 
     match diesel::insert_into(clients).values(&dummy).execute(&*conn) {
@@ -178,7 +226,7 @@ fn incr_dummy(conn: &SqliteConnection) -> Result<IpAddr, Error> {
 
     trace!("dummy: {:?}", dummy);
 
-    let new_ip = increment(dummy.internal_ip.parse()?)?;
+    let new_ip = increment(dummy.internal_ip.parse()?, 32)?;
 
     diesel::update(clients.filter(mesh_ip.eq("0.0.0.0")))
         .set(internal_ip.eq(&new_ip.to_string()))
@@ -187,6 +235,81 @@ fn incr_dummy(conn: &SqliteConnection) -> Result<IpAddr, Error> {
     Ok(new_ip)
 }
 
+/// The ipv6 version of incr_dummy, returns None if this exit doesn't offer ipv6
+fn incr_dummy_ipv6(conn: &SqliteConnection) -> Result<Option<IpAddr>, Error> {
+    use self::schema::clients::dsl::*;
+
+    let exit_network = SETTING.get_exit_network();
+    let start_ipv6 = match exit_network.ipv6() {
+        Some((_, start_ipv6)) => start_ipv6,
+        None => return Ok(None),
+    };
+
+    add_dummy(&conn)?;
+    let dummy: models::Client = clients
+        .filter(mesh_ip.eq("0.0.0.0"))
+        .load::<models::Client>(&*conn)
+        .expect("failed loading dummy")[0]
+        .clone();
+
+    // a dummy from before ipv6 was turned on doesn't have a prefix yet
+    let last_prefix = if dummy.internal_ipv6.is_empty() {
+        start_ipv6
+    } else {
+        dummy.internal_ipv6.parse()?
+    };
+    let new_prefix = increment(last_prefix, exit_network.client_ipv6_prefix_len)?;
+
+    diesel::update(clients.filter(mesh_ip.eq("0.0.0.0")))
+        .set(internal_ipv6.eq(&new_prefix.to_string()))
+        .execute(&*conn)?;
+
+    Ok(Some(new_prefix))
+}
+
+/// Gets the ipv6 prefix for a registered client, clients that registered before ipv6 was turned
+/// on or whose prefix is outside of the current range are given a new one
+fn client_ipv6_prefix(
+    record: &models::Client,
+    conn: &SqliteConnection,
+) -> Result<Option<IpAddr>, Error> {
+    use self::schema::clients::dsl::{clients, internal_ipv6};
+
+    let exit_network = SETTING.get_exit_network();
+    let own_ipv6 = match exit_network.ipv6() {
+        Some((own_ipv6, _)) => own_ipv6,
+        None => return Ok(None),
+    };
+
+    if !record.internal_ipv6.is_empty() {
+        let current_prefix = record.internal_ipv6.parse()?;
+        let current_subnet = IpNetwork::new(own_ipv6, exit_network.ipv6_netmask)?;
+        if current_subnet.contains(current_prefix) {
+            return Ok(Some(current_prefix));
+        }
+    }
+
+    let new_prefix = incr_dummy_ipv6(conn)?;
+    if let Some(new_prefix) = new_prefix {
+        info!("Assigning {} to {}", new_prefix, record.mesh_ip);
+        diesel::update(clients.find(&record.mesh_ip))
+            .set(internal_ipv6.eq(&new_prefix.to_string()))
+            .execute(&*conn)?;
+    }
+    Ok(new_prefix)
+}
+
+fn client_details(internal_ip: IpAddr, ipv6_prefix: Option<IpAddr>) -> ExitClientDetails {
+    ExitClientDetails {
+        client_internal_ip: internal_ip,
+        client_ipv6_prefix: ipv6_prefix,
+        client_ipv6_prefix_len: match ipv6_prefix {
+            Some(_) => SETTING.get_exit_network().client_ipv6_prefix_len,
+            None => 0,
+        },
+    }
+}
+
 fn update_client(client: &ExitClientIdentity, conn: &SqliteConnection) -> Result<(), Error> {
     use self::schema::clients::dsl::{clients, email, wg_port, wg_pubkey};
     let mail_addr = match client.clone().reg_details.email {
@@ -256,6 +379,7 @@ fn client_exists(ip: &IpAddr, conn: &SqliteConnection) -> Result<bool, Error> {
 fn client_to_new_db_client(
     client: ExitClientIdentity,
     new_ip: IpAddr,
+    new_ipv6: Option<IpAddr>,
     country: String,
 ) -> models::Client {
     let mut rng = rand::thread_rng();
@@ -270,6 +394,8 @@ fn client_to_new_db_client(
         email_code: format!("{:06}", rand_code),
         verified: false,
         email_sent_time: 0,
+        internal_ipv6: new_ipv6.map(|ip| ip.to_string()).unwrap_or_default(),
+        rate_limit: 0,
     }
 }
 
@@ -372,10 +498,12 @@ impl Handler<SetupClient> for DbClient {
 
                         if verif_done(&their_record)? {
                             info!("{:?} is now registered", client);
+                            let ipv6_prefix = client_ipv6_prefix(&their_record, &conn)?;
                             Ok(ExitState::Registered {
-                                our_details: ExitClientDetails {
-                                    client_internal_ip: their_record.internal_ip.parse()?,
-                                },
+                                our_details: client_details(
+                                    their_record.internal_ip.parse()?,
+                                    ipv6_prefix,
+                                ),
                                 general_details: get_exit_info(),
                                 message: "Registration OK".to_string(),
                             })
@@ -413,6 +541,7 @@ impl Handler<SetupClient> for DbClient {
                         // first time seeing
 
                         let new_ip = incr_dummy(&conn)?;
+                        let new_ipv6 = incr_dummy_ipv6(&conn)?;
 
                         let user_country = if SETTING.get_allowed_countries().is_empty() {
                             String::new()
@@ -420,7 +549,7 @@ impl Handler<SetupClient> for DbClient {
                             get_country(&msg.1)?
                         };
 
-                        let c = client_to_new_db_client(client, new_ip, user_country);
+                        let c = client_to_new_db_client(client, new_ip, new_ipv6, user_country);
 
                         diesel::insert_into(clients).values(&c).execute(&conn)?;
 
@@ -507,11 +636,10 @@ impl Handler<ClientStatus> for DbClient {
                 }
 
                 update_client(&client, &conn)?;
+                let ipv6_prefix = client_ipv6_prefix(&their_record, &conn)?;
 
                 Ok(ExitState::Registered {
-                    our_details: ExitClientDetails {
-                        client_internal_ip: current_ip,
-                    },
+                    our_details: client_details(current_ip, ipv6_prefix),
                     general_details: get_exit_info(),
                     message: "Registration OK".to_string(),
                 })
@@ -4,11 +4,16 @@
 //! In this loop the exit checks it's database for registered users and deploys the endpoint for
 //! their exit tunnel
 
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::time::{Duration, Instant};
 
 use actix::prelude::*;
 use actix::registry::SystemService;
 
+use futures::future::join_all;
+use futures::Future;
+
 use rita_exit::db_client::{DbClient, ListClients};
 
 use rita_exit::traffic_watcher::{TrafficWatcher, Watch};
@@ -20,11 +25,20 @@ use failure::Error;
 use settings::{RitaCommonSettings, RitaExitSettings};
 use SETTING;
 
-use althea_kernel_interface::{ExitClient, KI};
+use althea_kernel_interface::{ClientRate, ExitClient, KI};
 
 use althea_types::Identity;
 
-pub struct RitaLoop;
+/// How often wg_exit's mtu is rediscovered, the paths to clients change as the mesh does
+const MTU_CHECK_INTERVAL_SECS: u64 = 600;
+
+#[derive(Default)]
+pub struct RitaLoop {
+    // the mtu that fits the path to every client, None until it has been discovered
+    wg_exit_mtu: Option<u32>,
+    // when wg_exit's mtu was last discovered
+    last_mtu_check: Option<Instant>,
+}
 
 impl Actor for RitaLoop {
     type Context = Context<Self>;
@@ -52,10 +66,50 @@ fn to_identity(client: Client) -> Identity {
     }
 }
 
+/// Download limits for every client the operator has limited
+fn to_rate_limits(clients: &[Client]) -> HashMap<IpAddr, ClientRate> {
+    let mut rates = HashMap::new();
+    for client in clients.iter().filter(|c| c.rate_limit > 0) {
+        let ip = match client.internal_ip.parse() {
+            Ok(ip) => ip,
+            Err(e) => {
+                warn!("Can't limit {}, bad internal ip {:?}", client.mesh_ip, e);
+                continue;
+            }
+        };
+        let internal_ipv6 = match SETTING.get_exit_network().ipv6() {
+            Some(_) if !client.internal_ipv6.is_empty() => match client.internal_ipv6.parse() {
+                Ok(prefix) => Some((prefix, SETTING.get_exit_network().client_ipv6_prefix_len)),
+                Err(e) => {
+                    warn!("Can't limit {} on ipv6, bad prefix {:?}", client.mesh_ip, e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        rates.insert(
+            ip,
+            ClientRate {
+                rate: client.rate_limit as u64,
+                internal_ipv6,
+            },
+        );
+    }
+    rates
+}
+
 fn to_exit_client(client: Client) -> Result<ExitClient, Error> {
+    let internal_ipv6 = match SETTING.get_exit_network().ipv6() {
+        Some(_) if !client.internal_ipv6.is_empty() => Some((
+            client.internal_ipv6.parse()?,
+            SETTING.get_exit_network().client_ipv6_prefix_len,
+        )),
+        _ => None,
+    };
     Ok(ExitClient {
         mesh_ip: client.mesh_ip.parse()?,
         internal_ip: client.internal_ip.parse()?,
+        internal_ipv6,
         port: client.wg_port.parse()?,
         public_key: client.wg_pubkey,
     })
@@ -71,7 +125,7 @@ impl Handler<Tick> for RitaLoop {
             DbClient::from_registry()
                 .send(ListClients {})
                 .into_actor(self)
-                .then(move |res, _act, _ctx| {
+                .then(move |res, act, ctx| {
                     let clients = res.unwrap().unwrap();
                     let ids = clients
                         .clone()
@@ -85,6 +139,8 @@ impl Handler<Tick> for RitaLoop {
 
                     trace!("got clients from db {:?}", clients);
 
+                    let rate_limits = to_rate_limits(&clients);
+
                     for c in clients {
                         if let Ok(c) = to_exit_client(c) {
                             wg_clients.push(c);
@@ -93,18 +149,61 @@ impl Handler<Tick> for RitaLoop {
 
                     trace!("converted clients {:?}", wg_clients);
 
+                    let mtu_due = match act.last_mtu_check {
+                        Some(checked) => {
+                            checked.elapsed() >= Duration::from_secs(MTU_CHECK_INTERVAL_SECS)
+                        }
+                        None => true,
+                    };
+                    if mtu_due && !wg_clients.is_empty() {
+                        act.last_mtu_check = Some(Instant::now());
+                        // one mtu for every client's packets, so it's the smallest of their paths
+                        let discoveries = wg_clients.iter().map(|c| {
+                            let mesh_ip = c.mesh_ip;
+                            KI.discover_tunnel_mtu_async(mesh_ip, None).then(move |res| {
+                                if let Err(ref e) = res {
+                                    warn!("Failed to find the mtu to client {} {:?}", mesh_ip, e);
+                                }
+                                Ok::<Option<u32>, Error>(res.ok())
+                            })
+                        });
+                        ctx.spawn(join_all(discoveries).into_actor(act).then(
+                            |mtus: Result<Vec<Option<u32>>, Error>, act, _ctx| {
+                                let mtu = mtus
+                                    .ok()
+                                    .and_then(|mtus| mtus.into_iter().filter_map(|m| m).min());
+                                if let Some(mtu) = mtu {
+                                    act.wg_exit_mtu = Some(mtu);
+                                    match KI.set_mtu("wg_exit", mtu) {
+                                        Ok(()) => info!("Set the mtu of wg_exit to {}", mtu),
+                                        Err(e) => warn!("Failed to set the mtu of wg_exit {:?}", e),
+                                    }
+                                }
+                                actix::fut::ok(())
+                            },
+                        ));
+                    }
+
                     let exit_status = KI.set_exit_wg_config(
                         wg_clients,
                         SETTING.get_exit_network().wg_tunnel_port,
                         &SETTING.get_network().wg_private_key_path,
                         &SETTING.get_exit_network().own_internal_ip,
                         SETTING.get_exit_network().netmask,
+                        SETTING.get_exit_network().ipv6().map(|(own_ipv6, _)| {
+                            (own_ipv6, SETTING.get_exit_network().ipv6_netmask)
+                        }),
+                        act.wg_exit_mtu,
                     );
 
                     match exit_status {
                         Ok(_) => (),
                         Err(e) => warn!("Error in Exit WG setup {:?}", e),
                     }
+
+                    if let Err(e) = KI.sync_client_rates("wg_exit", &rate_limits) {
+                        warn!("Error in Exit rate limit setup {:?}", e);
+                    }
                     info!(
                         "Rita Exit loop completed in {}s {}ms",
                         start.elapsed().as_secs(),
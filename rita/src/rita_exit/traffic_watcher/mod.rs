@@ -13,7 +13,9 @@ use althea_kernel_interface::KI;
 
 use althea_types::Identity;
 
-use babel_monitor::Babel;
+use babel_monitor::BabelTable;
+
+use rita_common::babel_listener::get_babel_table;
 
 use rita_common::debt_keeper;
 use rita_common::debt_keeper::DebtKeeper;
@@ -21,8 +23,7 @@ use rita_common::debt_keeper::DebtKeeper;
 use num256::Int256;
 
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::net::IpAddr;
 
 use ipnetwork::IpNetwork;
 
@@ -31,8 +32,13 @@ use SETTING;
 
 use failure::Error;
 
+use futures::Future;
+
 pub struct TrafficWatcher {
     last_seen_bytes: HashMap<String, WgUsage>,
+    /// Whether a watch is still running, two at once would bill each client against the same
+    /// last_seen_bytes
+    watching: bool,
 }
 
 impl Actor for TrafficWatcher {
@@ -47,6 +53,10 @@ impl SystemService for TrafficWatcher {
         }
         KI.setup_nat(&SETTING.get_network().external_nic.clone().unwrap())
             .unwrap();
+        if SETTING.get_exit_network().ipv6().is_some() {
+            KI.setup_ipv6_forwarding(&SETTING.get_network().external_nic.clone().unwrap())
+                .unwrap();
+        }
 
         info!("Traffic Watcher started");
     }
@@ -55,6 +65,7 @@ impl Default for TrafficWatcher {
     fn default() -> TrafficWatcher {
         TrafficWatcher {
             last_seen_bytes: HashMap::new(),
+            watching: false,
         }
     }
 }
@@ -66,27 +77,57 @@ impl Message for Watch {
 }
 
 impl Handler<Watch> for TrafficWatcher {
-    type Result = Result<(), Error>;
+    type Result = ResponseActFuture<Self, (), Error>;
 
     fn handle(&mut self, msg: Watch, _: &mut Context<Self>) -> Self::Result {
-        let stream = TcpStream::connect::<SocketAddr>(
-            format!("[::1]:{}", SETTING.get_network().babel_port).parse()?,
-        )?;
+        // each client is billed for the difference from last_seen_bytes, so nothing is lost
+        // by skipping a watch while one is still running
+        if self.watching {
+            return Box::new(actix::fut::err(format_err!(
+                "The previous watch is still running"
+            )));
+        }
+        self.watching = true;
 
-        watch(&mut self.last_seen_bytes, Babel::new(stream), msg.0)
+        Arbiter::spawn(KI.get_wg_exit_clients_online_async().then(|res| {
+            match res {
+                Ok(users) => info!("Total of {} users online", users),
+                Err(e) => warn!("Getting clients failed with {:?}", e),
+            }
+            Ok(())
+        }));
+
+        // without routes nothing can be billed, so wg isn't asked for the counters either
+        Box::new(
+            get_babel_table()
+                .and_then(|babel| {
+                    KI.read_wg_counters_async("wg_exit")
+                        .map_err(|e| {
+                            warn!(
+                                "Error getting input counters {:?} traffic has gone unaccounted!",
+                                e
+                            );
+                            e
+                        }).map(move |counters| (counters, babel))
+                }).into_actor(self)
+                .then(move |res, act, _ctx| {
+                    act.watching = false;
+                    actix::fut::result(res.and_then(|(counters, babel)| {
+                        watch(&mut act.last_seen_bytes, &babel, msg.0, counters)
+                    }))
+                }),
+        )
     }
 }
 
 /// This traffic watcher watches how much traffic each we send and receive from each client.
-pub fn watch<T: Read + Write>(
+pub fn watch(
     usage_history: &mut HashMap<String, WgUsage>,
-    mut babel: Babel<T>,
+    babel: &BabelTable,
     clients: Vec<Identity>,
+    counters: HashMap<String, WgUsage>,
 ) -> Result<(), Error> {
-    babel.start_connection()?;
-
-    trace!("Getting routes");
-    let routes = babel.parse_routes()?;
+    let routes = babel.routes();
     info!("Got routes: {:?}", routes);
 
     let mut identities: HashMap<String, Identity> = HashMap::new();
@@ -108,10 +149,11 @@ pub fn watch<T: Read + Write>(
 
     // insert ourselves as a destination, don't think this is actually needed
     let mut destinations = HashMap::new();
-    destinations.insert(
-        our_id.wg_public_key,
-        Int256::from(babel.get_local_fee().unwrap()),
-    );
+    let local_fee = match babel.local_fee {
+        Some(fee) => fee,
+        None => bail!("No local fee from babel"),
+    };
+    destinations.insert(our_id.wg_public_key, Int256::from(local_fee));
 
     for route in &routes {
         // Only ip6
@@ -128,17 +170,6 @@ pub fn watch<T: Read + Write>(
         }
     }
 
-    let counters = match KI.read_wg_counters("wg_exit") {
-        Ok(res) => res,
-        Err(e) => {
-            warn!(
-                "Error getting input counters {:?} traffic has gone unaccounted!",
-                e
-            );
-            return Err(e);
-        }
-    };
-
     trace!("exit counters: {:?}", counters);
 
     let mut total_in: u64 = 0;
@@ -251,11 +282,6 @@ pub fn watch<T: Read + Write>(
     }
     info!("Total exit income of {:?} Wei this round", total_income);
 
-    match KI.get_wg_exit_clients_online() {
-        Ok(users) => info!("Total of {} users online", users),
-        Err(e) => warn!("Getting clients failed with {:?}", e),
-    }
-
     for (from, amount) in debts {
         let update = debt_keeper::TrafficUpdate {
             from: from.clone(),
@@ -270,15 +296,37 @@ pub fn watch<T: Read + Write>(
 
 #[cfg(test)]
 mod tests {
-    extern crate env_logger;
+    extern crate fake_babel;
 
+    use self::fake_babel::{FakeBabel, Topology};
     use super::*;
+    use babel_monitor::Babel;
+    use std::net::TcpStream;
 
     #[test]
-    #[ignore]
-    fn debug_babel_socket_client() {
-        env_logger::init();
-        let bm_stream = TcpStream::connect::<SocketAddr>("[::1]:9001".parse().unwrap()).unwrap();
-        watch(&mut HashMap::new(), Babel::new(bm_stream), Vec::new()).unwrap();
+    fn test_watch_starts_new_counters() {
+        let fake = FakeBabel::start(
+            Topology::new()
+                .local_fee(10)
+                .neighbor("wg0", "fe80::1", 256)
+                .route("fd00::2/128", "fe80::1", 256, 0),
+        ).unwrap();
+        let mut babel = Babel::new(TcpStream::connect(fake.addr()).unwrap());
+        babel.start_connection().unwrap();
+        let table = BabelTable::from(babel.dump().unwrap());
+        SETTING.get_network_mut().mesh_ip = Some("fd00::1".parse().unwrap());
+
+        // a tunnel seen for the first time is only billed for what it does after this read
+        let mut history = HashMap::new();
+        let mut counters = HashMap::new();
+        let usage = WgUsage {
+            upload: 1000,
+            download: 5000,
+        };
+        counters.insert("client".to_string(), usage.clone());
+        watch(&mut history, &table, Vec::new(), counters).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history["client"].upload, usage.upload);
+        assert_eq!(history["client"].download, usage.download);
     }
 }
@@ -46,7 +46,7 @@ use std::time::Duration;
 use althea_kernel_interface::KernelInterface;
 
 #[cfg(not(test))]
-use althea_kernel_interface::LinuxCommandRunner;
+use althea_kernel_interface::new_kernel_interface;
 #[cfg(test)]
 use althea_kernel_interface::TestCommandRunner;
 
@@ -74,7 +74,7 @@ lazy_static! {
 
 #[cfg(not(test))]
 lazy_static! {
-    static ref KI: Box<KernelInterface> = Box::new(LinuxCommandRunner {});
+    static ref KI: Box<KernelInterface> = new_kernel_interface();
 }
 
 fn default_discovery_ip() -> Ipv6Addr {
@@ -380,6 +380,38 @@ pub struct ExitNetworkSettings {
     pub exit_start_ip: IpAddr,
     /// The netmask, in bits to mask out, for the exit tunnel
     pub netmask: u8,
+    /// This is the exit's own ipv6 address in the exit wireguard tunnel, clients are only given
+    /// ipv6 when both this and exit_start_ipv6 are set
+    #[serde(default)]
+    pub own_internal_ipv6: Option<IpAddr>,
+    /// The netmask of the ipv6 range routed to this exit, it has to cover every client prefix
+    #[serde(default = "default_ipv6_netmask")]
+    pub ipv6_netmask: u8,
+    /// This is the first ipv6 prefix delegated to clients, every new client gets the next one
+    #[serde(default)]
+    pub exit_start_ipv6: Option<IpAddr>,
+    /// The length of the prefix delegated to each client, a /64 or shorter lets the client
+    /// autoconfigure its LAN
+    #[serde(default = "default_client_ipv6_prefix_len")]
+    pub client_ipv6_prefix_len: u8,
+}
+
+fn default_ipv6_netmask() -> u8 {
+    48
+}
+
+fn default_client_ipv6_prefix_len() -> u8 {
+    64
+}
+
+impl ExitNetworkSettings {
+    /// Both ipv6 settings if the exit offers ipv6 to its clients
+    pub fn ipv6(&self) -> Option<(IpAddr, IpAddr)> {
+        match (self.own_internal_ipv6, self.exit_start_ipv6) {
+            (Some(own_ip), Some(start_ip)) => Some((own_ip, start_ip)),
+            _ => None,
+        }
+    }
 }
 
 impl Default for ExitNetworkSettings {
@@ -391,6 +423,10 @@ impl Default for ExitNetworkSettings {
             own_internal_ip: "172.16.255.254".parse().unwrap(),
             exit_start_ip: "172.16.0.0".parse().unwrap(),
             netmask: 12,
+            own_internal_ipv6: None,
+            ipv6_netmask: default_ipv6_netmask(),
+            exit_start_ipv6: None,
+            client_ipv6_prefix_len: default_client_ipv6_prefix_len(),
         }
     }
 }